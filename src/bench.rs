@@ -0,0 +1,221 @@
+//! Measuring raw transfer performance against a single peer.
+//!
+//! This drives the same [`Peer`] connection code a real download does, but keeps fetching pieces
+//! until time runs out and throws the data away once it has been checked.
+
+use crate::peer::{Peer, RequestTimings};
+use crate::piece::Piece;
+use crate::resolve::Prefer;
+use crate::torrent::Torrent;
+use anyhow::Context;
+use serde::Serialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// How long to keep downloading for.
+    pub duration: Duration,
+    /// Whether to hash each piece; turning this off takes hashing cost out of the picture.
+    pub verify: bool,
+    /// Which address family to try first if the peer is given by name.
+    pub prefer: Prefer,
+}
+
+/// What came out of a [`bench_peer`] run.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub peer: SocketAddr,
+    pub elapsed_secs: f64,
+    /// Every block byte received, including those of a piece cut short by the end of the run.
+    pub bytes: usize,
+    /// Pieces received in full.
+    pub pieces: usize,
+    /// Pieces that failed their hash check (always 0 without verification).
+    pub hash_failures: usize,
+    /// Bytes received in each whole second of the run.
+    pub bytes_per_second: Vec<usize>,
+    pub rtt_ms: Percentiles,
+    /// Requests outstanding each time a request was sent.
+    pub mean_in_flight: f64,
+    pub max_in_flight: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    fn of(mut samples: Vec<Duration>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort();
+        let at = |q: f64| {
+            let i = ((samples.len() - 1) as f64 * q).round() as usize;
+            samples[i].as_secs_f64() * 1000.0
+        };
+        Self {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: at(1.0),
+        }
+    }
+}
+
+impl Report {
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed_secs > 0.0 {
+            self.bytes as f64 / self.elapsed_secs
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} bytes ({} pieces) in {:.1}s, {:.1} KiB/s",
+            self.peer,
+            self.bytes,
+            self.pieces,
+            self.elapsed_secs,
+            self.throughput() / 1024.0
+        )?;
+        if self.hash_failures > 0 {
+            writeln!(f, "hash failures: {}", self.hash_failures)?;
+        }
+        writeln!(
+            f,
+            "request rtt: p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+            self.rtt_ms.p50, self.rtt_ms.p90, self.rtt_ms.p99, self.rtt_ms.max
+        )?;
+        writeln!(
+            f,
+            "requests in flight: mean {:.1}, max {}",
+            self.mean_in_flight, self.max_in_flight
+        )?;
+        write!(f, "throughput over time (KiB/s):")?;
+        for (second, bytes) in self.bytes_per_second.iter().enumerate() {
+            write!(f, "\n  {:>4}s {:>10.1}", second + 1, *bytes as f64 / 1024.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Download pieces from the peer at `addr` back to back until `options.duration` has passed.
+pub async fn bench_peer(t: &Torrent, host: &str, options: &BenchOptions) -> anyhow::Result<Report> {
+    let info_hash = t.info_hash()?;
+    let mut peer = Peer::connect(host, options.prefer, info_hash, t.num_pieces())
+        .await
+        .with_context(|| format!("connect to {host}"))?;
+    let addr = peer.addr();
+    peer.time_requests();
+    let have: Vec<_> = (0..t.num_pieces())
+        .filter(|&piece_i| peer.has_piece(piece_i))
+        .collect();
+    anyhow::ensure!(!have.is_empty(), "{addr} has no pieces to download");
+
+    let start = Instant::now();
+    let deadline = tokio::time::Instant::from_std(start + options.duration);
+    let mut report = Report {
+        peer: addr,
+        elapsed_secs: 0.0,
+        bytes: 0,
+        pieces: 0,
+        hash_failures: 0,
+        bytes_per_second: Vec::new(),
+        rtt_ms: Percentiles::default(),
+        mean_in_flight: 0.0,
+        max_in_flight: 0,
+    };
+    for &piece_i in have.iter().cycle() {
+        let piece = Piece::new(piece_i, t, std::slice::from_ref(&peer));
+        let bytes_per_second = &mut report.bytes_per_second;
+        let fetch = peer.fetch_piece(&piece, |len| {
+            let second = start.elapsed().as_secs() as usize;
+            if bytes_per_second.len() <= second {
+                bytes_per_second.resize(second + 1, 0);
+            }
+            bytes_per_second[second] += len;
+        });
+        let Ok(data) = tokio::time::timeout_at(deadline, fetch).await else {
+            break;
+        };
+        let data = data.with_context(|| format!("download piece {piece_i}"))?;
+        report.pieces += 1;
+        if options.verify && !t.verify_piece(piece_i, &data) {
+            report.hash_failures += 1;
+        }
+    }
+    report.elapsed_secs = start.elapsed().as_secs_f64();
+    report.bytes = report.bytes_per_second.iter().sum();
+
+    let RequestTimings { rtts, in_flight } = peer.take_timings();
+    report.rtt_ms = Percentiles::of(rtts);
+    if !in_flight.is_empty() {
+        report.mean_in_flight = in_flight.iter().sum::<usize>() as f64 / in_flight.len() as f64;
+        report.max_in_flight = in_flight.iter().copied().max().unwrap_or(0);
+    }
+    Ok(report)
+}
+
+/// Parse a duration like `30s`, `500ms`, `2m`, or a plain number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n
+        .parse()
+        .map_err(|_| format!("`{s}` doesn't start with a number"))?;
+    match unit {
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "ms" => Ok(Duration::from_millis(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        "h" => Ok(Duration::from_secs(n * 60 * 60)),
+        _ => Err(format!(
+            "unknown unit `{unit}` in `{s}` (expected ms, s, m or h)"
+        )),
+    }
+}
+
+#[test]
+fn durations() {
+    assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+    assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+    assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+    assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+    assert!(parse_duration("s").is_err());
+    assert!(parse_duration("3d").is_err());
+}
+
+#[tokio::test]
+async fn bench_against_mock_peer() {
+    use crate::mock::{self, Behaviour};
+
+    let data = mock::data(3 * 32768 + 1000);
+    let (_, peers, t) = mock::swarm(&data, 32768, vec![Behaviour::default()]).await;
+    let options = BenchOptions {
+        duration: Duration::from_millis(300),
+        verify: true,
+        prefer: Prefer::Any,
+    };
+    let report = bench_peer(&t, &peers[0].addr().to_string(), &options)
+        .await
+        .unwrap();
+    // it keeps going round the torrent until the time is up
+    assert!(report.pieces > 4, "{report}");
+    assert_eq!(report.hash_failures, 0);
+    assert!(report.bytes >= report.pieces * 1000);
+    assert!(report.rtt_ms.p50 <= report.rtt_ms.max);
+    // both blocks of a piece are asked for at once
+    assert_eq!(report.max_in_flight, 2);
+    assert!(report.elapsed_secs >= 0.3);
+}
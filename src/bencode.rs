@@ -0,0 +1,758 @@
+//! A small, span-aware reader for raw bencode.
+//!
+//! serde_bencode is what we use to get typed structs out of metainfo and tracker responses, but it
+//! can't tell us _where_ in the input something went wrong, nor hand back the exact bytes of a
+//! sub-value. This module fills those gaps.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// A decoded bencode value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    /// A human-readable name for the kind of value this is, for use in error messages.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "an integer",
+            Value::Bytes(_) => "a byte string",
+            Value::List(_) => "a list",
+            Value::Dict(_) => "a dictionary",
+        }
+    }
+
+    /// The value as JSON, the way the `decode` command prints it.
+    ///
+    /// Byte strings that are valid UTF-8 become JSON strings; any others (piece hashes, compact
+    /// peer lists) become `0x`-prefixed hex, as do dictionary keys.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Int(n) => (*n).into(),
+            Value::Bytes(bytes) => json_string(bytes).into(),
+            Value::List(values) => values.iter().map(Value::to_json).collect(),
+            Value::Dict(dict) => dict
+                .iter()
+                .map(|(key, value)| (json_string(key), value.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+
+    /// The value a JSON document stands for, undoing [`Value::to_json`].
+    ///
+    /// With `raw_hex`, strings (and keys) of `0x` and an even number of hex digits become the
+    /// bytes they spell out, so binary fields survive the round trip; ordinary text that happens
+    /// to look like that would too, which is why it's optional.
+    pub fn from_json(json: &serde_json::Value, raw_hex: bool) -> anyhow::Result<Self> {
+        use serde_json::Value as Json;
+        Ok(match json {
+            Json::Number(n) => Value::Int(
+                n.as_i64()
+                    .ok_or_else(|| anyhow::anyhow!("bencode only has integers, not {n}"))?,
+            ),
+            Json::String(s) => Value::Bytes(json_bytes(s, raw_hex)),
+            Json::Array(values) => Value::List(
+                values
+                    .iter()
+                    .map(|value| Value::from_json(value, raw_hex))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            Json::Object(map) => Value::Dict(
+                map.iter()
+                    .map(|(key, value)| {
+                        Ok((json_bytes(key, raw_hex), Value::from_json(value, raw_hex)?))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            Json::Bool(_) | Json::Null => anyhow::bail!("bencode has no equivalent of {json}"),
+        })
+    }
+}
+
+fn json_bytes(s: &str, raw_hex: bool) -> Vec<u8> {
+    s.strip_prefix("0x")
+        .filter(|_| raw_hex)
+        .and_then(|digits| hex::decode(digits).ok())
+        .unwrap_or_else(|| s.as_bytes().to_vec())
+}
+
+fn json_string(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => format!("0x{}", hex::encode(bytes)),
+    }
+}
+
+/// Values round-trip through serde_bencode unchanged, so that typed structs can carry along keys
+/// they don't model.
+impl serde::Serialize for Value {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        match self {
+            Value::Int(n) => serializer.serialize_i64(*n),
+            Value::Bytes(bytes) => serializer.serialize_bytes(bytes),
+            Value::List(values) => serializer.collect_seq(values),
+            Value::Dict(dict) => {
+                let mut map = serializer.serialize_map(Some(dict.len()))?;
+                for (key, value) in dict {
+                    map.serialize_entry(serde_bytes::Bytes::new(key), value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Value {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> serde::de::Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a bencode value")
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Int(v))
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Value, E> {
+        i64::try_from(v)
+            .map(Value::Int)
+            .map_err(|_| E::custom(format!("integer {v} is out of range")))
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(Value::Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::Bytes(v))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::Bytes(v.as_bytes().to_vec()))
+    }
+
+    fn visit_string<E: serde::de::Error>(self, v: String) -> Result<Value, E> {
+        Ok(Value::Bytes(v.into_bytes()))
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut values = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Value::List(values))
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut dict = BTreeMap::new();
+        while let Some((key, value)) = map.next_entry::<serde_bytes::ByteBuf, Value>()? {
+            dict.insert(key.into_vec(), value);
+        }
+        Ok(Value::Dict(dict))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{reason} at byte {offset}")]
+pub struct Error {
+    /// Offset into the input at which the problem was found.
+    pub offset: usize,
+    pub reason: String,
+}
+
+/// How deeply lists and dictionaries may nest before [`decode`] gives up, so that a few kilobytes
+/// of `l`s can't overflow the stack.
+pub const MAX_DEPTH: usize = 64;
+
+/// Decode the value at the start of `input`, returning it along with whatever follows it.
+pub fn decode(input: &[u8]) -> Result<(Value, &[u8]), Error> {
+    decode_with_depth(input, MAX_DEPTH)
+}
+
+/// Like [`decode`], but with lists and dictionaries nesting at most `max_depth` deep.
+pub fn decode_with_depth(input: &[u8], max_depth: usize) -> Result<(Value, &[u8]), Error> {
+    let mut decoder = Decoder::new(input);
+    decoder.max_depth = max_depth;
+    let value = decoder.value()?;
+    Ok((value, &input[decoder.pos..]))
+}
+
+/// Decode `input`, which must hold exactly one value and nothing after it.
+pub fn decode_all(input: &[u8]) -> Result<Value, Error> {
+    let (value, rest) = decode(input)?;
+    if !rest.is_empty() {
+        return Err(Error {
+            offset: input.len() - rest.len(),
+            reason: format!("{} bytes of trailing data after the value", rest.len()),
+        });
+    }
+    Ok(value)
+}
+
+/// Encode `value` as canonical bencode: dictionary keys in raw byte order, and integers in their
+/// shortest form.
+///
+/// The same value always encodes to the same bytes, which is what anything writing metainfo
+/// needs.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out);
+    out
+}
+
+/// Like [`encode`], but appending to `out`.
+pub fn encode_into(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Int(n) => out.extend(format!("i{n}e").bytes()),
+        Value::Bytes(bytes) => encode_bytes(bytes, out),
+        Value::List(values) => {
+            out.push(b'l');
+            for value in values {
+                encode_into(value, out);
+            }
+            out.push(b'e');
+        }
+        Value::Dict(dict) => {
+            // a BTreeMap over the raw keys is already in canonical order
+            out.push(b'd');
+            for (key, value) in dict {
+                encode_bytes(key, out);
+                encode_into(value, out);
+            }
+            out.push(b'e');
+        }
+    }
+}
+
+/// Append `bytes` as a bencode byte string.
+pub fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend(format!("{}:", bytes.len()).bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// Turn anything serde can serialize into a [`Value`], which puts it in canonical order no matter
+/// how its fields were declared or flattened.
+pub fn to_value<T: serde::Serialize>(value: &T) -> Result<Value, serde_bencode::Error> {
+    let bytes = serde_bencode::to_bytes(value)?;
+    let (value, _) = decode(&bytes)
+        .map_err(|e| serde_bencode::Error::Custom(format!("serde_bencode produced {e}")))?;
+    // reordering keys never changes the encoded length, but quietly merging duplicates does
+    if encode(&value).len() != bytes.len() {
+        return Err(serde_bencode::Error::Custom(String::from(
+            "value serializes with duplicate dictionary keys",
+        )));
+    }
+    Ok(value)
+}
+
+/// A bencode value borrowed from the input it was decoded from.
+///
+/// Byte strings point into the input rather than being copied out of it, and lists and
+/// dictionaries are only walked when iterated over, so picking a few fields (even a huge
+/// `pieces` string) out of a metainfo file allocates nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueRef<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(ListRef<'a>),
+    Dict(DictRef<'a>),
+}
+
+impl<'a> ValueRef<'a> {
+    /// A copy of the value that no longer borrows the input.
+    pub fn into_owned(self) -> Value {
+        match self {
+            ValueRef::Int(n) => Value::Int(n),
+            ValueRef::Bytes(bytes) => Value::Bytes(bytes.to_vec()),
+            ValueRef::List(list) => Value::List(list.iter().map(ValueRef::into_owned).collect()),
+            ValueRef::Dict(dict) => Value::Dict(
+                dict.iter()
+                    .map(|(key, value)| (key.to_vec(), value.into_owned()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The exact bytes the value was decoded from.
+    pub fn raw(&self) -> Option<&'a [u8]> {
+        match self {
+            ValueRef::List(ListRef { raw }) | ValueRef::Dict(DictRef { raw }) => Some(raw),
+            ValueRef::Int(_) | ValueRef::Bytes(_) => None,
+        }
+    }
+}
+
+/// A list within some input, already checked to be well-formed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListRef<'a> {
+    raw: &'a [u8],
+}
+
+impl<'a> ListRef<'a> {
+    pub fn iter(&self) -> impl Iterator<Item = ValueRef<'a>> {
+        let mut decoder = Decoder::new(self.raw);
+        decoder.pos = 1;
+        std::iter::from_fn(move || {
+            (decoder.peek().ok()? != b'e')
+                .then(|| decoder.value_ref().expect("checked when decoded"))
+        })
+    }
+}
+
+/// A dictionary within some input, already checked to be well-formed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DictRef<'a> {
+    raw: &'a [u8],
+}
+
+impl<'a> DictRef<'a> {
+    /// The entries in the order they appear in the input.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], ValueRef<'a>)> {
+        let mut decoder = Decoder::new(self.raw);
+        decoder.pos = 1;
+        std::iter::from_fn(move || {
+            (decoder.peek().ok()? != b'e').then(|| {
+                let key = decoder.bytes_ref().expect("checked when decoded");
+                (key, decoder.value_ref().expect("checked when decoded"))
+            })
+        })
+    }
+
+    /// The value of the first entry with `key`.
+    pub fn get(&self, key: &[u8]) -> Option<ValueRef<'a>> {
+        self.iter().find(|&(k, _)| k == key).map(|(_, value)| value)
+    }
+}
+
+/// Like [`decode`], but borrowing from `input` instead of copying out of it.
+pub fn decode_ref(input: &[u8]) -> Result<(ValueRef<'_>, &[u8]), Error> {
+    let mut decoder = Decoder::new(input);
+    let value = decoder.value_ref()?;
+    Ok((value, &input[decoder.pos..]))
+}
+
+/// The keys of a dictionary, each with the byte range of its value.
+pub type DictSpans = Vec<(Vec<u8>, Range<usize>)>;
+
+/// Split the dictionary at the start of `input` into its keys and the byte ranges of their values.
+///
+/// The ranges are relative to `input`, and keys are returned in the order they appear.
+pub fn dict_spans(input: &[u8]) -> Result<DictSpans, Error> {
+    let mut decoder = Decoder::new(input);
+    decoder.expect(b'd', "a dictionary")?;
+    let mut entries = Vec::new();
+    while decoder.peek()? != b'e' {
+        let key = decoder.bytes()?;
+        let start = decoder.pos;
+        decoder.value()?;
+        entries.push((key, start..decoder.pos));
+    }
+    Ok(entries)
+}
+
+/// Split the list at the start of `input` into the byte ranges of its items.
+///
+/// The ranges are relative to `input`.
+pub fn list_spans(input: &[u8]) -> Result<Vec<Range<usize>>, Error> {
+    let mut decoder = Decoder::new(input);
+    decoder.expect(b'l', "a list")?;
+    let mut items = Vec::new();
+    while decoder.peek()? != b'e' {
+        let start = decoder.pos;
+        decoder.value()?;
+        items.push(start..decoder.pos);
+    }
+    Ok(items)
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    /// How many lists and dictionaries we are inside of.
+    depth: usize,
+    max_depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pos: 0,
+            depth: 0,
+            max_depth: MAX_DEPTH,
+        }
+    }
+
+    /// Step into a list or dictionary, unless that would nest too deeply.
+    fn descend(&mut self) -> Result<(), Error> {
+        if self.depth == self.max_depth {
+            return Err(self.error(format!("nested more than {} levels deep", self.max_depth)));
+        }
+        self.depth += 1;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn error(&self, reason: impl Into<String>) -> Error {
+        Error {
+            offset: self.pos,
+            reason: reason.into(),
+        }
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.input
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| self.error("unexpected end of input"))
+    }
+
+    fn expect(&mut self, byte: u8, what: &str) -> Result<(), Error> {
+        if self.peek()? != byte {
+            return Err(self.error(format!("expected {what}")));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Consume bytes up to (and including) `end`, returning the ones before it.
+    fn until(&mut self, end: u8) -> Result<&'a [u8], Error> {
+        let rest = &self.input[self.pos..];
+        let n = rest
+            .iter()
+            .position(|&b| b == end)
+            .ok_or_else(|| self.error(format!("missing terminating '{}'", end as char)))?;
+        self.pos += n + 1;
+        Ok(&rest[..n])
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        match self.peek()? {
+            b'i' => self.int().map(Value::Int),
+            b'0'..=b'9' => self.bytes().map(Value::Bytes),
+            b'l' => {
+                self.descend()?;
+                let mut items = Vec::new();
+                while self.peek()? != b'e' {
+                    items.push(self.value()?);
+                }
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(Value::List(items))
+            }
+            b'd' => {
+                self.descend()?;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key_at = self.pos;
+                    let key = self.bytes().map_err(|mut e| {
+                        e.offset = key_at;
+                        e.reason = String::from("dictionary keys must be byte strings");
+                        e
+                    })?;
+                    let value = self.value()?;
+                    dict.insert(key, value);
+                }
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(Value::Dict(dict))
+            }
+            b => Err(self.error(format!("unexpected byte {:?}", b as char))),
+        }
+    }
+
+    fn value_ref(&mut self) -> Result<ValueRef<'a>, Error> {
+        let start = self.pos;
+        match self.peek()? {
+            b'i' => self.int().map(ValueRef::Int),
+            b'0'..=b'9' => self.bytes_ref().map(ValueRef::Bytes),
+            b'l' => {
+                self.descend()?;
+                while self.peek()? != b'e' {
+                    self.value_ref()?;
+                }
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(ValueRef::List(ListRef {
+                    raw: &self.input[start..self.pos],
+                }))
+            }
+            b'd' => {
+                self.descend()?;
+                while self.peek()? != b'e' {
+                    let key_at = self.pos;
+                    self.bytes_ref().map_err(|mut e| {
+                        e.offset = key_at;
+                        e.reason = String::from("dictionary keys must be byte strings");
+                        e
+                    })?;
+                    self.value_ref()?;
+                }
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(ValueRef::Dict(DictRef {
+                    raw: &self.input[start..self.pos],
+                }))
+            }
+            b => Err(self.error(format!("unexpected byte {:?}", b as char))),
+        }
+    }
+
+    fn int(&mut self) -> Result<i64, Error> {
+        let start = self.pos;
+        self.expect(b'i', "an integer")?;
+        let digits = self.until(b'e')?;
+        let malformed = || Error {
+            offset: start,
+            reason: String::from("malformed integer"),
+        };
+        let unsigned = digits.strip_prefix(b"-").unwrap_or(digits);
+        if unsigned.is_empty()
+            || !unsigned.iter().all(u8::is_ascii_digit)
+            || (unsigned.len() > 1 && unsigned[0] == b'0')
+            || digits == b"-0"
+        {
+            return Err(malformed());
+        }
+        std::str::from_utf8(digits)
+            .expect("all ascii digits")
+            .parse()
+            .map_err(|_| malformed())
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        self.bytes_ref().map(<[u8]>::to_vec)
+    }
+
+    fn bytes_ref(&mut self) -> Result<&'a [u8], Error> {
+        let start = self.pos;
+        if !self.peek()?.is_ascii_digit() {
+            return Err(self.error("expected a byte string"));
+        }
+        let len = self.until(b':')?;
+        let len: usize = std::str::from_utf8(len)
+            .ok()
+            .filter(|len| len.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|len| len.parse().ok())
+            .ok_or(Error {
+                offset: start,
+                reason: String::from("malformed byte string length"),
+            })?;
+        let bytes = self
+            .input
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or(Error {
+                offset: start,
+                reason: format!("byte string of length {len} runs past the end of the input"),
+            })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+#[test]
+fn decode_nested() {
+    let (value, rest) = decode(b"d3:cow3:moo4:spaml1:ai-3eee!").unwrap();
+    assert_eq!(rest, b"!");
+    let Value::Dict(dict) = value else {
+        panic!("not a dict");
+    };
+    assert_eq!(dict[&b"cow"[..]], Value::Bytes(b"moo".to_vec()));
+    assert_eq!(
+        dict[&b"spam"[..]],
+        Value::List(vec![Value::Bytes(b"a".to_vec()), Value::Int(-3)])
+    );
+}
+
+#[test]
+fn decode_errors_carry_offsets() {
+    assert_eq!(decode(b"li03ee").unwrap_err().offset, 1);
+    assert_eq!(decode(b"l5:abce").unwrap_err().offset, 1);
+    assert_eq!(decode(b"d1:a").unwrap_err().offset, 4);
+}
+
+#[test]
+fn malformed_input_is_an_error() {
+    let error = |input: &[u8]| decode_all(input).unwrap_err().to_string();
+    assert_eq!(error(b"i-0e"), "malformed integer at byte 0");
+    assert_eq!(error(b"i03e"), "malformed integer at byte 0");
+    assert_eq!(error(b"ie"), "malformed integer at byte 0");
+    assert_eq!(error(b"i12"), "missing terminating 'e' at byte 1");
+    assert_eq!(error(b"l"), "unexpected end of input at byte 1");
+    assert_eq!(error(b"li1e"), "unexpected end of input at byte 4");
+    assert_eq!(
+        error(b"5:abc"),
+        "byte string of length 5 runs past the end of the input at byte 0"
+    );
+    assert_eq!(error(b"-1:a"), "unexpected byte '-' at byte 0");
+    assert_eq!(
+        error(b"di1ei2ee"),
+        "dictionary keys must be byte strings at byte 1"
+    );
+    assert_eq!(
+        error(b"i1ei2e"),
+        "3 bytes of trailing data after the value at byte 3"
+    );
+    assert_eq!(error(b""), "unexpected end of input at byte 0");
+
+    // deep nesting stops at the limit rather than overflowing the stack
+    let deep = [b'l'; 10_000];
+    assert_eq!(
+        error(&deep),
+        format!("nested more than {MAX_DEPTH} levels deep at byte {MAX_DEPTH}")
+    );
+    let nested = |n| [vec![b'l'; n], vec![b'e'; n]].concat();
+    assert!(decode_with_depth(&nested(3), 3).is_ok());
+    assert!(decode_with_depth(&nested(4), 3).is_err());
+}
+
+#[test]
+fn borrowed_values_point_into_the_input() {
+    // a metainfo file with 100k pieces, whose 2 MB of hashes must never be copied
+    let npieces = 100_000;
+    let mut input = b"d8:announce3:url4:infod6:lengthi1e4:name1:x6:pieces".to_vec();
+    input.extend(format!("{}:", 20 * npieces).bytes());
+    input.extend((0..20 * npieces).map(|i| i as u8));
+    input.extend(b"ee");
+
+    let (ValueRef::Dict(torrent), rest) = decode_ref(&input).unwrap() else {
+        panic!("not a dict");
+    };
+    assert!(rest.is_empty());
+    assert_eq!(torrent.get(b"announce"), Some(ValueRef::Bytes(b"url")));
+    let Some(ValueRef::Dict(info)) = torrent.get(b"info") else {
+        panic!("no info dict");
+    };
+    let Some(ValueRef::Bytes(pieces)) = info.get(b"pieces") else {
+        panic!("no pieces");
+    };
+    assert_eq!(pieces.len(), 20 * npieces);
+    assert!(input.as_ptr_range().contains(&pieces.as_ptr()));
+    assert!(pieces
+        .chunks(20)
+        .all(|hash| input.as_ptr_range().contains(&hash.as_ptr())));
+    assert_eq!(
+        info.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+        [&b"length"[..], b"name", b"pieces"]
+    );
+    // the raw info dict is exactly what hashes to the info hash
+    let raw = ValueRef::Dict(info).raw().unwrap();
+    assert_eq!(
+        raw,
+        &input[b"d8:announce3:url4:info".len()..input.len() - 1]
+    );
+
+    // and borrowed or owned, it's the same value
+    let (list, _) = decode_ref(b"l1:ai-3ed1:xleee").unwrap();
+    assert_eq!(list.into_owned(), decode(b"l1:ai-3ed1:xleee").unwrap().0);
+    assert_eq!(
+        decode_ref(b"l1:a").unwrap_err(),
+        decode(b"l1:a").unwrap_err()
+    );
+}
+
+#[test]
+fn spans_of_dict_values() {
+    let input = b"d1:ai1e4:infod1:xi2eee";
+    let spans = dict_spans(input).unwrap();
+    assert_eq!(spans[0], (b"a".to_vec(), 4..7));
+    assert_eq!(spans[1].0, b"info");
+    assert_eq!(&input[spans[1].1.clone()], b"d1:xi2ee");
+}
+
+#[test]
+fn encode_is_canonical() {
+    let mut dict = BTreeMap::new();
+    dict.insert(b"zebra".to_vec(), Value::Int(-7));
+    dict.insert(b"Zebra".to_vec(), Value::Int(0));
+    dict.insert(
+        b"list".to_vec(),
+        Value::List(vec![Value::Bytes(b"".to_vec()), Value::Int(10)]),
+    );
+    let bytes = encode(&Value::Dict(dict));
+    assert_eq!(bytes, b"d5:Zebrai0e4:listl0:i10ee5:zebrai-7ee");
+    assert_eq!(encode(&decode(&bytes).unwrap().0), bytes);
+
+    // an unsorted dictionary comes back sorted
+    let (value, _) = decode(b"d1:bi1e1:ai2ee").unwrap();
+    assert_eq!(encode(&value), b"d1:ai2e1:bi1ee");
+
+    #[derive(serde::Serialize)]
+    struct Backwards {
+        z: u8,
+        a: &'static str,
+    }
+    assert_eq!(
+        encode(&to_value(&Backwards { z: 1, a: "x" }).unwrap()),
+        b"d1:a1:x1:zi1ee"
+    );
+}
+
+#[test]
+fn json_for_text_and_binary() {
+    let json = |input: &[u8]| decode(input).unwrap().0.to_json().to_string();
+    assert_eq!(json(b"5:hello"), r#""hello""#);
+    assert_eq!(json(b"i-52e"), "-52");
+    // the length counts bytes, not characters
+    assert_eq!(json("l6:héllo1:xe".as_bytes()), r#"["héllo","x"]"#);
+    assert_eq!(
+        json(b"d1:a1:x2:\xff\x002:\xfe\x01e"),
+        r#"{"0xff00":"0xfe01","a":"x"}"#
+    );
+
+    // a real torrent, hashes and all
+    let Value::Dict(sample) = decode(include_bytes!("../sample.torrent")).unwrap().0 else {
+        panic!("not a dict");
+    };
+    let sample = Value::Dict(sample).to_json();
+    assert_eq!(
+        sample["announce"],
+        "http://bittorrent-test-tracker.codecrafters.io/announce"
+    );
+    assert_eq!(sample["info"]["length"], 92063);
+    let pieces = sample["info"]["pieces"].as_str().unwrap();
+    assert!(pieces.starts_with("0xe876f67a2a8886e8"), "{pieces}");
+    assert_eq!(pieces.len(), 2 + 2 * 60);
+}
+
+#[test]
+fn json_round_trips() {
+    let original = include_bytes!("../sample.torrent");
+    let json = decode(original).unwrap().0.to_json();
+    let value = Value::from_json(&json, true).unwrap();
+    assert_eq!(encode(&value), original);
+    // without raw_hex, the pieces stay the text they were printed as
+    let Value::Dict(info) = &Value::from_json(&json["info"], false).unwrap() else {
+        panic!("not a dict");
+    };
+    assert_eq!(
+        info[&b"pieces"[..]],
+        Value::Bytes(json["info"]["pieces"].as_str().unwrap().into())
+    );
+
+    let json: serde_json::Value = serde_json::from_str(r#"{"b": [1, "0xzz"], "a": -3}"#).unwrap();
+    assert_eq!(
+        encode(&Value::from_json(&json, true).unwrap()),
+        b"d1:ai-3e1:bli1e4:0xzzee"
+    );
+    for bad in ["1.5", "true", "[null]"] {
+        let json = serde_json::from_str(bad).unwrap();
+        assert!(Value::from_json(&json, false).is_err(), "{bad}");
+    }
+}
@@ -0,0 +1,288 @@
+//! Deciding which peers to unchoke.
+//!
+//! While leeching we reciprocate: the peers that give us the most data get upload slots. Once we
+//! are seeding there is nothing to reciprocate, so slots go to the peers we can upload to fastest
+//! instead, with one slot rotating round-robin so that slow or far-away peers get a turn too.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Peers unchoked at any one time, including the rotating slot.
+pub const UNCHOKE_SLOTS: usize = 4;
+
+/// Rechoke rounds the rotating slot stays with one peer before moving on.
+pub const ROTATE_EVERY: u64 = 3;
+
+/// How long a seed waits for an unchoked peer to request something before choking it again.
+pub const REQUEST_IDLE: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// We still want data, so reward the peers that send it.
+    Leech,
+    /// We have everything, so spend upload slots where they go furthest.
+    Seed,
+}
+
+/// What we currently know about a connected peer.
+#[derive(Debug, Clone)]
+pub struct Observation {
+    pub addr: SocketAddr,
+    /// Whether the peer has told us it is interested.
+    pub interested: bool,
+    /// When the peer last sent us a Request.
+    pub last_request: Option<Instant>,
+    /// Bytes per second the peer has recently been sending us.
+    pub download_rate: f64,
+    /// Bytes per second we have recently been sending the peer.
+    pub upload_rate: f64,
+}
+
+/// The choking state of a single torrent's peers.
+#[derive(Debug)]
+pub struct Choker {
+    mode: Mode,
+    slots: usize,
+    round: u64,
+    unchoked: BTreeSet<SocketAddr>,
+    /// When each currently unchoked peer was unchoked.
+    unchoked_since: HashMap<SocketAddr, Instant>,
+    /// The round each peer last held the rotating slot in.
+    last_rotated: HashMap<SocketAddr, u64>,
+    rotating: Option<SocketAddr>,
+}
+
+impl Choker {
+    pub fn new(mode: Mode) -> Self {
+        Self::with_slots(mode, UNCHOKE_SLOTS)
+    }
+
+    pub fn with_slots(mode: Mode, slots: usize) -> Self {
+        Self {
+            mode,
+            slots: slots.max(1),
+            round: 0,
+            unchoked: BTreeSet::new(),
+            unchoked_since: HashMap::new(),
+            last_rotated: HashMap::new(),
+            rotating: None,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// The download finished; from now on we hand out slots as a seed.
+    pub fn completed(&mut self) {
+        if self.mode != Mode::Seed {
+            self.mode = Mode::Seed;
+            // the peers we were reciprocating with have no claim on us any more
+            self.rotating = None;
+        }
+    }
+
+    pub fn is_unchoked(&self, addr: SocketAddr) -> bool {
+        self.unchoked.contains(&addr)
+    }
+
+    /// Unchoke `addr` right away if a slot is free, rather than have it wait for the next round.
+    /// Returns whether it is unchoked.
+    pub fn unchoke_if_free(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if !self.unchoked.contains(&addr) && self.unchoked.len() < self.slots {
+            self.unchoked.insert(addr);
+            self.unchoked_since.insert(addr, now);
+        }
+        self.unchoked.contains(&addr)
+    }
+
+    /// `addr` went away, freeing its slot.
+    pub fn disconnected(&mut self, addr: SocketAddr) {
+        self.unchoked.remove(&addr);
+        self.unchoked_since.remove(&addr);
+        if self.rotating == Some(addr) {
+            self.rotating = None;
+        }
+    }
+
+    /// Run one rechoke round over the currently connected `peers`, returning the ones that should
+    /// be unchoked; everyone else should be choked.
+    pub fn rechoke(&mut self, peers: &[Observation], now: Instant) -> BTreeSet<SocketAddr> {
+        self.round += 1;
+        let eligible: Vec<&Observation> = peers
+            .iter()
+            .filter(|p| p.interested && !self.idle(p, now))
+            .collect();
+
+        let rate = |p: &Observation| match self.mode {
+            Mode::Leech => p.download_rate,
+            Mode::Seed => p.upload_rate,
+        };
+        let mut ranked = eligible.clone();
+        ranked.sort_by(|a, b| rate(b).total_cmp(&rate(a)).then(a.addr.cmp(&b.addr)));
+
+        // the rotating slot keeps its peer for a few rounds, as long as that peer still wants it
+        let rotation_due = self.round % ROTATE_EVERY == 1;
+        let keep = self
+            .rotating
+            .filter(|addr| !rotation_due && eligible.iter().any(|p| p.addr == *addr));
+
+        let mut unchoke: BTreeSet<SocketAddr> = ranked
+            .iter()
+            .map(|p| p.addr)
+            .filter(|addr| Some(*addr) != keep)
+            .take(self.slots - 1)
+            .collect();
+
+        self.rotating = keep.or_else(|| {
+            // round-robin: whoever has waited longest for the slot (or never had it) goes next
+            eligible
+                .iter()
+                .filter(|p| !unchoke.contains(&p.addr))
+                .min_by_key(|p| (self.last_rotated.get(&p.addr).copied(), p.addr))
+                .map(|p| p.addr)
+        });
+        if let Some(addr) = self.rotating {
+            self.last_rotated.insert(addr, self.round);
+            unchoke.insert(addr);
+        }
+
+        self.unchoked_since.retain(|addr, _| unchoke.contains(addr));
+        for &addr in &unchoke {
+            self.unchoked_since.entry(addr).or_insert(now);
+        }
+        self.unchoked = unchoke.clone();
+        unchoke
+    }
+
+    /// Whether a seed should stop waiting on an unchoked peer that isn't asking for anything.
+    fn idle(&self, peer: &Observation, now: Instant) -> bool {
+        if self.mode != Mode::Seed {
+            return false;
+        }
+        let Some(&since) = self.unchoked_since.get(&peer.addr) else {
+            return false;
+        };
+        let active = peer.last_request.map_or(since, |last| last.max(since));
+        now.duration_since(active) >= REQUEST_IDLE
+    }
+}
+
+#[cfg(test)]
+fn synthetic_peers(rates: &[f64]) -> Vec<Observation> {
+    rates
+        .iter()
+        .enumerate()
+        .map(|(i, &rate)| Observation {
+            addr: SocketAddr::from(([10, 0, 0, i as u8 + 1], 6881)),
+            interested: true,
+            last_request: None,
+            download_rate: 0.0,
+            upload_rate: rate,
+        })
+        .collect()
+}
+
+#[test]
+fn seeding_rotates_through_slow_peers() {
+    // how fast each peer drains data when we upload to it
+    let drain = [900.0, 800.0, 700.0, 50.0, 40.0, 30.0, 20.0, 10.0];
+    let mut peers = synthetic_peers(&drain);
+    let mut choker = Choker::new(Mode::Seed);
+    let mut turns = HashMap::<SocketAddr, usize>::new();
+    let start = Instant::now();
+    for round in 0..60 {
+        let now = start + Duration::from_secs(10 * round);
+        let unchoked = choker.rechoke(&peers, now);
+        assert_eq!(unchoked.len(), UNCHOKE_SLOTS);
+        // only unchoked peers get (and keep requesting) data, so only they have a measured rate
+        for (peer, &drain) in peers.iter_mut().zip(&drain) {
+            if unchoked.contains(&peer.addr) {
+                peer.upload_rate = drain;
+                peer.last_request = Some(now);
+                *turns.entry(peer.addr).or_default() += 1;
+            } else {
+                peer.upload_rate = 0.0;
+            }
+        }
+    }
+
+    // every peer got a turn, however slow
+    for peer in &peers {
+        assert!(
+            turns.get(&peer.addr).copied().unwrap_or(0) > 0,
+            "{} starved",
+            peer.addr
+        );
+    }
+    // but the fast ones got the lion's share
+    let fast: usize = peers[..3].iter().map(|p| turns[&p.addr]).sum();
+    let slow: usize = peers[3..].iter().map(|p| turns[&p.addr]).sum();
+    assert!(fast > 2 * slow, "fast {fast} vs slow {slow}");
+}
+
+#[test]
+fn seeding_chokes_peers_that_stop_requesting() {
+    let mut peers = synthetic_peers(&[100.0, 90.0]);
+    let mut choker = Choker::with_slots(Mode::Seed, 2);
+    let start = Instant::now();
+    assert_eq!(choker.rechoke(&peers, start).len(), 2);
+
+    // the first peer keeps asking for blocks; the second goes quiet
+    let later = start + REQUEST_IDLE;
+    peers[0].last_request = Some(later);
+    let unchoked = choker.rechoke(&peers, later);
+    assert!(unchoked.contains(&peers[0].addr));
+    assert!(!unchoked.contains(&peers[1].addr));
+
+    // once choked, it gets a fresh chance when the slot comes round again
+    let unchoked = choker.rechoke(&peers, later + Duration::from_secs(1));
+    assert!(unchoked.contains(&peers[1].addr));
+}
+
+#[test]
+fn completing_switches_to_seed_ranking() {
+    let mut peers = synthetic_peers(&[10.0, 20.0, 30.0]);
+    // peer 0 sends us the most, peer 2 drains the fastest
+    peers[0].download_rate = 1000.0;
+    let mut choker = Choker::with_slots(Mode::Leech, 2);
+    let now = Instant::now();
+    let unchoked = choker.rechoke(&peers, now);
+    assert!(unchoked.contains(&peers[0].addr));
+
+    choker.completed();
+    assert_eq!(choker.mode(), Mode::Seed);
+    // the regular slot now goes to the fastest drain ...
+    let unchoked = choker.rechoke(&peers, now);
+    assert!(unchoked.contains(&peers[2].addr));
+    assert_eq!(unchoked.len(), 2);
+}
+
+#[test]
+fn free_slots_are_handed_out_between_rounds() {
+    let peers = synthetic_peers(&[10.0, 20.0, 30.0]);
+    let mut choker = Choker::with_slots(Mode::Seed, 2);
+    let now = Instant::now();
+    assert!(choker.unchoke_if_free(peers[0].addr, now));
+    assert!(choker.unchoke_if_free(peers[0].addr, now));
+    assert!(choker.unchoke_if_free(peers[1].addr, now));
+    assert!(!choker.unchoke_if_free(peers[2].addr, now));
+
+    choker.disconnected(peers[0].addr);
+    assert!(!choker.is_unchoked(peers[0].addr));
+    assert!(choker.unchoke_if_free(peers[2].addr, now));
+}
+
+#[test]
+fn uninterested_peers_stay_choked() {
+    let mut peers = synthetic_peers(&[10.0, 20.0]);
+    peers[1].interested = false;
+    let mut choker = Choker::new(Mode::Seed);
+    let unchoked = choker.rechoke(&peers, Instant::now());
+    assert_eq!(
+        unchoked.into_iter().collect::<Vec<_>>(),
+        vec![peers[0].addr]
+    );
+    assert!(!choker.is_unchoked(peers[1].addr));
+}
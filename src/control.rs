@@ -0,0 +1,164 @@
+//! Steering a running download: the commands it takes, and the keys that send them.
+//!
+//! Keypresses in an interactive `download` and the SIGUSR1/SIGUSR2 signals all end up as a
+//! [`Command`], and go through [`Console::apply`] from there.
+
+use crate::download::DownloadHandle;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Pause,
+    Resume,
+    TogglePause,
+    /// Raise the rate limit a step, or lift it at the top.
+    Faster,
+    /// Lower the rate limit a step, imposing one if there wasn't any.
+    Slower,
+    ToggleVerbose,
+    /// Stop the download gracefully, as if interrupted.
+    Quit,
+}
+
+impl Command {
+    /// The command a key stands for, if any.
+    pub fn from_key(key: u8) -> Option<Self> {
+        match key {
+            b'p' | b'P' => Some(Command::TogglePause),
+            b'+' | b'=' => Some(Command::Faster),
+            b'-' | b'_' => Some(Command::Slower),
+            b'v' | b'V' => Some(Command::ToggleVerbose),
+            b'q' | b'Q' => Some(Command::Quit),
+            _ => None,
+        }
+    }
+}
+
+/// The rate limit [`Command::Slower`] starts out with, in bytes per second.
+pub const RATE_START: u64 = 1024 * 1024;
+
+/// The lowest rate limit [`Command::Slower`] goes down to.
+pub const RATE_MIN: u64 = 16 * 1024;
+
+/// The highest rate limit [`Command::Faster`] goes up to before lifting the limit.
+pub const RATE_MAX: u64 = 64 * 1024 * 1024;
+
+/// The rate limit a step above `limit`; each step doubles it.
+pub fn faster(limit: Option<u64>) -> Option<u64> {
+    limit
+        .map(|limit| limit * 2)
+        .filter(|&limit| limit <= RATE_MAX)
+}
+
+/// The rate limit a step below `limit`; each step halves it.
+pub fn slower(limit: Option<u64>) -> Option<u64> {
+    Some(limit.map_or(RATE_START, |limit| (limit / 2).max(RATE_MIN)))
+}
+
+/// The display side of a download's controls.
+#[derive(Debug, Default)]
+pub struct Console {
+    /// Whether to show connection stats under the progress line.
+    pub verbose: bool,
+}
+
+impl Console {
+    /// Carry out `command` on `download`, returning what to tell the user about it.
+    pub fn apply<S>(&mut self, command: Command, download: &DownloadHandle<S>) -> Option<String> {
+        match command {
+            Command::TogglePause if download.is_paused() => self.apply(Command::Resume, download),
+            Command::TogglePause => self.apply(Command::Pause, download),
+            Command::Pause if download.is_paused() => None,
+            Command::Pause => {
+                download.pause();
+                Some("download paused".into())
+            }
+            Command::Resume if !download.is_paused() => None,
+            Command::Resume => {
+                download.resume();
+                Some("download resumed".into())
+            }
+            Command::Faster | Command::Slower => {
+                let limit = if command == Command::Faster {
+                    faster(download.rate_limit())
+                } else {
+                    slower(download.rate_limit())
+                };
+                download.set_rate_limit(limit);
+                Some(match limit {
+                    Some(limit) => format!("rate limit {} KiB/s", limit / 1024),
+                    None => "no rate limit".into(),
+                })
+            }
+            Command::ToggleVerbose => {
+                self.verbose = !self.verbose;
+                None
+            }
+            Command::Quit => {
+                download.cancel();
+                Some("stopping".into())
+            }
+        }
+    }
+}
+
+#[test]
+fn keys_and_rate_steps() {
+    assert_eq!(Command::from_key(b'p'), Some(Command::TogglePause));
+    assert_eq!(Command::from_key(b'+'), Some(Command::Faster));
+    assert_eq!(Command::from_key(b'-'), Some(Command::Slower));
+    assert_eq!(Command::from_key(b'q'), Some(Command::Quit));
+    assert_eq!(Command::from_key(b'x'), None);
+
+    assert_eq!(slower(None), Some(RATE_START));
+    assert_eq!(slower(Some(RATE_START)), Some(RATE_START / 2));
+    assert_eq!(slower(Some(RATE_MIN)), Some(RATE_MIN));
+    assert_eq!(faster(Some(RATE_START)), Some(RATE_START * 2));
+    assert_eq!(faster(Some(RATE_MAX)), None);
+    assert_eq!(faster(None), None);
+}
+
+#[tokio::test]
+async fn commands_steer_a_running_download() {
+    use crate::download::Outcome;
+    use crate::mock::{self, Behaviour};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let data = mock::data(32768);
+    let stalled = Behaviour {
+        stall: true,
+        ..Behaviour::default()
+    };
+    let (_tracker, _peers, t) = mock::swarm(&data, 32768, vec![stalled]).await;
+
+    let mut download = DownloadHandle::spawn(t, Arc::default());
+    let mut console = Console::default();
+    let keys = |keys: &[u8]| -> Vec<Command> {
+        keys.iter().filter_map(|&k| Command::from_key(k)).collect()
+    };
+
+    for command in keys(b"p") {
+        assert_eq!(
+            console.apply(command, &download).as_deref(),
+            Some("download paused")
+        );
+    }
+    assert!(download.is_paused());
+    // the signals' explicit pause is a no-op on a paused download
+    assert_eq!(console.apply(Command::Pause, &download), None);
+    for command in keys(b"p--+v") {
+        console.apply(command, &download);
+    }
+    assert!(!download.is_paused());
+    assert_eq!(download.rate_limit(), Some(RATE_START));
+    assert!(console.verbose);
+
+    for command in keys(b"q") {
+        console.apply(command, &download);
+    }
+    let outcome = tokio::time::timeout(Duration::from_secs(2), download.wait())
+        .await
+        .expect("quitting is prompt")
+        .unwrap();
+    assert!(matches!(outcome, Outcome::Cancelled));
+}
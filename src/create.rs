@@ -0,0 +1,249 @@
+//! Making a metainfo file for data we have.
+
+use crate::torrent::{File, Hashes, Info, Keys, Torrent};
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// How many pieces [`piece_length_for`] aims for.
+pub const PIECE_TARGET: usize = 1500;
+
+/// The smallest piece length [`piece_length_for`] picks.
+pub const PLENGTH_MIN: usize = 16 * 1024;
+
+/// The largest piece length [`piece_length_for`] picks.
+pub const PLENGTH_MAX: usize = 16 * 1024 * 1024;
+
+/// The piece length for `length` bytes of content: the smallest power of two that splits it into
+/// at most [`PIECE_TARGET`] pieces, kept between [`PLENGTH_MIN`] and [`PLENGTH_MAX`].
+pub fn piece_length_for(length: usize) -> usize {
+    length
+        .div_ceil(PIECE_TARGET)
+        .next_power_of_two()
+        .clamp(PLENGTH_MIN, PLENGTH_MAX)
+}
+
+/// A torrent of the file or directory at `path`, announcing to `announce`.
+///
+/// A directory becomes a multi-file torrent of every regular file below it, in order of their
+/// paths, so the same content always makes the same info dictionary. Up to `threads` pieces are
+/// hashed at once, and only those are in memory.
+pub fn create(
+    path: &Path,
+    announce: String,
+    plength: Option<usize>,
+    threads: usize,
+) -> anyhow::Result<Torrent> {
+    let name = path
+        .canonicalize()
+        .with_context(|| format!("find {}", path.display()))?
+        .file_name()
+        .context("a torrent needs something with a name")?
+        .to_str()
+        .context("the name isn't UTF-8")?
+        .to_string();
+    let meta = std::fs::metadata(path).with_context(|| format!("stat {}", path.display()))?;
+    let (keys, paths) = if meta.is_dir() {
+        let mut found = Vec::new();
+        walk(path, &mut Vec::new(), &mut found)?;
+        found.sort();
+        anyhow::ensure!(!found.is_empty(), "{} has no files in it", path.display());
+        let files = found
+            .iter()
+            .map(|(components, length)| File {
+                length: *length,
+                path: components.clone(),
+                attr: None,
+                md5sum: None,
+                extra: BTreeMap::new(),
+            })
+            .collect();
+        let paths = found
+            .iter()
+            .map(|(components, _)| path.join(components.iter().collect::<PathBuf>()))
+            .collect();
+        (Keys::MultiFile { files }, paths)
+    } else {
+        let length = meta.len() as usize;
+        (Keys::SingleFile { length }, vec![path.to_path_buf()])
+    };
+
+    let length = match &keys {
+        Keys::SingleFile { length } => *length,
+        Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+    };
+    let plength = plength.unwrap_or_else(|| piece_length_for(length));
+    anyhow::ensure!(plength > 0, "the piece length must be positive");
+    let pieces = hash_pieces(&paths, length, plength, threads)?;
+
+    let mut t = Torrent::new(
+        announce,
+        Info {
+            name,
+            plength,
+            pieces,
+            keys,
+            private: None,
+            md5sum: None,
+            extra: BTreeMap::new(),
+        },
+    );
+    t.created_by = Some(format!(
+        "{} {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    ));
+    t.validate()?;
+    Ok(t)
+}
+
+/// Collect every regular file below `dir`, with its path from the torrent's root and its length.
+fn walk(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    found: &mut Vec<(Vec<String>, usize)>,
+) -> anyhow::Result<()> {
+    let entries = std::fs::read_dir(dir).with_context(|| format!("list {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("list {}", dir.display()))?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("{name:?} in {} isn't UTF-8", dir.display()))?;
+        let path = entry.path();
+        let meta = std::fs::metadata(&path).with_context(|| format!("stat {}", path.display()))?;
+        prefix.push(name);
+        if meta.is_dir() {
+            walk(&path, prefix, found)?;
+        } else if meta.is_file() {
+            found.push((prefix.clone(), meta.len() as usize));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// Hash the concatenation of the files at `paths`, which should come to `length` bytes.
+fn hash_pieces(
+    paths: &[PathBuf],
+    length: usize,
+    plength: usize,
+    threads: usize,
+) -> anyhow::Result<Hashes> {
+    let mut reader = Concat {
+        paths: paths.iter(),
+        file: None,
+    };
+    let mut hashes = Vec::with_capacity(length.div_ceil(plength));
+    let mut read = 0;
+    loop {
+        let mut batch = Vec::new();
+        while batch.len() < threads.max(1) {
+            let piece = reader.piece(plength)?;
+            if piece.is_empty() {
+                break;
+            }
+            read += piece.len();
+            batch.push(piece);
+        }
+        if batch.is_empty() {
+            break;
+        }
+        std::thread::scope(|scope| {
+            let hashing: Vec<_> = batch
+                .iter()
+                .map(|piece| scope.spawn(move || <[u8; 20]>::from(Sha1::digest(piece))))
+                .collect();
+            for hashing in hashing {
+                hashes.push(hashing.join().expect("hashing doesn't panic"));
+            }
+        });
+    }
+    anyhow::ensure!(
+        read == length,
+        "read {read} bytes where there were {length}; did the files change while hashing?"
+    );
+    Ok(Hashes(hashes))
+}
+
+/// The files at `paths` read back to back.
+struct Concat<'a> {
+    paths: std::slice::Iter<'a, PathBuf>,
+    file: Option<(std::fs::File, &'a Path)>,
+}
+
+impl Concat<'_> {
+    /// The next `plength` bytes, or fewer at the end.
+    fn piece(&mut self, plength: usize) -> anyhow::Result<Vec<u8>> {
+        let mut piece = vec![0; plength];
+        let mut filled = 0;
+        while filled < plength {
+            let Some((file, path)) = &mut self.file else {
+                let Some(path) = self.paths.next() else {
+                    break;
+                };
+                let file = std::fs::File::open(path)
+                    .with_context(|| format!("open {}", path.display()))?;
+                self.file = Some((file, path));
+                continue;
+            };
+            match file
+                .read(&mut piece[filled..])
+                .with_context(|| format!("read {}", path.display()))?
+            {
+                0 => self.file = None,
+                n => filled += n,
+            }
+        }
+        piece.truncate(filled);
+        Ok(piece)
+    }
+}
+
+#[test]
+fn piece_lengths_aim_for_a_target_count() {
+    assert_eq!(piece_length_for(0), PLENGTH_MIN);
+    assert_eq!(piece_length_for(1000), PLENGTH_MIN);
+    assert_eq!(piece_length_for(1500 * 256 * 1024), 256 * 1024);
+    assert_eq!(piece_length_for(1500 * 256 * 1024 + 1), 512 * 1024);
+    assert_eq!(piece_length_for(usize::MAX / 2), PLENGTH_MAX);
+}
+
+#[test]
+fn created_torrents_verify_and_hash_the_same_every_time() {
+    use crate::piece::FileVerifier;
+
+    let data = crate::mock::data(3 * 16384 + 1000);
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("album");
+    std::fs::create_dir_all(root.join("disc1")).unwrap();
+    std::fs::write(root.join("disc1/b.bin"), &data[..20000]).unwrap();
+    std::fs::write(root.join("disc1/a.bin"), &data[20000..20001]).unwrap();
+    std::fs::write(root.join("cover.jpg"), &data[20001..]).unwrap();
+
+    let announce = String::from("http://tracker.example.com/announce");
+    let t = create(&root, announce.clone(), Some(16384), 1).unwrap();
+    assert_eq!(t.info.name, "album");
+    let Keys::MultiFile { files } = &t.info.keys else {
+        panic!("a directory makes a multi-file torrent");
+    };
+    let paths: Vec<_> = files.iter().map(|file| file.path.join("/")).collect();
+    assert_eq!(paths, ["cover.jpg", "disc1/a.bin", "disc1/b.bin"]);
+    assert_eq!(t.length(), data.len());
+    assert!(FileVerifier::open(&t, &root).all(|r| r.unwrap().1));
+
+    // parallel hashing makes the same torrent, and so does reading it back
+    let again = create(&root, announce.clone(), Some(16384), 4).unwrap();
+    assert_eq!(again.info_hash().unwrap(), t.info_hash().unwrap());
+    let parsed = Torrent::from_bytes(&t.to_bytes().unwrap()).unwrap();
+    assert_eq!(parsed.info_hash().unwrap(), t.info_hash().unwrap());
+
+    let single = create(&root.join("cover.jpg"), announce, None, 2).unwrap();
+    assert!(
+        matches!(single.info.keys, Keys::SingleFile { length } if length == data.len() - 20001)
+    );
+    assert_eq!(single.info.plength, PLENGTH_MIN);
+    assert!(FileVerifier::open(&single, &root.join("cover.jpg")).all(|r| r.unwrap().1));
+}
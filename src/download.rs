@@ -1,186 +1,1171 @@
-use crate::peer::Peer;
+use crate::peer::{Peer, PeerIdCheck, PieceError, TooManyViolations, PIPELINE};
 use crate::piece::Piece;
-use crate::torrent::{File, Keys, Torrent};
-use crate::tracker::TrackerResponse;
+use crate::pool::PeerPool;
+use crate::progress::PieceState;
+use crate::throttle::Throttle;
+use crate::torrent::{File, InfoHash, Keys, Torrent};
+use crate::tracker::{
+    AnnounceSchedule, AnnounceStrategy, Connected, Disconnect, Listeners, Peers, TrackerResponse,
+    TransferStats, ANNOUNCE_BACKOFF, MIN_REANNOUNCE,
+};
 use crate::BLOCK_MAX;
-use anyhow::anyhow;
 use anyhow::Context;
 use futures_util::stream::StreamExt;
-use sha1::{Digest, Sha1};
 use std::collections::BinaryHeap;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_util::sync::{CancellationToken, DropGuard};
 
-pub(crate) async fn all(t: &Torrent) -> anyhow::Result<Downloaded> {
+/// How a download ended, short of failing.
+#[derive(Debug)]
+pub enum Outcome<S = Downloaded> {
+    /// Everything is in (and verified), and the storage has been finalized.
+    Complete(S),
+    /// The download was cancelled before it finished; whatever was in flight has been abandoned.
+    Cancelled,
+}
+
+/// A download running in the background.
+///
+/// By default dropping the handle cancels the download, see [`DownloadHandle::cancel_on_drop`].
+pub struct DownloadHandle<S = Downloaded> {
+    cancel: CancellationToken,
+    on_drop: Option<DropGuard>,
+    paused: watch::Sender<bool>,
+    throttle: Arc<Throttle>,
+    task: JoinHandle<anyhow::Result<Outcome<S>>>,
+}
+
+impl DownloadHandle {
+    /// Download `t` into memory in the background.
+    pub fn spawn(t: Torrent, stats: Arc<TransferStats>) -> Self {
+        Self::builder(t, stats).spawn()
+    }
+
+    /// Set up a download of `t` to spawn, which by default goes into memory and announces no
+    /// listeners.
+    pub fn builder(t: Torrent, stats: Arc<TransferStats>) -> DownloadBuilder {
+        DownloadBuilder {
+            storage: Downloaded::new(&t),
+            t,
+            stats,
+            listeners: Listeners::default(),
+            peers: None,
+            grace: PAUSE_GRACE,
+            min_reannounce: MIN_REANNOUNCE,
+            announce_backoff: ANNOUNCE_BACKOFF,
+            announce_strategy: AnnounceStrategy::Tiered,
+            peer_id_check: PeerIdCheck::Refuse,
+            pipeline: PIPELINE,
+        }
+    }
+}
+
+/// A download about to be spawned; see [`DownloadHandle::builder`].
+pub struct DownloadBuilder<S = Downloaded> {
+    t: Torrent,
+    stats: Arc<TransferStats>,
+    listeners: Listeners,
+    peers: Option<Vec<SocketAddr>>,
+    grace: Duration,
+    min_reannounce: Duration,
+    announce_backoff: Duration,
+    announce_strategy: AnnounceStrategy,
+    peer_id_check: PeerIdCheck,
+    pipeline: usize,
+    storage: S,
+}
+
+impl<S: Storage> DownloadBuilder<S> {
+    /// Announce `listeners` to the tracker.
+    pub fn listeners(mut self, listeners: Listeners) -> Self {
+        self.listeners = listeners;
+        self
+    }
+
+    /// Download from `peers` alone, without asking the tracker for any.
+    pub fn peers(mut self, peers: Vec<SocketAddr>) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    /// Resume with `pieces` already in the storage and verified: they count as done, both for
+    /// what we tell the tracker is `left` and for what we tell peers we have, and aren't
+    /// downloaded again.
+    pub fn verified(self, pieces: impl IntoIterator<Item = usize>) -> Self {
+        for piece_i in pieces {
+            self.stats.set_piece_state(piece_i, PieceState::Done);
+        }
+        self
+    }
+
+    /// Go about the first announce as `strategy` says; re-announces are always
+    /// [`AnnounceStrategy::Tiered`].
+    pub fn announce_strategy(mut self, strategy: AnnounceStrategy) -> Self {
+        self.announce_strategy = strategy;
+        self
+    }
+
+    /// Hold peers to the peer ids the tracker gave for them as `check` says, instead of refusing
+    /// those whose handshake carries another.
+    pub fn peer_id_check(mut self, check: PeerIdCheck) -> Self {
+        self.peer_id_check = check;
+        self
+    }
+
+    /// Keep up to `pipeline` block requests outstanding with each peer, instead of [`PIPELINE`].
+    pub fn pipeline(mut self, pipeline: usize) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// Hold on to idle connections for `grace` while paused, instead of [`PAUSE_GRACE`].
+    #[cfg(test)]
+    pub(crate) fn grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    /// Re-announce as often as every `floor`, instead of [`MIN_REANNOUNCE`].
+    #[cfg(test)]
+    pub(crate) fn min_reannounce(mut self, floor: Duration) -> Self {
+        self.min_reannounce = floor;
+        self
+    }
+
+    /// Wait `backoff` after the first failed re-announce, instead of [`ANNOUNCE_BACKOFF`].
+    #[cfg(test)]
+    pub(crate) fn announce_backoff(mut self, backoff: Duration) -> Self {
+        self.announce_backoff = backoff;
+        self
+    }
+
+    /// Put the pieces in `storage` instead.
+    pub fn storage<T: Storage>(self, storage: T) -> DownloadBuilder<T> {
+        DownloadBuilder {
+            t: self.t,
+            stats: self.stats,
+            listeners: self.listeners,
+            peers: self.peers,
+            grace: self.grace,
+            min_reannounce: self.min_reannounce,
+            announce_backoff: self.announce_backoff,
+            announce_strategy: self.announce_strategy,
+            peer_id_check: self.peer_id_check,
+            pipeline: self.pipeline,
+            storage,
+        }
+    }
+
+    pub fn spawn(self) -> DownloadHandle<S> {
+        let Self {
+            t,
+            stats,
+            listeners,
+            peers,
+            grace,
+            min_reannounce,
+            announce_backoff,
+            announce_strategy,
+            peer_id_check,
+            pipeline,
+            storage,
+        } = self;
+        let cancel = CancellationToken::new();
+        let (paused, pause) = watch::channel(false);
+        let throttle = Arc::new(Throttle::default());
+        let task = tokio::spawn({
+            let cancel = cancel.clone();
+            let controls = Controls {
+                paused: pause,
+                grace,
+                min_reannounce,
+                announce_backoff,
+                announce_strategy,
+                peer_id_check,
+                pipeline,
+                throttle: Arc::clone(&throttle),
+            };
+            let source = match peers {
+                Some(peers) => Source::Peers(peers),
+                None => Source::Tracker(listeners),
+            };
+            async move { all(&t, &stats, &source, &cancel, controls, storage).await }
+        });
+        DownloadHandle {
+            on_drop: Some(cancel.clone().drop_guard()),
+            cancel,
+            paused,
+            throttle,
+            task,
+        }
+    }
+}
+
+impl<S> DownloadHandle<S> {
+    /// Stop requesting blocks until [`DownloadHandle::resume`].
+    ///
+    /// Blocks already requested still arrive and count. Peers are told we're not interested, and
+    /// if the pause lasts longer than [`PAUSE_GRACE`] we disconnect from them altogether.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    /// Undo [`DownloadHandle::pause`], redialing peers if we had to let them go.
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Cap the download at `limit` bytes per second from now on, or lift the cap with `None`.
+    pub fn set_rate_limit(&self, limit: Option<u64>) {
+        self.throttle.set_limit(limit);
+    }
+
+    pub fn rate_limit(&self) -> Option<u64> {
+        self.throttle.limit()
+    }
+
+    /// Stop the download as soon as possible; [`DownloadHandle::wait`] then returns
+    /// [`Outcome::Cancelled`] (unless the download had already finished).
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Whether dropping this handle cancels the download, or leaves it running detached.
+    pub fn cancel_on_drop(&mut self, cancel: bool) {
+        match (cancel, self.on_drop.take()) {
+            (true, None) => self.on_drop = Some(self.cancel.clone().drop_guard()),
+            (true, Some(guard)) => self.on_drop = Some(guard),
+            (false, Some(guard)) => {
+                guard.disarm();
+            }
+            (false, None) => {}
+        }
+    }
+
+    /// Wait for the download to end.
+    ///
+    /// Must not be called again once it has returned.
+    pub async fn wait(&mut self) -> anyhow::Result<Outcome<S>> {
+        match (&mut self.task).await {
+            Ok(outcome) => outcome,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => Err(e).context("download task went away"),
+        }
+    }
+}
+
+/// How long [`piece`] gives a single peer to deliver the piece before moving on to the next.
+pub const PIECE_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Download piece `piece_i` on its own, trying `candidates` one after the other until one of them
+/// delivers it intact, with up to `pipeline` block requests outstanding.
+///
+/// Any failure once we've picked a peer (a bad handshake, never being unchoked, protocol
+/// violations, running out of `timeout`, or data that doesn't match the hash) moves on to the next
+/// candidate, until `max_attempts` peers have been tried. A peer whose bitfield shows it doesn't
+/// have the piece is passed over without counting as an attempt.
+pub async fn piece(
+    t: &Torrent,
+    piece_i: usize,
+    candidates: &[SocketAddr],
+    max_attempts: usize,
+    timeout: Duration,
+    pipeline: usize,
+) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        piece_i < t.num_pieces(),
+        "the torrent only has {} pieces",
+        t.num_pieces()
+    );
+    anyhow::ensure!(max_attempts > 0, "at least one attempt is needed");
     let info_hash = t.info_hash()?;
-    let peer_info = TrackerResponse::query(t, info_hash)
-        .await
-        .context("query tracker for peer info")?;
+    let most = candidates.len().min(max_attempts);
+    let mut attempts = 0;
+    for &addr in candidates {
+        if attempts == most {
+            break;
+        }
+        let fetch = async {
+            let mut peer = Peer::new(addr, info_hash, t.num_pieces()).await?;
+            // well inside the attempt, so that a peer that never unchokes us is reported as such
+            peer.set_unchoke_timeout(timeout / 2);
+            peer.set_pipeline(pipeline);
+            anyhow::Ok(peer.download_piece(t, piece_i).await?)
+        };
+        let result = match tokio::time::timeout(timeout, fetch).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!("timed out after {timeout:?}")),
+        };
+        match result {
+            Ok(data) => return Ok(data),
+            Err(e) if matches!(e.downcast_ref(), Some(PieceError::Missing { .. })) => {
+                eprintln!("{e}; trying another peer");
+            }
+            Err(e) => {
+                attempts += 1;
+                eprintln!("attempt {attempts}/{most} with {addr} failed: {e:#}");
+            }
+        }
+    }
+    anyhow::ensure!(
+        !candidates.is_empty(),
+        "no peers to download piece {piece_i} from"
+    );
+    anyhow::ensure!(
+        attempts > 0,
+        "none of the {} peers have piece {piece_i}",
+        candidates.len()
+    );
+    anyhow::bail!("giving up on piece {piece_i} after {attempts} failed attempts")
+}
 
-    let mut peer_list = Vec::new();
-    let mut peers = futures_util::stream::iter(peer_info.peers.0.iter())
-        .map(|&peer_addr| async move {
-            let peer = Peer::new(peer_addr, info_hash).await;
-            (peer_addr, peer)
-        })
-        .buffer_unordered(5 /* user config */);
-    while let Some((peer_addr, peer)) = peers.next().await {
-        match peer {
-            Ok(peer) => {
-                peer_list.push(peer);
-                if peer_list.len() >= 5
-                /* TODO: user config */
-                {
-                    break;
-                }
+/// A `START..END` range of piece indices, either end of which may be left open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PieceRange {
+    pub start: Option<usize>,
+    /// Exclusive.
+    pub end: Option<usize>,
+}
+
+impl PieceRange {
+    /// The range this is in a torrent of `npieces` pieces, if it is a valid and non-empty one.
+    pub fn resolve(&self, npieces: usize) -> anyhow::Result<Range<usize>> {
+        let range = self.start.unwrap_or(0)..self.end.unwrap_or(npieces);
+        anyhow::ensure!(
+            range.end <= npieces,
+            "the torrent only has {npieces} pieces, so the range can't end at {}",
+            range.end
+        );
+        anyhow::ensure!(
+            range.start < range.end,
+            "piece range {}..{} is empty",
+            range.start,
+            range.end
+        );
+        Ok(range)
+    }
+}
+
+/// Parse a piece range like `100..120`, `..50`, or `1200..`.
+pub fn parse_piece_range(s: &str) -> Result<PieceRange, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("piece range `{s}` should look like START..END"))?;
+    let index = |i: &str| {
+        (!i.is_empty())
+            .then(|| i.parse::<usize>())
+            .transpose()
+            .map_err(|e| format!("invalid piece index `{i}`: {e}"))
+    };
+    Ok(PieceRange {
+        start: index(start)?,
+        end: index(end)?,
+    })
+}
+
+/// Where a piece range download puts the pieces it gets.
+#[derive(Debug)]
+pub enum PieceOutput {
+    /// At their offsets in a file as long as the whole torrent, leaving the rest of it a hole.
+    Sparse(FileStorage),
+    /// Each in a `piece-N.bin` of its own under a directory.
+    Split(PathBuf),
+}
+
+impl PieceOutput {
+    /// A sparse output file for `t` at `path`, keeping whatever an earlier run already put there.
+    pub async fn sparse(path: &Path, t: &Torrent) -> anyhow::Result<Self> {
+        Ok(Self::Sparse(FileStorage::create(path, t).await?))
+    }
+
+    pub fn split(dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        Ok(Self::Split(dir.to_path_buf()))
+    }
+
+    pub async fn write(&mut self, piece_i: usize, data: &[u8]) -> anyhow::Result<()> {
+        match self {
+            PieceOutput::Sparse(storage) => {
+                storage
+                    .write_block(piece_i, 0, data)
+                    .await
+                    .with_context(|| format!("write piece {piece_i}"))?;
+                // a tokio file finishes writes in the background, and we may be dropped any time
+                storage.flush().await.context("flush output")
+            }
+            PieceOutput::Split(dir) => {
+                let path = dir.join(format!("piece-{piece_i}.bin"));
+                std::fs::write(&path, data).with_context(|| format!("write {}", path.display()))
+            }
+        }
+    }
+}
+
+/// Download just the pieces in `range`, one after the other, going to [`piece`] for each, and
+/// writing the ones that arrive intact to `output`.
+///
+/// Returns how each piece went; one piece failing doesn't stop the others from being tried.
+pub async fn piece_range(
+    t: &Torrent,
+    range: Range<usize>,
+    candidates: &[SocketAddr],
+    max_attempts: usize,
+    pipeline: usize,
+    output: &mut PieceOutput,
+) -> Vec<(usize, anyhow::Result<()>)> {
+    let mut results = Vec::with_capacity(range.len());
+    for piece_i in range {
+        let fetch = piece(
+            t,
+            piece_i,
+            candidates,
+            max_attempts,
+            PIECE_ATTEMPT_TIMEOUT,
+            pipeline,
+        );
+        let result = match fetch.await {
+            Ok(data) => output.write(piece_i, &data).await,
+            Err(e) => Err(e),
+        };
+        results.push((piece_i, result));
+    }
+    results
+}
+
+/// How long a download stays connected to its peers while paused.
+pub const PAUSE_GRACE: Duration = Duration::from_secs(60);
+
+/// A download's side of [`DownloadHandle::pause`] and [`DownloadHandle::set_rate_limit`].
+pub(crate) struct Controls {
+    pub(crate) paused: watch::Receiver<bool>,
+    /// How long to hold on to idle connections while paused.
+    pub(crate) grace: Duration,
+    /// The shortest we wait between announces.
+    pub(crate) min_reannounce: Duration,
+    /// How long we wait after the first re-announce that fails.
+    pub(crate) announce_backoff: Duration,
+    /// How to go about the first announce.
+    pub(crate) announce_strategy: AnnounceStrategy,
+    /// What to do about peers that don't go by the peer id the tracker gave for them.
+    pub(crate) peer_id_check: PeerIdCheck,
+    /// How many block requests to keep outstanding with each peer.
+    pub(crate) pipeline: usize,
+    pub(crate) throttle: Arc<Throttle>,
+}
+
+impl Controls {
+    /// For downloads nobody can pause or slow down.
+    pub(crate) fn none() -> Self {
+        Self {
+            paused: watch::channel(false).1,
+            grace: PAUSE_GRACE,
+            min_reannounce: MIN_REANNOUNCE,
+            announce_backoff: ANNOUNCE_BACKOFF,
+            announce_strategy: AnnounceStrategy::Tiered,
+            peer_id_check: PeerIdCheck::Refuse,
+            pipeline: PIPELINE,
+            throttle: Arc::default(),
+        }
+    }
+}
+
+/// Where a download gets its peers from.
+#[derive(Debug)]
+pub(crate) enum Source {
+    /// The torrent's tracker, announcing these listeners to it.
+    Tracker(Listeners),
+    /// Just these peers, leaving the tracker out of it.
+    Peers(Vec<SocketAddr>),
+}
+
+pub(crate) async fn all<S: Storage>(
+    t: &Torrent,
+    stats: &TransferStats,
+    source: &Source,
+    cancel: &CancellationToken,
+    controls: Controls,
+    mut storage: S,
+) -> anyhow::Result<Outcome<S>> {
+    let info_hash = t.info_hash()?;
+    let mut schedule = AnnounceSchedule::new(controls.min_reannounce, controls.announce_backoff);
+    let peers = match source {
+        Source::Tracker(listeners) => tokio::select! {
+            biased;
+            _ = cancel.cancelled() => return Ok(Outcome::Cancelled),
+            peer_info = TrackerResponse::started(t, info_hash, listeners, stats, controls.announce_strategy) => {
+                let peer_info = peer_info.context("query tracker for peer info")?;
+                schedule.succeeded(&peer_info);
+                dialable(&peer_info, listeners)
+            }
+        },
+        Source::Peers(peers) => Peers {
+            addrs: peers.clone(),
+            ..Peers::default()
+        },
+    };
+
+    let (found, mut new_peers) = mpsc::unbounded_channel();
+    let reannounce = async {
+        match source {
+            Source::Tracker(listeners) => {
+                reannounce(t, info_hash, listeners, stats, schedule, found).await
+            }
+            Source::Peers(_) => std::future::pending().await,
+        }
+    };
+
+    let session = Session {
+        info_hash,
+        stats,
+        source,
+        controls,
+        storage: &mut storage,
+    };
+    // dropping the transfer future abandons every connection and request it has in flight
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => {
+            if let Source::Tracker(listeners) = source {
+                TrackerResponse::stopped(t, info_hash, listeners, stats).await;
+            }
+            Ok(Outcome::Cancelled)
+        }
+        () = reannounce => unreachable!("re-announcing goes on for as long as the download"),
+        downloaded = transfer(t, session, &peers, &mut new_peers) => {
+            downloaded?;
+            if let Source::Tracker(listeners) = source {
+                TrackerResponse::completed(t, info_hash, listeners, stats).await;
+            }
+            Ok(Outcome::Complete(storage))
+        }
+    }
+}
+
+/// Announce to the tracker as `schedule` says, to keep it up to date with our progress and to
+/// hear about new peers, which go to `found`.
+///
+/// A tracker that stops answering doesn't stop the download, which carries on with the peers it
+/// has while we back off.
+async fn reannounce(
+    t: &Torrent,
+    info_hash: InfoHash,
+    listeners: &Listeners,
+    stats: &TransferStats,
+    mut schedule: AnnounceSchedule,
+    found: mpsc::UnboundedSender<Peers>,
+) {
+    loop {
+        stats.set_next_announce(schedule.next());
+        tokio::time::sleep_until(schedule.next().into()).await;
+        match TrackerResponse::query(t, info_hash, listeners, stats).await {
+            Ok(peer_info) => {
+                schedule.succeeded(&peer_info);
+                let _ = found.send(dialable(&peer_info, listeners));
             }
             Err(e) => {
-                eprintln!("failed to connect to peer {peer_addr:?}: {e:?}");
+                let after = schedule.failed();
+                eprintln!(
+                    "re-announce failed ({} in a row), trying again in {}s: {e:#}",
+                    schedule.failures(),
+                    after.as_secs()
+                );
             }
         }
     }
-    drop(peers);
-    let mut peers = peer_list;
+}
 
-    let mut need_pieces = BinaryHeap::new();
-    let mut no_peers = Vec::new();
-    for piece_i in 0..t.info.pieces.0.len() {
-        let piece = Piece::new(piece_i, &t, &peers);
-        if piece.peers().is_empty() {
-            no_peers.push(piece);
-        } else {
-            need_pieces.push(piece);
+/// The peers `peer_info` names that are worth dialing, in a random order: no repeats, nothing
+/// unroutable, and not us, as far as we know our address.
+fn dialable(peer_info: &TrackerResponse, listeners: &Listeners) -> Peers {
+    let mut peers = peer_info.peers.clone();
+    peers.dedup();
+    let own = (peer_info.external_ip)
+        .or(listeners.announce_ip)
+        .map(|ip| SocketAddr::new(ip, listeners.port()));
+    peers.sanitize(own);
+    peers.shuffle();
+    peers
+}
+
+/// Add `peers` to `pool`, along with the ids the tracker gave for them. Returns whether any of
+/// them were new.
+fn learn(pool: &mut PeerPool, peers: Peers) -> bool {
+    let mut learned = false;
+    for addr in peers.addrs {
+        learned |= pool.add(addr);
+        if let Some(&peer_id) = peers.ids.get(&addr) {
+            pool.expect_id(addr, peer_id);
         }
     }
+    learned
+}
 
-    // TODO
-    assert!(no_peers.is_empty());
+/// What a download goes by from its first peer to its last piece.
+struct Session<'a, S> {
+    info_hash: InfoHash,
+    stats: &'a TransferStats,
+    source: &'a Source,
+    controls: Controls,
+    /// Where verified pieces go.
+    storage: &'a mut S,
+}
+
+async fn transfer(
+    t: &Torrent,
+    session: Session<'_, impl Storage>,
+    peers_found: &Peers,
+    new_peers: &mut mpsc::UnboundedReceiver<Peers>,
+) -> anyhow::Result<()> {
+    let Session {
+        info_hash,
+        stats,
+        source,
+        mut controls,
+        storage,
+    } = session;
+    let mut pool = if t.is_private() {
+        PeerPool::private()
+    } else {
+        PeerPool::default()
+    };
+    learn(&mut pool, peers_found.clone());
+    let (mut peers, mut connected) = dial(
+        &mut pool,
+        info_hash,
+        t.num_pieces(),
+        stats,
+        &controls,
+        MAX_PEERS,
+    )
+    .await;
 
-    // TODO: this is dumb because all the pieces for a given torrent may not fit in memory!
-    // should probably write every piece to disk so that we can also resume downloads, and seed
-    // later on.
-    let mut all_pieces = vec![0; t.length()];
-    while let Some(piece) = need_pieces.pop() {
-        // the + (BLOCK_MAX - 1) rounds up
+    let mut need_pieces = BinaryHeap::new();
+    // the pieces nobody we're connected to has (yet), which wait for a Have or for new peers
+    let mut no_peers = Vec::new();
+    let resumed = stats.piece_map(t.num_pieces());
+    let pending = (0..t.num_pieces()).filter(|&piece_i| resumed.0[piece_i] != PieceState::Done);
+    regroup(t, &peers, pending, &mut need_pieces, &mut no_peers);
+    let mut peers_changed = false;
+
+    loop {
+        // peers the tracker told us about since the last piece; the pool passes over the ones it
+        // already knows, connected or not
+        while let Ok(found) = new_peers.try_recv() {
+            learn(&mut pool, found);
+        }
+        // on top of those, peers we lost come up for another try once their cooldown is over
+        if peers.len() < MAX_PEERS {
+            let want = MAX_PEERS - peers.len();
+            let (more, more_connected) =
+                dial(&mut pool, info_hash, t.num_pieces(), stats, &controls, want).await;
+            if !more.is_empty() {
+                peers.extend(more);
+                connected.extend(more_connected);
+                peers_changed = true;
+            }
+        }
+        // a change of peers leaves the peer indices of every piece stale; short of that, Haves that
+        // came in during the last piece may still have found a holder for some that had none
+        let mut pending: Vec<_> = no_peers.drain(..).map(|p| p.index()).collect();
+        if std::mem::take(&mut peers_changed) {
+            pending.extend(need_pieces.drain().map(|p| p.index()));
+        }
+        regroup(t, &peers, pending, &mut need_pieces, &mut no_peers);
+        let Some(mut piece) = need_pieces.pop() else {
+            let Some(piece_i) = no_peers.first().map(Piece::index) else {
+                break;
+            };
+            anyhow::ensure!(!peers.is_empty(), "no peers left to get piece {piece_i}");
+            let Source::Tracker(_) = source else {
+                anyhow::bail!("no connected peer has piece {piece_i}");
+            };
+            // none of the peers we have has anything we still need, so let them go: they're
+            // dialed again along with whoever the tracker names next, by when they may have more
+            eprintln!("no connected peer has piece {piece_i}, waiting for the tracker");
+            for peer in peers.drain(..) {
+                pool.release(peer.addr());
+            }
+            connected.clear();
+            let found = new_peers.recv().await.context("re-announcing stopped")?;
+            learn(&mut pool, found);
+            continue;
+        };
+        stats.set_piece_state(piece.index(), PieceState::InFlight);
         let piece_size = piece.length();
-        let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-        let peers: Vec<_> = peers
-            .iter_mut()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
-            .collect();
-
-        let (submit, tasks) = kanal::bounded_async(nblocks);
-        for block in 0..nblocks {
-            submit
-                .send(block)
-                .await
-                .expect("bound holds all these items");
-        }
-        let (finish, mut done) = tokio::sync::mpsc::channel(nblocks);
-        let mut participants = futures_util::stream::futures_unordered::FuturesUnordered::new();
-        for peer in peers {
-            participants.push(peer.participate(
-                piece.index(),
-                piece_size,
-                nblocks,
-                submit.clone(),
-                tasks.clone(),
-                finish.clone(),
-            ));
-        }
-        drop(submit);
-        drop(finish);
-        drop(tasks);
-
-        eprintln!("start receive loop");
+        let nblocks = piece_size.div_ceil(BLOCK_MAX);
         let mut all_blocks = vec![0u8; piece_size];
+        let mut have_block = vec![false; nblocks];
         let mut bytes_received = 0;
+
+        // each round hands the missing blocks to the peers we have; a round only ends early if a
+        // pause went on for so long that we let go of all of them
         loop {
-            tokio::select! {
-                joined = participants.next(), if !participants.is_empty() => {
-                    // if a participant ends early, it's either slow or failed
-                    eprintln!("participant finished");
-                    match joined {
-                        None => {
-                            // there are no peers!
-                            // this must mean we are about to get None from done.recv(),
-                            // so we'll handle it there
-                        }
-                        Some(Ok(_)) => {
-                            // the peer gave up because it timed out
-                            // nothing to do, except maybe de-prioritize this peer for later
-                            // TODO
-                        }
-                        Some(Err(_)) => {
-                            // the peer failed and should be removed
-                            // it already isn't participating in this piece any more, so this is
-                            // more of an indicator that we shouldn't try this peer again, and
-                            // should remove it from the global peer list
-                            // TODO
+            let holders: Vec<_> = peers
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
+                .collect();
+
+            let (submit, tasks) = kanal::bounded_async(nblocks);
+            for block in (0..nblocks).filter(|&block| !have_block[block]) {
+                submit
+                    .send(block)
+                    .await
+                    .expect("bound holds all these items");
+            }
+            let (finish, mut done) = tokio::sync::mpsc::channel(nblocks);
+            let mut participants = futures_util::stream::futures_unordered::FuturesUnordered::new();
+            for peer in holders {
+                let addr = peer.addr();
+                let participate = peer.participate(
+                    piece.index(),
+                    piece_size,
+                    nblocks,
+                    submit.clone(),
+                    tasks.clone(),
+                    finish.clone(),
+                );
+                participants.push(async move { (addr, participate.await) });
+            }
+            drop(submit);
+            drop(finish);
+            drop(tasks);
+
+            eprintln!("start receive loop");
+            let mut paused_since = controls.paused.borrow().then(tokio::time::Instant::now);
+            let mut let_go = false;
+            let mut failed = Vec::new();
+            loop {
+                tokio::select! {
+                    joined = participants.next(), if !participants.is_empty() => {
+                        // if a participant ends early, it's either slow or failed
+                        eprintln!("participant finished");
+                        match joined {
+                            None => {
+                                // there are no peers!
+                                // this must mean we are about to get None from done.recv(),
+                                // so we'll handle it there
+                            }
+                            Some((_, Ok(_))) => {
+                                // the peer gave up because it timed out
+                                // nothing to do, except maybe de-prioritize this peer for later
+                                // TODO
+                            }
+                            Some((addr, Err(e))) => {
+                                eprintln!("dropping {addr}: {e:#}");
+                                stats.record_disconnect(if e.is::<TooManyViolations>() {
+                                    Disconnect::ProtocolViolation
+                                } else {
+                                    Disconnect::Error
+                                });
+                                // it already isn't participating in this piece any more; it goes
+                                // once the round is over, and cools down before we try it again
+                                pool.disconnected(addr, Instant::now());
+                                failed.push(addr);
+                            }
                         }
                     }
-                }
-                piece = done.recv() => {
-                    if let Some(piece) = piece {
-                        eprintln!("got piece");
-                        // keep track of the bytes in message
-                        let piece = crate::peer::Piece::ref_from_bytes(&piece.payload[..])
-                            .expect("always get all Piece response fields from peer");
-                        bytes_received += piece.block().len();
-                        all_blocks[piece.begin() as usize..][..piece.block().len()].copy_from_slice(piece.block());
-                        if bytes_received == piece_size {
-                            // have received every piece
-                            // this must mean that all participations have either exited or are
-                            // waiting for more work -- in either case, it is okay to drop all the
-                            // participant futures.
+                    piece = done.recv() => {
+                        if let Some(piece) = piece {
+                            eprintln!("got piece");
+                            pool.exchanged_data(piece.from);
+                            // keep track of the bytes in message
+                            let block = piece.begin / BLOCK_MAX;
+                            if !std::mem::replace(&mut have_block[block], true) {
+                                bytes_received += piece.data.len();
+                                all_blocks[piece.begin..][..piece.data.len()].copy_from_slice(&piece.data);
+                            }
+                            if bytes_received == piece_size {
+                                // have received every piece
+                                // this must mean that all participations have either exited or are
+                                // waiting for more work -- in either case, it is okay to drop all the
+                                // participant futures.
+                                break;
+                            }
+                        } else {
+                            eprintln!("got pieces end");
+                            // there are no peers left, so we can't progress!
                             break;
                         }
-                    } else {
-                        eprintln!("got pieces end");
-                        // there are no peers left, so we can't progress!
+                    }
+                    Ok(()) = controls.paused.changed() => {
+                        // the participants stop and start themselves; we just keep the tracker
+                        // informed and keep an eye on how long the pause goes on
+                        if *controls.paused.borrow_and_update() {
+                            paused_since = Some(tokio::time::Instant::now());
+                            if let Source::Tracker(listeners) = source {
+                                TrackerResponse::paused(t, info_hash, listeners, stats).await;
+                            }
+                        } else {
+                            paused_since = None;
+                        }
+                    }
+                    _ = tokio::time::sleep_until(
+                        paused_since.unwrap_or_else(tokio::time::Instant::now) + controls.grace
+                    ), if paused_since.is_some() => {
+                        let_go = true;
                         break;
                     }
                 }
             }
+            drop(participants);
+            if !failed.is_empty() {
+                // `peers` and `connected` go together, one entry per connection
+                for addr in failed {
+                    if let Some(peer_i) = peers.iter().position(|peer| peer.addr() == addr) {
+                        peers.remove(peer_i);
+                        connected.remove(peer_i);
+                    }
+                }
+                // which leaves the peer indices of every piece stale
+                piece = Piece::new(piece.index(), t, &peers);
+                peers_changed = true;
+            }
+            if !let_go {
+                break;
+            }
+
+            eprintln!(
+                "paused for over {:?}, disconnecting from all peers",
+                controls.grace
+            );
+            for peer in peers.drain(..) {
+                pool.release(peer.addr());
+            }
+            connected.clear();
+            if controls.paused.wait_for(|&paused| !paused).await.is_err() {
+                anyhow::bail!("download went away while paused");
+            }
+            (peers, connected) = dial(
+                &mut pool,
+                info_hash,
+                t.num_pieces(),
+                stats,
+                &controls,
+                MAX_PEERS,
+            )
+            .await;
+            // whoever we ended up with, the peer indices of every piece are stale now
+            piece = Piece::new(piece.index(), t, &peers);
+            peers_changed = true;
         }
-        drop(participants);
 
-        if bytes_received == piece_size {
-            // great, we got all the bytes
+        if bytes_received < piece_size {
+            // everyone who had it dropped out or gave up on unchoking us, so it waits its turn
+            // again, for them or whoever else turns up with it
+            stats.set_piece_state(piece.index(), PieceState::Pending);
+            no_peers.push(piece);
+            continue;
+        }
+
+        if !t.verify_piece(piece.index(), &all_blocks) {
+            stats.record_hash_failure();
+            stats.set_piece_state(piece.index(), PieceState::Failed);
+            // TODO: figure out who sent the bad data, and try again without them
+            anyhow::bail!("piece {} failed its hash check", piece.index());
+        }
+        storage
+            .write_block(piece.index(), 0, &all_blocks)
+            .await
+            .with_context(|| format!("store piece {}", piece.index()))?;
+        stats.record_downloaded(piece_size);
+        stats.set_piece_state(piece.index(), PieceState::Done);
+        for peer in &mut peers {
+            if let Err(e) = peer.send_have(piece.index()).await {
+                // it'll fail the next piece it takes part in, and be dealt with then
+                eprintln!(
+                    "failed to tell {} about piece {}: {e}",
+                    peer.addr(),
+                    piece.index()
+                );
+            }
+        }
+    }
+
+    storage.flush().await.context("flush downloaded pieces")?;
+    storage.finalize().await.context("finalize download")
+}
+
+/// Sort `pieces` by whether any of `peers` has them, into `need` (in the order to download them in)
+/// or `no_peers`.
+fn regroup(
+    t: &Torrent,
+    peers: &[Peer],
+    pieces: impl IntoIterator<Item = usize>,
+    need: &mut BinaryHeap<Piece>,
+    no_peers: &mut Vec<Piece>,
+) {
+    for piece_i in pieces {
+        let piece = Piece::new(piece_i, t, peers);
+        if piece.peers().is_empty() {
+            no_peers.push(piece);
         } else {
-            // we'll need to connect to more peers, and make sure that those additional peers also
-            // have this piece, and then download the pieces we _didn't_ get from them.
-            // probably also stick this back onto the pieces_heap.
-            anyhow::bail!("no peers left to get piece {}", piece.index());
+            need.push(piece);
         }
+    }
+}
 
-        let mut hasher = Sha1::new();
-        hasher.update(&all_blocks);
-        let hash: [u8; 20] = match hasher.finalize().try_into() {
-            Ok(h) => h,
-            Err(_) => return Err(anyhow!("Error converting to [u8; 20]")),
-        };
-        assert_eq!(hash, piece.hash());
+/// How many peers a download is connected to at once, at most.
+const MAX_PEERS: usize = 5 /* TODO: user config */;
+
+/// Connect to up to `limit` dialable peers of a torrent with `npieces` pieces from `pool`.
+///
+/// The peers stop requesting blocks whenever `controls` says they're paused, and otherwise only
+/// request as fast as its throttle allows.
+async fn dial<'s>(
+    pool: &mut PeerPool,
+    info_hash: InfoHash,
+    npieces: usize,
+    stats: &'s TransferStats,
+    controls: &Controls,
+    limit: usize,
+) -> (Vec<Peer>, Vec<Connected<'s>>) {
+    let now = Instant::now();
+    let candidates: Vec<_> = std::iter::from_fn(|| pool.next_dialable(now)).collect();
+
+    let mut peer_list = Vec::new();
+    let mut connected = Vec::new();
+    let mut dialed = Vec::new();
+    let check = controls.peer_id_check;
+    let expected: Vec<_> = candidates
+        .iter()
+        .map(|&addr| (addr, pool.peer_id(addr)))
+        .collect();
+    let mut peers = futures_util::stream::iter(expected)
+        .map(|(peer_addr, peer_id)| async move {
+            let peer = Peer::expecting(peer_addr, info_hash, npieces, peer_id, check).await;
+            (peer_addr, peer)
+        })
+        .buffer_unordered(5 /* user config */);
+    while let Some((peer_addr, peer)) = peers.next().await {
+        dialed.push(peer_addr);
+        match peer {
+            Ok(mut peer) => {
+                if let Err(e) = peer.send_bitfield(&stats.piece_map(npieces)).await {
+                    eprintln!("failed to send our bitfield to {peer_addr:?}: {e:?}");
+                    stats.record_disconnect(Disconnect::Error);
+                    pool.disconnected(peer_addr, Instant::now());
+                    continue;
+                }
+                peer.follow_pause(controls.paused.clone());
+                peer.follow_throttle(Arc::clone(&controls.throttle));
+                peer.set_pipeline(controls.pipeline);
+                peer_list.push(peer);
+                connected.push(stats.connected());
+                if peer_list.len() >= limit {
+                    break;
+                }
+            }
+            Err(e) => {
+                eprintln!("failed to connect to peer {peer_addr:?}: {e:?}");
+                stats.record_disconnect(Disconnect::Dial);
+                pool.disconnected(peer_addr, Instant::now());
+            }
+        }
+    }
+    drop(peers);
+    for peer_addr in candidates {
+        if !dialed.contains(&peer_addr) {
+            pool.release(peer_addr);
+        }
+    }
+    (peer_list, connected)
+}
+
+/// Where a download puts the pieces it gets, and reads them back from to upload them.
+///
+/// Blocks are addressed by piece and offset within the piece, and all of a piece is only written
+/// once it has been verified.
+pub trait Storage: Send + 'static {
+    fn write_block(
+        &mut self,
+        piece: usize,
+        offset: usize,
+        data: &[u8],
+    ) -> impl Future<Output = io::Result<()>> + Send;
+
+    fn read_block(
+        &mut self,
+        piece: usize,
+        offset: usize,
+        len: usize,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send;
+
+    /// Make sure everything written so far has reached the backend.
+    fn flush(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Called once, after the last piece has been written and flushed.
+    fn finalize(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
 
-        all_pieces[piece.index() * t.info.plength..][..piece_size].copy_from_slice(&all_blocks);
+/// The pieces of `t` that `storage` already holds intact, as an interrupted download leaves
+/// them, for [`DownloadBuilder::verified`].
+pub async fn verified_pieces(t: &Torrent, storage: &mut impl Storage) -> io::Result<Vec<usize>> {
+    let mut verified = Vec::new();
+    for piece_i in 0..t.num_pieces() {
+        let data = storage
+            .read_block(piece_i, 0, t.piece_length_for(piece_i))
+            .await?;
+        if t.verify_piece(piece_i, &data) {
+            verified.push(piece_i);
+        }
     }
+    Ok(verified)
+}
 
-    Ok(Downloaded {
-        bytes: all_pieces,
-        files: match &t.info.keys {
-            Keys::SingleFile { length } => vec![File {
-                length: *length,
-                path: vec![t.info.name.clone()],
-            }],
-            Keys::MultiFile { files } => files.clone(),
-        },
-    })
+/// Where block `offset..offset + len` of `piece` lies in a torrent of `length` bytes with pieces
+/// of `plength`, if it's in there at all.
+fn block_range(
+    length: usize,
+    plength: usize,
+    piece: usize,
+    offset: usize,
+    len: usize,
+) -> io::Result<Range<usize>> {
+    let start = piece * plength + offset;
+    if offset + len > plength || start + len > length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "block {offset}..{} of piece {piece} is out of bounds",
+                offset + len
+            ),
+        ));
+    }
+    Ok(start..start + len)
 }
 
+/// A whole download in memory: the default [`Storage`].
+#[derive(Debug)]
 pub struct Downloaded {
     bytes: Vec<u8>, // TODO: maybe Bytes?
+    plength: usize,
     files: Vec<File>,
 }
 
+impl Downloaded {
+    pub fn new(t: &Torrent) -> Self {
+        Self {
+            bytes: vec![0; t.length()],
+            plength: t.info.plength,
+            files: match &t.info.keys {
+                Keys::SingleFile { length } => vec![File {
+                    length: *length,
+                    path: vec![t.info.name.clone()],
+                    attr: None,
+                    md5sum: None,
+                    extra: Default::default(),
+                }],
+                Keys::MultiFile { files } => files.clone(),
+            },
+        }
+    }
+}
+
+impl Storage for Downloaded {
+    async fn write_block(&mut self, piece: usize, offset: usize, data: &[u8]) -> io::Result<()> {
+        let range = block_range(self.bytes.len(), self.plength, piece, offset, data.len())?;
+        self.bytes[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    async fn read_block(&mut self, piece: usize, offset: usize, len: usize) -> io::Result<Vec<u8>> {
+        let range = block_range(self.bytes.len(), self.plength, piece, offset, len)?;
+        Ok(self.bytes[range].to_vec())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A download written straight to one file holding all of the torrent, each block at its offset.
+#[derive(Debug)]
+pub struct FileStorage {
+    file: tokio::fs::File,
+    length: usize,
+    plength: usize,
+}
+
+impl FileStorage {
+    /// Store `t` in the file at `path`, keeping whatever an earlier run already put there.
+    pub async fn create(path: &Path, t: &Torrent) -> anyhow::Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)
+            .await
+            .with_context(|| format!("open {}", path.display()))?;
+        if file.metadata().await?.len() < t.length() as u64 {
+            file.set_len(t.length() as u64)
+                .await
+                .with_context(|| format!("extend {}", path.display()))?;
+        }
+        Ok(Self {
+            file,
+            length: t.length(),
+            plength: t.info.plength,
+        })
+    }
+}
+
+impl FileStorage {
+    /// Read `t` out of the file at `path`, as a finished download leaves it.
+    pub async fn open(path: &Path, t: &Torrent) -> anyhow::Result<Self> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("open {}", path.display()))?;
+        Ok(Self {
+            file,
+            length: t.length(),
+            plength: t.info.plength,
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    async fn write_block(&mut self, piece: usize, offset: usize, data: &[u8]) -> io::Result<()> {
+        let range = block_range(self.length, self.plength, piece, offset, data.len())?;
+        self.file.seek(SeekFrom::Start(range.start as u64)).await?;
+        self.file.write_all(data).await
+    }
+
+    async fn read_block(&mut self, piece: usize, offset: usize, len: usize) -> io::Result<Vec<u8>> {
+        let range = block_range(self.length, self.plength, piece, offset, len)?;
+        self.file.seek(SeekFrom::Start(range.start as u64)).await?;
+        let mut data = vec![0; len];
+        self.file.read_exact(&mut data).await?;
+        Ok(data)
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().await
+    }
+
+    async fn finalize(&mut self) -> io::Result<()> {
+        self.file.sync_all().await
+    }
+}
+
 impl<'a> IntoIterator for &'a Downloaded {
     type Item = DownloadedFile<'a>;
     type IntoIter = DownloadedIter<'a>;
@@ -208,11 +1193,16 @@ impl<'d> DownloadedIter<'d> {
 impl<'d> Iterator for DownloadedIter<'d> {
     type Item = DownloadedFile<'d>;
 
+    /// The next file to write out; padding files are only skipped over.
     fn next(&mut self) -> Option<Self::Item> {
-        let file = self.file_iter.next()?;
-        let bytes = &self.downloaded.bytes[self.offset..][..file.length];
-        self.offset += file.length;
-        Some(DownloadedFile { file, bytes })
+        loop {
+            let file = self.file_iter.next()?;
+            let bytes = &self.downloaded.bytes[self.offset..][..file.length];
+            self.offset += file.length;
+            if !file.is_padding() {
+                return Some(DownloadedFile { file, bytes });
+            }
+        }
     }
 }
 
@@ -230,3 +1220,892 @@ impl<'d> DownloadedFile<'d> {
         self.bytes
     }
 }
+
+/// Turn an advisory name (from the metainfo, or a magnet link's `dn`) into a single path component
+/// that is safe to create in the output directory.
+pub fn sanitize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_end_matches('.');
+    if name.is_empty() || name.chars().all(|c| c == '.') {
+        String::from("download")
+    } else {
+        name.to_string()
+    }
+}
+
+/// Where a download into `output` keeps its transfer totals between runs.
+pub fn stats_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".stats");
+    PathBuf::from(path)
+}
+
+/// `dir/name`, or if that is taken, the first free `dir/name.N.ext` counting up from 1.
+pub fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    first_free(dir, name, Path::exists)
+}
+
+/// `dir/name`, unless that is `taken`, in which case the first `dir/name.N.ext` that isn't.
+fn first_free(dir: &Path, name: &str, taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let candidate = dir.join(name);
+    if !taken(&candidate) {
+        return candidate;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, Some(ext)),
+        _ => (name, None),
+    };
+    (1..)
+        .map(|n| match ext {
+            Some(ext) => dir.join(format!("{stem}.{n}.{ext}")),
+            None => dir.join(format!("{stem}.{n}")),
+        })
+        .find(|candidate| !taken(candidate))
+        .expect("some suffix is always free")
+}
+
+/// Where to write a download when the user didn't give us `-o`: its (sanitized) name in the
+/// current directory, without clobbering anything already there.
+///
+/// An earlier download's output, which has its `.stats` beside it, is picked up again so that the
+/// download resumes; only someone else's file sends us to a numbered name.
+pub fn default_output(name: &str) -> PathBuf {
+    resumable_path(Path::new("."), &sanitize_name(name))
+}
+
+fn resumable_path(dir: &Path, name: &str) -> PathBuf {
+    first_free(dir, name, |candidate| {
+        candidate.exists() && !stats_path(candidate).exists()
+    })
+}
+
+/// Move a download that was started under a provisional name (e.g. a magnet link's `dn`) to its
+/// real name once that is known, keeping whatever data and `.stats` were already written.
+///
+/// Renaming keeps the files' locks, so a [`SessionLock`](crate::lock::SessionLock) taken on the
+/// provisional paths goes on guarding the download. Returns the path it now lives at.
+pub fn rename_provisional(provisional: &Path, name: &str) -> std::io::Result<PathBuf> {
+    let name = sanitize_name(name);
+    if provisional.file_name().and_then(|f| f.to_str()) == Some(name.as_str()) {
+        return Ok(provisional.to_path_buf());
+    }
+    let dir = provisional.parent().unwrap_or(Path::new("."));
+    // another download's state is as much in the way as its data
+    let target = first_free(dir, &name, |candidate| {
+        candidate.exists() || stats_path(candidate).exists()
+    });
+    let moves = [
+        (provisional.to_path_buf(), target.clone()),
+        (stats_path(provisional), stats_path(&target)),
+    ];
+    for (from, to) in moves {
+        if from.exists() {
+            std::fs::rename(from, to)?;
+        }
+    }
+    Ok(target)
+}
+
+#[test]
+fn sanitized_names() {
+    assert_eq!(sanitize_name("ubuntu.iso"), "ubuntu.iso");
+    assert_eq!(sanitize_name("../etc/passwd"), ".._etc_passwd");
+    assert_eq!(sanitize_name("a\tb:c"), "a_b_c");
+    assert_eq!(sanitize_name(".."), "download");
+    assert_eq!(sanitize_name("  "), "download");
+}
+
+#[test]
+fn unique_paths_get_numeric_suffixes() {
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(unique_path(dir.path(), "a.txt"), dir.path().join("a.txt"));
+    std::fs::write(dir.path().join("a.txt"), b"").unwrap();
+    assert_eq!(unique_path(dir.path(), "a.txt"), dir.path().join("a.1.txt"));
+    std::fs::write(dir.path().join("a.1.txt"), b"").unwrap();
+    assert_eq!(unique_path(dir.path(), "a.txt"), dir.path().join("a.2.txt"));
+    std::fs::write(dir.path().join("noext"), b"").unwrap();
+    assert_eq!(unique_path(dir.path(), "noext"), dir.path().join("noext.1"));
+    std::fs::write(dir.path().join(".hidden"), b"").unwrap();
+    assert_eq!(
+        unique_path(dir.path(), ".hidden"),
+        dir.path().join(".hidden.1")
+    );
+}
+
+#[test]
+fn renaming_provisional_keeps_partial_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let provisional = dir.path().join("from magnet");
+    std::fs::write(&provisional, b"partial").unwrap();
+    std::fs::write(dir.path().join("real.iso"), b"someone else's").unwrap();
+
+    let renamed = rename_provisional(&provisional, "real.iso").unwrap();
+    assert_eq!(renamed, dir.path().join("real.1.iso"));
+    assert!(!provisional.exists());
+    assert_eq!(std::fs::read(&renamed).unwrap(), b"partial");
+    assert_eq!(
+        std::fs::read(dir.path().join("real.iso")).unwrap(),
+        b"someone else's"
+    );
+
+    // already under the right name
+    assert_eq!(rename_provisional(&renamed, "real.1.iso").unwrap(), renamed);
+}
+
+#[test]
+fn renaming_provisional_takes_the_resume_state_along() {
+    let dir = tempfile::tempdir().unwrap();
+    let provisional = dir.path().join("from magnet");
+    std::fs::write(&provisional, b"partial").unwrap();
+    let stats = TransferStats::new(10, 20);
+    stats.save(&stats_path(&provisional)).unwrap();
+    // only the state of some other download is left under the real name
+    std::fs::write(dir.path().join("real.iso.stats"), b"").unwrap();
+
+    let renamed = rename_provisional(&provisional, "real.iso").unwrap();
+    assert_eq!(renamed, dir.path().join("real.1.iso"));
+    assert!(!stats_path(&provisional).exists());
+    let loaded = TransferStats::load(&stats_path(&renamed)).unwrap();
+    assert_eq!((loaded.uploaded(), loaded.downloaded()), (10, 20));
+    assert_eq!(std::fs::read(&renamed).unwrap(), b"partial");
+}
+
+#[test]
+fn default_outputs_resume_earlier_downloads() {
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(
+        resumable_path(dir.path(), "a.iso"),
+        dir.path().join("a.iso")
+    );
+    std::fs::write(dir.path().join("a.iso"), b"partial").unwrap();
+    std::fs::write(dir.path().join("a.iso.stats"), b"").unwrap();
+    assert_eq!(
+        resumable_path(dir.path(), "a.iso"),
+        dir.path().join("a.iso")
+    );
+
+    // a file without state isn't one of ours
+    std::fs::write(dir.path().join("b.iso"), b"someone else's").unwrap();
+    assert_eq!(
+        resumable_path(dir.path(), "b.iso"),
+        dir.path().join("b.1.iso")
+    );
+}
+
+#[cfg(test)]
+async fn download_from(behaviours: Vec<crate::mock::Behaviour>) -> anyhow::Result<Vec<u8>> {
+    use crate::mock;
+
+    let data = mock::data(3 * 32768 + 1000);
+    let (tracker, _peers, t) = mock::swarm(&data, 32768, behaviours).await;
+
+    let Outcome::Complete(downloaded) = all(
+        &t,
+        &TransferStats::default(),
+        &Source::Tracker(Listeners::default()),
+        &CancellationToken::new(),
+        Controls::none(),
+        Downloaded::new(&t),
+    )
+    .await?
+    else {
+        unreachable!("nobody cancels");
+    };
+    let bytes = downloaded.bytes;
+    assert_eq!(bytes, data);
+    // the tracker hears that we started, and then once that we're done
+    let events: Vec<_> = tracker
+        .requests()
+        .iter()
+        .map(|target| {
+            let query = mock::query(target);
+            query
+                .into_iter()
+                .find(|(k, _)| k == "event")
+                .map(|(_, v)| v)
+        })
+        .collect();
+    assert_eq!(events, [Some("started".into()), Some("completed".into())]);
+    Ok(bytes)
+}
+
+#[tokio::test]
+async fn downloads_through_a_udp_tracker() {
+    use crate::mock::{self, Behaviour, MockPeer, MockUdpTracker, UdpBehaviour};
+
+    let data = mock::data(2 * 32768 + 1000);
+    let t = mock::torrent_for("http://unused/announce", &data, 32768);
+    let peer = MockPeer::serve(&t, data.clone(), Behaviour::default()).await;
+    let tracker = MockUdpTracker::serve(&[peer.addr()], UdpBehaviour::default()).await;
+    let t = mock::torrent_for(&tracker.announce_url(), &data, 32768);
+    let mut download = DownloadHandle::builder(t, Arc::default()).spawn();
+    let Outcome::Complete(downloaded) = download.wait().await.unwrap() else {
+        panic!("nobody cancelled");
+    };
+    assert_eq!(downloaded.bytes, data);
+    // started, and then completed
+    let events: Vec<_> = tracker.announces().iter().map(|a| a[83]).collect();
+    assert_eq!(events, [2, 1]);
+}
+
+#[tokio::test]
+async fn blocks_answered_out_of_order_land_in_place() {
+    download_from(vec![crate::mock::Behaviour {
+        reorder: true,
+        ..Default::default()
+    }])
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn blocks_dropped_by_a_choke_are_asked_for_again() {
+    download_from(vec![crate::mock::Behaviour {
+        choke_after: Some(1),
+        ..Default::default()
+    }])
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn occasional_liars_are_tolerated() {
+    use crate::mock::{Behaviour, Lie};
+
+    for lie in [Lie::Index, Lie::Begin, Lie::Length] {
+        download_from(vec![Behaviour {
+            lie: Some(lie),
+            lies: 2,
+            ..Behaviour::default()
+        }])
+        .await
+        .unwrap_or_else(|e| panic!("{lie:?}: {e:?}"));
+    }
+}
+
+#[tokio::test]
+async fn persistent_liars_are_dropped() {
+    use crate::mock::{Behaviour, Lie};
+
+    for lie in [Lie::Index, Lie::Begin, Lie::Length] {
+        let liar = Behaviour {
+            lie: Some(lie),
+            lies: usize::MAX,
+            ..Behaviour::default()
+        };
+        let e = download_from(vec![liar.clone()]).await.unwrap_err();
+        assert!(e.to_string().contains("no peers left"), "{lie:?}: {e:?}");
+
+        // an honest peer picks up the slack
+        download_from(vec![liar, Behaviour::default()])
+            .await
+            .unwrap_or_else(|e| panic!("{lie:?}: {e:?}"));
+    }
+}
+
+#[tokio::test]
+async fn cancel_with_stalled_peer() {
+    use crate::mock::{self, Behaviour};
+    use std::time::Duration;
+
+    let data = mock::data(32768);
+    let stalled = Behaviour {
+        stall: true,
+        ..Behaviour::default()
+    };
+    let (tracker, peers, t) = mock::swarm(&data, 32768, vec![stalled]).await;
+    let peer = &peers[0];
+
+    let mut handle = DownloadHandle::spawn(t, Arc::default());
+    // give it time to get stuck waiting on the peer
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    handle.cancel();
+    let outcome = tokio::time::timeout(Duration::from_secs(2), handle.wait())
+        .await
+        .expect("cancellation is prompt")
+        .unwrap();
+    assert!(matches!(outcome, Outcome::Cancelled));
+    // both blocks of the piece were asked for, and neither is wanted any more
+    assert_eq!(peer.requests(), 2);
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while peer.cancels().len() < 2 && std::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(peer.cancels().len(), 2);
+
+    let requests = tracker.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(mock::query(&requests[0]).contains(&("event".into(), "started".into())));
+    assert!(mock::query(&requests[1]).contains(&("event".into(), "stopped".into())));
+}
+
+#[tokio::test]
+async fn dropping_the_handle_cancels() {
+    use crate::mock::{self, Behaviour};
+    use std::time::Duration;
+
+    let data = mock::data(32768);
+    let stalled = Behaviour {
+        stall: true,
+        ..Behaviour::default()
+    };
+    let (tracker, _peers, t) = mock::swarm(&data, 32768, vec![stalled]).await;
+
+    let handle = DownloadHandle::spawn(t.clone(), Arc::default());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    drop(handle);
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while tracker.requests().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("dropped download announces stopped");
+
+    // ... unless asked not to
+    let mut handle = DownloadHandle::spawn(t, Arc::default());
+    handle.cancel_on_drop(false);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    drop(handle);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(tracker.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn single_piece_moves_on_from_misbehaving_peers() {
+    use crate::mock::{self, Behaviour, Lie, MockPeer};
+
+    let data = mock::data(3 * 32768 + 1000);
+    let liar = Behaviour {
+        lie: Some(Lie::Begin),
+        lies: usize::MAX,
+        ..Behaviour::default()
+    };
+    let corrupt = Behaviour {
+        corrupt: true,
+        ..Behaviour::default()
+    };
+    let (_, peers, t) = mock::swarm(&data, 32768, vec![liar, corrupt, Behaviour::default()]).await;
+    let candidates: Vec<_> = peers.iter().map(|p| SocketAddr::from(p.addr())).collect();
+
+    let got = piece(&t, 1, &candidates, 5, PIECE_ATTEMPT_TIMEOUT, PIPELINE)
+        .await
+        .unwrap();
+    assert_eq!(got, data[32768..2 * 32768]);
+
+    // with only two attempts we never get to the honest peer
+    let e = piece(&t, 1, &candidates, 2, PIECE_ATTEMPT_TIMEOUT, PIPELINE)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "giving up on piece 1 after 2 failed attempts"
+    );
+
+    // a peer that never answers costs us the timeout, and then the next one is tried
+    let stall = MockPeer::serve(
+        &t,
+        data.clone(),
+        Behaviour {
+            stall: true,
+            ..Behaviour::default()
+        },
+    )
+    .await;
+    let candidates = [SocketAddr::from(stall.addr()), candidates[2]];
+    let got = piece(&t, 3, &candidates, 5, Duration::from_millis(200), PIPELINE)
+        .await
+        .unwrap();
+    assert_eq!(got, data[3 * 32768..]);
+}
+
+#[tokio::test]
+async fn single_piece_passes_over_peers_without_it() {
+    use crate::mock::{self, Behaviour};
+
+    let data = mock::data(3 * 32768);
+    let lacking = Behaviour {
+        lacks: Some(1),
+        ..Behaviour::default()
+    };
+    let behaviours = vec![lacking.clone(), lacking, Behaviour::default()];
+    let (_, peers, t) = mock::swarm(&data, 32768, behaviours).await;
+    let candidates: Vec<_> = peers.iter().map(|p| SocketAddr::from(p.addr())).collect();
+
+    // neither peer without the piece uses up the one attempt
+    let got = piece(&t, 1, &candidates, 1, PIECE_ATTEMPT_TIMEOUT, PIPELINE)
+        .await
+        .unwrap();
+    assert_eq!(got, data[32768..2 * 32768]);
+
+    let e = piece(&t, 1, &candidates[..2], 5, PIECE_ATTEMPT_TIMEOUT, PIPELINE)
+        .await
+        .unwrap_err();
+    assert_eq!(e.to_string(), "none of the 2 peers have piece 1");
+
+    // not even trying isn't the same as nobody having it
+    let e = piece(&t, 1, &candidates, 0, PIECE_ATTEMPT_TIMEOUT, PIPELINE)
+        .await
+        .unwrap_err();
+    assert_eq!(e.to_string(), "at least one attempt is needed");
+}
+
+#[cfg(test)]
+async fn slow_download(grace: Duration) -> (Vec<u8>, crate::mock::MockPeer, DownloadHandle) {
+    use crate::mock::{self, Behaviour};
+
+    let data = mock::data(3 * 32768 + 1000);
+    let behaviour = Behaviour {
+        delay: Duration::from_millis(20),
+        ..Behaviour::default()
+    };
+    let (_, mut peers, t) = mock::swarm(&data, 32768, vec![behaviour]).await;
+    let peer = peers.remove(0);
+    let download = DownloadHandle::builder(t, Arc::default())
+        .grace(grace)
+        .spawn();
+    // let the download get going
+    while peer.requests() < 2 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    (data, peer, download)
+}
+
+#[tokio::test]
+async fn no_requests_while_paused() {
+    let (data, peer, mut download) = slow_download(PAUSE_GRACE).await;
+    download.pause();
+    assert!(download.is_paused());
+    // whatever was already requested still arrives ...
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let requested = peer.requests();
+    // ... but nothing new is asked for
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(peer.requests(), requested);
+    assert!(requested < 7, "the download should be nowhere near done");
+
+    download.resume();
+    let Outcome::Complete(downloaded) = download.wait().await.unwrap() else {
+        panic!("nobody cancelled");
+    };
+    assert_eq!(downloaded.bytes, data);
+    assert_eq!(peer.connections(), 1);
+}
+
+#[tokio::test]
+async fn long_pauses_disconnect_and_redial() {
+    let (data, peer, mut download) = slow_download(Duration::from_millis(100)).await;
+    download.pause();
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let requested = peer.requests();
+    assert_eq!(peer.connections(), 1);
+
+    download.resume();
+    let Outcome::Complete(downloaded) = download.wait().await.unwrap() else {
+        panic!("nobody cancelled");
+    };
+    assert_eq!(downloaded.bytes, data);
+    // the rest came over a new connection
+    assert_eq!(peer.connections(), 2);
+    assert!(peer.requests() > requested);
+
+    // which opened with a bitfield of the pieces done before the pause, if there were any, and
+    // then each connection heard about the pieces that got done while it was up
+    use crate::peer::Message;
+    let mut announcements = peer.announcements();
+    for _ in 0..50 {
+        // the last Have can still be on its way
+        if announcements
+            .iter()
+            .filter(|(_, msg)| matches!(msg, Message::Have(_)))
+            .count()
+            == 4
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        announcements = peer.announcements();
+    }
+    let haves = |connection| -> Vec<usize> {
+        announcements
+            .iter()
+            .filter(|(c, _)| *c == connection)
+            .filter_map(|(_, msg)| match msg {
+                Message::Have(index) => Some(*index as usize),
+                _ => None,
+            })
+            .collect()
+    };
+    let before = haves(0);
+    let mut second = announcements.iter().filter(|(c, _)| *c == 1);
+    if !before.is_empty() {
+        let (_, bitfield) = second.next().unwrap();
+        let done = crate::progress::PieceMap(
+            (0..4)
+                .map(|i| {
+                    if before.contains(&i) {
+                        PieceState::Done
+                    } else {
+                        PieceState::Pending
+                    }
+                })
+                .collect(),
+        );
+        assert_eq!(*bitfield, Message::Bitfield(done.bitfield().into()));
+    }
+    let after: Vec<usize> = (0..4).filter(|i| !before.contains(i)).collect();
+    assert_eq!(haves(1), after);
+    assert!(second.all(|(_, msg)| matches!(msg, Message::Have(_))));
+}
+
+#[tokio::test]
+async fn rate_limits_slow_requests_down() {
+    use crate::mock;
+
+    // four blocks at four blocks a second: the first goes straight away, the rest wait their turn
+    let data = mock::data(4 * BLOCK_MAX);
+    let (_tracker, _peers, t) = mock::swarm(&data, 4 * BLOCK_MAX, vec![Default::default()]).await;
+
+    let mut handle = DownloadHandle::spawn(t, Arc::default());
+    handle.set_rate_limit(Some(4 * BLOCK_MAX as u64));
+    let started = Instant::now();
+    let Outcome::Complete(downloaded) = handle.wait().await.unwrap() else {
+        unreachable!("nobody cancels");
+    };
+    assert_eq!(downloaded.bytes, data);
+    assert!(started.elapsed() >= Duration::from_millis(700));
+}
+
+#[tokio::test]
+async fn pieces_nobody_has_wait_for_someone_who_does() {
+    use crate::mock::{self, Behaviour, MockPeer};
+    use std::net::SocketAddrV4;
+
+    let data = mock::data(3 * 32768 + 1000);
+    let t = mock::torrent_for("http://unused/announce", &data, 32768);
+    let lacking = Behaviour {
+        lacks: Some(1),
+        ..Behaviour::default()
+    };
+    let partial = MockPeer::serve(&t, data.clone(), lacking).await;
+    let full = MockPeer::serve(&t, data.clone(), Behaviour::default()).await;
+
+    // on its own, the peer without piece 1 gets us everything else, and then we're stuck
+    let mut download = DownloadHandle::builder(t.clone(), Arc::default())
+        .peers(vec![partial.addr().into()])
+        .spawn();
+    let e = download.wait().await.unwrap_err();
+    assert_eq!(e.to_string(), "no connected peer has piece 1");
+
+    // but with a tracker, piece 1 waits until a re-announce turns up a peer that has it
+    let right_away = |peers: &[SocketAddrV4]| {
+        let body = mock::peers_response(peers);
+        [
+            b"d8:intervali0e".as_slice(),
+            &body[b"d8:intervali1800e".len()..],
+        ]
+        .concat()
+    };
+    let tracker = mock::MockTracker::serve(vec![
+        right_away(&[partial.addr()]),
+        right_away(&[partial.addr(), full.addr()]),
+    ])
+    .await;
+    let t = mock::torrent_for(&tracker.announce_url(), &data, 32768);
+    let mut download = DownloadHandle::builder(t, Arc::default())
+        .min_reannounce(Duration::from_millis(20))
+        .spawn();
+    let Outcome::Complete(downloaded) = download.wait().await.unwrap() else {
+        panic!("nobody cancelled");
+    };
+    assert_eq!(downloaded.bytes, data);
+}
+
+#[tokio::test]
+async fn reannounces_bring_in_new_peers_once() {
+    use crate::mock::{self, Behaviour, MockPeer};
+    use std::net::SocketAddrV4;
+
+    let data = mock::data(3 * 32768 + 1000);
+    let t = mock::torrent_for("http://unused/announce", &data, 32768);
+    let slow = Behaviour {
+        delay: Duration::from_millis(30),
+        ..Behaviour::default()
+    };
+    let first = MockPeer::serve(&t, data.clone(), slow.clone()).await;
+    let second = MockPeer::serve(&t, data.clone(), slow).await;
+    // a tracker that wants to hear from us again right away, and only knows about the second
+    // peer from its second answer on
+    let right_away = |peers: &[SocketAddrV4]| {
+        let body = mock::peers_response(peers);
+        [
+            b"d8:intervali0e".as_slice(),
+            &body[b"d8:intervali1800e".len()..],
+        ]
+        .concat()
+    };
+    let tracker = mock::MockTracker::serve(vec![
+        right_away(&[first.addr()]),
+        right_away(&[first.addr(), second.addr()]),
+    ])
+    .await;
+    let t = mock::torrent_for(&tracker.announce_url(), &data, 32768);
+    let mut download = DownloadHandle::builder(t, Arc::default())
+        .min_reannounce(Duration::from_millis(20))
+        .spawn();
+    let Outcome::Complete(downloaded) = download.wait().await.unwrap() else {
+        panic!("nobody cancelled");
+    };
+    assert_eq!(downloaded.bytes, data);
+
+    let events: Vec<_> = tracker
+        .requests()
+        .iter()
+        .map(|target| {
+            let query = mock::query(target);
+            query
+                .into_iter()
+                .find(|(k, _)| k == "event")
+                .map(|(_, v)| v)
+        })
+        .collect();
+    assert_eq!(events.first(), Some(&Some("started".into())));
+    assert_eq!(events.last(), Some(&Some("completed".into())));
+    let periodic = events.iter().filter(|event| event.is_none()).count();
+    assert!(periodic >= 2, "only {periodic} re-announces");
+    // every answer lists the first peer again, but we stay connected to it
+    assert_eq!(first.connections(), 1);
+    assert_eq!(second.connections(), 1);
+}
+
+#[tokio::test]
+async fn downloads_ride_out_a_tracker_outage() {
+    use crate::mock::{self, Behaviour, MockPeer, MockResponse, MockTracker};
+    use std::net::SocketAddrV4;
+
+    let data = mock::data(3 * 32768 + 1000);
+    let t = mock::torrent_for("http://unused/announce", &data, 32768);
+    let slow = Behaviour {
+        delay: Duration::from_millis(50),
+        ..Behaviour::default()
+    };
+    let first = MockPeer::serve(&t, data.clone(), slow.clone()).await;
+    let second = MockPeer::serve(&t, data.clone(), slow).await;
+    let right_away = |peers: &[SocketAddrV4]| {
+        let body = mock::peers_response(peers);
+        let body = [
+            b"d8:intervali0e".as_slice(),
+            &body[b"d8:intervali1800e".len()..],
+        ]
+        .concat();
+        MockResponse::ok(body)
+    };
+    // the tracker fails three re-announces in a row, and only then tells us of the second peer
+    let down = MockResponse::ok(b"d14:failure reason4:downe".to_vec());
+    let tracker = MockTracker::serve_responses(vec![
+        right_away(&[first.addr()]),
+        down.clone(),
+        down.clone(),
+        down,
+        right_away(&[first.addr(), second.addr()]),
+    ])
+    .await;
+    let t = mock::torrent_for(&tracker.announce_url(), &data, 32768);
+    let stats = Arc::new(TransferStats::default());
+    let mut download = DownloadHandle::builder(t, Arc::clone(&stats))
+        .min_reannounce(Duration::from_millis(20))
+        .announce_backoff(Duration::from_millis(5))
+        .spawn();
+    let Outcome::Complete(downloaded) = download.wait().await.unwrap() else {
+        panic!("nobody cancelled");
+    };
+    assert_eq!(downloaded.bytes, data);
+
+    let (ok, failed) = stats.announces();
+    assert_eq!(failed, 3);
+    assert!(ok >= 3, "only {ok} announces worked");
+    assert!(stats.next_announce().is_some());
+    assert_eq!(second.connections(), 1);
+}
+
+#[test]
+fn piece_ranges() {
+    let range = |s| parse_piece_range(s).unwrap();
+    assert_eq!(range("100..120").resolve(2000).unwrap(), 100..120);
+    assert_eq!(range("..50").resolve(2000).unwrap(), 0..50);
+    assert_eq!(range("1200..").resolve(2000).unwrap(), 1200..2000);
+    assert_eq!(range("..").resolve(3).unwrap(), 0..3);
+
+    assert!(parse_piece_range("5").is_err());
+    assert!(parse_piece_range("a..b").is_err());
+    let e = range("10..2001").resolve(2000).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "the torrent only has 2000 pieces, so the range can't end at 2001"
+    );
+    assert!(range("20..10").resolve(2000).is_err());
+    assert!(range("2000..").resolve(2000).is_err());
+}
+
+#[tokio::test]
+async fn padding_files_are_not_written_out() {
+    let data = crate::mock::data(256 + 100);
+    // the pad aligns b.bin to the second piece, and is all zeros
+    let mut padded = data[..250].to_vec();
+    padded.extend([0; 6]);
+    padded.extend(&data[256..]);
+    let files = [
+        ("a.bin", 250, false),
+        (".pad", 6, true),
+        ("b.bin", 100, false),
+    ];
+    let t = crate::piece::multi_file_torrent(&padded, 256, &files);
+
+    let mut downloaded = Downloaded::new(&t);
+    for (piece_i, piece) in padded.chunks(256).enumerate() {
+        downloaded.write_block(piece_i, 0, piece).await.unwrap();
+    }
+    let written: Vec<_> = (&downloaded)
+        .into_iter()
+        .map(|file| (file.path().join("/"), file.bytes().to_vec()))
+        .collect();
+    assert_eq!(
+        written,
+        [
+            (String::from("a.bin"), data[..250].to_vec()),
+            (String::from("b.bin"), data[256..].to_vec())
+        ]
+    );
+}
+
+#[tokio::test]
+async fn piece_ranges_land_at_their_offsets() {
+    use crate::mock::{self, Behaviour};
+
+    let data = mock::data(3 * 16384 + 1000);
+    let corrupt = Behaviour {
+        corrupt: true,
+        ..Behaviour::default()
+    };
+    let (_, peers, t) = mock::swarm(&data, 16384, vec![Behaviour::default(), corrupt]).await;
+    let (good, bad) = (&peers[0], &peers[1]);
+    let dir = tempfile::tempdir().unwrap();
+
+    let path = dir.path().join("out.bin");
+    let mut output = PieceOutput::sparse(&path, &t).await.unwrap();
+    let results = piece_range(&t, 1..4, &[good.addr().into()], 1, PIPELINE, &mut output).await;
+    assert!(results.iter().all(|(_, r)| r.is_ok()), "{results:?}");
+    drop(output);
+    let written = std::fs::read(&path).unwrap();
+    assert_eq!(written.len(), data.len());
+    assert!(written[..16384].iter().all(|&b| b == 0));
+    assert_eq!(written[16384..], data[16384..]);
+
+    // a bad copy of the region is reported piece by piece, and nothing of it is kept
+    let split = dir.path().join("pieces");
+    let mut output = PieceOutput::split(&split).unwrap();
+    let results = piece_range(&t, 0..2, &[bad.addr().into()], 1, PIPELINE, &mut output).await;
+    assert_eq!(
+        results
+            .iter()
+            .map(|(i, r)| (*i, r.is_ok()))
+            .collect::<Vec<_>>(),
+        vec![(0, false), (1, false)]
+    );
+    assert_eq!(std::fs::read_dir(&split).unwrap().count(), 0);
+
+    let results = piece_range(&t, 3..4, &[good.addr().into()], 1, PIPELINE, &mut output).await;
+    assert!(results[0].1.is_ok());
+    assert_eq!(
+        std::fs::read(split.join("piece-3.bin")).unwrap(),
+        data[3 * 16384..]
+    );
+}
+
+/// Download `data` from a mock swarm into `storage`, returning what it then reads back.
+#[cfg(test)]
+async fn download_into<S: Storage>(storage: S, data: &[u8], plength: usize) -> Vec<u8> {
+    use crate::mock::{self, Behaviour};
+
+    let (_tracker, _peers, t) = mock::swarm(data, plength, vec![Behaviour::default()]).await;
+    let mut download = DownloadHandle::builder(t.clone(), Arc::default())
+        .storage(storage)
+        .spawn();
+    let Outcome::Complete(mut storage) = download.wait().await.unwrap() else {
+        panic!("download was cancelled");
+    };
+    let mut read = Vec::new();
+    for piece_i in 0..t.num_pieces() {
+        let len = t.piece_length_for(piece_i);
+        read.extend(storage.read_block(piece_i, 0, len).await.unwrap());
+    }
+    // blocks needn't line up with pieces, but must stay within one
+    let tail = storage.read_block(1, plength - 10, 10).await.unwrap();
+    assert_eq!(tail, data[2 * plength - 10..2 * plength]);
+    assert!(storage.read_block(0, plength - 10, 11).await.is_err());
+    read
+}
+
+#[tokio::test]
+async fn every_storage_gets_the_same_download() {
+    let data = crate::mock::data(3 * 32768 + 1000);
+    let t = crate::mock::torrent_for("http://unused/announce", &data, 32768);
+
+    let in_memory = download_into(Downloaded::new(&t), &data, 32768).await;
+    assert_eq!(in_memory, data);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("out");
+    let file = FileStorage::create(&path, &t).await.unwrap();
+    let on_disk = download_into(file, &data, 32768).await;
+    assert_eq!(on_disk, data);
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+}
+
+#[tokio::test]
+async fn resumed_downloads_report_what_they_already_have() {
+    use crate::mock::{self, Behaviour};
+
+    let data = mock::data(3 * 32768 + 1000);
+    let (tracker, peers, t) = mock::swarm(&data, 32768, vec![Behaviour::default()]).await;
+    let peer = &peers[0];
+
+    // the first two pieces made it to storage last time, and the stats file says as much
+    let mut storage = Downloaded::new(&t);
+    storage.bytes[..2 * 32768].copy_from_slice(&data[..2 * 32768]);
+    storage.bytes[2 * 32768] = 1;
+    let verified = verified_pieces(&t, &mut storage).await.unwrap();
+    assert_eq!(verified, [0, 1]);
+    let stats = Arc::new(TransferStats::new(0, 2 * 32768));
+    let mut download = DownloadHandle::builder(t.clone(), Arc::clone(&stats))
+        .storage(storage)
+        .verified(verified)
+        .spawn();
+    let Outcome::Complete(downloaded) = download.wait().await.unwrap() else {
+        panic!("nobody cancelled");
+    };
+    assert_eq!(downloaded.bytes, data);
+    // the two blocks of piece 2, and the one of the short last piece
+    assert_eq!(peer.requests(), 3);
+
+    let counters: Vec<_> = tracker
+        .requests()
+        .iter()
+        .map(|target| {
+            let query = mock::query(target);
+            let get = |key: &str| {
+                let (_, value) = query.iter().find(|(k, _)| k == key).unwrap();
+                value.parse::<usize>().unwrap()
+            };
+            (get("downloaded"), get("left"), get("uploaded"))
+        })
+        .collect();
+    assert_eq!(counters, [(2 * 32768, 32768 + 1000, 0), (data.len(), 0, 0)]);
+}
@@ -0,0 +1,422 @@
+//! Checking on a torrent's swarm across all of its trackers, without joining it.
+
+use crate::torrent::{InfoHash, Torrent};
+use crate::tracker::{
+    self, NotBencode, Swarm, TrackerClient, TrackerError, TrackerRequest, UnexpectedStatus,
+};
+use crate::DEFAULT_PORT;
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How many trackers we wait on at once.
+pub const CONCURRENCY: usize = 8;
+
+/// How we got a tracker to tell us about the swarm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Method {
+    Scrape,
+    /// An announce asking for no peers, for trackers that can't be scraped.
+    Announce,
+}
+
+/// How asking one tracker went, in short.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Timeout,
+    /// The tracker answered with this HTTP status and no usable body.
+    Http(u16),
+    /// The tracker's `failure reason`.
+    Failure(String),
+    /// Anything else, like a refused connection.
+    Error(String),
+}
+
+impl Status {
+    fn of(e: &anyhow::Error) -> Self {
+        for cause in e.chain() {
+            if let Some(TrackerError::Failure(reason)) = cause.downcast_ref() {
+                return Status::Failure(reason.clone());
+            }
+            if let Some(e) = cause.downcast_ref::<NotBencode>() {
+                if !e.meta.status.is_success() {
+                    return Status::Http(e.meta.status.as_u16());
+                }
+            }
+            if let Some(e) = cause.downcast_ref::<UnexpectedStatus>() {
+                return Status::Http(e.meta.status.as_u16());
+            }
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                if e.is_timeout() {
+                    return Status::Timeout;
+                }
+                if let Some(status) = e.status() {
+                    return Status::Http(status.as_u16());
+                }
+            }
+        }
+        Status::Error(format!("{e:#}"))
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Ok => f.write_str("ok"),
+            Status::Timeout => f.write_str("timeout"),
+            Status::Http(status) => write!(f, "HTTP {status}"),
+            Status::Failure(reason) => write!(f, "failure: {reason}"),
+            Status::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
+
+/// What one tracker said, or why it didn't.
+#[derive(Debug, Serialize)]
+pub struct TrackerHealth {
+    /// The tracker's announce URL, with any password redacted.
+    pub tracker: String,
+    pub status: Status,
+    /// `None` if neither method got an answer.
+    pub method: Option<Method>,
+    pub swarm: Option<Swarm>,
+    /// How many peers the tracker handed out, if we asked it for any.
+    pub peers: Option<usize>,
+    pub error: Option<String>,
+    pub elapsed_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub trackers: Vec<TrackerHealth>,
+    /// The biggest numbers any tracker gave: trackers of one torrent mostly see the same peers,
+    /// so adding them up would count most of them several times.
+    pub summary: Swarm,
+}
+
+/// Ask every tracker of `t` about its swarm, scraping those that allow it and announcing to the
+/// rest, and giving each of them up to `timeout`.
+pub async fn check(t: &Torrent, timeout: Duration) -> anyhow::Result<Health> {
+    survey(t, timeout, Method::Scrape).await
+}
+
+/// Announce to every tracker of `t`, in every tier, giving each of them up to `timeout`; unlike
+/// [`check`], this counts the peers each one hands out.
+pub async fn announce(t: &Torrent, timeout: Duration) -> anyhow::Result<Health> {
+    survey(t, timeout, Method::Announce).await
+}
+
+/// Ask [`CONCURRENCY`] trackers at a time, starting with `first`, so that a hung one only ever
+/// holds up its own row.
+async fn survey(t: &Torrent, timeout: Duration, first: Method) -> anyhow::Result<Health> {
+    let info_hash = t.info_hash()?;
+    let checks = t.trackers().into_iter().map(|announce| async move {
+        let start = Instant::now();
+        let result = tokio::time::timeout(timeout, ask(t, &announce, info_hash, first)).await;
+        let (status, answer, error) = match result {
+            Ok(Ok(answer)) => (Status::Ok, Some(answer), None),
+            Ok(Err(e)) => (Status::of(&e), None, Some(format!("{e:#}"))),
+            Err(_) => (
+                Status::Timeout,
+                None,
+                Some(format!("timed out after {timeout:?}")),
+            ),
+        };
+        TrackerHealth {
+            tracker: tracker::redacted(&announce),
+            status,
+            method: answer.map(|(method, _, _)| method),
+            swarm: answer.map(|(_, swarm, _)| swarm),
+            peers: answer.and_then(|(_, _, peers)| peers),
+            error,
+            elapsed_ms: start.elapsed().as_millis(),
+        }
+    });
+    let trackers: Vec<_> = futures_util::stream::iter(checks)
+        .buffered(CONCURRENCY)
+        .collect()
+        .await;
+
+    let swarms: Vec<&Swarm> = trackers.iter().filter_map(|t| t.swarm.as_ref()).collect();
+    let max = |field: fn(&Swarm) -> Option<usize>| swarms.iter().filter_map(|s| field(s)).max();
+    let summary = Swarm {
+        seeders: max(|s| s.seeders),
+        leechers: max(|s| s.leechers),
+        completed: max(|s| s.completed),
+    };
+    Ok(Health { trackers, summary })
+}
+
+/// The swarm as one tracker sees it, and how many peers it gave us if we announced for some.
+///
+/// Scraping falls back to an announce for no peers, which tells us about the swarm all the same.
+async fn ask(
+    t: &Torrent,
+    announce: &str,
+    info_hash: InfoHash,
+    first: Method,
+) -> anyhow::Result<(Method, Swarm, Option<usize>)> {
+    if first == Method::Scrape {
+        if let Ok(swarm) = tracker::scrape(announce, info_hash).await {
+            return Ok((Method::Scrape, swarm, None));
+        }
+    }
+    let numwant = match first {
+        Method::Scrape => 0,
+        Method::Announce => tracker::DEFAULT_NUMWANT,
+    };
+    let mut request = TrackerRequest::new(crate::peer::PeerId::ours(), DEFAULT_PORT, t.length());
+    request.numwant = Some(numwant);
+    let response = TrackerClient::shared()
+        .announce(announce, info_hash, &request, None)
+        .await?;
+    let peers = (numwant > 0).then_some(response.peers.addrs.len());
+    Ok((Method::Announce, response.swarm(), peers))
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let number = |n: Option<usize>| n.map_or_else(|| String::from("-"), |n| n.to_string());
+        let width = self
+            .trackers
+            .iter()
+            .map(|t| t.tracker.len())
+            .chain(["tracker".len()])
+            .max()
+            .unwrap_or_default();
+        writeln!(
+            f,
+            "{:width$}  {:8}  {:>8}  {:>8}  {:>9}  {:>7}",
+            "tracker", "method", "seeders", "leechers", "completed", "time"
+        )?;
+        for t in &self.trackers {
+            write!(f, "{:width$}  ", t.tracker)?;
+            match (&t.method, &t.swarm, &t.error) {
+                (Some(method), Some(swarm), _) => writeln!(
+                    f,
+                    "{:8}  {:>8}  {:>8}  {:>9}  {:>5}ms",
+                    format!("{method:?}").to_lowercase(),
+                    number(swarm.seeders),
+                    number(swarm.leechers),
+                    number(swarm.completed),
+                    t.elapsed_ms
+                )?,
+                (_, _, error) => writeln!(
+                    f,
+                    "failed after {}ms: {}",
+                    t.elapsed_ms,
+                    error.as_deref().unwrap_or("no answer")
+                )?,
+            }
+        }
+        let responded = self.trackers.iter().filter(|t| t.swarm.is_some()).count();
+        write!(
+            f,
+            "summary: {} seeders, {} leechers, {} completed ({responded} of {} trackers responded)",
+            number(self.summary.seeders),
+            number(self.summary.leechers),
+            number(self.summary.completed),
+            self.trackers.len()
+        )
+    }
+}
+
+/// The per-tracker status table of the `trackers` command.
+pub struct Statuses<'a>(pub &'a Health);
+
+impl fmt::Display for Statuses<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .0
+            .trackers
+            .iter()
+            .map(|t| t.tracker.len())
+            .chain(["tracker".len()])
+            .max()
+            .unwrap_or_default();
+        write!(
+            f,
+            "{:width$}  {:>5}  {:>7}  status",
+            "tracker", "peers", "time"
+        )?;
+        for t in &self.0.trackers {
+            let peers = t.peers.map_or_else(|| String::from("-"), |n| n.to_string());
+            write!(
+                f,
+                "\n{:width$}  {peers:>5}  {:>5}ms  {}",
+                t.tracker, t.elapsed_ms, t.status
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn every_tracker_gets_its_own_row() {
+    use crate::bencode::Value;
+    use crate::mock::{self, MockResponse, MockTracker};
+
+    let data = mock::data(1000);
+    let t = mock::torrent_for("http://unused/announce", &data, 1000);
+    let info_hash = t.info_hash().unwrap();
+
+    let mut scrape = b"d5:filesd20:".to_vec();
+    scrape.extend(info_hash.0);
+    scrape.extend(b"d8:completei5e10:downloadedi50e10:incompletei2eeee");
+    let scrapes = MockTracker::serve(vec![scrape]).await;
+    let announces = MockTracker::serve_responses(vec![
+        MockResponse {
+            status: 404,
+            headers: Vec::new(),
+            body: Vec::new(),
+        },
+        MockResponse::ok(b"d8:completei7e10:incompletei1e5:peers0:e".to_vec()),
+    ])
+    .await;
+    let dead = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/announce", listener.local_addr().unwrap())
+    };
+    // accepts connections (into its backlog), but never answers
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let silent_url = format!("http://{}/announce", silent.local_addr().unwrap());
+
+    let mut t = mock::torrent_for(&scrapes.announce_url(), &data, 1000);
+    let urls = [
+        scrapes.announce_url(),
+        announces.announce_url(),
+        dead,
+        silent_url,
+    ];
+    t.extra.insert(
+        b"announce-list".to_vec(),
+        Value::List(
+            urls.iter()
+                .map(|url| Value::List(vec![Value::Bytes(url.as_bytes().to_vec())]))
+                .collect(),
+        ),
+    );
+
+    let started = Instant::now();
+    let health = check(&t, Duration::from_millis(300)).await.unwrap();
+    assert!(
+        started.elapsed() < Duration::from_secs(2),
+        "the checks run at once"
+    );
+
+    let rows: Vec<_> = health
+        .trackers
+        .iter()
+        .map(|t| (t.tracker.as_str(), t.method, t.swarm))
+        .collect();
+    let swarm = |seeders, leechers, completed| Swarm {
+        seeders: Some(seeders),
+        leechers: Some(leechers),
+        completed,
+    };
+    assert_eq!(
+        rows,
+        vec![
+            (&*urls[0], Some(Method::Scrape), Some(swarm(5, 2, Some(50)))),
+            (&*urls[1], Some(Method::Announce), Some(swarm(7, 1, None))),
+            (&*urls[2], None, None),
+            (&*urls[3], None, None),
+        ]
+    );
+    assert!(scrapes.requests()[0].starts_with("/scrape?info_hash="));
+    let announce = &announces.requests()[1];
+    assert!(mock::query(announce).contains(&("numwant".into(), "0".into())));
+    assert_eq!(
+        health.trackers[3].error.as_deref(),
+        Some("timed out after 300ms")
+    );
+    assert_eq!(health.summary, swarm(7, 2, Some(50)));
+
+    let table = health.to_string();
+    assert!(
+        table.ends_with("summary: 7 seeders, 2 leechers, 50 completed (2 of 4 trackers responded)"),
+        "{table}"
+    );
+    drop(silent);
+}
+
+#[tokio::test]
+async fn trackers_are_announced_to_a_few_at_a_time() {
+    use crate::bencode::Value;
+    use crate::mock::{self, MockResponse, MockTracker};
+
+    let peers = [
+        "10.0.0.1:6881".parse().unwrap(),
+        "10.0.0.2:6881".parse().unwrap(),
+    ];
+    let ok = MockTracker::serve(vec![mock::peers_response(&peers)]).await;
+    let missing = MockTracker::serve_responses(vec![MockResponse {
+        status: 404,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }])
+    .await;
+    let refusing = MockTracker::serve(vec![b"d14:failure reason12:unregisterede".to_vec()]).await;
+    // more hung trackers than we ask at once, so the last of them has to wait its turn
+    let mut silent = Vec::new();
+    for _ in 0..CONCURRENCY + 1 {
+        silent.push(tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+
+    let data = mock::data(1000);
+    let mut t = mock::torrent_for(&ok.announce_url(), &data, 1000);
+    let mut urls = vec![
+        ok.announce_url(),
+        missing.announce_url(),
+        refusing.announce_url(),
+    ];
+    urls.extend(
+        silent
+            .iter()
+            .map(|l| format!("http://{}/announce", l.local_addr().unwrap())),
+    );
+    t.extra.insert(
+        b"announce-list".to_vec(),
+        Value::List(vec![Value::List(
+            urls.iter()
+                .map(|url| Value::Bytes(url.as_bytes().to_vec()))
+                .collect(),
+        )]),
+    );
+
+    let timeout = Duration::from_millis(300);
+    let started = Instant::now();
+    let health = announce(&t, timeout).await.unwrap();
+    let elapsed = started.elapsed();
+    assert!(elapsed >= 2 * timeout, "{elapsed:?}");
+    assert!(elapsed < 6 * timeout, "{elapsed:?}");
+
+    let rows: Vec<_> = health
+        .trackers
+        .iter()
+        .map(|t| (t.tracker.as_str(), t.status.clone(), t.peers))
+        .collect();
+    let mut expected = vec![
+        (&*urls[0], Status::Ok, Some(2)),
+        (&*urls[1], Status::Http(404), None),
+        (&*urls[2], Status::Failure("unregistered".into()), None),
+    ];
+    expected.extend(urls[3..].iter().map(|url| (&**url, Status::Timeout, None)));
+    assert_eq!(rows, expected);
+    assert!(mock::query(&ok.requests()[0]).contains(&("numwant".into(), "50".into())));
+
+    let table = Statuses(&health).to_string();
+    let lines: Vec<_> = table.lines().collect();
+    assert_eq!(lines.len(), urls.len() + 1);
+    assert!(
+        lines[1].ends_with("ms  ok") && lines[1].contains("    2  "),
+        "{table}"
+    );
+    assert!(lines[2].ends_with("HTTP 404"), "{table}");
+    assert!(lines[3].ends_with("failure: unregistered"), "{table}");
+    assert!(lines[4].ends_with("timeout"), "{table}");
+}
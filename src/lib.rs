@@ -1,8 +1,27 @@
 pub const DEFAULT_PORT: u16 = 6881;
 pub const BLOCK_MAX: usize = 1 << 14;
 
+pub mod bench;
+pub mod bencode;
+pub mod choke;
+pub mod control;
+pub mod create;
 pub mod download;
+pub mod health;
+pub mod lock;
+pub mod magnet;
+pub mod metadata;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(test)]
+mod mock;
 pub mod peer;
+pub mod pex;
 pub mod piece;
+pub mod pool;
+pub mod progress;
+pub mod resolve;
+pub mod seed;
+pub mod throttle;
 pub mod torrent;
 pub mod tracker;
@@ -0,0 +1,167 @@
+//! Keeping two runs off the same files.
+//!
+//! A second `download` into the same output would interleave its writes with the first one's and
+//! clobber its saved stats, so each run holds an advisory exclusive lock on those files for as
+//! long as it goes on. The OS drops the locks with the process, so a crash leaves none behind.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockError {
+    #[error(
+        "another instance is already downloading this torrent ({} is locked{})",
+        .path.display(),
+        .pid.map(|pid| format!(" by pid {pid}")).unwrap_or_default()
+    )]
+    Held { path: PathBuf, pid: Option<u32> },
+    #[error("could not lock {}", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// Exclusive locks on the files of one run, released when it's dropped.
+#[derive(Debug)]
+pub struct SessionLock {
+    _files: Vec<File>,
+}
+
+impl SessionLock {
+    /// Lock every file in `paths`, creating those that don't exist yet, or fail straight away if
+    /// another run holds any of them.
+    pub fn acquire<P: AsRef<Path>>(paths: &[P]) -> Result<Self, LockError> {
+        let mut files = Vec::new();
+        for path in paths {
+            let path = path.as_ref().to_path_buf();
+            let io_error = |source| LockError::Io {
+                path: path.clone(),
+                source,
+            };
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .map_err(io_error)?;
+            match file.try_lock() {
+                Ok(()) => files.push(file),
+                Err(TryLockError::WouldBlock) => {
+                    let pid = holder(&file);
+                    return Err(LockError::Held { path, pid });
+                }
+                Err(TryLockError::Error(e)) => return Err(io_error(e)),
+            }
+        }
+        Ok(Self { _files: files })
+    }
+}
+
+/// Which process holds the lock on `file`, as far as the OS will tell us.
+#[cfg(target_os = "linux")]
+fn holder(file: &File) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = file.metadata().ok()?;
+    let locks = std::fs::read_to_string("/proc/locks").ok()?;
+    holder_in(&locks, metadata.dev(), metadata.ino())
+}
+
+/// The pid holding a lock on inode `ino` of device `dev` in `locks`, the contents of /proc/locks.
+#[cfg(target_os = "linux")]
+fn holder_in(locks: &str, dev: u64, ino: u64) -> Option<u32> {
+    // the same split of a dev_t into major and minor numbers as glibc's
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    // lines like `1: FLOCK  ADVISORY  WRITE 4242 00:2a:1234567 0 EOF`, with the device numbers in
+    // hex, each followed by whoever is waiting for that lock as `1: -> FLOCK  ADVISORY ...`
+    locks.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) == Some(&"->") {
+            return None;
+        }
+        let (pid, id) = (fields.get(4)?, fields.get(5)?);
+        let mut id = id.split(':');
+        let id = (
+            u64::from_str_radix(id.next()?, 16).ok()?,
+            u64::from_str_radix(id.next()?, 16).ok()?,
+            id.next()?.parse::<u64>().ok()?,
+        );
+        (id == (major, minor, ino))
+            .then(|| pid.parse().ok())
+            .flatten()
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn holder(_file: &File) -> Option<u32> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn holders_match_device_and_inode() {
+    let locks = "\
+1: FLOCK  ADVISORY  WRITE 100 08:01:1234 0 EOF
+2: FLOCK  ADVISORY  WRITE 200 00:2a:1234 0 EOF
+2: -> FLOCK  ADVISORY  WRITE 300 00:2a:5678 0 EOF
+3: FLOCK  ADVISORY  WRITE 400 00:2a:5678 0 EOF
+";
+    // device 0:42 is 0x2a in /proc/locks
+    assert_eq!(holder_in(locks, 42, 1234), Some(200));
+    assert_eq!(holder_in(locks, 8 << 8 | 1, 1234), Some(100));
+    // the waiter's fields are shifted over by the arrow, and it doesn't hold anything anyway
+    assert_eq!(holder_in(locks, 42, 5678), Some(400));
+    assert_eq!(holder_in(locks, 42, 9), None);
+}
+
+#[tokio::test]
+async fn a_second_download_fails_fast() {
+    use crate::download::DownloadHandle;
+    use crate::mock::{self, Behaviour};
+    use std::sync::Arc;
+
+    let data = mock::data(32768);
+    let stalled = Behaviour {
+        stall: true,
+        ..Behaviour::default()
+    };
+    let (_tracker, _peers, t) = mock::swarm(&data, 32768, vec![stalled]).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("mock.bin");
+    let stats = dir.path().join("mock.bin.stats");
+    let start = |t| {
+        SessionLock::acquire(&[&output, &stats])
+            .map(|lock| (lock, DownloadHandle::spawn(t, Arc::default())))
+    };
+
+    let (lock, first) = start(t.clone()).unwrap();
+    let Err(e) = start(t.clone()) else {
+        panic!("the second download started too");
+    };
+    assert!(
+        matches!(e, LockError::Held { ref path, .. } if *path == output),
+        "{e:?}"
+    );
+    let message = e.to_string();
+    assert!(
+        message.starts_with("another instance is already downloading this torrent"),
+        "{message}"
+    );
+    if cfg!(target_os = "linux") {
+        assert!(
+            message.ends_with(&format!("by pid {})", std::process::id())),
+            "{message}"
+        );
+    }
+
+    // once the first run is over, the files are free again
+    first.cancel();
+    drop(lock);
+    let (_lock, second) = start(t).unwrap();
+    second.cancel();
+}
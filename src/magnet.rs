@@ -0,0 +1,230 @@
+//! Magnet links: a torrent named by its info hash alone, with hints on where to find peers for
+//! it and what it's called.
+
+use crate::bencode::{self, Value};
+use crate::torrent::{InfoHash, InfoHashError, Torrent, TorrentError};
+use crate::tracker::percent_encode;
+use std::fmt;
+
+/// What a `magnet:?` URI tells us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    /// The display name (`dn`), which is only a suggestion until we have the metadata.
+    pub name: Option<String>,
+    /// Tracker URLs (`tr`), in the order given.
+    pub trackers: Vec<String>,
+    /// Web seed URLs (`ws`, BEP 19), in the order given.
+    pub web_seeds: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MagnetError {
+    #[error("not a magnet link (they start with `magnet:?`): {0}")]
+    Scheme(String),
+
+    #[error("malformed magnet query: {0}")]
+    Query(#[from] serde_urlencoded::de::Error),
+
+    #[error("magnet link has no `xt` with a BitTorrent info hash (`urn:btih:`)")]
+    MissingTopic,
+
+    #[error(transparent)]
+    InfoHash(#[from] InfoHashError),
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self, MagnetError> {
+        let query = uri
+            .get(..8)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("magnet:?"))
+            .map(|_| &uri[8..])
+            .ok_or_else(|| MagnetError::Scheme(uri.to_string()))?;
+        let params: Vec<(String, String)> = serde_urlencoded::from_str(query)?;
+
+        let mut link = MagnetLink {
+            info_hash: InfoHash::default(),
+            name: None,
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+        };
+        let mut info_hash = None;
+        for (key, value) in params {
+            match key.as_str() {
+                // there may be other topics, like a v2 `urn:btmh:`, so the first btih wins
+                "xt" if info_hash.is_none() => {
+                    if let Some(hash) = strip_prefix_ignore_case(&value, "urn:btih:") {
+                        info_hash = Some(hash.parse()?);
+                    }
+                }
+                "dn" if link.name.is_none() => link.name = Some(value),
+                "tr" => link.trackers.push(value),
+                "ws" => link.web_seeds.push(value),
+                _ => {}
+            }
+        }
+        link.info_hash = info_hash.ok_or(MagnetError::MissingTopic)?;
+        Ok(link)
+    }
+}
+
+impl MagnetLink {
+    /// The magnet link to share `t` by: its info hash, name, and every tracker and web seed it
+    /// names.
+    pub fn of(t: &Torrent) -> Result<Self, serde_bencode::Error> {
+        Ok(MagnetLink {
+            info_hash: t.info_hash()?,
+            name: Some(t.info.name.clone()),
+            trackers: t.trackers(),
+            web_seeds: t.url_list.clone().unwrap_or_default(),
+        })
+    }
+}
+
+impl MagnetLink {
+    /// The torrent this link names, once `info` (the bencoded info dictionary, as peers sent it)
+    /// is in: each of its trackers a tier of its own, and its web seeds.
+    pub fn to_torrent(&self, info: &[u8]) -> Result<Torrent, TorrentError> {
+        let urls = |urls: &[String]| {
+            Value::List(
+                urls.iter()
+                    .map(|url| Value::Bytes(url.clone().into()))
+                    .collect(),
+            )
+        };
+        // keys in canonical order, and the info dictionary as it came so that its hash holds
+        let mut bytes = b"d".to_vec();
+        if let Some(tracker) = self.trackers.first() {
+            bencode::encode_bytes(b"announce", &mut bytes);
+            bencode::encode_bytes(tracker.as_bytes(), &mut bytes);
+        }
+        if self.trackers.len() > 1 {
+            let tiers = self.trackers.chunks(1).map(urls).collect();
+            bencode::encode_bytes(b"announce-list", &mut bytes);
+            bencode::encode_into(&Value::List(tiers), &mut bytes);
+        }
+        bencode::encode_bytes(b"info", &mut bytes);
+        bytes.extend_from_slice(info);
+        if !self.web_seeds.is_empty() {
+            bencode::encode_bytes(b"url-list", &mut bytes);
+            bencode::encode_into(&urls(&self.web_seeds), &mut bytes);
+        }
+        bytes.push(b'e');
+        Torrent::from_bytes(&bytes)
+    }
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "magnet:?xt=urn:btih:{}", self.info_hash)?;
+        if let Some(name) = &self.name {
+            write!(f, "&dn={}", percent_encode(name.as_bytes()))?;
+        }
+        for tracker in &self.trackers {
+            write!(f, "&tr={}", percent_encode(tracker.as_bytes()))?;
+        }
+        for web_seed in &self.web_seeds {
+            write!(f, "&ws={}", percent_encode(web_seed.as_bytes()))?;
+        }
+        Ok(())
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    s.get(..prefix.len())
+        .filter(|start| start.eq_ignore_ascii_case(prefix))
+        .map(|_| &s[prefix.len()..])
+}
+
+#[test]
+fn fetched_info_makes_the_torrent() {
+    let t = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+    let mut link = MagnetLink::of(&t).unwrap();
+    link.trackers.push("udp://backup.example.com:6969".into());
+    link.web_seeds.push("http://mirror.example.com/".into());
+
+    let made = link.to_torrent(&t.info_bytes().unwrap()).unwrap();
+    assert_eq!(made.info_hash().unwrap(), link.info_hash);
+    assert_eq!(made.info.name, t.info.name);
+    assert_eq!(made.trackers(), link.trackers);
+    assert_eq!(made.url_list, Some(link.web_seeds));
+}
+
+#[test]
+fn magnet_links_parse() {
+    let hash = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+    let link = MagnetLink::parse(&format!(
+        "magnet:?xt=urn:btih:{hash}&dn=sample%20file.txt\
+         &tr=http%3A%2F%2Ftracker.example.com%2Fannounce%3Fpasskey%3Da%26b\
+         &tr=udp%3A%2F%2Fbackup.example.net%3A1337&ws=http%3A%2F%2Fseed.example.com%2F"
+    ))
+    .unwrap();
+    assert_eq!(link.info_hash.to_string(), hash);
+    assert_eq!(link.name.as_deref(), Some("sample file.txt"));
+    assert_eq!(
+        link.trackers,
+        [
+            "http://tracker.example.com/announce?passkey=a&b",
+            "udp://backup.example.net:1337"
+        ]
+    );
+    assert_eq!(link.web_seeds, ["http://seed.example.com/"]);
+
+    // base32, in either case, names the same torrent
+    for base32 in [
+        "22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7",
+        "22pzdzvsvzgfijdi2edtu4ou5ijypgt7",
+    ] {
+        let link = MagnetLink::parse(&format!("MAGNET:?xt=urn:btih:{base32}")).unwrap();
+        assert_eq!(link.info_hash.to_string(), hash);
+        assert!(link.name.is_none() && link.trackers.is_empty());
+    }
+    // and other topics are passed over
+    let link =
+        MagnetLink::parse(&format!("magnet:?xt=urn:btmh:1220aa&xt=urn:btih:{hash}")).unwrap();
+    assert_eq!(link.info_hash.to_string(), hash);
+}
+
+#[test]
+fn torrents_make_magnet_links_that_parse_back() {
+    let t = Torrent::from_bytes(include_bytes!(
+        "../tests/fixtures/torrents/multi-file-attrs.torrent"
+    ))
+    .unwrap();
+    let mut link = MagnetLink::of(&t).unwrap();
+    link.name = Some(String::from("a b&c/d~e.f"));
+    let uri = link.to_string();
+    assert_eq!(
+        uri,
+        "magnet:?xt=urn:btih:".to_string()
+            + &t.info_hash().unwrap().to_string()
+            + "&dn=a%20b%26c%2Fd~e.f"
+            + "&tr=http%3A%2F%2Ftracker.example.com%3A6969%2Fannounce"
+            + "&tr=udp%3A%2F%2Fbackup.example.net%3A1337"
+    );
+    let parsed = MagnetLink::parse(&uri).unwrap();
+    assert_eq!(parsed.info_hash, t.info_hash().unwrap());
+    assert_eq!(parsed, link);
+}
+
+#[test]
+fn malformed_magnet_links() {
+    let error = |uri: &str| MagnetLink::parse(uri).unwrap_err().to_string();
+    assert_eq!(
+        error("http://example.com/"),
+        "not a magnet link (they start with `magnet:?`): http://example.com/"
+    );
+    assert_eq!(
+        error("magnet:?dn=x&tr=http%3A%2F%2Ft"),
+        "magnet link has no `xt` with a BitTorrent info hash (`urn:btih:`)"
+    );
+    assert_eq!(
+        error("magnet:?xt=urn:btih:abc"),
+        "info hash `abc` is neither 40 hex digits nor 32 base32 characters"
+    );
+    assert_eq!(
+        error("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT1"),
+        "info hash `22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT1` is not valid base32: '1' is not in A-Z or 2-7"
+    );
+    assert!(error(&format!("magnet:?xt=urn:btih:{}", "g".repeat(40))).contains("not valid hex"));
+}
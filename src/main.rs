@@ -1,38 +1,144 @@
 use anyhow::Context;
-use bittorrent_starter_rust::torrent::{self, Torrent};
+use bittorrent_starter_rust::lock::SessionLock;
+use bittorrent_starter_rust::resolve::{self, Prefer};
+use bittorrent_starter_rust::torrent::Torrent;
 use bittorrent_starter_rust::tracker::*;
-use bittorrent_starter_rust::{peer::*, BLOCK_MAX, DEFAULT_PORT};
+use bittorrent_starter_rust::{
+    bench, bencode, control, create, download, health, magnet, metadata, piece, progress, seed,
+};
+use bittorrent_starter_rust::{peer::*, DEFAULT_PORT};
 use clap::{Parser, Subcommand};
-use futures_util::{SinkExt, StreamExt};
-use serde_bencode;
-use serde_json::{Map, Value};
-use sha1::{Digest, Sha1};
-use std::fs::read;
-use std::net::SocketAddrV4;
+use std::io::IsTerminal;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Go by this 20-byte peer id instead of a random `-RS0001-` one.
+    #[arg(long, global = true)]
+    peer_id: Option<PeerId>,
+    /// Send HTTP tracker requests through this proxy (`http://`, `https://`, `socks5://` or
+    /// `socks5h://`), instead of the one `HTTP_PROXY`, `HTTPS_PROXY`, or `ALL_PROXY` names.
+    #[arg(long, global = true, value_name = "URL")]
+    proxy: Option<String>,
+    /// Go to trackers directly, whatever proxy the environment names.
+    #[arg(long, global = true, conflicts_with = "proxy")]
+    no_proxy: bool,
+    /// Connect to peers and trackers from this local address.
+    #[arg(long, global = true, value_name = "ADDR")]
+    bind: Option<std::net::IpAddr>,
     #[command(subcommand)]
     command: Command,
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
+    #[command(group(clap::ArgGroup::new("input").required(true).args(["value", "file", "stdin"])))]
     Decode {
+        value: Option<String>,
+        /// Decode the raw bytes of this file instead.
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// Decode the raw bytes of standard input instead.
+        #[arg(long)]
+        stdin: bool,
+    },
+    /// Turn a JSON document into canonical bencode, the reverse of `decode`.
+    Encode {
         value: String,
+        /// Write strings of `0x` and hex digits as the bytes they spell, as `decode` prints
+        /// binary strings.
+        #[arg(long)]
+        raw_hex: bool,
     },
     Info {
         torrent: PathBuf,
+        /// Print a magnet link for the torrent instead.
+        #[arg(long)]
+        magnet: bool,
     },
+    /// Print the tracker and info hash of a magnet link.
+    #[clap(name = "magnet_parse")]
+    MagnetParse { uri: String },
     Peers {
         torrent: PathBuf,
+        /// Print at most this many peers.
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Only print IPv4 peers.
+        #[arg(long)]
+        ipv4_only: bool,
+        /// Skip peers listening on these ports (e.g. `1-1024,8080`).
+        #[arg(long, value_delimiter = ',', value_parser = parse_port_range)]
+        exclude_ports: Vec<RangeInclusive<u16>>,
+        /// Print peers sorted (`stable`) or shuffled (`random`) instead of in tracker order.
+        #[arg(long)]
+        sort: Option<PeerOrder>,
+        /// Print the tracker's peer list exactly as received, ignoring all other options.
+        #[arg(long)]
+        raw: bool,
+        #[command(flatten)]
+        announce_ip: AnnounceIp,
+        /// Announce this event (`started`, `completed` or `stopped`) instead of a regular
+        /// announce.
+        #[arg(long)]
+        event: Option<Event>,
+        /// Ask the tracker for this many peers.
+        #[arg(long, default_value_t = DEFAULT_NUMWANT)]
+        numwant: usize,
+        /// Keep tracker answers in this directory (the platform's cache directory if none is
+        /// given), and reuse them until the tracker would want to hear from us again.
+        #[arg(long, value_name = "DIR", num_args = 0..=1)]
+        cache_dir: Option<Option<PathBuf>>,
+        /// Announce even if a cached answer is still fresh.
+        #[arg(long, requires = "cache_dir")]
+        no_cache: bool,
+    },
+    /// Send one announce with exactly the parameters given, and print what the tracker said back,
+    /// for debugging trackers.
+    Announce {
+        torrent: PathBuf,
+        /// Announce to this tracker instead of the torrent's first.
+        #[arg(long)]
+        tracker: Option<String>,
+        #[arg(long, default_value_t = 0)]
+        uploaded: usize,
+        #[arg(long, default_value_t = 0)]
+        downloaded: usize,
+        /// Report this many bytes left instead of the whole torrent.
+        #[arg(long)]
+        left: Option<usize>,
+        /// Announce this event (`started`, `completed` or `stopped`).
+        #[arg(long)]
+        event: Option<Event>,
+        /// Leave `numwant` out unless given.
+        #[arg(long)]
+        numwant: Option<usize>,
+        #[arg(long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Ask for the compact peer list (1), or the dictionary one (0).
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=1))]
+        compact: u8,
+        /// Send this as the `ip` parameter, verbatim.
+        #[arg(long)]
+        ip: Option<String>,
+        /// Send this as the `ipv6` parameter, verbatim.
+        #[arg(long)]
+        ipv6: Option<String>,
     },
     Handshake {
         torrent: PathBuf,
+        /// The peer's `address:port` or `hostname:port`.
         peer: String,
+        #[command(flatten)]
+        family: FamilyPreference,
+        /// Fail unless the peer answers with this peer id, e.g. the one a tracker gave for it.
+        #[arg(long, value_name = "PEER_ID")]
+        expect_peer_id: Option<PeerId>,
     },
     #[clap(name = "download_piece")]
     DownloadPiece {
@@ -40,336 +146,955 @@ enum Command {
         output: PathBuf,
         torrent: PathBuf,
         piece: usize,
+        /// Try at most this many of the tracker's peers before giving up.
+        #[arg(
+            long,
+            default_value_t = 5,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        max_attempts: usize,
+        /// Keep up to this many block requests outstanding with the peer.
+        #[arg(long, default_value_t = PIPELINE)]
+        pipeline: usize,
+        #[command(flatten)]
+        announce_ip: AnnounceIp,
     },
     Download {
+        /// Where to write the download; defaults to the torrent's name in the current directory.
+        #[arg(short)]
+        output: Option<PathBuf>,
+        /// The .torrent file, or a magnet link to fetch the torrent's metadata for from peers.
+        torrent: PathBuf,
+        /// Show which pieces are done as a grid under the progress line.
+        #[arg(long)]
+        progress_map: bool,
+        /// Print a JSON progress event to stdout on every progress tick.
+        #[arg(long)]
+        json_progress: bool,
+        /// Don't read key commands (p, +, -, v, q) from a terminal on stdin.
+        #[arg(long)]
+        no_interactive: bool,
+        /// Only download pieces START..END (either end may be left open), verifying each and
+        /// writing it at its offset in an otherwise sparse output file.
+        #[arg(long, value_name = "START..END", value_parser = download::parse_piece_range)]
+        pieces: Option<download::PieceRange>,
+        /// With --pieces, write each piece to its own `piece-N.bin` in the output directory.
+        #[arg(long, requires = "pieces")]
+        split: bool,
+        /// Download from these peers (`address:port` or `hostname:port`) instead of the
+        /// tracker's.
+        #[arg(long)]
+        peer: Vec<String>,
+        /// With --pieces, try at most this many peers for each piece before giving up on it.
+        #[arg(
+            long,
+            default_value_t = 5,
+            requires = "pieces",
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        max_attempts: usize,
+        #[command(flatten)]
+        announce_ip: AnnounceIp,
+        /// Start by announcing to every tracker at once rather than tier by tier, taking the
+        /// peers of all that answer within a few seconds.
+        #[arg(long)]
+        aggressive_announce: bool,
+        /// Only warn about peers whose handshake carries a different peer id than the tracker
+        /// gave for them, instead of dropping them.
+        #[arg(long)]
+        allow_peer_id_mismatch: bool,
+        /// Keep up to this many block requests outstanding with each peer.
+        #[arg(long, default_value_t = PIPELINE)]
+        pipeline: usize,
+        /// Serve Prometheus metrics for the download on this address.
+        #[cfg(feature = "metrics")]
+        #[arg(long, value_name = "ADDR")]
+        metrics_addr: Option<std::net::SocketAddr>,
+    },
+    /// Make a .torrent file for a file, or for everything in a directory.
+    Create {
+        path: PathBuf,
+        /// The tracker URL to put in the torrent.
+        #[arg(long)]
+        announce: String,
         #[arg(short)]
         output: PathBuf,
+        /// Split the content into pieces of this many bytes, instead of a power of two that
+        /// makes around 1500 of them.
+        #[arg(long)]
+        piece_length: Option<usize>,
+    },
+    /// Check a downloaded file, or a directory of a multi-file torrent's files, against the
+    /// torrent's piece hashes.
+    Verify {
+        torrent: PathBuf,
+        path: PathBuf,
+        /// Also check files against the MD5 sums the torrent has for them, warning about any
+        /// that don't match.
+        #[arg(long)]
+        md5: bool,
+    },
+    /// Seed a file we have all of to whoever connects, until killed.
+    ServeFile {
+        torrent: PathBuf,
+        /// The file holding the torrent's data, as `download` leaves it.
+        file: PathBuf,
+        #[arg(long, default_value = "127.0.0.1:6881")]
+        listen: std::net::SocketAddr,
+    },
+    /// Ask the torrent's trackers how big its swarm is, without announcing.
+    Scrape { torrent: PathBuf },
+    /// Ask every tracker of a torrent how many seeders and leechers it has.
+    Health {
+        torrent: PathBuf,
+        /// Give up on trackers that haven't answered after this long, e.g. `10s`.
+        #[arg(long, default_value = "10s", value_parser = bench::parse_duration)]
+        timeout: std::time::Duration,
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Announce to every tracker of a torrent and show which ones work.
+    Trackers {
         torrent: PathBuf,
+        /// Give up on trackers that haven't answered after this long, e.g. `10s`.
+        #[arg(long, default_value = "5s", value_parser = bench::parse_duration)]
+        timeout: std::time::Duration,
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Download from a single peer for a while and report how fast it went.
+    BenchPeer {
+        torrent: PathBuf,
+        /// The peer's `address:port` or `hostname:port`.
+        peer: String,
+        #[command(flatten)]
+        family: FamilyPreference,
+        /// How long to run for, e.g. `30s` or `2m`.
+        #[arg(long, default_value = "30s", value_parser = bench::parse_duration)]
+        duration: std::time::Duration,
+        /// Don't hash the downloaded pieces.
+        #[arg(long)]
+        no_verify: bool,
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
     },
 }
 
-fn decode_bencoded_value(encoded_value: &str) -> Result<(Value, &str), anyhow::Error> {
-    match encoded_value.chars().next() {
-        Some('i') => {
-            let (n, rest) = encoded_value
-                .split_at(1)
-                .1
-                .split_once('e')
-                .and_then(|(digits, rest)| {
-                    let n = digits.parse::<i64>().ok()?;
-                    Some((n.into(), rest))
-                })
-                .ok_or_else(|| anyhow::anyhow!("Failed to parse integer"))?;
-            Ok((n, rest))
+/// Turns SIGUSR1 and SIGUSR2 into requests to pause and resume a download.
+struct PauseSignals {
+    #[cfg(unix)]
+    usr1: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    usr2: tokio::signal::unix::Signal,
+}
+
+impl PauseSignals {
+    fn new() -> anyhow::Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Ok(Self {
+                usr1: signal(SignalKind::user_defined1()).context("listen for SIGUSR1")?,
+                usr2: signal(SignalKind::user_defined2()).context("listen for SIGUSR2")?,
+            })
         }
-        Some('l') => {
-            let mut values = Vec::new();
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.is_empty() && rest.starts_with(|c| c != 'e') {
-                let (v, remainder) = decode_bencoded_value(rest)?;
-                values.push(v);
-                rest = remainder;
+        #[cfg(not(unix))]
+        Ok(Self {})
+    }
+
+    /// Wait for the next signal, and the command it stands for.
+    async fn next(&mut self) -> control::Command {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = self.usr1.recv() => control::Command::Pause,
+            _ = self.usr2.recv() => control::Command::Resume,
+        }
+        #[cfg(not(unix))]
+        std::future::pending().await
+    }
+}
+
+/// Puts the terminal on stdin into cbreak mode, where keys arrive as they are pressed and aren't
+/// echoed, until dropped. Ctrl-C still interrupts.
+struct KeyMode {
+    saved: String,
+}
+
+impl KeyMode {
+    fn enable() -> anyhow::Result<Self> {
+        let saved = stty(&["-g"])?.trim().to_string();
+        stty(&["-icanon", "-echo", "min", "1"])?;
+        Ok(Self { saved })
+    }
+}
+
+impl Drop for KeyMode {
+    fn drop(&mut self) {
+        if let Err(e) = stty(&[&self.saved]) {
+            eprintln!("failed to restore the terminal: {e:#}");
+        }
+    }
+}
+
+fn stty(args: &[&str]) -> anyhow::Result<String> {
+    let output = std::process::Command::new("stty")
+        .args(args)
+        .stdin(std::process::Stdio::inherit())
+        .output()
+        .context("run stty")?;
+    anyhow::ensure!(output.status.success(), "stty {} failed", args.join(" "));
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Read key commands from stdin on a thread of their own, since a blocked read would otherwise
+/// hold up the runtime's shutdown.
+fn read_keys() -> tokio::sync::mpsc::UnboundedReceiver<control::Command> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        use std::io::Read;
+        for key in std::io::stdin().lock().bytes() {
+            let Ok(key) = key else {
+                break;
+            };
+            if let Some(command) = control::Command::from_key(key) {
+                if tx.send(command).is_err() {
+                    break;
+                }
             }
-            Ok((Value::Array(values), &rest[1..]))
         }
-        Some('d') => {
-            let mut dict = Map::new();
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.is_empty() && rest.starts_with(|c| c != 'e') {
-                let (key, remainder) = decode_bencoded_value(rest)?;
-                let (value, remainder) = decode_bencoded_value(remainder)?;
-                if let Value::String(key_str) = key {
-                    dict.insert(key_str, value);
-                } else {
-                    return Err(anyhow::anyhow!("Dictionary keys must be strings"));
+    });
+    rx
+}
+
+/// How often the progress display is refreshed.
+const PROGRESS_TICK: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Set a download of the magnet link `uri` up: fetch the metadata from the peers its trackers (or
+/// `--peer`) give, into `output` or, failing that, a provisional file named after the link that
+/// takes the torrent's name once the metadata is in.
+///
+/// The output and its `.stats` are locked before anything else, and stay locked through the
+/// rename.
+async fn fetch_magnet(
+    uri: &str,
+    output: Option<PathBuf>,
+    peers: Vec<std::net::SocketAddr>,
+    listeners: &Listeners,
+) -> anyhow::Result<(Torrent, PathBuf, SessionLock)> {
+    let link = magnet::MagnetLink::parse(uri)?;
+    let provisional = output.is_none();
+    let output = output.unwrap_or_else(|| {
+        let name = link.name.clone();
+        download::default_output(&name.unwrap_or_else(|| link.info_hash.to_string()))
+    });
+    let lock = SessionLock::acquire(&[&output, &download::stats_path(&output)])?;
+    let candidates = if peers.is_empty() {
+        anyhow::ensure!(
+            !link.trackers.is_empty(),
+            "the magnet link names no trackers"
+        );
+        // how much is left isn't known until we have the metadata
+        let mut request = TrackerRequest::new(PeerId::ours(), listeners.port(), 1);
+        request.advertise(listeners);
+        let mut last_error = None;
+        let mut response = None;
+        for tracker in &link.trackers {
+            let announce =
+                TrackerClient::shared().announce(tracker, link.info_hash, &request, None);
+            let e = match tokio::time::timeout(ANNOUNCE_TIMEOUT, announce).await {
+                Ok(Ok(answer)) => {
+                    response = Some(answer);
+                    break;
                 }
-                rest = remainder;
+                Ok(Err(e)) => e,
+                Err(_) => anyhow::anyhow!("tracker timed out after {ANNOUNCE_TIMEOUT:?}"),
+            };
+            eprintln!("announce to {} failed: {e:#}", redacted(tracker));
+            last_error = Some(e);
+        }
+        match response {
+            Some(response) => PeerFilter::default().apply(&response.peers),
+            None => return Err(last_error.expect("at least one tracker was tried")),
+        }
+    } else {
+        peers
+    };
+    let (torrent, output) = if provisional {
+        metadata::fetch_torrent_renaming(&link, &candidates, &output).await?
+    } else {
+        (metadata::fetch_torrent(&link, &candidates).await?, output)
+    };
+    Ok((torrent, output, lock))
+}
+
+/// Keep redrawing the progress display (if stderr is a terminal) and emitting JSON progress events
+/// (if asked to) until aborted.
+async fn show_progress(
+    stats: Arc<TransferStats>,
+    npieces: usize,
+    total: usize,
+    with_map: bool,
+    json: bool,
+    verbose: Arc<AtomicBool>,
+) {
+    let tty = std::io::stderr().is_terminal();
+    let mut drawn_lines = 0;
+    let mut tick = tokio::time::interval(PROGRESS_TICK);
+    loop {
+        tick.tick().await;
+        let map = stats.piece_map(npieces);
+        let downloaded = stats.downloaded().min(total);
+        if json {
+            let event = progress::ProgressEvent::new(&map, downloaded, total);
+            println!(
+                "{}",
+                serde_json::to_string(&event).expect("progress events always serialize")
+            );
+        }
+        if tty {
+            let mut status = progress::status(&map, downloaded, total, with_map);
+            if verbose.load(Ordering::Relaxed) {
+                status.push('\n');
+                status.push_str(&progress::connection_stats(&stats));
+            }
+            // move back up over the previous status block and draw over it
+            if drawn_lines > 0 {
+                eprint!("\x1b[{drawn_lines}A");
             }
-            let json_map: Map<String, Value> = dict.into_iter().collect();
-            Ok((Value::Object(json_map), &rest[1..]))
+            eprintln!("\x1b[J{status}");
+            drawn_lines = status.lines().count();
         }
-        Some('0'..='9') => {
-            let (len, rest) = encoded_value.split_once(':').ok_or_else(|| {
-                anyhow::anyhow!("Failed to split length and remainder for string")
-            })?;
-            let len = len
-                .parse::<usize>()
-                .map_err(|_| anyhow::anyhow!("Failed to parse length"))?;
-            Ok((Value::String(rest[..len].to_string()), &rest[len..]))
+    }
+}
+
+/// Which address family to try first when a peer's hostname resolves to both.
+#[derive(clap::Args, Debug)]
+#[group(multiple = false)]
+struct FamilyPreference {
+    /// Try IPv4 addresses first.
+    #[arg(long)]
+    prefer_v4: bool,
+    /// Try IPv6 addresses first.
+    #[arg(long)]
+    prefer_v6: bool,
+}
+
+impl FamilyPreference {
+    fn prefer(&self) -> Prefer {
+        if self.prefer_v4 {
+            Prefer::V4
+        } else if self.prefer_v6 {
+            Prefer::V6
+        } else {
+            Prefer::Any
         }
-        _ => Err(anyhow::anyhow!(
-            "Unhandled encoded value: {}",
-            encoded_value
-        )),
     }
 }
 
-fn urlencode(t: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(3 * t.len());
-    for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+#[derive(clap::Args, Debug)]
+struct AnnounceIp {
+    /// Have trackers hand out this address for us instead of the one our announces come from.
+    #[arg(long, visible_alias = "ip", value_name = "ADDR", value_parser = parse_announce_ip)]
+    announce_ip: Option<std::net::IpAddr>,
+    /// Give trackers this as our IPv6 address (BEP 7), instead of the one we listen on.
+    #[arg(long, visible_alias = "ipv6", value_name = "ADDR")]
+    announce_ipv6: Option<std::net::Ipv6Addr>,
+    /// Where we accept peers, e.g. the address `serve-file` listens on; once per address family.
+    #[arg(long, value_name = "ADDR", default_value = "0.0.0.0:6881")]
+    listen: Vec<std::net::SocketAddr>,
+}
+
+impl AnnounceIp {
+    fn listeners(&self) -> Listeners {
+        if let Some(ip) = self.announce_ip.filter(is_private) {
+            eprintln!(
+                "warning: announcing {ip}, which only peers on the same private network can reach"
+            );
+        }
+        Listeners {
+            announce_ip: self.announce_ip,
+            announce_ipv6: self.announce_ipv6,
+            ..Listeners::bound(self.listen.iter().copied())
+        }
     }
-    encoded
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    if let Some(peer_id) = args.peer_id {
+        PeerId::set(peer_id)?;
+    }
+    if let Some(ip) = args.bind {
+        resolve::bind_to(ip)?;
+    }
+    let proxies = match (&args.proxy, args.no_proxy) {
+        (Some(url), _) => Some(Proxies::all(url)?),
+        (None, true) => Some(Proxies::none()),
+        (None, false) => None,
+    };
+    if let Some(proxies) = proxies {
+        TrackerClient::set_shared(TrackerClient::builder().proxy(proxies).build()?)?;
+    }
 
     match args.command {
-        Command::Decode { value } => {
+        Command::Decode { value, file, stdin } => {
             eprintln!("Logs from your program will appear here!");
-            let decoded_value = decode_bencoded_value(&value)?;
-            println!("{}", decoded_value.0.to_string());
+            let input = match (value, file) {
+                (Some(value), _) => value.into_bytes(),
+                (None, Some(file)) => tokio::fs::read(&file)
+                    .await
+                    .with_context(|| format!("read {}", file.display()))?,
+                (None, None) => {
+                    debug_assert!(stdin);
+                    let mut input = Vec::new();
+                    tokio::io::stdin()
+                        .read_to_end(&mut input)
+                        .await
+                        .context("read standard input")?;
+                    input
+                }
+            };
+            let decoded_value = bencode::decode_all(&input)?;
+            println!("{}", decoded_value.to_json());
+        }
+        Command::Encode { value, raw_hex } => {
+            let json: serde_json::Value = serde_json::from_str(&value).context("parse JSON")?;
+            let value = bencode::Value::from_json(&json, raw_hex)?;
+            use std::io::Write;
+            std::io::stdout().write_all(&bencode::encode(&value))?;
         }
-        Command::Info { torrent } => {
-            let dot_torrent = read(&torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+        Command::Info { torrent, magnet } => {
+            let t = Torrent::from_file(&torrent)?;
+            if magnet {
+                println!("{}", magnet::MagnetLink::of(&t)?);
+                return Ok(());
+            }
             eprintln!("{t:?}");
-            println!("Tracker URL: {}", t.announce);
-
-            match &t.info.keys {
-                torrent::Keys::SingleFile { length } => println!("Length: {}", length),
-                _ => todo!(),
+            print!("{}", t.summary()?);
+        }
+        Command::MagnetParse { uri } => {
+            let link = magnet::MagnetLink::parse(&uri)?;
+            for tracker in &link.trackers {
+                println!("Tracker URL: {}", redacted(tracker));
             }
+            println!("Info Hash: {}", link.info_hash);
+        }
+
+        Command::Peers {
+            torrent,
+            limit,
+            ipv4_only,
+            exclude_ports,
+            sort,
+            raw,
+            announce_ip,
+            event,
+            numwant,
+            cache_dir,
+            no_cache,
+        } => {
+            let t = Torrent::from_file(&torrent)?;
+            let length = t.length();
 
             let info_hash = t.info_hash()?;
-            println!("Info Hash: {}", hex::encode(&info_hash));
-            println!("Piece Length: {}", t.info.plength);
-            println!("Piece Hashes:");
-            for hash in t.info.pieces.0 {
-                println!("{}", hex::encode(&hash));
-            }
-        }
+            let listeners = announce_ip.listeners();
+            let mut request = TrackerRequest::new(PeerId::ours(), listeners.port(), length);
+            request.advertise(&listeners);
+            request.event = event;
+            request.numwant = Some(numwant);
+            request.key = Some(session_key());
 
-        Command::Peers { torrent } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
-            let length = match t.info.keys {
-                torrent::Keys::SingleFile { length } => length,
-                _ => {
-                    todo!();
+            let cache = match cache_dir {
+                Some(dir) => Some(ResponseCache::new(
+                    dir.or_else(ResponseCache::default_dir)
+                        .context("no cache directory to use, so pass one to --cache-dir")?,
+                )),
+                None => None,
+            };
+            let response = match cache {
+                Some(cache) => {
+                    // an event is news the tracker has to hear, however recently we asked it
+                    let reuse = !no_cache && event.is_none();
+                    let (response, age) = cache.announce(&t, info_hash, &request, reuse).await?;
+                    if let Some(age) = age {
+                        eprintln!(
+                            "using the tracker's answer from {}s ago; pass --no-cache to announce again",
+                            age.as_secs()
+                        );
+                    }
+                    response
+                }
+                None => {
+                    TrackerClient::shared()
+                        .announce_tiers(&t, info_hash, &request, None)
+                        .await?
                 }
             };
-
-            let info_hash = t.info_hash()?;
-            let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
-                port: DEFAULT_PORT,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                compact: 1,
+            if raw {
+                if !response.peers.addrs.is_empty() {
+                    println!("{}", response.peers);
+                }
+            } else {
+                let filter = PeerFilter {
+                    limit,
+                    ipv4_only,
+                    exclude_ports,
+                    order: sort,
+                };
+                for peer in filter.apply(&response.peers) {
+                    println!("{peer}");
+                }
+            }
+        }
+        Command::Announce {
+            torrent,
+            tracker,
+            uploaded,
+            downloaded,
+            left,
+            event,
+            numwant,
+            port,
+            compact,
+            ip,
+            ipv6,
+        } => {
+            let t = Torrent::from_file(&torrent)?;
+            let tracker = match tracker {
+                Some(tracker) => tracker,
+                None => (t.trackers().into_iter().next())
+                    .context("the torrent names no tracker, so pass --tracker")?,
             };
 
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-            let response = reqwest::get(&tracker_url).await.context("query tracker")?;
-            let response = response.bytes().await.context("fetch tracker response")?;
-            let response: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
-            for peer in &response.peers.0 {
-                println!("{}:{}", peer.ip(), peer.port());
+            let mut request = TrackerRequest::new(PeerId::ours(), port, left.unwrap_or(t.length()));
+            request.uploaded = uploaded;
+            request.downloaded = downloaded;
+            request.event = event;
+            request.numwant = numwant;
+            request.compact = compact;
+            if compact == 0 {
+                request.no_peer_id = None;
+            }
+            request.ip = ip;
+            request.ipv6 = ipv6;
+            request.key = Some(session_key());
+
+            let (meta, body) = TrackerClient::shared()
+                .announce_raw(&tracker, t.info_hash()?, &request)
+                .await?;
+            println!("Status: {}", meta.status);
+            match bencode::decode_all(&body) {
+                Ok(value) => println!("{}", value.to_json()),
+                Err(e) => println!("Not bencode ({e}): {}", String::from_utf8_lossy(&body)),
+            }
+            match TrackerResponse::from_bytes(&body) {
+                Ok(response) => {
+                    println!("Interval: {}", response.interval);
+                    if let Some(min_interval) = response.min_interval {
+                        println!("Min interval: {min_interval}");
+                    }
+                    println!("Peers:");
+                    if !response.peers.to_string().is_empty() {
+                        println!("{}", response.peers);
+                    }
+                }
+                Err(e) => println!("Not an announce response: {e:#}"),
             }
         }
-        Command::Handshake { torrent, peer } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+        Command::Handshake {
+            torrent,
+            peer,
+            family,
+            expect_peer_id,
+        } => {
+            let t = Torrent::from_file(&torrent)?;
 
             let info_hash = t.info_hash()?;
-            let peer = peer.parse::<SocketAddrV4>().context("parse peer address")?;
-            let mut peer = tokio::net::TcpStream::connect(peer)
-                .await
-                .context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-            {
-                let handshake_bytes =
-                    &mut handshake as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
-                // Safety: Handshake is a POD with repr(c) and repr(packed)
-                let handshake_bytes: &mut [u8; std::mem::size_of::<Handshake>()] =
-                    unsafe { &mut *handshake_bytes };
-                peer.write_all(handshake_bytes)
-                    .await
-                    .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
-                    .await
-                    .context("read handshake")?;
-            }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-            println!("Peer ID: {}", hex::encode(&handshake.peer_id));
+            let conn = PeerConnection::connect_host(
+                &peer,
+                family.prefer(),
+                info_hash,
+                PeerId::ours(),
+                CONNECT_TIMEOUT,
+            )
+            .await?;
+            eprintln!("connected to {}", conn.addr());
+            conn.check_peer_id(expect_peer_id.map(|id| id.0))?;
+            println!("Peer ID: {}", hex::encode(conn.peer_id()));
+            println!("Extensions: {}", conn.reserved());
         }
         Command::DownloadPiece {
             output,
             torrent,
             piece: piece_i,
+            max_attempts,
+            pipeline,
+            announce_ip,
         } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
-            let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!();
-            };
-            assert!(piece_i < t.info.pieces.0.len());
-
+            let t = Torrent::from_file(&torrent)?;
+            let length = t.length();
             let info_hash = t.info_hash()?;
-            let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                compact: 1,
-            };
+            let listeners = announce_ip.listeners();
+            let mut request = TrackerRequest::new(PeerId::ours(), listeners.port(), length);
+            request.advertise(&listeners);
 
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-            let response = reqwest::get(tracker_url).await.context("query tracker")?;
-            let response = response.bytes().await.context("fetch tracker response")?;
-            let tracker_info: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
+            let tracker_info = TrackerClient::shared()
+                .announce_tiers(&t, info_hash, &request, None)
+                .await?;
+
+            let candidates: Vec<_> = PeerFilter::default().apply(&tracker_info.peers);
+            let all_blocks = download::piece(
+                &t,
+                piece_i,
+                &candidates,
+                max_attempts,
+                download::PIECE_ATTEMPT_TIMEOUT,
+                pipeline,
+            )
+            .await?;
 
-            let peer = &tracker_info.peers.0[0];
-            let mut peer = tokio::net::TcpStream::connect(peer)
+            tokio::fs::write(&output, all_blocks)
                 .await
-                .context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-            {
-                let handshake_bytes = handshake.as_bytes_mut();
-                peer.write_all(handshake_bytes)
+                .context("write out downloaded piece")?;
+            println!("Piece {piece_i} downloaded to {}.", output.display());
+        }
+        Command::Download {
+            output,
+            torrent,
+            progress_map,
+            json_progress,
+            no_interactive,
+            pieces,
+            split,
+            peer,
+            max_attempts,
+            announce_ip,
+            aggressive_announce,
+            allow_peer_id_mismatch,
+            pipeline,
+            #[cfg(feature = "metrics")]
+            metrics_addr,
+        } => {
+            let listeners = announce_ip.listeners();
+            let mut peers = Vec::new();
+            for host in &peer {
+                peers.push(resolve::resolve(host, Prefer::Any).await?[0]);
+            }
+            let (torrent, output, magnet_lock) = match torrent.to_str() {
+                Some(uri) if uri.starts_with("magnet:") => {
+                    // the lock would leave a file where the pieces' directory has to go
+                    anyhow::ensure!(!split, "--split needs a .torrent file");
+                    let (torrent, output, lock) =
+                        fetch_magnet(uri, output, peers.clone(), &listeners).await?;
+                    (torrent, output, Some(lock))
+                }
+                _ => {
+                    let torrent = Torrent::from_file(&torrent)?;
+                    let output =
+                        output.unwrap_or_else(|| download::default_output(&torrent.info.name));
+                    (torrent, output, None)
+                }
+            };
+            if let Some(pieces) = pieces {
+                let range = pieces.resolve(torrent.num_pieces())?;
+                let candidates = if peers.is_empty() {
+                    let info_hash = torrent.info_hash()?;
+                    let stats = TransferStats::default();
+                    let tracker_info =
+                        TrackerResponse::query(&torrent, info_hash, &listeners, &stats)
+                            .await
+                            .context("query tracker for peer info")?;
+                    PeerFilter::default().apply(&tracker_info.peers)
+                } else {
+                    peers
+                };
+                // split pieces each get a file of their own, so only a sparse output needs guarding
+                let _lock = match magnet_lock {
+                    Some(lock) => Some(lock),
+                    None => (!split)
+                        .then(|| SessionLock::acquire(&[&output]))
+                        .transpose()?,
+                };
+                let mut out = if split {
+                    download::PieceOutput::split(&output)?
+                } else {
+                    download::PieceOutput::sparse(&output, &torrent).await?
+                };
+                let results = download::piece_range(
+                    &torrent,
+                    range,
+                    &candidates,
+                    max_attempts,
+                    pipeline,
+                    &mut out,
+                )
+                .await;
+                let mut failed = 0;
+                for (piece_i, result) in &results {
+                    match result {
+                        Ok(()) => println!("piece {piece_i}: ok"),
+                        Err(e) => {
+                            failed += 1;
+                            println!("piece {piece_i}: failed: {e:#}");
+                        }
+                    }
+                }
+                anyhow::ensure!(failed == 0, "{failed} of {} pieces failed", results.len());
+                println!("Pieces written to {}.", output.display());
+                return Ok(());
+            }
+            torrent.print_tree();
+            // totals reported to the tracker carry over between runs against the same output
+            let stats_path = download::stats_path(&output);
+            // held until we're done with both, however the download ends
+            let _lock = match magnet_lock {
+                Some(lock) => lock,
+                None => SessionLock::acquire(&[&output, &stats_path])?,
+            };
+            let stats = Arc::new(TransferStats::load(&stats_path)?);
+            #[cfg(feature = "metrics")]
+            if let Some(addr) = metrics_addr {
+                let metrics = Arc::new(bittorrent_starter_rust::metrics::Metrics::default());
+                metrics.track(&torrent, Arc::clone(&stats))?;
+                let listener = tokio::net::TcpListener::bind(addr)
                     .await
-                    .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
+                    .with_context(|| format!("listen for metrics scrapes on {addr}"))?;
+                tokio::spawn(metrics.serve(listener));
+            }
+            // torrent.download_all_to_file(output).await?;
+            let verbose = Arc::new(AtomicBool::new(false));
+            let ticker = tokio::spawn(show_progress(
+                Arc::clone(&stats),
+                torrent.num_pieces(),
+                torrent.length(),
+                progress_map,
+                json_progress,
+                Arc::clone(&verbose),
+            ));
+            let mut download =
+                download::DownloadHandle::builder(torrent.clone(), Arc::clone(&stats))
+                    .listeners(listeners);
+            if aggressive_announce {
+                download = download.announce_strategy(AnnounceStrategy::Concurrent);
+            }
+            if allow_peer_id_mismatch {
+                download = download.peer_id_check(PeerIdCheck::Warn);
+            }
+            download = download.pipeline(pipeline);
+            if !peers.is_empty() {
+                download = download.peers(peers);
+            }
+            // whatever an earlier run left in place intact needn't come down again
+            let resuming = output.exists();
+            let mut storage = download::FileStorage::create(&output, &torrent).await?;
+            if resuming {
+                let verified = download::verified_pieces(&torrent, &mut storage)
                     .await
-                    .context("read handshake")?;
+                    .with_context(|| format!("check {} for finished pieces", output.display()))?;
+                if !verified.is_empty() {
+                    eprintln!(
+                        "resuming with {} of {} pieces already verified",
+                        verified.len(),
+                        torrent.num_pieces()
+                    );
+                }
+                download = download.verified(verified);
             }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-            // println!("Peer ID: {}", hex::encode(&handshake.peer_id));
-
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-            let bitfield = peer
-                .next()
-                .await
-                .expect("peer always sends a bitfields")
-                .context("peer message was invalid")?;
-            assert_eq!(bitfield.tag, MessageTag::Bitfield);
-            // NOTE: we assume that the bitfield covers all pieces
-
-            peer.send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
-            .await
-            .context("send interested message")?;
-
-            let unchoke = peer
-                .next()
-                .await
-                .expect("peer always sends an unchoke")
-                .context("peer message was invalid")?;
-            assert_eq!(unchoke.tag, MessageTag::Unchoke);
-            assert!(unchoke.payload.is_empty());
-
-            let piece_hash = &t.info.pieces.0[piece_i];
-            let piece_size = if piece_i == t.info.pieces.0.len() - 1 {
-                let md = length % t.info.plength;
-                if md == 0 {
-                    t.info.plength
-                } else {
-                    md
+            let mut download = download.storage(storage).spawn();
+            let mut signals = PauseSignals::new()?;
+            let interactive = !no_interactive && std::io::stdin().is_terminal();
+            // restores the terminal when dropped, however we leave this block
+            let key_mode = interactive
+                .then(KeyMode::enable)
+                .transpose()
+                .unwrap_or_else(|e| {
+                    eprintln!("key commands unavailable: {e:#}");
+                    None
+                });
+            let mut keys = key_mode.as_ref().map(|_| {
+                eprintln!("keys: p pause/resume, +/- rate limit, v connection stats, q quit");
+                read_keys()
+            });
+            let mut console = control::Console::default();
+            let outcome = loop {
+                let command = tokio::select! {
+                    outcome = download.wait() => break outcome,
+                    _ = tokio::signal::ctrl_c() => {
+                        download.cancel();
+                        break download.wait().await;
+                    }
+                    command = signals.next() => command,
+                    Some(command) = async {
+                        match &mut keys {
+                            Some(keys) => keys.recv().await,
+                            None => std::future::pending().await,
+                        }
+                    } => command,
+                };
+                let was_paused = download.is_paused();
+                if let Some(message) = console.apply(command, &download) {
+                    eprintln!("{message}");
+                }
+                verbose.store(console.verbose, Ordering::Relaxed);
+                if download.is_paused() && !was_paused {
+                    stats.save(&stats_path)?;
                 }
-            } else {
-                t.info.plength
             };
-            // the + (BLOCK_MAX - 1) rounds up
-            let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-            // eprintln!("{nblocks} blocks of at most {BLOCK_MAX} to reach {piece_size}");
-            let mut all_blocks = Vec::with_capacity(piece_size);
-            for block in 0..nblocks {
-                let block_size = if block == nblocks - 1 {
-                    let md = piece_size % BLOCK_MAX;
-                    if md == 0 {
-                        BLOCK_MAX
-                    } else {
-                        md
+            drop(key_mode);
+            ticker.abort();
+            stats.save(&stats_path)?;
+            match outcome? {
+                // the file storage has already put every piece in place
+                download::Outcome::Complete(_) => {}
+                download::Outcome::Cancelled => eprintln!("download cancelled"),
+            }
+        }
+        Command::Create {
+            path,
+            announce,
+            output,
+            piece_length,
+        } => {
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            let t = create::create(&path, announce, piece_length, threads)?;
+            std::fs::write(&output, t.to_bytes()?)
+                .with_context(|| format!("write {}", output.display()))?;
+            println!(
+                "Created {} with {} pieces of {} bytes.",
+                output.display(),
+                t.num_pieces(),
+                t.info.plength
+            );
+            println!("Info Hash: {}", t.info_hash()?);
+        }
+        Command::Verify { torrent, path, md5 } => {
+            let t = Torrent::from_file(&torrent)?;
+            let npieces = t.num_pieces();
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            let root = path.clone();
+            // hashing is all CPU and blocking reads, so keep it off the runtime's threads
+            let (failed, mismatches) = tokio::task::spawn_blocking(move || {
+                let mut failed = Vec::new();
+                for result in piece::FileVerifier::open(&t, &root).parallel(threads) {
+                    let (piece_i, ok) = result?;
+                    if !ok {
+                        failed.push(piece_i);
                     }
+                }
+                let mismatches = if md5 {
+                    piece::md5_mismatches(&t, &root)?
                 } else {
-                    BLOCK_MAX
+                    Vec::new()
                 };
-                // eprintln!("block #{block} is {block_size}b");
-                let mut request = Request::new(
-                    piece_i as u32,
-                    (block * BLOCK_MAX) as u32,
-                    block_size as u32,
+                std::io::Result::Ok((failed, mismatches))
+            })
+            .await?
+            .with_context(|| format!("read {}", path.display()))?;
+            // md5sum is advisory, so only the piece hashes decide whether the data is good
+            for mismatch in mismatches {
+                eprintln!("warning: {} doesn't match its md5sum", mismatch.display());
+            }
+            println!("{}/{npieces} pieces OK", npieces - failed.len());
+            if !failed.is_empty() {
+                let failed: Vec<_> = failed.iter().map(usize::to_string).collect();
+                println!("Failed pieces: {}", failed.join(", "));
+                anyhow::bail!(
+                    "{} of {npieces} pieces are missing or corrupt",
+                    failed.len()
                 );
-                let request_bytes = Vec::from(request.as_bytes_mut());
-                peer.send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
-                .await
-                .with_context(|| format!("send request for block {block}"))?;
-
-                let piece = peer
-                    .next()
-                    .await
-                    .expect("peer always sends a piece")
-                    .context("peer message was invalid")?;
-                assert_eq!(piece.tag, MessageTag::Piece);
-                assert!(!piece.payload.is_empty());
-
-                let piece = Piece::ref_from_bytes(&piece.payload[..])
-                    .expect("always get all Piece response fields from peer");
-                assert_eq!(piece.index() as usize, piece_i);
-                assert_eq!(piece.begin() as usize, block * BLOCK_MAX);
-                assert_eq!(piece.block().len(), block_size);
-                all_blocks.extend(piece.block());
             }
-
-            assert_eq!(all_blocks.len(), piece_size);
-
-            let mut hasher = Sha1::new();
-            hasher.update(&all_blocks);
-            let hash: [u8; 20] = hasher
-                .finalize()
-                .try_into()
-                .expect("GenericArray<_, 20> == [_; 20]");
-            assert_eq!(&hash, piece_hash);
-
-            tokio::fs::write(&output, all_blocks)
+        }
+        Command::ServeFile {
+            torrent,
+            file,
+            listen,
+        } => {
+            let t = Torrent::from_file(&torrent)?;
+            let seed = seed::Seed::from_file(&t, &file).await?;
+            let listener = tokio::net::TcpListener::bind(listen)
                 .await
-                .context("write out downloaded piece")?;
-            println!("Piece {piece_i} downloaded to {}.", output.display());
+                .with_context(|| format!("listen for peers on {listen}"))?;
+            let listen = listener
+                .local_addr()
+                .context("find the address we listen on")?;
+            println!("Serving {} on {listen}.", file.display());
+            seed.serve(listener).await?;
         }
-        Command::Download { output, torrent } => {
-            let torrent = Torrent::read(torrent).await?;
-            torrent.print_tree();
-            // torrent.download_all_to_file(output).await?;
-            let files = torrent.download_all().await?;
-            tokio::fs::write(
-                output,
-                files.into_iter().next().expect("always one file").bytes(),
-            )
-            .await?;
+        Command::Scrape { torrent } => {
+            let t = Torrent::from_file(&torrent)?;
+            let info_hash = t.info_hash()?;
+            let trackers = t.trackers();
+            anyhow::ensure!(!trackers.is_empty(), "the torrent lists no trackers");
+            // the first tracker that answers speaks for the swarm
+            let mut last_error = None;
+            for tracker in &trackers {
+                match scrape(tracker, info_hash).await {
+                    Ok(swarm) => {
+                        let count =
+                            |n: Option<usize>| n.map_or("unknown".into(), |n| n.to_string());
+                        println!("Seeders: {}", count(swarm.seeders));
+                        println!("Leechers: {}", count(swarm.leechers));
+                        println!("Completed: {}", count(swarm.completed));
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        eprintln!("scrape of {} failed: {e:#}", redacted(tracker));
+                        last_error = Some(e);
+                    }
+                }
+            }
+            return Err(last_error.expect("at least one tracker was tried"));
+        }
+        Command::Health {
+            torrent,
+            timeout,
+            json,
+        } => {
+            let t = Torrent::from_file(&torrent)?;
+            let health = health::check(&t, timeout).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&health)?);
+            } else {
+                println!("{health}");
+            }
+        }
+        Command::Trackers {
+            torrent,
+            timeout,
+            json,
+        } => {
+            let t = Torrent::from_file(&torrent)?;
+            let health = health::announce(&t, timeout).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&health)?);
+            } else {
+                println!("{}", health::Statuses(&health));
+            }
+        }
+        Command::BenchPeer {
+            torrent,
+            peer,
+            family,
+            duration,
+            no_verify,
+            json,
+        } => {
+            let t = Torrent::from_file(&torrent)?;
+            let options = bench::BenchOptions {
+                duration,
+                verify: !no_verify,
+                prefer: family.prefer(),
+            };
+            let report = bench::bench_peer(&t, &peer, &options).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("{report}");
+            }
         }
     }
     Ok(())
@@ -0,0 +1,1051 @@
+//! Exchanging a torrent's info dictionary over the extension protocol (BEP 9, `ut_metadata`), so
+//! that peers who only have a magnet link can get it from peers who have the whole torrent.
+
+use crate::bencode;
+use crate::download;
+use crate::magnet::MagnetLink;
+use crate::peer::{Handshake, Message, MessageFramer, CONNECT_TIMEOUT};
+use crate::pool::PeerPool;
+use crate::torrent::{InfoHash, Torrent};
+use anyhow::Context;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::Framed;
+
+/// Metadata is exchanged in pieces of this size; only the last one may be shorter.
+pub const METADATA_PIECE: usize = 16 * 1024;
+
+/// The extended message id of the extended handshake itself.
+pub const EXTENDED_HANDSHAKE: u8 = 0;
+
+/// The id we ask peers to use for `ut_metadata` messages they send us.
+pub const UT_METADATA: u8 = 1;
+
+/// How many metadata pieces we serve a single connection per [`METADATA_WINDOW`].
+///
+/// Enough to fetch metadata several times over for all but enormous torrents, but not enough to
+/// turn us into a free bandwidth source.
+pub const METADATA_REQUESTS: u32 = 64;
+
+pub const METADATA_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a peer we fetch metadata from gets to send each message, before we move on to the
+/// next peer.
+pub const METADATA_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The payload of an extended handshake (BEP 10), as far as we care about it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// The extensions the sender supports, and the message ids it wants them sent with.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// The size of the info dictionary, if the sender has it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+    /// The port the sender listens on, which for an incoming connection is rarely the port it
+    /// connected from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// The receiver's address as the sender sees it: 4 bytes for IPv4, 16 for IPv6.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub yourip: Option<Vec<u8>>,
+}
+
+impl ExtendedHandshake {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("extended handshakes always encode")
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, MetadataError> {
+        Ok(serde_bencode::from_bytes(payload)?)
+    }
+
+    /// Where to dial the sender of this handshake, which connected to us from `source`.
+    pub fn listen_addr(&self, source: SocketAddr) -> SocketAddr {
+        match self.p {
+            Some(port) if port != 0 => SocketAddr::new(source.ip(), port),
+            _ => source,
+        }
+    }
+
+    /// Our own address, according to the sender.
+    pub fn yourip(&self) -> Option<IpAddr> {
+        let bytes = self.yourip.as_deref()?;
+        if let Ok(v4) = <[u8; 4]>::try_from(bytes) {
+            Some(IpAddr::from(v4))
+        } else {
+            <[u8; 16]>::try_from(bytes).ok().map(IpAddr::from)
+        }
+    }
+
+    /// The `yourip` to send a peer at `ip`.
+    pub fn compact_ip(ip: IpAddr) -> Vec<u8> {
+        match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        }
+    }
+
+    /// The id the sender wants `ut_metadata` messages sent with, if it supports them at all.
+    pub fn ut_metadata(&self) -> Option<u8> {
+        self.m.get("ut_metadata").copied().filter(|&id| id != 0)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetadataError {
+    #[error(transparent)]
+    Bencode(#[from] bencode::Error),
+    #[error(transparent)]
+    Parse(#[from] serde_bencode::Error),
+    #[error("unknown ut_metadata message type {0}")]
+    UnknownType(u8),
+    #[error("ut_metadata data message without a total_size")]
+    MissingTotalSize,
+}
+
+/// A `ut_metadata` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+/// The bencoded dictionary each message starts with; a data message has the data right after it.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (header, data) = match self {
+            MetadataMessage::Request { piece } => (
+                Header {
+                    msg_type: 0,
+                    piece: *piece,
+                    total_size: None,
+                },
+                &[][..],
+            ),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (
+                Header {
+                    msg_type: 1,
+                    piece: *piece,
+                    total_size: Some(*total_size),
+                },
+                &data[..],
+            ),
+            MetadataMessage::Reject { piece } => (
+                Header {
+                    msg_type: 2,
+                    piece: *piece,
+                    total_size: None,
+                },
+                &[][..],
+            ),
+        };
+        let mut bytes = serde_bencode::to_bytes(&header).expect("headers always encode");
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, MetadataError> {
+        let (_, data) = bencode::decode(payload)?;
+        let header: Header = serde_bencode::from_bytes(&payload[..payload.len() - data.len()])?;
+        let piece = header.piece;
+        match header.msg_type {
+            0 => Ok(MetadataMessage::Request { piece }),
+            1 => Ok(MetadataMessage::Data {
+                piece,
+                total_size: header.total_size.ok_or(MetadataError::MissingTotalSize)?,
+                data: data.to_vec(),
+            }),
+            2 => Ok(MetadataMessage::Reject { piece }),
+            n => Err(MetadataError::UnknownType(n)),
+        }
+    }
+}
+
+/// Answers one connection's metadata requests.
+#[derive(Debug)]
+pub struct MetadataServer<'a> {
+    info: &'a [u8],
+    /// When the current rate-limiting window started, and how many pieces we served in it.
+    window: Option<(Instant, u32)>,
+}
+
+impl<'a> MetadataServer<'a> {
+    pub fn new(info: &'a [u8]) -> Self {
+        Self { info, window: None }
+    }
+
+    pub fn pieces(&self) -> usize {
+        self.info.len().div_ceil(METADATA_PIECE)
+    }
+
+    /// Answer a request for metadata piece `piece`, rejecting it if there's no such piece or the
+    /// peer has had its fill for now.
+    pub fn respond(&mut self, piece: usize, now: Instant) -> MetadataMessage {
+        if piece >= self.pieces() {
+            return MetadataMessage::Reject { piece };
+        }
+        let (since, served) = self.window.get_or_insert((now, 0));
+        if now.duration_since(*since) >= METADATA_WINDOW {
+            (*since, *served) = (now, 0);
+        }
+        if *served >= METADATA_REQUESTS {
+            return MetadataMessage::Reject { piece };
+        }
+        *served += 1;
+        let data = self
+            .info
+            .chunks(METADATA_PIECE)
+            .nth(piece)
+            .expect("in range");
+        MetadataMessage::Data {
+            piece,
+            total_size: self.info.len(),
+            data: data.to_vec(),
+        }
+    }
+}
+
+/// The largest info dictionary we are willing to assemble; anything claiming to be bigger is more
+/// likely to be an attempt at making us allocate than a real torrent.
+pub const METADATA_MAX: usize = 16 * 1024 * 1024;
+
+/// Where one metadata piece is at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Slot {
+    Missing,
+    Requested { from: SocketAddr },
+    Received { from: SocketAddr, data: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AssemblyError {
+    #[error("metadata of {0} bytes is not something we will assemble")]
+    BadSize(usize),
+    #[error("{from} is banned as a metadata source")]
+    Banned { from: SocketAddr },
+    #[error("{from} says the metadata is {got} bytes, but it is {expected}")]
+    SizeMismatch {
+        from: SocketAddr,
+        expected: usize,
+        got: usize,
+    },
+    #[error("{from} sent metadata piece {piece}, but there are only {pieces}")]
+    OutOfRange {
+        from: SocketAddr,
+        piece: usize,
+        pieces: usize,
+    },
+    #[error("{from} sent {got} bytes for metadata piece {piece}, which should be {expected}")]
+    PieceLength {
+        from: SocketAddr,
+        piece: usize,
+        expected: usize,
+        got: usize,
+    },
+    #[error("the assembled metadata doesn't match the info hash; banned {}", list(.banned))]
+    HashMismatch { banned: Vec<SocketAddr> },
+}
+
+fn list(addrs: &[SocketAddr]) -> String {
+    addrs
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Puts an info dictionary back together from `ut_metadata` pieces, which may come from several
+/// peers, in any order, and more than once.
+#[derive(Debug)]
+pub struct MetadataAssembler {
+    info_hash: InfoHash,
+    size: usize,
+    slots: Vec<Slot>,
+    banned: BTreeSet<SocketAddr>,
+}
+
+impl MetadataAssembler {
+    /// Start assembling `size` bytes of metadata (as a peer's handshake announced it) for the
+    /// torrent with `info_hash`.
+    pub fn new(info_hash: InfoHash, size: usize) -> Result<Self, AssemblyError> {
+        if size == 0 || size > METADATA_MAX {
+            return Err(AssemblyError::BadSize(size));
+        }
+        Ok(Self {
+            info_hash,
+            size,
+            slots: vec![Slot::Missing; size.div_ceil(METADATA_PIECE)],
+            banned: BTreeSet::new(),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn slots(&self) -> &[Slot] {
+        &self.slots
+    }
+
+    pub fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.banned.contains(&addr)
+    }
+
+    /// How long metadata piece `piece` has to be; all but the last are [`METADATA_PIECE`].
+    fn piece_len(&self, piece: usize) -> usize {
+        (self.size - piece * METADATA_PIECE).min(METADATA_PIECE)
+    }
+
+    /// Pick a piece for `from` to send us, and mark it requested.
+    pub fn next_request(&mut self, from: SocketAddr) -> Option<usize> {
+        if self.is_banned(from) {
+            return None;
+        }
+        let piece = self.slots.iter().position(|s| *s == Slot::Missing)?;
+        self.slots[piece] = Slot::Requested { from };
+        Some(piece)
+    }
+
+    /// `from` rejected our request for `piece`, so someone else will have to send it.
+    pub fn rejected(&mut self, from: SocketAddr, piece: usize) {
+        if let Some(slot) = self.slots.get_mut(piece) {
+            if *slot == (Slot::Requested { from }) {
+                *slot = Slot::Missing;
+            }
+        }
+    }
+
+    /// `from` went away; hand out whatever it still owed us to others.
+    pub fn abandon(&mut self, from: SocketAddr) {
+        for slot in &mut self.slots {
+            if *slot == (Slot::Requested { from }) {
+                *slot = Slot::Missing;
+            }
+        }
+    }
+
+    /// Take in a `ut_metadata` data message from `from`, returning the metadata once it is
+    /// complete and checked against the info hash.
+    ///
+    /// Duplicates of pieces we already have are ignored, and unrequested pieces are taken as
+    /// gladly as requested ones. If the finished metadata doesn't hash right, there is no telling
+    /// which piece was bad, so all of it is thrown away and everyone who contributed is banned.
+    pub fn receive(
+        &mut self,
+        from: SocketAddr,
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, AssemblyError> {
+        if self.is_banned(from) {
+            return Err(AssemblyError::Banned { from });
+        }
+        if total_size != self.size {
+            return Err(AssemblyError::SizeMismatch {
+                from,
+                expected: self.size,
+                got: total_size,
+            });
+        }
+        if piece >= self.slots.len() {
+            return Err(AssemblyError::OutOfRange {
+                from,
+                piece,
+                pieces: self.slots.len(),
+            });
+        }
+        let expected = self.piece_len(piece);
+        if data.len() != expected {
+            return Err(AssemblyError::PieceLength {
+                from,
+                piece,
+                expected,
+                got: data.len(),
+            });
+        }
+        if matches!(self.slots[piece], Slot::Received { .. }) {
+            return Ok(None);
+        }
+        self.slots[piece] = Slot::Received { from, data };
+        if !self
+            .slots
+            .iter()
+            .all(|s| matches!(s, Slot::Received { .. }))
+        {
+            return Ok(None);
+        }
+
+        let mut info = Vec::with_capacity(self.size);
+        let mut sources = BTreeSet::new();
+        let empty = vec![Slot::Missing; self.slots.len()];
+        for slot in std::mem::replace(&mut self.slots, empty) {
+            let Slot::Received { from, data } = slot else {
+                unreachable!("all slots were received");
+            };
+            info.extend(data);
+            sources.insert(from);
+        }
+        let hash = InfoHash(Sha1::digest(&info).into());
+        if hash != self.info_hash {
+            self.banned.extend(&sources);
+            return Err(AssemblyError::HashMismatch {
+                banned: sources.into_iter().collect(),
+            });
+        }
+        Ok(Some(info))
+    }
+}
+
+fn extended(id: u8, payload: &[u8]) -> Message {
+    Message::Extended {
+        id,
+        payload: Bytes::copy_from_slice(payload),
+    }
+}
+
+/// Hand out `info` (the bencoded info dictionary) to everyone who connects to `listener`, and
+/// tell `pool` what they say about their listen addresses and ours.
+pub async fn serve(
+    listener: TcpListener,
+    info_hash: InfoHash,
+    info: Vec<u8>,
+    pool: Arc<Mutex<PeerPool>>,
+) -> anyhow::Result<()> {
+    let info = Arc::new(info);
+    loop {
+        let (stream, addr) = listener.accept().await.context("accept peer")?;
+        let info = Arc::clone(&info);
+        let pool = Arc::clone(&pool);
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, info_hash, &info, &pool).await {
+                eprintln!("serving metadata to {addr} failed: {e:?}");
+            }
+        });
+    }
+}
+
+/// Answer `ut_metadata` requests on an incoming connection until the peer hangs up.
+pub async fn serve_connection(
+    mut stream: TcpStream,
+    info_hash: InfoHash,
+    info: &[u8],
+    pool: &Mutex<PeerPool>,
+) -> anyhow::Result<()> {
+    let source = stream.peer_addr().context("peer address")?;
+    let port = stream.local_addr().context("local address")?.port();
+    let handshake = Handshake::read(&mut stream).await?;
+    anyhow::ensure!(
+        handshake.info_hash == info_hash,
+        "peer asked for a torrent we don't have"
+    );
+    let mut reply = Handshake::new(info_hash, crate::peer::PeerId::ours().0);
+    reply.reserved = reply.reserved.with_ltep();
+    reply.write(&mut stream).await?;
+
+    let mut stream = Framed::new(stream, MessageFramer::default());
+    let ours = ExtendedHandshake {
+        m: BTreeMap::from([(String::from("ut_metadata"), UT_METADATA)]),
+        metadata_size: Some(info.len()),
+        p: Some(port),
+        yourip: Some(ExtendedHandshake::compact_ip(source.ip())),
+    };
+    stream
+        .send(extended(EXTENDED_HANDSHAKE, &ours.to_bytes()))
+        .await
+        .context("send extended handshake")?;
+
+    let mut server = MetadataServer::new(info);
+    let mut their_id = None;
+    while let Some(msg) = stream.next().await {
+        let Message::Extended { id, payload } = msg.context("peer message was invalid")? else {
+            continue;
+        };
+        let payload = &payload[..];
+        match id {
+            EXTENDED_HANDSHAKE => {
+                let theirs =
+                    ExtendedHandshake::from_bytes(payload).context("parse extended handshake")?;
+                let mut pool = pool.lock().expect("nobody panics holding the pool");
+                // the port an incoming connection comes from is no use for dialing it later
+                if theirs.p.is_some() {
+                    pool.canonicalize(source, theirs.listen_addr(source));
+                }
+                if let Some(ours) = theirs.yourip() {
+                    pool.observe_yourip(source.ip(), ours);
+                }
+                their_id = theirs.ut_metadata();
+            }
+            UT_METADATA => {
+                let Some(their_id) = their_id else {
+                    // they never told us how to reply
+                    continue;
+                };
+                if let MetadataMessage::Request { piece } =
+                    MetadataMessage::from_bytes(payload).context("parse ut_metadata message")?
+                {
+                    let response = server.respond(piece, Instant::now());
+                    stream
+                        .send(extended(their_id, &response.to_bytes()))
+                        .await
+                        .context("send ut_metadata response")?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Fetch the info dictionary of the torrent with `info_hash` from the peer at `addr`, checking it
+/// against the hash.
+pub async fn fetch(addr: SocketAddr, info_hash: InfoHash) -> anyhow::Result<Vec<u8>> {
+    fetch_any(&[addr], info_hash).await
+}
+
+/// Like [`fetch`], but trying `candidates` in turn until one of them gets us the metadata.
+///
+/// Pieces carry over from one peer to the next, unless the result fails its hash check, in which
+/// case the peers it came from are banned and we start over with the rest.
+///
+/// Each peer gets [`CONNECT_TIMEOUT`] to answer our handshake, and [`METADATA_READ_TIMEOUT`] for
+/// every message after that.
+pub async fn fetch_any(candidates: &[SocketAddr], info_hash: InfoHash) -> anyhow::Result<Vec<u8>> {
+    fetch_any_within(
+        candidates,
+        info_hash,
+        CONNECT_TIMEOUT,
+        METADATA_READ_TIMEOUT,
+    )
+    .await
+}
+
+async fn fetch_any_within(
+    candidates: &[SocketAddr],
+    info_hash: InfoHash,
+    connect: Duration,
+    read: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    let mut assembler = None;
+    for &addr in candidates {
+        match fetch_into(addr, info_hash, &mut assembler, connect, read).await {
+            Ok(info) => return Ok(info),
+            Err(e) => {
+                eprintln!("fetching metadata from {addr} failed: {e:#}");
+                if let Some(assembler) = &mut assembler {
+                    assembler.abandon(addr);
+                }
+            }
+        }
+    }
+    anyhow::bail!(
+        "none of the {} peers we tried sent us the metadata",
+        candidates.len()
+    )
+}
+
+/// The torrent `link` names, with the metadata fetched from whichever of `candidates` has it.
+pub async fn fetch_torrent(
+    link: &MagnetLink,
+    candidates: &[SocketAddr],
+) -> anyhow::Result<Torrent> {
+    let info = fetch_any(candidates, link.info_hash).await?;
+    link.to_torrent(&info)
+        .context("make a torrent of the fetched metadata")
+}
+
+/// Like [`fetch_torrent`], for a download that went under the `provisional` name until now: its
+/// data and resume state move to the name the metadata gives, which is handed back.
+pub async fn fetch_torrent_renaming(
+    link: &MagnetLink,
+    candidates: &[SocketAddr],
+    provisional: &Path,
+) -> anyhow::Result<(Torrent, PathBuf)> {
+    let t = fetch_torrent(link, candidates).await?;
+    let output = download::rename_provisional(provisional, &t.info.name)
+        .with_context(|| format!("move {} to the torrent's name", provisional.display()))?;
+    Ok((t, output))
+}
+
+async fn fetch_into(
+    addr: SocketAddr,
+    info_hash: InfoHash,
+    assembler: &mut Option<MetadataAssembler>,
+    connect: Duration,
+    read: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    if assembler.as_ref().is_some_and(|a| a.is_banned(addr)) {
+        anyhow::bail!("{addr} already sent us bad metadata");
+    }
+    let opening = async {
+        let mut stream = crate::resolve::tcp_connect(addr)
+            .await
+            .context("connect to peer")?;
+        let mut handshake = Handshake::new(info_hash, crate::peer::PeerId::ours().0);
+        handshake.reserved = handshake.reserved.with_ltep();
+        handshake.write(&mut stream).await?;
+        let handshake = Handshake::read(&mut stream).await?;
+        anyhow::ensure!(
+            handshake.reserved.ltep(),
+            "peer doesn't support the extension protocol"
+        );
+        anyhow::Ok(stream)
+    };
+    let stream = tokio::time::timeout(connect, opening)
+        .await
+        .with_context(|| format!("peer didn't answer our handshake within {connect:?}"))??;
+
+    let mut stream = Framed::new(stream, MessageFramer::default());
+    let ours = ExtendedHandshake {
+        m: BTreeMap::from([(String::from("ut_metadata"), UT_METADATA)]),
+        ..Default::default()
+    };
+    stream
+        .send(extended(EXTENDED_HANDSHAKE, &ours.to_bytes()))
+        .await
+        .context("send extended handshake")?;
+
+    let mut their_id = None;
+    while let Some(msg) = tokio::time::timeout(read, stream.next())
+        .await
+        .with_context(|| format!("peer sent nothing for {read:?}"))?
+    {
+        let Message::Extended { id, payload } = msg.context("peer message was invalid")? else {
+            continue;
+        };
+        let payload = &payload[..];
+        match id {
+            EXTENDED_HANDSHAKE => {
+                let theirs =
+                    ExtendedHandshake::from_bytes(payload).context("parse extended handshake")?;
+                let id = theirs
+                    .ut_metadata()
+                    .context("peer doesn't serve metadata")?;
+                let size = theirs
+                    .metadata_size
+                    .context("peer didn't say how big the metadata is")?;
+                match assembler {
+                    Some(assembler) => anyhow::ensure!(
+                        assembler.size() == size,
+                        "peer says the metadata is {size} bytes, but others said {}",
+                        assembler.size()
+                    ),
+                    None => *assembler = Some(MetadataAssembler::new(info_hash, size)?),
+                }
+                their_id = Some(id);
+            }
+            UT_METADATA => {
+                let assembler = assembler
+                    .as_mut()
+                    .context("peer sent metadata before its extended handshake")?;
+                match MetadataMessage::from_bytes(payload)? {
+                    MetadataMessage::Data {
+                        piece,
+                        total_size,
+                        data,
+                    } => {
+                        if let Some(info) = assembler.receive(addr, piece, total_size, data)? {
+                            return Ok(info);
+                        }
+                    }
+                    MetadataMessage::Reject { piece } => {
+                        assembler.rejected(addr, piece);
+                        anyhow::bail!("peer rejected our request for metadata piece {piece}")
+                    }
+                    other => anyhow::bail!("unexpected ut_metadata message: {other:?}"),
+                }
+            }
+            _ => continue,
+        }
+
+        let (Some(their_id), Some(assembler)) = (their_id, assembler.as_mut()) else {
+            continue;
+        };
+        // one request at a time, each sent once the previous one has been answered
+        if assembler.slots().contains(&Slot::Requested { from: addr }) {
+            continue;
+        }
+        let piece = assembler
+            .next_request(addr)
+            .context("nothing left to ask this peer for")?;
+        stream
+            .send(extended(
+                their_id,
+                &MetadataMessage::Request { piece }.to_bytes(),
+            ))
+            .await
+            .context("request metadata piece")?;
+    }
+    anyhow::bail!("peer closed the connection before sending all the metadata")
+}
+
+#[test]
+fn message_round_trips() {
+    let messages = [
+        MetadataMessage::Request { piece: 0 },
+        MetadataMessage::Data {
+            piece: 1,
+            total_size: 16384 + 3,
+            data: b"abc".to_vec(),
+        },
+        MetadataMessage::Reject { piece: 7 },
+    ];
+    for msg in messages {
+        assert_eq!(MetadataMessage::from_bytes(&msg.to_bytes()).unwrap(), msg);
+    }
+    assert_eq!(
+        MetadataMessage::Request { piece: 0 }.to_bytes(),
+        b"d8:msg_typei0e5:piecei0ee"
+    );
+    assert!(matches!(
+        MetadataMessage::from_bytes(b"d8:msg_typei1e5:piecei0ee"),
+        Err(MetadataError::MissingTotalSize)
+    ));
+    assert!(matches!(
+        MetadataMessage::from_bytes(b"d8:msg_typei9e5:piecei0ee"),
+        Err(MetadataError::UnknownType(9))
+    ));
+}
+
+#[test]
+fn server_rejects_out_of_range_and_over_budget() {
+    let info = vec![7u8; METADATA_PIECE + 100];
+    let mut server = MetadataServer::new(&info);
+    let t0 = Instant::now();
+    assert_eq!(server.pieces(), 2);
+    assert_eq!(server.respond(2, t0), MetadataMessage::Reject { piece: 2 });
+    let MetadataMessage::Data {
+        total_size, data, ..
+    } = server.respond(1, t0)
+    else {
+        panic!("piece 1 exists");
+    };
+    assert_eq!((total_size, data.len()), (info.len(), 100));
+
+    for _ in 1..METADATA_REQUESTS {
+        assert!(matches!(
+            server.respond(0, t0),
+            MetadataMessage::Data { .. }
+        ));
+    }
+    assert_eq!(server.respond(0, t0), MetadataMessage::Reject { piece: 0 });
+    assert!(matches!(
+        server.respond(0, t0 + METADATA_WINDOW),
+        MetadataMessage::Data { .. }
+    ));
+}
+
+#[tokio::test]
+async fn magnet_peer_fetches_metadata_from_us() {
+    // a torrent with enough pieces that its info dictionary spans several metadata pieces
+    let npieces = 2000;
+    let mut bytes = format!(
+        "d8:announce9:http://x/4:infod6:lengthi{}e4:name7:big.iso12:piece lengthi16384e6:pieces{}:",
+        npieces * 16384,
+        npieces * 20
+    )
+    .into_bytes();
+    for i in 0..npieces {
+        bytes.extend(Sha1::digest((i as u32).to_be_bytes()));
+    }
+    bytes.extend(b"ee");
+    let t = crate::torrent::Torrent::from_bytes(&bytes).unwrap();
+    let info = t.info_bytes().unwrap().into_owned();
+    assert!(info.len() > 2 * METADATA_PIECE);
+    let info_hash = t.info_hash().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, info_hash, info.clone(), Arc::default()));
+
+    // all a magnet link gives the other side is the info hash
+    let fetched = fetch(addr, info_hash).await.unwrap();
+    assert_eq!(fetched, info);
+    let fetched = crate::torrent::Torrent::from_bytes(
+        &[&b"d8:announce9:http://x/4:info"[..], &fetched, b"e"].concat(),
+    )
+    .unwrap();
+    assert_eq!(fetched.info_hash().unwrap(), info_hash);
+
+    // and a peer asking about some other torrent gets nowhere
+    assert!(fetch(addr, InfoHash([0; 20])).await.is_err());
+}
+
+#[tokio::test]
+async fn magnet_downloads_move_to_the_real_name() {
+    let t = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+    let info_hash = t.info_hash().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info = t.info_bytes().unwrap().into_owned();
+    tokio::spawn(serve(listener, info_hash, info, Arc::default()));
+
+    let dir = tempfile::tempdir().unwrap();
+    let provisional = dir.path().join(info_hash.to_string());
+    let _lock =
+        crate::lock::SessionLock::acquire(&[&provisional, &download::stats_path(&provisional)])
+            .unwrap();
+    crate::tracker::TransferStats::new(0, 1234)
+        .save(&download::stats_path(&provisional))
+        .unwrap();
+
+    let link = MagnetLink {
+        name: None,
+        ..MagnetLink::of(&t).unwrap()
+    };
+    let (fetched, output) = fetch_torrent_renaming(&link, &[addr], &provisional)
+        .await
+        .unwrap();
+    assert_eq!(fetched.info_hash().unwrap(), info_hash);
+    assert_eq!(output, dir.path().join(&t.info.name));
+    assert!(!provisional.exists());
+    let stats = crate::tracker::TransferStats::load(&download::stats_path(&output)).unwrap();
+    assert_eq!(stats.downloaded(), 1234);
+    // the lock moved along with the files
+    assert!(crate::lock::SessionLock::acquire(&[&output]).is_err());
+}
+
+#[cfg(test)]
+fn source(i: u8) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, i], 6881))
+}
+
+#[test]
+fn assembler_takes_pieces_in_any_order_and_more_than_once() {
+    let mut rng = fastrand::Rng::with_seed(228);
+    for _ in 0..100 {
+        let size = rng.usize(1..5 * METADATA_PIECE);
+        let info: Vec<u8> = std::iter::repeat_with(|| rng.u8(..)).take(size).collect();
+        let mut assembler =
+            MetadataAssembler::new(InfoHash(Sha1::digest(&info).into()), size).unwrap();
+        let pieces: Vec<&[u8]> = info.chunks(METADATA_PIECE).collect();
+
+        // every piece at least once, some of them again, from whichever peer
+        let mut deliveries: Vec<usize> = (0..pieces.len()).collect();
+        deliveries.extend((0..rng.usize(..4)).map(|_| rng.usize(..pieces.len())));
+        rng.shuffle(&mut deliveries);
+        let mut seen = BTreeSet::new();
+        for piece in deliveries {
+            seen.insert(piece);
+            let from = source(rng.u8(1..=3));
+            let result = assembler.receive(from, piece, size, pieces[piece].to_vec());
+            if seen.len() < pieces.len() {
+                assert_eq!(result, Ok(None));
+            } else {
+                assert_eq!(result, Ok(Some(info.clone())));
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn assembler_checks_sizes() {
+    let info = vec![7u8; METADATA_PIECE + 100];
+    let info_hash = InfoHash(Sha1::digest(&info).into());
+    let mut assembler = MetadataAssembler::new(info_hash, info.len()).unwrap();
+    let a = source(1);
+    assert_eq!(assembler.next_request(a), Some(0));
+    assert_eq!(assembler.next_request(a), Some(1));
+    assert_eq!(assembler.next_request(a), None);
+
+    // the last piece is short, but no shorter than what's left
+    assert!(matches!(
+        assembler.receive(a, 1, info.len(), vec![7; 99]),
+        Err(AssemblyError::PieceLength {
+            piece: 1,
+            expected: 100,
+            got: 99,
+            ..
+        })
+    ));
+    assert!(matches!(
+        assembler.receive(a, 0, info.len(), vec![7; METADATA_PIECE - 1]),
+        Err(AssemblyError::PieceLength { piece: 0, .. })
+    ));
+    assert!(matches!(
+        assembler.receive(a, 2, info.len(), vec![7; 100]),
+        Err(AssemblyError::OutOfRange { pieces: 2, .. })
+    ));
+    let e = assembler
+        .receive(a, 1, info.len() + 1, vec![7; 100])
+        .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "10.0.0.1:6881 says the metadata is 16485 bytes, but it is 16484"
+    );
+    assert_eq!(assembler.receive(a, 1, info.len(), vec![7; 100]), Ok(None));
+
+    // whatever was only requested goes back up for grabs
+    assembler.abandon(a);
+    assert_eq!(assembler.slots()[0], Slot::Missing);
+    assert!(matches!(assembler.slots()[1], Slot::Received { .. }));
+
+    assert!(MetadataAssembler::new(info_hash, 0).is_err());
+    assert!(MetadataAssembler::new(info_hash, METADATA_MAX + 1).is_err());
+}
+
+#[test]
+fn assembler_bans_the_sources_of_corrupt_metadata() {
+    let info = vec![7u8; 2 * METADATA_PIECE];
+    let mut assembler =
+        MetadataAssembler::new(InfoHash(Sha1::digest(&info).into()), info.len()).unwrap();
+    let (a, b, c) = (source(1), source(2), source(3));
+    let size = info.len();
+    assert_eq!(
+        assembler.receive(a, 0, size, info[..METADATA_PIECE].to_vec()),
+        Ok(None)
+    );
+    let mut corrupt = info[METADATA_PIECE..].to_vec();
+    corrupt[0] ^= 1;
+    assert_eq!(
+        assembler.receive(b, 1, size, corrupt),
+        Err(AssemblyError::HashMismatch { banned: vec![a, b] })
+    );
+
+    // everything is gone, and only an untainted peer gets to try again
+    assert!(assembler.slots().iter().all(|s| *s == Slot::Missing));
+    assert_eq!(assembler.next_request(a), None);
+    assert!(matches!(
+        assembler.receive(b, 0, size, info[..METADATA_PIECE].to_vec()),
+        Err(AssemblyError::Banned { .. })
+    ));
+    assert_eq!(assembler.next_request(c), Some(0));
+    assert_eq!(
+        assembler.receive(c, 1, size, info[METADATA_PIECE..].to_vec()),
+        Ok(None)
+    );
+    assert_eq!(
+        assembler.receive(c, 0, size, info[..METADATA_PIECE].to_vec()),
+        Ok(Some(info))
+    );
+}
+
+#[tokio::test]
+async fn fetching_moves_on_from_a_peer_with_bad_metadata() {
+    let info = crate::mock::data(2 * METADATA_PIECE + 5);
+    let info_hash = InfoHash(Sha1::digest(&info).into());
+    let mut bad = info.clone();
+    bad[METADATA_PIECE] ^= 0xff;
+
+    let mut peers = Vec::new();
+    for info in [bad, info.clone()] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        peers.push(listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, info_hash, info, Arc::default()));
+    }
+    assert!(fetch(peers[0], info_hash).await.is_err());
+    assert_eq!(fetch_any(&peers, info_hash).await.unwrap(), info);
+}
+
+#[tokio::test]
+async fn fetching_moves_on_from_stalled_peers() {
+    let info = crate::mock::data(METADATA_PIECE + 5);
+    let info_hash = InfoHash(Sha1::digest(&info).into());
+
+    // one that accepts connections (into its backlog), but never answers our handshake
+    let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    // and one that answers it, and then says nothing more
+    let mute = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut peers = vec![silent.local_addr().unwrap(), mute.local_addr().unwrap()];
+    tokio::spawn(async move {
+        let (mut stream, _) = mute.accept().await.unwrap();
+        Handshake::read(&mut stream).await.unwrap();
+        let mut handshake = Handshake::new(info_hash, *b"-MOCK00-000000000000");
+        handshake.reserved = handshake.reserved.with_ltep();
+        handshake.write(&mut stream).await.unwrap();
+        std::future::pending::<()>().await;
+    });
+    let good = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    peers.push(good.local_addr().unwrap());
+    tokio::spawn(serve(good, info_hash, info.clone(), Arc::default()));
+
+    let timeout = Duration::from_millis(200);
+    let fetch = fetch_any_within(&peers, info_hash, timeout, timeout);
+    let fetched = tokio::time::timeout(Duration::from_secs(5), fetch)
+        .await
+        .expect("stalled peers are given up on")
+        .unwrap();
+    assert_eq!(fetched, info);
+}
+
+#[tokio::test]
+async fn incoming_peers_are_kept_under_their_listen_address() {
+    let info = crate::mock::data(100);
+    let info_hash = InfoHash(Sha1::digest(&info).into());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pool = Arc::new(Mutex::new(PeerPool::default()));
+    tokio::spawn(serve(listener, info_hash, info, Arc::clone(&pool)));
+
+    // a scripted peer that listens on port 7000 and thinks we are 203.0.113.5
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let source = stream.local_addr().unwrap();
+    let mut handshake = Handshake::new(info_hash, *b"-SCRIPT-000000000000");
+    handshake.reserved = handshake.reserved.with_ltep();
+    handshake.write(&mut stream).await.unwrap();
+    Handshake::read(&mut stream).await.unwrap();
+    let mut stream = Framed::new(stream, MessageFramer::default());
+    let theirs = ExtendedHandshake {
+        m: BTreeMap::from([(String::from("ut_metadata"), 3)]),
+        p: Some(7000),
+        yourip: Some(vec![203, 0, 113, 5]),
+        ..Default::default()
+    };
+    stream
+        .send(extended(EXTENDED_HANDSHAKE, &theirs.to_bytes()))
+        .await
+        .unwrap();
+
+    // we tell it where we listen and where it is, too
+    let Message::Extended { id, payload } = stream.next().await.unwrap().unwrap() else {
+        panic!("not an extended message");
+    };
+    assert_eq!(id, EXTENDED_HANDSHAKE);
+    let ours = ExtendedHandshake::from_bytes(&payload).unwrap();
+    assert_eq!(ours.p, Some(addr.port()));
+    assert_eq!(ours.yourip(), Some(source.ip()));
+
+    let canonical = SocketAddr::new(source.ip(), 7000);
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while !pool.lock().unwrap().contains(canonical) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the handshake reaches the pool");
+    let pool = pool.lock().unwrap();
+    assert!(!pool.contains(source));
+    assert_eq!(pool.len(), 1);
+    // a single peer's word isn't enough to go on for our own address
+    assert_eq!(pool.external_ip(), None);
+}
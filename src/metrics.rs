@@ -0,0 +1,310 @@
+//! A Prometheus endpoint for keeping an eye on long-running sessions.
+//!
+//! Everything here is read straight out of each torrent's [`TransferStats`], the same counters we
+//! report to the tracker, so the two never disagree.
+
+use crate::torrent::Torrent;
+use crate::tracker::{Disconnect, TransferStats};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The torrents whose stats are exposed on the metrics endpoint.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    torrents: Mutex<Vec<Tracked>>,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    /// The `info_hash` and `name` labels, already escaped.
+    labels: String,
+    length: usize,
+    stats: Arc<TransferStats>,
+    /// Totals as of the previous scrape, which the rates are computed against.
+    last: (Instant, usize, usize),
+}
+
+impl Metrics {
+    pub fn track(&self, t: &Torrent, stats: Arc<TransferStats>) -> anyhow::Result<()> {
+        let labels = format!(
+            "info_hash=\"{}\",name=\"{}\"",
+            t.info_hash()?,
+            escape(&t.info.name)
+        );
+        let last = (Instant::now(), stats.uploaded(), stats.downloaded());
+        self.torrents.lock().unwrap().push(Tracked {
+            labels,
+            length: t.length(),
+            stats,
+            last,
+        });
+        Ok(())
+    }
+
+    /// The current value of every metric, in the Prometheus text exposition format.
+    ///
+    /// The upload and download rates are averaged over the time since the previous call.
+    pub fn render(&self) -> String {
+        let mut torrents = self.torrents.lock().unwrap();
+        let now = Instant::now();
+        let rates: Vec<_> = torrents
+            .iter_mut()
+            .map(|t| {
+                let (then, uploaded, downloaded) =
+                    std::mem::replace(&mut t.last, (now, t.stats.uploaded(), t.stats.downloaded()));
+                let secs = now.duration_since(then).as_secs_f64();
+                let rate = |before: usize, after: usize| {
+                    if secs > 0.0 {
+                        after.saturating_sub(before) as f64 / secs
+                    } else {
+                        0.0
+                    }
+                };
+                (rate(downloaded, t.last.2), rate(uploaded, t.last.1))
+            })
+            .collect();
+
+        let mut out = String::new();
+        let each = |f: fn(&Tracked) -> f64| torrents.iter().map(move |t| (t.labels.clone(), f(t)));
+
+        family(
+            &mut out,
+            "bittorrent_connected_peers",
+            "gauge",
+            "Peers we currently have a connection to.",
+            each(|t| t.stats.connected_peers() as f64),
+        );
+        family(
+            &mut out,
+            "bittorrent_download_rate_bytes",
+            "gauge",
+            "Verified bytes downloaded per second since the previous scrape.",
+            torrents
+                .iter()
+                .zip(&rates)
+                .map(|(t, &(down, _))| (t.labels.clone(), down)),
+        );
+        family(
+            &mut out,
+            "bittorrent_upload_rate_bytes",
+            "gauge",
+            "Bytes uploaded per second since the previous scrape.",
+            torrents
+                .iter()
+                .zip(&rates)
+                .map(|(t, &(_, up))| (t.labels.clone(), up)),
+        );
+        family(
+            &mut out,
+            "bittorrent_pieces_verified",
+            "gauge",
+            "Pieces that passed their hash check this session.",
+            each(|t| t.stats.pieces_verified() as f64),
+        );
+        family(
+            &mut out,
+            "bittorrent_completion_ratio",
+            "gauge",
+            "Fraction of the torrent we have verified data for.",
+            each(|t| {
+                if t.length == 0 {
+                    1.0
+                } else {
+                    (t.stats.downloaded() as f64 / t.length as f64).min(1.0)
+                }
+            }),
+        );
+        family(
+            &mut out,
+            "bittorrent_downloaded_bytes_total",
+            "counter",
+            "Verified bytes downloaded, including previous sessions.",
+            each(|t| t.stats.downloaded() as f64),
+        );
+        family(
+            &mut out,
+            "bittorrent_uploaded_bytes_total",
+            "counter",
+            "Bytes uploaded, including previous sessions.",
+            each(|t| t.stats.uploaded() as f64),
+        );
+        family(
+            &mut out,
+            "bittorrent_hash_failures_total",
+            "counter",
+            "Pieces whose data didn't match their hash.",
+            each(|t| t.stats.hash_failures() as f64),
+        );
+        family(
+            &mut out,
+            "bittorrent_announces_total",
+            "counter",
+            "Tracker announces, by whether they got a usable response.",
+            torrents.iter().flat_map(|t| {
+                let (ok, failed) = t.stats.announces();
+                [
+                    (format!("{},outcome=\"ok\"", t.labels), ok as f64),
+                    (format!("{},outcome=\"error\"", t.labels), failed as f64),
+                ]
+            }),
+        );
+        family(
+            &mut out,
+            "bittorrent_peer_disconnects_total",
+            "counter",
+            "Peer connections we gave up on, by reason.",
+            torrents.iter().flat_map(|t| {
+                Disconnect::ALL.into_iter().map(|reason| {
+                    (
+                        format!("{},reason=\"{}\"", t.labels, reason.as_str()),
+                        t.stats.disconnects(reason) as f64,
+                    )
+                })
+            }),
+        );
+        out
+    }
+
+    /// Answer scrapes of `/metrics` on `listener` until it fails.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let metrics = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = metrics.respond(stream).await {
+                    eprintln!("metrics scrape failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        // we only need the request line, but read the whole head so the client isn't cut off
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !head.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 || head.len() + n > 8192 {
+                return Ok(());
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let target = head.split(' ').nth(1).unwrap_or_default();
+        let (status, body) = if target == "/metrics" {
+            ("200 OK", self.render())
+        } else {
+            ("404 Not Found", String::new())
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+/// Write out one metric family, with a sample per `(labels, value)`.
+fn family(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, f64)>,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{name}{{{labels}}} {value}");
+    }
+}
+
+/// Escape a label value as the text format requires.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+async fn scrape(addr: std::net::SocketAddr) -> std::collections::HashMap<String, f64> {
+    let body = reqwest::get(format!("http://{addr}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    body.lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| {
+            let (series, value) = line.rsplit_once(' ').expect("sample has a value");
+            // drop the per-torrent labels, there's only the one torrent
+            let series = series
+                .split_once('{')
+                .map(|(name, labels)| {
+                    let rest: Vec<_> = labels
+                        .trim_end_matches('}')
+                        .split(',')
+                        .filter(|l| !l.starts_with("info_hash=") && !l.starts_with("name="))
+                        .collect();
+                    if rest.is_empty() {
+                        name.to_string()
+                    } else {
+                        format!("{name}{{{}}}", rest.join(","))
+                    }
+                })
+                .unwrap_or_else(|| series.to_string());
+            (series, value.parse().expect("sample value is a number"))
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn scrape_during_download() {
+    use crate::mock::{self, Behaviour};
+
+    let data = mock::data(2 * 32768 + 1000);
+    let (_tracker, _peers, t) = mock::swarm(&data, 32768, vec![Behaviour::default()]).await;
+
+    let stats = Arc::new(TransferStats::default());
+    let metrics = Arc::new(Metrics::default());
+    metrics.track(&t, Arc::clone(&stats)).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Arc::clone(&metrics).serve(listener));
+
+    let before = scrape(addr).await;
+    assert_eq!(before["bittorrent_completion_ratio"], 0.0);
+    assert_eq!(before["bittorrent_announces_total{outcome=\"ok\"}"], 0.0);
+
+    t.download(Arc::clone(&stats)).wait().await.unwrap();
+
+    let after = scrape(addr).await;
+    assert_eq!(after["bittorrent_completion_ratio"], 1.0);
+    assert_eq!(after["bittorrent_pieces_verified"], 3.0);
+    assert_eq!(
+        after["bittorrent_downloaded_bytes_total"],
+        data.len() as f64
+    );
+    assert!(after["bittorrent_download_rate_bytes"] > 0.0);
+    assert_eq!(after["bittorrent_connected_peers"], 0.0);
+    assert_eq!(after["bittorrent_hash_failures_total"], 0.0);
+    // started, and then completed
+    assert_eq!(after["bittorrent_announces_total{outcome=\"ok\"}"], 2.0);
+    assert_eq!(after["bittorrent_announces_total{outcome=\"error\"}"], 0.0);
+    assert_eq!(
+        after["bittorrent_peer_disconnects_total{reason=\"dial\"}"],
+        0.0
+    );
+}
+
+#[test]
+fn label_values_are_escaped() {
+    assert_eq!(escape(r#"a "b" \c"#), r#"a \"b\" \\c"#);
+    assert_eq!(escape("a\nb"), "a\\nb");
+}
@@ -0,0 +1,749 @@
+//! In-process stand-ins for the remote ends we talk to, for use in tests.
+
+use crate::peer::{Handshake, Message, MessageFramer};
+use crate::torrent::Torrent;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio_util::codec::Framed;
+
+/// A minimal HTTP tracker that records every request it receives.
+pub(crate) struct MockTracker {
+    addr: SocketAddr,
+    /// The target and the whole head of every request.
+    requests: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl MockTracker {
+    /// Answer with the given bodies in order, repeating the last one once they run out.
+    pub(crate) async fn serve(bodies: Vec<Vec<u8>>) -> Self {
+        Self::serve_responses(bodies.into_iter().map(MockResponse::ok).collect()).await
+    }
+
+    pub(crate) async fn serve_responses(responses: Vec<MockResponse>) -> Self {
+        Self::serve_with(responses, None).await
+    }
+
+    /// Like [`MockTracker::serve`], but turning away announces without an `Authorization` header
+    /// of exactly `authorization` with a 401.
+    pub(crate) async fn serve_private(authorization: &str, bodies: Vec<Vec<u8>>) -> Self {
+        let responses = bodies.into_iter().map(MockResponse::ok).collect();
+        Self::serve_with(responses, Some(authorization.to_string())).await
+    }
+
+    async fn serve_with(responses: Vec<MockResponse>, authorization: Option<String>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requests);
+        tokio::spawn(async move {
+            let mut responses = responses.into_iter().peekable();
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    break;
+                };
+                let response = if responses.len() > 1 {
+                    responses.next()
+                } else {
+                    responses.peek().cloned()
+                };
+                let response = response.unwrap_or_else(|| MockResponse::ok(Vec::new()));
+                respond(stream, response, authorization.as_deref(), &log).await;
+            }
+        });
+        Self { addr, requests }
+    }
+
+    pub(crate) fn announce_url(&self) -> String {
+        format!("http://{}/announce", self.addr)
+    }
+
+    /// The request targets (path and query string) received so far.
+    pub(crate) fn requests(&self) -> Vec<String> {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().map(|(target, _)| target.clone()).collect()
+    }
+
+    /// The header lines of the requests received so far, names lowercased.
+    pub(crate) fn headers(&self) -> Vec<Vec<(String, String)>> {
+        let requests = self.requests.lock().unwrap();
+        requests
+            .iter()
+            .map(|(_, head)| {
+                head.lines()
+                    .skip(1)
+                    .filter_map(|line| line.split_once(':'))
+                    .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string()))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// One canned HTTP response.
+#[derive(Debug, Clone)]
+pub(crate) struct MockResponse {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(&'static str, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl MockResponse {
+    pub(crate) fn ok(body: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    pub(crate) fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    response: MockResponse,
+    authorization: Option<&str>,
+    log: &Mutex<Vec<(String, String)>>,
+) {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => head.extend_from_slice(&buf[..n]),
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let target = head.split(' ').nth(1).unwrap_or_default().to_string();
+    log.lock().unwrap().push((target, head.to_string()));
+    let authorized = authorization.is_none_or(|expected| {
+        head.lines().any(|line| {
+            line.split_once(':').is_some_and(|(name, value)| {
+                name.eq_ignore_ascii_case("authorization") && value.trim() == expected
+            })
+        })
+    });
+    let response = if authorized {
+        response
+    } else {
+        MockResponse {
+            status: 401,
+            headers: vec![("WWW-Authenticate", "Basic realm=\"mock\"".into())],
+            body: Vec::new(),
+        }
+    };
+
+    let MockResponse {
+        status,
+        headers,
+        body,
+    } = response;
+    let mut head = format!(
+        "HTTP/1.1 {status} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    let mut response = head.into_bytes();
+    response.extend(body);
+    let _ = stream.write_all(&response).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Decode the query string of a recorded request target.
+pub(crate) fn query(target: &str) -> Vec<(String, String)> {
+    let query = target.split_once('?').map(|(_, q)| q).unwrap_or_default();
+    serde_urlencoded::from_str(query).unwrap()
+}
+
+/// A minimal UDP tracker (BEP 15) handing out the same peers to everyone, and recording every
+/// datagram it receives.
+pub(crate) struct MockUdpTracker {
+    addr: SocketAddr,
+    packets: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+/// How a [`MockUdpTracker`] answers.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UdpBehaviour {
+    /// Ignore this many datagrams before answering any.
+    pub(crate) drop_first: usize,
+    /// Answer announces with this error instead of peers.
+    pub(crate) refuse: Option<&'static str>,
+}
+
+/// The connection ID every [`MockUdpTracker`] hands out, and insists on.
+pub(crate) const UDP_CONNECTION_ID: u64 = 0x0123_4567_89ab_cdef;
+
+impl MockUdpTracker {
+    pub(crate) async fn serve(peers: &[SocketAddrV4], behaviour: UdpBehaviour) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&packets);
+        let mut compact = Vec::new();
+        for peer in peers {
+            compact.extend(peer.ip().octets());
+            compact.extend(peer.port().to_be_bytes());
+        }
+        let npeers = peers.len() as u32;
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let mut dropped = 0;
+            loop {
+                let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+                    break;
+                };
+                let packet = buf[..len].to_vec();
+                log.lock().unwrap().push(packet.clone());
+                if dropped < behaviour.drop_first {
+                    dropped += 1;
+                    continue;
+                }
+                let (Some(head), Some(transaction)) = (packet.get(..8), packet.get(12..16)) else {
+                    continue;
+                };
+                let action = &packet[8..12];
+                let mut reply = Vec::new();
+                if head == 0x41727101980u64.to_be_bytes() && action == [0, 0, 0, 0] {
+                    reply.extend(0u32.to_be_bytes());
+                    reply.extend(transaction);
+                    reply.extend(UDP_CONNECTION_ID.to_be_bytes());
+                } else if head != UDP_CONNECTION_ID.to_be_bytes() {
+                    reply.extend(3u32.to_be_bytes());
+                    reply.extend(transaction);
+                    reply.extend(b"unknown connection id");
+                } else if let Some(reason) = behaviour.refuse {
+                    reply.extend(3u32.to_be_bytes());
+                    reply.extend(transaction);
+                    reply.extend(reason.as_bytes());
+                } else {
+                    reply.extend(1u32.to_be_bytes());
+                    reply.extend(transaction);
+                    reply.extend(1800u32.to_be_bytes());
+                    reply.extend(0u32.to_be_bytes());
+                    reply.extend(npeers.to_be_bytes());
+                    reply.extend(&compact);
+                }
+                let _ = socket.send_to(&reply, from).await;
+            }
+        });
+        Self { addr, packets }
+    }
+
+    pub(crate) fn announce_url(&self) -> String {
+        format!("udp://{}/announce", self.addr)
+    }
+
+    /// Every datagram received so far, dropped or not.
+    pub(crate) fn packets(&self) -> Vec<Vec<u8>> {
+        self.packets.lock().unwrap().clone()
+    }
+
+    /// The announces received so far, and not the connect requests.
+    pub(crate) fn announces(&self) -> Vec<Vec<u8>> {
+        self.packets()
+            .into_iter()
+            .filter(|packet| packet.get(8..12) == Some(&[0, 0, 0, 1]))
+            .collect()
+    }
+}
+
+/// A minimal WebSocket tracker, answering every announce with a ping and then `messages`, and
+/// recording the announces it receives.
+pub(crate) struct MockWsTracker {
+    addr: SocketAddr,
+    secure: bool,
+    announces: Arc<Mutex<Vec<serde_json::Value>>>,
+}
+
+impl MockWsTracker {
+    pub(crate) async fn serve(messages: Vec<serde_json::Value>) -> Self {
+        Self::serve_with(messages, None).await
+    }
+
+    /// Like [`MockWsTracker::serve`], but over TLS with a certificate for 127.0.0.1 that
+    /// `tests/fixtures/tls/ca.pem` vouches for.
+    pub(crate) async fn serve_tls(messages: Vec<serde_json::Value>) -> Self {
+        let identity = native_tls::Identity::from_pkcs8(
+            include_bytes!("../tests/fixtures/tls/tracker.pem"),
+            include_bytes!("../tests/fixtures/tls/tracker.key"),
+        )
+        .unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        Self::serve_with(messages, Some(acceptor.into())).await
+    }
+
+    async fn serve_with(
+        messages: Vec<serde_json::Value>,
+        tls: Option<tokio_native_tls::TlsAcceptor>,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let secure = tls.is_some();
+        let announces = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&announces);
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    break;
+                };
+                match &tls {
+                    Some(tls) => {
+                        let Ok(stream) = tls.accept(stream).await else {
+                            continue;
+                        };
+                        answer_ws(stream, &messages, &log).await;
+                    }
+                    None => answer_ws(stream, &messages, &log).await,
+                }
+            }
+        });
+        Self {
+            addr,
+            secure,
+            announces,
+        }
+    }
+
+    pub(crate) fn announce_url(&self) -> String {
+        let scheme = if self.secure { "wss" } else { "ws" };
+        format!("{scheme}://{}/announce", self.addr)
+    }
+
+    /// The announce messages received so far.
+    pub(crate) fn announces(&self) -> Vec<serde_json::Value> {
+        self.announces.lock().unwrap().clone()
+    }
+}
+
+async fn answer_ws<S>(
+    stream: S,
+    messages: &[serde_json::Value],
+    log: &Mutex<Vec<serde_json::Value>>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio_tungstenite::tungstenite::Message;
+
+    let Ok(mut socket) = tokio_tungstenite::accept_async(stream).await else {
+        return;
+    };
+    let Some(Ok(Message::Text(announce))) = socket.next().await else {
+        return;
+    };
+    log.lock()
+        .unwrap()
+        .push(serde_json::from_str(&announce).unwrap());
+    let _ = socket.send(Message::Ping(b"hi".to_vec())).await;
+    for message in messages {
+        let _ = socket.send(Message::text(message.to_string())).await;
+    }
+    // the pong, and whatever else the client sends before it goes
+    while let Some(Ok(_)) = socket.next().await {}
+}
+
+/// A minimal SOCKS5 proxy (RFC 1928, no authentication) that takes every connection to `upstream`
+/// whatever it was asked for, and records what that was.
+pub(crate) struct MockSocks5 {
+    addr: SocketAddr,
+    /// The `host:port` of every CONNECT, as asked.
+    targets: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockSocks5 {
+    pub(crate) async fn serve(upstream: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&targets);
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    break;
+                };
+                let log = Arc::clone(&log);
+                tokio::spawn(async move {
+                    let Ok(target) = socks5_handshake(&mut stream).await else {
+                        return;
+                    };
+                    log.lock().unwrap().push(target);
+                    let Ok(mut upstream) = TcpStream::connect(upstream).await else {
+                        return;
+                    };
+                    let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+                });
+            }
+        });
+        Self { addr, targets }
+    }
+
+    pub(crate) fn url(&self, scheme: &str) -> String {
+        format!("{scheme}://{}", self.addr)
+    }
+
+    pub(crate) fn targets(&self) -> Vec<String> {
+        self.targets.lock().unwrap().clone()
+    }
+}
+
+/// Agree on no authentication, take a CONNECT request and grant it, handing back its target.
+async fn socks5_handshake(stream: &mut TcpStream) -> std::io::Result<String> {
+    let [_version, nmethods] = [stream.read_u8().await?, stream.read_u8().await?];
+    stream.read_exact(&mut vec![0; nmethods.into()]).await?;
+    stream.write_all(&[5, 0]).await?;
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut name = vec![0; stream.read_u8().await?.into()];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+        _ => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            format!("[{}]", std::net::Ipv6Addr::from(ip))
+        }
+    };
+    let port = stream.read_u16().await?;
+    // granted, bound to 0.0.0.0:0
+    stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    Ok(format!("{host}:{port}"))
+}
+
+/// A compact-model announce response listing `peers`.
+pub(crate) fn peers_response(peers: &[SocketAddrV4]) -> Vec<u8> {
+    let mut compact = Vec::new();
+    for peer in peers {
+        compact.extend(peer.ip().octets());
+        compact.extend(peer.port().to_be_bytes());
+    }
+    let mut body = format!("d8:intervali1800e5:peers{}:", compact.len()).into_bytes();
+    body.extend(compact);
+    body.push(b'e');
+    body
+}
+
+/// A single-file torrent with two pieces announcing to `announce`.
+pub(crate) fn torrent(announce: &str) -> Torrent {
+    let mut bytes = format!(
+        "d8:announce{}:{announce}4:infod6:lengthi40000e4:name8:mock.txt12:piece lengthi32768e6:pieces40:",
+        announce.len()
+    )
+    .into_bytes();
+    bytes.extend([1; 20]);
+    bytes.extend([2; 20]);
+    bytes.extend(b"ee");
+    Torrent::from_bytes(&bytes).unwrap()
+}
+
+/// A torrent whose pieces really are the SHA-1 hashes of `data`.
+pub(crate) fn torrent_for(announce: &str, data: &[u8], plength: usize) -> Torrent {
+    let mut bytes = format!(
+        "d8:announce{}:{announce}4:infod6:lengthi{}e4:name8:mock.bin12:piece lengthi{plength}e6:pieces{}:",
+        announce.len(),
+        data.len(),
+        data.len().div_ceil(plength) * 20
+    )
+    .into_bytes();
+    for piece in data.chunks(plength) {
+        bytes.extend(Sha1::digest(piece));
+    }
+    bytes.extend(b"ee");
+    Torrent::from_bytes(&bytes).unwrap()
+}
+
+/// A [`MockPeer`] seeding `data` for each of `behaviours`, a tracker that hands them all out, and
+/// a torrent of `data` in `plength` pieces that announces to it.
+pub(crate) async fn swarm(
+    data: &[u8],
+    plength: usize,
+    behaviours: Vec<Behaviour>,
+) -> (MockTracker, Vec<MockPeer>, Torrent) {
+    // the pieces only depend on the data, so the announce URL can be patched in afterwards
+    let t = torrent_for("http://unused/announce", data, plength);
+    let mut peers = Vec::new();
+    for behaviour in behaviours {
+        peers.push(MockPeer::serve(&t, data.to_vec(), behaviour).await);
+    }
+    let addrs: Vec<_> = peers.iter().map(MockPeer::addr).collect();
+    let tracker = MockTracker::serve(vec![peers_response(&addrs)]).await;
+    let t = torrent_for(&tracker.announce_url(), data, plength);
+    (tracker, peers, t)
+}
+
+/// How a [`MockPeer`] deviates from the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Lie {
+    /// Answer requests with the wrong piece index.
+    Index,
+    /// Answer requests with the wrong block offset.
+    Begin,
+    /// Answer requests with one byte too few.
+    Length,
+}
+
+/// The script a [`MockPeer`] follows.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Behaviour {
+    /// Lie like this in answer to the first `lies` requests.
+    pub(crate) lie: Option<Lie>,
+    pub(crate) lies: usize,
+    /// Accept requests, but never answer them.
+    pub(crate) stall: bool,
+    /// Send data that doesn't match the piece hashes.
+    pub(crate) corrupt: bool,
+    /// Wait this long before answering each request.
+    pub(crate) delay: Duration,
+    /// Choke us once after answering this many requests, dropping whatever we request until
+    /// unchoking again [`REUNCHOKE`] later. A peer that [lacks](Behaviour::lacks) a piece
+    /// announces it while we're choked.
+    pub(crate) choke_after: Option<usize>,
+    /// Send a keep-alive before every block.
+    pub(crate) keep_alives: bool,
+    /// Open with an extended handshake ahead of the bitfield, and send a message of a type we
+    /// don't implement (BEP 6's Suggest Piece) before every block.
+    pub(crate) chatter: bool,
+    /// Never unchoke us, however interested we say we are.
+    pub(crate) never_unchoke: bool,
+    /// Leave this piece out of the bitfield.
+    pub(crate) lacks: Option<usize>,
+    /// Answer requests in pairs, the second of each pair first, with an odd one out answered
+    /// once [`HOLD`] goes by without another request.
+    pub(crate) reorder: bool,
+}
+
+/// How long a [`Behaviour::choke_after`] peer stays choked.
+pub(crate) const REUNCHOKE: Duration = Duration::from_millis(50);
+
+/// How long a [`Behaviour::reorder`] peer holds on to an answer, waiting for another request.
+pub(crate) const HOLD: Duration = Duration::from_millis(20);
+
+/// A seeder holding all of `data`, following a configurable script.
+pub(crate) struct MockPeer {
+    addr: SocketAddr,
+    connections: Arc<AtomicUsize>,
+    seen: Arc<Seen>,
+}
+
+/// What a [`MockPeer`] got from the other end, over all connections.
+#[derive(Debug, Default)]
+struct Seen {
+    requests: AtomicUsize,
+    /// Every Bitfield and Have message, along with the number of the connection it came over.
+    announcements: Mutex<Vec<(usize, Message)>>,
+    /// Every Cancel message, over all connections.
+    cancels: Mutex<Vec<Message>>,
+}
+
+impl MockPeer {
+    pub(crate) async fn serve(t: &Torrent, data: Vec<u8>, behaviour: Behaviour) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let t = t.clone();
+        let data = Arc::new(data);
+        let connections = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::new(Seen::default());
+        tokio::spawn({
+            let connections = Arc::clone(&connections);
+            let seen = Arc::clone(&seen);
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let connection = connections.fetch_add(1, Ordering::SeqCst);
+                    let t = t.clone();
+                    let data = Arc::clone(&data);
+                    let behaviour = behaviour.clone();
+                    let seen = Arc::clone(&seen);
+                    tokio::spawn(async move {
+                        let _ = seed(stream, &t, &data, behaviour, &seen, connection).await;
+                    });
+                }
+            }
+        });
+        Self {
+            addr,
+            connections,
+            seen,
+        }
+    }
+
+    /// How many connections the peer has accepted so far.
+    pub(crate) fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// How many requests the peer has received so far, over all connections.
+    pub(crate) fn requests(&self) -> usize {
+        self.seen.requests.load(Ordering::SeqCst)
+    }
+
+    /// The Bitfield and Have messages received so far, each with the number of the connection
+    /// (counting from 0) it came over.
+    pub(crate) fn announcements(&self) -> Vec<(usize, Message)> {
+        self.seen.announcements.lock().unwrap().clone()
+    }
+
+    /// The Cancel messages received so far, over all connections.
+    pub(crate) fn cancels(&self) -> Vec<Message> {
+        self.seen.cancels.lock().unwrap().clone()
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddrV4 {
+        match self.addr {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!("bound to 127.0.0.1"),
+        }
+    }
+}
+
+async fn seed(
+    mut stream: TcpStream,
+    t: &Torrent,
+    data: &[u8],
+    mut behaviour: Behaviour,
+    seen: &Seen,
+    connection: usize,
+) -> anyhow::Result<()> {
+    let info_hash = t.info_hash()?;
+    let npieces = t.num_pieces();
+    let plength = t.info.plength;
+    let requests = &seen.requests;
+    let handshake = Handshake::read(&mut stream).await?;
+    anyhow::ensure!(handshake.info_hash == info_hash, "wrong info hash");
+    Handshake::new(info_hash, *b"-MOCK00-000000000000")
+        .write(&mut stream)
+        .await?;
+
+    let mut stream = Framed::new(stream, MessageFramer::default());
+    let mut bitfield = vec![0u8; npieces.div_ceil(8)];
+    for piece_i in (0..npieces).filter(|&i| Some(i) != behaviour.lacks) {
+        bitfield[piece_i / 8] |= 1u8.rotate_right(piece_i as u32 % 8 + 1);
+    }
+    if behaviour.chatter {
+        stream
+            .send(Message::Extended {
+                id: 0,
+                payload: Bytes::from_static(b"d1:md11:ut_metadatai1eee"),
+            })
+            .await?;
+    }
+    stream.send(Message::Bitfield(bitfield.into())).await?;
+
+    let mut choking = true;
+    let mut answered = 0;
+    let mut choked_until = None;
+    let mut held = None;
+    loop {
+        let msg = tokio::select! {
+            msg = stream.next() => match msg {
+                Some(msg) => msg?,
+                None => break,
+            },
+            _ = tokio::time::sleep_until(choked_until.unwrap_or_else(tokio::time::Instant::now)),
+                if choked_until.is_some() =>
+            {
+                choked_until = None;
+                stream.send(Message::Unchoke).await?;
+                continue;
+            }
+            _ = tokio::time::sleep(HOLD), if held.is_some() => {
+                stream.send(held.take().expect("only while holding")).await?;
+                continue;
+            }
+        };
+        match msg {
+            Message::Interested if !behaviour.never_unchoke && std::mem::take(&mut choking) => {
+                stream.send(Message::Unchoke).await?;
+            }
+            Message::Request { .. } if behaviour.stall || choked_until.is_some() => {
+                // a choked peer's requests are dropped, as BEP 3 allows
+                requests.fetch_add(1, Ordering::SeqCst);
+            }
+            Message::Request {
+                mut index,
+                mut begin,
+                length,
+            } => {
+                requests.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(behaviour.delay).await;
+                let length = length as usize;
+                let start = index as usize * plength + begin as usize;
+                let mut block = &data[start..start + length];
+                if behaviour.lies > 0 {
+                    behaviour.lies -= 1;
+                    match behaviour.lie {
+                        Some(Lie::Index) => index += 1,
+                        Some(Lie::Begin) => begin += 1,
+                        Some(Lie::Length) => block = &block[..length - 1],
+                        None => {}
+                    }
+                }
+                let mut block = block.to_vec();
+                if behaviour.corrupt {
+                    *block.last_mut().unwrap() ^= 0xff;
+                }
+                if behaviour.keep_alives {
+                    stream.send(Message::KeepAlive).await?;
+                }
+                if behaviour.chatter {
+                    stream
+                        .send(Message::Unknown {
+                            tag: 13,
+                            payload: Bytes::copy_from_slice(&index.to_be_bytes()),
+                        })
+                        .await?;
+                }
+                let piece = Message::Piece {
+                    index,
+                    begin,
+                    block: block.into(),
+                };
+                match held.take() {
+                    Some(earlier) => {
+                        stream.send(piece).await?;
+                        stream.send(earlier).await?;
+                    }
+                    None if behaviour.reorder => held = Some(piece),
+                    None => stream.send(piece).await?,
+                }
+                answered += 1;
+                if behaviour.choke_after == Some(answered) {
+                    stream.send(Message::Choke).await?;
+                    if let Some(lacked) = behaviour.lacks {
+                        stream.send(Message::Have(lacked as u32)).await?;
+                    }
+                    choked_until = Some(tokio::time::Instant::now() + REUNCHOKE);
+                }
+            }
+            Message::Bitfield(_) | Message::Have(_) => {
+                seen.announcements.lock().unwrap().push((connection, msg));
+            }
+            Message::Cancel { .. } => seen.cancels.lock().unwrap().push(msg),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Some deterministic, non-repeating bytes to download.
+pub(crate) fn data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
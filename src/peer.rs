@@ -1,60 +1,508 @@
+use crate::progress::PieceMap;
+use crate::resolve::{self, Prefer};
+use crate::throttle::Throttle;
+use crate::torrent::{InfoHash, Torrent};
 use crate::BLOCK_MAX;
 use anyhow::Context;
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures_util::{SinkExt, StreamExt};
-use std::net::SocketAddrV4;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio_util::codec::Decoder;
 use tokio_util::codec::Encoder;
-use tokio_util::codec::Framed;
+use tokio_util::codec::{FramedRead, FramedWrite};
 
 // TODO: ideally, Peer should keep track of what pieces we have downloaded (and references to them)
 // so that we can respond to Requests from the other side. also, choking/unchoking the other side.
-pub(crate) struct Peer {
-    addr: SocketAddrV4,
-    stream: Framed<TcpStream, MessageFramer>,
+pub struct Peer {
+    conn: PeerConnection,
     bitfield: Bitfield,
     choked: bool,
+    /// Whether we last told the peer we are interested.
+    interested: bool,
+    violations: Violations,
+    timings: Option<RequestTimings>,
+    /// Set while the download this connection belongs to is paused.
+    pause: Option<watch::Receiver<bool>>,
+    /// The rate limit of the download this connection belongs to.
+    throttle: Option<Arc<Throttle>>,
+    /// How long we wait on a peer that keeps us choked.
+    unchoke_timeout: Duration,
+    /// How many block requests we keep outstanding at once.
+    pipeline: usize,
 }
 
-impl Peer {
-    pub async fn new(peer_addr: SocketAddrV4, info_hash: [u8; 20]) -> anyhow::Result<Self> {
-        let mut peer = tokio::net::TcpStream::connect(peer_addr)
-            .await
-            .context("connect to peer")?;
-        let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-        {
-            let handshake_bytes = handshake.as_bytes_mut();
-            peer.write_all(handshake_bytes)
-                .await
-                .context("write handshake")?;
-            peer.read_exact(handshake_bytes)
+/// How long a peer gets to accept our connection and answer our handshake.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a peer gets after the handshake to send its first message, which is usually its
+/// bitfield.
+pub const OPENING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long we let a connection go without sending anything before sending a keep-alive.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// How long a peer may go without sending anything, keep-alives included, before we give up on
+/// it; peers are meant to send a keep-alive every two minutes.
+pub const READ_IDLE_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
+/// How long we wait by default for a peer that keeps us choked to unchoke us, before or in the
+/// middle of a piece.
+pub const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A connection to a peer that has answered our handshake, carrying whole messages from there on.
+///
+/// While it's open, a task of its own sends a keep-alive whenever nothing else has gone out for
+/// [`KEEP_ALIVE_INTERVAL`], and reading gives up on a peer that has been silent for
+/// [`READ_IDLE_TIMEOUT`].
+pub struct PeerConnection {
+    addr: SocketAddr,
+    reader: FramedRead<OwnedReadHalf, MessageFramer>,
+    writer: Arc<tokio::sync::Mutex<Writer>>,
+    keep_alive: JoinHandle<()>,
+    read_idle: Duration,
+    /// The handshake the peer answered ours with.
+    handshake: Handshake,
+}
+
+/// The sending side of a [`PeerConnection`], shared with its keep-alive task.
+struct Writer {
+    sink: FramedWrite<OwnedWriteHalf, MessageFramer>,
+    last_write: Instant,
+}
+
+impl PeerConnection {
+    /// Connect to `addr` and exchange handshakes about `info_hash`, going by `peer_id`, all
+    /// within `timeout`.
+    pub async fn connect(
+        addr: SocketAddr,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        let connect = async {
+            let stream = resolve::tcp_connect(addr)
                 .await
-                .context("read handshake")?;
+                .context("connect to peer")?;
+            Self::handshake(stream, addr, info_hash, peer_id).await
+        };
+        tokio::time::timeout(timeout, connect)
+            .await
+            .with_context(|| format!("{addr} didn't answer within {timeout:?}"))?
+    }
+
+    /// Like [`PeerConnection::connect`], but for a `host:port` that may need resolving first.
+    pub async fn connect_host(
+        host: &str,
+        prefer: Prefer,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        let connect = async {
+            let (stream, addr) = resolve::connect(host, prefer).await?;
+            Self::handshake(stream, addr, info_hash, peer_id).await
+        };
+        tokio::time::timeout(timeout, connect)
+            .await
+            .with_context(|| format!("{host} didn't answer within {timeout:?}"))?
+    }
+
+    async fn handshake(
+        mut stream: TcpStream,
+        addr: SocketAddr,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+    ) -> anyhow::Result<Self> {
+        Handshake::new(info_hash, peer_id.0)
+            .write(&mut stream)
+            .await?;
+        let handshake = Handshake::read(&mut stream).await?;
+        handshake.check(addr, info_hash, None)?;
+        let (reader, writer) = stream.into_split();
+        let writer = Arc::new(tokio::sync::Mutex::new(Writer {
+            sink: FramedWrite::new(writer, MessageFramer::default()),
+            last_write: Instant::now(),
+        }));
+        Ok(Self {
+            addr,
+            reader: FramedRead::new(reader, MessageFramer::default()),
+            keep_alive: Self::keep_alive(&writer, KEEP_ALIVE_INTERVAL),
+            writer,
+            read_idle: READ_IDLE_TIMEOUT,
+            handshake,
+        })
+    }
+
+    /// Send a keep-alive over `writer` whenever nothing has gone out for `interval`, until the
+    /// connection fails or is dropped.
+    fn keep_alive(writer: &Arc<tokio::sync::Mutex<Writer>>, interval: Duration) -> JoinHandle<()> {
+        let writer = Arc::downgrade(writer);
+        tokio::spawn(async move {
+            let mut due = Instant::now() + interval;
+            loop {
+                tokio::time::sleep_until(due.into()).await;
+                let Some(writer) = writer.upgrade() else {
+                    return;
+                };
+                let mut writer = writer.lock().await;
+                if writer.last_write.elapsed() >= interval {
+                    if writer.sink.send(Message::KeepAlive).await.is_err() {
+                        return;
+                    }
+                    writer.last_write = Instant::now();
+                }
+                due = writer.last_write + interval;
+            }
+        })
+    }
+
+    /// Send keep-alives after `keep_alive` instead of [`KEEP_ALIVE_INTERVAL`], and give up on
+    /// the peer after `read_idle` instead of [`READ_IDLE_TIMEOUT`].
+    #[cfg(test)]
+    pub(crate) fn idle_times(&mut self, keep_alive: Duration, read_idle: Duration) {
+        self.keep_alive.abort();
+        self.keep_alive = Self::keep_alive(&self.writer, keep_alive);
+        self.read_idle = read_idle;
+    }
+
+    /// The address we ended up connected to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The id the peer gave in its handshake.
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.handshake.peer_id
+    }
+
+    /// Check that the peer goes by `peer_id`, if we know which id it should go by.
+    pub fn check_peer_id(&self, peer_id: Option<[u8; 20]>) -> Result<(), PeerError> {
+        let theirs = &self.handshake;
+        theirs.check(self.addr, theirs.info_hash, peer_id)
+    }
+
+    /// The extensions the peer's handshake announced.
+    pub fn reserved(&self) -> Reserved {
+        self.handshake.reserved
+    }
+
+    pub async fn send(&mut self, msg: Message) -> std::io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        writer.sink.send(msg).await?;
+        writer.last_write = Instant::now();
+        Ok(())
+    }
+
+    /// Tell the peer we no longer want the block of `length` bytes at `begin` in piece `index`.
+    pub async fn cancel(&mut self, index: u32, begin: u32, length: u32) -> std::io::Result<()> {
+        self.send(Message::Cancel {
+            index,
+            begin,
+            length,
+        })
+        .await
+    }
+
+    /// Start keeping track of the requests for piece `index` we send over this connection.
+    fn in_flight(&self, index: u32) -> InFlight {
+        InFlight {
+            index,
+            writer: Arc::downgrade(&self.writer),
+            requests: VecDeque::new(),
         }
-        anyhow::ensure!(handshake.length == 19);
-        anyhow::ensure!(&handshake.bittorrent == b"BitTorrent protocol");
-        let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-        let bitfield = peer
-            .next()
+    }
+
+    /// The next message from the peer, or `None` once it hangs up.
+    pub async fn recv(&mut self) -> anyhow::Result<Option<Message>> {
+        self.next()
+            .await
+            .transpose()
+            .context("peer message was invalid")
+    }
+
+    /// Like [`PeerConnection::recv`], as a stream would have it: a peer that stays silent for too
+    /// long is an error of kind [`std::io::ErrorKind::TimedOut`].
+    ///
+    /// Messages of types we don't know are passed over here, so nothing past this sees them.
+    async fn next(&mut self) -> Option<std::io::Result<Message>> {
+        loop {
+            match tokio::time::timeout(self.read_idle, self.reader.next()).await {
+                Ok(Some(Ok(Message::Unknown { .. }))) => continue,
+                Ok(msg) => return msg,
+                Err(_) => {
+                    return Some(Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("{} sent nothing for {:?}", self.addr, self.read_idle),
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for the peer's first message, passing over keep-alives and the
+    /// extended handshake some peers send ahead of their bitfield, and work out from it which of
+    /// a torrent of `npieces` pieces the peer has.
+    ///
+    /// A peer that opens with anything but a bitfield (or, with the Fast Extension, a Have All or
+    /// Have None) has nothing yet, and that first message is handed back to be dealt with.
+    pub async fn wait_bitfield(
+        &mut self,
+        npieces: usize,
+        timeout: Duration,
+    ) -> anyhow::Result<(Bitfield, Option<Message>)> {
+        let first = async {
+            loop {
+                match self.recv().await? {
+                    Some(Message::KeepAlive | Message::Extended { .. }) => continue,
+                    Some(msg) => return Ok(msg),
+                    None => {
+                        anyhow::bail!("peer closed the connection instead of sending a bitfield")
+                    }
+                }
+            }
+        };
+        let first = tokio::time::timeout(timeout, first)
             .await
-            .expect("peer always sends a bitfields")
-            .context("peer message was invalid")?;
-        anyhow::ensure!(bitfield.tag == MessageTag::Bitfield);
+            .with_context(|| format!("{} sent nothing within {timeout:?}", self.addr))??;
+        opening(self.addr, first, npieces)
+    }
+
+    /// Tell the peer we're interested in what it has.
+    pub async fn interested(&mut self) -> std::io::Result<()> {
+        self.send(Message::Interested).await
+    }
+
+    /// Wait for the peer to unchoke us, keeping `bitfield` up to date with any pieces it says it
+    /// got in the meantime.
+    pub async fn wait_unchoke(&mut self, bitfield: &mut Bitfield) -> anyhow::Result<()> {
+        loop {
+            match self.recv().await? {
+                Some(Message::Unchoke) => return Ok(()),
+                Some(Message::Have(index)) => bitfield.saw_have(index),
+                Some(_) => {}
+                None => anyhow::bail!("peer closed the connection while choking us"),
+            }
+        }
+    }
+}
+
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        self.keep_alive.abort();
+    }
+}
+
+/// The block requests for piece `index` that are out over a connection, oldest first: the block,
+/// where it starts, its length, and when we asked for it.
+///
+/// Whatever is still unanswered when this goes away, because the piece was finished with other
+/// peers' blocks or the download stopped, is cancelled, so that the peer doesn't go on sending
+/// blocks nobody wants.
+struct InFlight {
+    index: u32,
+    writer: Weak<tokio::sync::Mutex<Writer>>,
+    requests: VecDeque<(usize, u32, usize, Instant)>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.requests.is_empty() {
+            return;
+        }
+        let (Some(writer), Ok(runtime)) =
+            (self.writer.upgrade(), tokio::runtime::Handle::try_current())
+        else {
+            return;
+        };
+        let cancels: Vec<_> = self
+            .requests
+            .drain(..)
+            .map(|(_, begin, length, _)| Message::Cancel {
+                index: self.index,
+                begin,
+                length: length as u32,
+            })
+            .collect();
+        // a drop can't wait for them to be written, so they go out on their own
+        runtime.spawn(async move {
+            let mut writer = writer.lock().await;
+            for cancel in cancels {
+                if writer.sink.send(cancel).await.is_err() {
+                    return;
+                }
+            }
+            writer.last_write = Instant::now();
+        });
+    }
+}
+
+/// Per-request measurements, for benchmarking a connection.
+#[derive(Debug, Default)]
+pub(crate) struct RequestTimings {
+    /// Time from sending each request to receiving its block.
+    pub(crate) rtts: Vec<Duration>,
+    /// The number of requests outstanding right after sending each request.
+    pub(crate) in_flight: Vec<usize>,
+}
+
+impl Peer {
+    /// Connect to a peer of the torrent with `info_hash` and `npieces` pieces.
+    pub async fn new(
+        peer_addr: SocketAddr,
+        info_hash: InfoHash,
+        npieces: usize,
+    ) -> anyhow::Result<Self> {
+        Self::expecting(peer_addr, info_hash, npieces, None, PeerIdCheck::Refuse).await
+    }
+
+    /// Like [`Peer::new`], but for a peer the tracker said goes by `peer_id`, which its
+    /// handshake is held to as `check` says.
+    pub async fn expecting(
+        peer_addr: SocketAddr,
+        info_hash: InfoHash,
+        npieces: usize,
+        peer_id: Option<[u8; 20]>,
+        check: PeerIdCheck,
+    ) -> anyhow::Result<Self> {
+        let conn =
+            PeerConnection::connect(peer_addr, info_hash, PeerId::ours(), CONNECT_TIMEOUT).await?;
+        match conn.check_peer_id(peer_id) {
+            Err(e @ PeerError::PeerIdMismatch { .. }) if check == PeerIdCheck::Warn => {
+                eprintln!("warning: {e}");
+            }
+            checked => checked?,
+        }
+        Self::opened(conn, npieces).await
+    }
+
+    /// Like [`Peer::new`], but for a `host:port` that may need resolving first.
+    pub async fn connect(
+        host: &str,
+        prefer: Prefer,
+        info_hash: InfoHash,
+        npieces: usize,
+    ) -> anyhow::Result<Self> {
+        let conn =
+            PeerConnection::connect_host(host, prefer, info_hash, PeerId::ours(), CONNECT_TIMEOUT)
+                .await?;
+        Self::opened(conn, npieces).await
+    }
+
+    /// Take over `conn` once the peer has said what it has.
+    async fn opened(mut conn: PeerConnection, npieces: usize) -> anyhow::Result<Self> {
+        let (mut bitfield, first) = conn.wait_bitfield(npieces, OPENING_TIMEOUT).await?;
+        let mut choked = true;
+        match first {
+            Some(Message::Unchoke) => choked = false,
+            Some(Message::Have(index)) => bitfield.saw_have(index),
+            // nothing else changes what we know about the peer
+            _ => {}
+        }
 
         Ok(Self {
-            addr: peer_addr,
-            stream: peer,
-            bitfield: Bitfield::from_payload(bitfield.payload),
-            choked: true,
+            conn,
+            bitfield,
+            choked,
+            interested: false,
+            violations: Violations::default(),
+            timings: None,
+            pause: None,
+            throttle: None,
+            unchoke_timeout: UNCHOKE_TIMEOUT,
+            pipeline: PIPELINE,
         })
     }
 
-    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
+    /// Keep up to `pipeline` block requests outstanding, rather than [`PIPELINE`].
+    pub fn set_pipeline(&mut self, pipeline: usize) {
+        self.pipeline = pipeline.max(1);
+    }
+
+    /// Give up on a peer that keeps us choked for `timeout`, rather than after
+    /// [`UNCHOKE_TIMEOUT`].
+    pub fn set_unchoke_timeout(&mut self, timeout: Duration) {
+        self.unchoke_timeout = timeout;
+    }
+
+    /// Stop requesting blocks whenever `paused` is set, until it is cleared again.
+    pub(crate) fn follow_pause(&mut self, paused: watch::Receiver<bool>) {
+        self.pause = Some(paused);
+    }
+
+    /// Only request blocks as fast as `throttle` lets us.
+    pub(crate) fn follow_throttle(&mut self, throttle: Arc<Throttle>) {
+        self.throttle = Some(throttle);
+    }
+
+    /// Tell the peer whether we want anything from it, unless it already knows.
+    async fn set_interested(&mut self, interested: bool) -> std::io::Result<()> {
+        if self.interested == interested {
+            return Ok(());
+        }
+        let msg = if interested {
+            Message::Interested
+        } else {
+            Message::NotInterested
+        };
+        self.conn.send(msg).await?;
+        self.interested = interested;
+        Ok(())
+    }
+
+    /// Tell the peer which pieces we have, as the first thing after the handshake. Having nothing,
+    /// we say nothing, as BEP 3 allows.
+    pub(crate) async fn send_bitfield(&mut self, have: &PieceMap) -> std::io::Result<()> {
+        if have.done() == 0 {
+            return Ok(());
+        }
+        self.conn
+            .send(Message::Bitfield(have.bitfield().into()))
+            .await
+    }
+
+    /// Tell the peer we just got piece `index`.
+    pub(crate) async fn send_have(&mut self, index: usize) -> std::io::Result<()> {
+        self.conn.send(Message::Have(index as u32)).await
+    }
+
+    /// Start keeping [`RequestTimings`] for this connection.
+    pub(crate) fn time_requests(&mut self) {
+        self.timings.get_or_insert_with(RequestTimings::default);
+    }
+
+    /// The timings collected since [`Peer::time_requests`] or the previous call.
+    pub(crate) fn take_timings(&mut self) -> RequestTimings {
+        self.timings
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// The address we ended up connected to.
+    pub fn addr(&self) -> SocketAddr {
+        self.conn.addr
+    }
+
+    pub fn has_piece(&self, piece_i: usize) -> bool {
         self.bitfield.has_piece(piece_i)
     }
 
+    /// Fetch blocks of piece `piece_i` off `tasks` until there are none left, keeping up to the
+    /// pipeline's worth of requests outstanding and sending each block we get to `finish`.
+    ///
+    /// Blocks may come back in any order. Whatever a choke or a wrong answer throws away goes
+    /// back on `submit`, for this or another peer to pick up again once it unchokes us; a peer
+    /// that keeps us choked for the unchoke timeout leaves the rest to the others. Dropping the
+    /// future cancels whatever requests it still has out.
     pub(crate) async fn participate(
         &mut self,
         piece_i: usize,
@@ -62,136 +510,477 @@ impl Peer {
         nblocks: usize,
         submit: kanal::AsyncSender<usize>,
         tasks: kanal::AsyncReceiver<usize>,
-        finish: tokio::sync::mpsc::Sender<Message>,
+        finish: tokio::sync::mpsc::Sender<Block>,
     ) -> anyhow::Result<()> {
-        anyhow::ensure!(self.bitfield.has_piece(piece_i));
-
-        self.stream
-            .send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
-            .await
-            .context("send interested message")?;
+        anyhow::ensure!(
+            self.bitfield.has_piece(piece_i),
+            "{} doesn't have piece {piece_i}",
+            self.conn.addr
+        );
 
+        let mut outstanding = self.conn.in_flight(piece_i as u32);
         // TODO: timeout, error, and return block to submit if .next() timed out
         'task: loop {
+            if outstanding.requests.is_empty() {
+                if let Some(mut pause) = self.pause.clone() {
+                    if *pause.borrow_and_update() {
+                        self.set_interested(false).await.with_context(|| {
+                            format!("send NotInterested message to {}", self.conn.addr)
+                        })?;
+                        if pause.wait_for(|&paused| !paused).await.is_err() {
+                            // the download is gone, so nobody is waiting for our blocks either
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+            self.set_interested(true)
+                .await
+                .with_context(|| format!("send Interested message to {}", self.conn.addr))?;
+            let unchoke_by = tokio::time::Instant::now() + self.unchoke_timeout;
             while self.choked {
-                let unchoke = self
-                    .stream
-                    .next()
-                    .await
-                    .expect("peer always sends an unchoke")
+                let Ok(unchoke) = tokio::time::timeout_at(unchoke_by, self.conn.next()).await
+                else {
+                    // nothing of ours is outstanding while we're choked, so we can just leave the
+                    // blocks to other peers
+                    return Ok(());
+                };
+                let unchoke = unchoke
+                    .context("peer closed the connection instead of unchoking us")?
                     .context("peer message was invalid")?;
-                match unchoke.tag {
-                    MessageTag::Unchoke => {
+                match unchoke {
+                    Message::Unchoke => {
                         self.choked = false;
-                        assert!(unchoke.payload.is_empty());
                         break;
                     }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
+                    Message::Have(index) => {
+                        // the download works out who has what again before its next piece
+                        self.bitfield.saw_have(index);
                     }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
+                    Message::KeepAlive | Message::Port(_) | Message::Unknown { .. } => {}
+                    Message::Interested
+                    | Message::NotInterested
+                    | Message::Request { .. }
+                    | Message::Cancel { .. } => {
                         // not allowing requests for now
                     }
-                    MessageTag::Piece => {
+                    Message::Extended { .. } => {
+                        // we don't advertise any extensions on download connections
+                    }
+                    Message::Piece { .. } => {
                         // piece that we no longer need/are responsible for
                     }
-                    MessageTag::Choke => {
+                    Message::Choke => {
                         anyhow::bail!("peer sent unchoke while unchoked");
                     }
-                    MessageTag::Bitfield => {
+                    Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
                         anyhow::bail!("peer sent bitfield after handshake has been completed");
                     }
                 }
             }
-            let Ok(block) = tasks.recv().await else {
-                break;
-            };
 
-            let block_size = if block == nblocks - 1 {
-                let md = piece_size % BLOCK_MAX;
-                if md == 0 {
-                    BLOCK_MAX
+            // top up the window, only waiting for a block to ask for when there are no answers
+            // to wait for instead
+            while outstanding.requests.len() < self.pipeline {
+                let block = if outstanding.requests.is_empty() {
+                    let mut pause = self.pause.clone();
+                    let paused = async {
+                        if let Some(pause) = &mut pause {
+                            if pause.wait_for(|&paused| paused).await.is_ok() {
+                                return;
+                            }
+                        }
+                        std::future::pending().await
+                    };
+                    // checking for a pause before every request (and none in between the check
+                    // and the request) means no new requests go out once the pause is set
+                    let block = tokio::select! {
+                        biased;
+                        _ = paused => continue 'task,
+                        block = tasks.recv() => block,
+                    };
+                    let Ok(block) = block else {
+                        break 'task;
+                    };
+                    block
                 } else {
-                    md
-                }
-            } else {
-                BLOCK_MAX
-            };
+                    if self.pause.as_ref().is_some_and(|pause| *pause.borrow()) {
+                        break;
+                    }
+                    match tasks.try_recv() {
+                        Ok(Some(block)) => block,
+                        _ => break,
+                    }
+                };
 
-            let mut request = Request::new(
-                piece_i as u32,
-                (block * BLOCK_MAX) as u32,
-                block_size as u32,
-            );
-            let request_bytes = Vec::from(request.as_bytes_mut());
-            self.stream
-                .send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
-                .await
-                .with_context(|| format!("send request for block {block}"))?;
+                let block_size = if block == nblocks - 1 {
+                    let md = piece_size % BLOCK_MAX;
+                    if md == 0 {
+                        BLOCK_MAX
+                    } else {
+                        md
+                    }
+                } else {
+                    BLOCK_MAX
+                };
 
-            let mut msg;
-            loop {
-                msg = self
-                    .stream
-                    .next()
+                if let Some(throttle) = &self.throttle {
+                    throttle.take(block_size).await;
+                }
+                let begin = (block * BLOCK_MAX) as u32;
+                self.conn
+                    .send(Message::Request {
+                        index: piece_i as u32,
+                        begin,
+                        length: block_size as u32,
+                    })
                     .await
-                    .expect("peer always sends a piece")
-                    .context("peer message was invalid")?;
+                    .with_context(|| format!("send request for block {block}"))?;
+                outstanding
+                    .requests
+                    .push_back((block, begin, block_size, Instant::now()));
+                if let Some(timings) = &mut self.timings {
+                    timings.in_flight.push(outstanding.requests.len());
+                }
+            }
+
+            let msg = self
+                .conn
+                .next()
+                .await
+                .context("peer closed the connection before sending the block")?
+                .context("peer message was invalid")?;
 
-                match msg.tag {
-                    MessageTag::Choke => {
-                        assert!(msg.payload.is_empty());
-                        self.choked = true;
+            match msg {
+                Message::Choke => {
+                    // a choking peer drops our requests, so their blocks are up for grabs again
+                    self.choked = true;
+                    for (block, ..) in outstanding.requests.drain(..) {
                         submit.send(block).await.expect("we still have a receiver");
-                        continue 'task;
                     }
-                    MessageTag::Piece => {
-                        let piece = Piece::ref_from_bytes(&msg.payload[..])
-                            .expect("always get all Piece response fields from peer");
-
-                        if piece.index() as usize != piece_i
-                            || piece.begin() as usize != block * BLOCK_MAX
-                        {
-                            // piece that we no longer need/are responsible for
-                        } else {
-                            assert_eq!(piece.block().len(), block_size);
-                            break;
+                }
+                Message::Piece {
+                    index,
+                    begin,
+                    block: data,
+                } => {
+                    // judge the block against the request at its offset, or failing that the
+                    // oldest one still outstanding
+                    let position = outstanding
+                        .requests
+                        .iter()
+                        .position(|&(_, requested, ..)| requested == begin)
+                        .unwrap_or(0);
+                    let Some((block, requested, length, sent)) =
+                        outstanding.requests.remove(position)
+                    else {
+                        // a block we never asked for, or one we gave up on after a choke
+                        continue;
+                    };
+                    match check_answer((piece_i as u32, requested, length), index, begin, &data) {
+                        Ok(()) => {
+                            if let Some(timings) = &mut self.timings {
+                                timings.rtts.push(sent.elapsed());
+                            }
+                            finish.send(Block {
+                                from: self.conn.addr,
+                                begin: begin as usize,
+                                data,
+                            })
+                            .await
+                            .expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
+                        }
+                        Err(mismatch) => {
+                            // drop the data, give the peer a strike, and put the block back up
+                            // for grabs (possibly by this same peer)
+                            let strike = self.violations.record(self.conn.addr, mismatch);
+                            submit.send(block).await.expect("we still have a receiver");
+                            strike?;
                         }
                     }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
-                    }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
-                    }
-                    MessageTag::Unchoke => {
-                        anyhow::bail!("peer sent unchoke while unchoked");
+                }
+                Message::Have(index) => {
+                    // the download works out who has what again before its next piece
+                    self.bitfield.saw_have(index);
+                }
+                Message::KeepAlive | Message::Port(_) | Message::Unknown { .. } => {}
+                Message::Interested
+                | Message::NotInterested
+                | Message::Request { .. }
+                | Message::Cancel { .. } => {
+                    // not allowing requests for now
+                }
+                Message::Extended { .. } => {
+                    // we don't advertise any extensions on download connections
+                }
+                Message::Unchoke => {
+                    anyhow::bail!("peer sent unchoke while unchoked");
+                }
+                Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                    anyhow::bail!("peer sent bitfield after handshake has been completed");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Download all of `piece` from this peer alone, calling `on_block` with the length of every
+    /// new block as it arrives.
+    ///
+    /// The data isn't checked against the piece's hash; that's up to the caller.
+    pub(crate) async fn fetch_piece(
+        &mut self,
+        piece: &crate::piece::Piece,
+        mut on_block: impl FnMut(usize),
+    ) -> anyhow::Result<Vec<u8>> {
+        let piece_size = piece.length();
+        let nblocks = piece_size.div_ceil(BLOCK_MAX);
+        let (submit, tasks) = kanal::bounded_async(nblocks);
+        for block in 0..nblocks {
+            submit
+                .send(block)
+                .await
+                .expect("bound holds all these items");
+        }
+        let (finish, mut done) = tokio::sync::mpsc::channel(nblocks);
+        let participate =
+            self.participate(piece.index(), piece_size, nblocks, submit, tasks, finish);
+        tokio::pin!(participate);
+
+        let mut data = vec![0u8; piece_size];
+        let mut have_block = vec![false; nblocks];
+        let mut received = 0;
+        while received < piece_size {
+            tokio::select! {
+                result = &mut participate => {
+                    // it holds on to the work queue itself, so it only ever gives up early on error
+                    result?;
+                    anyhow::bail!("peer stopped sending blocks");
+                }
+                Some(block) = done.recv() => {
+                    let block_i = block.begin / BLOCK_MAX;
+                    if std::mem::replace(&mut have_block[block_i], true) {
+                        continue;
                     }
-                    MessageTag::Bitfield => {
-                        anyhow::bail!("peer sent bitfield after handshake has been completed");
+                    data[block.begin..][..block.data.len()].copy_from_slice(&block.data);
+                    received += block.data.len();
+                    on_block(block.data.len());
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// Download and verify piece `index` of `t` from this peer alone, keeping up to the pipeline's
+    /// worth of block requests outstanding and re-requesting whatever a choke throws away.
+    ///
+    /// This is the whole piece state machine in one call, for when there's only the one peer to
+    /// care about; the download engine spreads blocks over many peers with
+    /// [`Peer::participate`] instead. It waits as long as the peer takes to send blocks, so
+    /// callers wanting a deadline should wrap it in one, but a peer that keeps us choked for the
+    /// unchoke timeout is given up on with [`PieceError::ChokedTimeout`].
+    pub async fn download_piece(
+        &mut self,
+        t: &Torrent,
+        index: usize,
+    ) -> Result<Vec<u8>, PieceError> {
+        let npieces = t.num_pieces();
+        if index >= npieces {
+            return Err(PieceError::OutOfRange { index, npieces });
+        }
+        let peer = self.conn.addr;
+        if !self.bitfield.has_piece(index) {
+            return Err(PieceError::Missing { peer, index });
+        }
+        let piece_length = t.piece_length_for(index);
+        let nblocks = piece_length.div_ceil(BLOCK_MAX);
+        let block_len = |block: usize| (piece_length - block * BLOCK_MAX).min(BLOCK_MAX);
+        let io = |source| PieceError::Io { peer, source };
+
+        let mut pending: VecDeque<usize> = (0..nblocks).collect();
+        let mut outstanding = VecDeque::new();
+        let mut have = vec![false; nblocks];
+        let mut data = vec![0u8; piece_length];
+        let mut received = 0;
+        // when we have to be unchoked by, while we're choked
+        let mut unchoke_by = None;
+        self.set_interested(true).await.map_err(io)?;
+        while received < nblocks {
+            while !self.choked && outstanding.len() < self.pipeline {
+                let Some(block) = pending.pop_front() else {
+                    break;
+                };
+                self.conn
+                    .send(Message::Request {
+                        index: index as u32,
+                        begin: (block * BLOCK_MAX) as u32,
+                        length: block_len(block) as u32,
+                    })
+                    .await
+                    .map_err(io)?;
+                outstanding.push_back(block);
+            }
+
+            let next = if self.choked {
+                let deadline = *unchoke_by
+                    .get_or_insert_with(|| tokio::time::Instant::now() + self.unchoke_timeout);
+                tokio::time::timeout_at(deadline, self.conn.next())
+                    .await
+                    .map_err(|_| PieceError::ChokedTimeout {
+                        peer,
+                        waited: self.unchoke_timeout,
+                    })?
+            } else {
+                unchoke_by = None;
+                self.conn.next().await
+            };
+            let msg = next
+                .ok_or(PieceError::Closed { peer, index })?
+                .map_err(io)?;
+            match msg {
+                Message::Choke => {
+                    // a choking peer drops our requests, so they all have to go out again later
+                    self.choked = true;
+                    pending.extend(outstanding.drain(..));
+                }
+                Message::Unchoke => self.choked = false,
+                Message::Piece {
+                    index: answered,
+                    begin,
+                    block: answer,
+                } => {
+                    // judge the block against the request its offset points at, or failing that
+                    // the oldest one still outstanding
+                    let block = Some(begin as usize / BLOCK_MAX)
+                        .filter(|&block| block < nblocks)
+                        .or(outstanding.front().copied())
+                        .unwrap_or(0);
+                    let requested = (index as u32, (block * BLOCK_MAX) as u32, block_len(block));
+                    match check_answer(requested, answered, begin, &answer) {
+                        Ok(()) => {
+                            outstanding.retain(|&b| b != block);
+                            pending.retain(|&b| b != block);
+                            // a block can turn up twice if it was in flight when we got choked
+                            if !std::mem::replace(&mut have[block], true) {
+                                data[block * BLOCK_MAX..][..answer.len()].copy_from_slice(&answer);
+                                received += 1;
+                            }
+                        }
+                        Err(mismatch) => {
+                            // ask again for whichever request that was supposed to answer
+                            let position =
+                                outstanding.iter().position(|&b| b == block).unwrap_or(0);
+                            if let Some(block) = outstanding.remove(position) {
+                                pending.push_back(block);
+                            }
+                            self.violations.strike(peer, mismatch)?;
+                        }
                     }
                 }
+                Message::Have(index) => self.bitfield.saw_have(index),
+                Message::KeepAlive | Message::Port(_) | Message::Unknown { .. } => {}
+                Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                    self.violations
+                        .strike(peer, "bitfield after the handshake had completed")?;
+                }
+                Message::Interested
+                | Message::NotInterested
+                | Message::Request { .. }
+                | Message::Cancel { .. }
+                | Message::Extended { .. } => {
+                    // not uploading, and no extensions on download connections
+                }
             }
+        }
 
-            finish.send(msg).await.expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
+        if !t.verify_piece(index, &data) {
+            return Err(PieceError::HashMismatch { peer, index });
         }
+        Ok(data)
+    }
+}
+
+/// Block requests a [`Peer`] keeps outstanding at once by default.
+pub const PIPELINE: usize = 5;
 
+/// Why [`Peer::download_piece`] failed.
+#[derive(Debug, thiserror::Error)]
+pub enum PieceError {
+    #[error("the torrent only has {npieces} pieces, so there is no piece {index}")]
+    OutOfRange { index: usize, npieces: usize },
+    #[error("{peer} doesn't have piece {index}")]
+    Missing { peer: SocketAddr, index: usize },
+    #[error("{peer} closed the connection before sending all of piece {index}")]
+    Closed { peer: SocketAddr, index: usize },
+    #[error("{peer} kept us choked for {waited:?}")]
+    ChokedTimeout { peer: SocketAddr, waited: Duration },
+    #[error("piece {index} from {peer} failed its hash check")]
+    HashMismatch { peer: SocketAddr, index: usize },
+    #[error(transparent)]
+    Violations(#[from] TooManyViolations),
+    #[error("connection to {peer} failed")]
+    Io {
+        peer: SocketAddr,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// Counts a peer's protocol violations, so that one stray message doesn't cost us the connection
+/// but a peer that keeps misbehaving does.
+#[derive(Debug, Default)]
+pub struct Violations {
+    count: u32,
+}
+
+impl Violations {
+    /// How many violations we put up with before disconnecting.
+    pub const MAX: u32 = 3;
+
+    /// Log a violation by `peer`, and error out once it has run out of strikes.
+    pub fn record(
+        &mut self,
+        peer: impl std::fmt::Display,
+        violation: impl std::fmt::Display,
+    ) -> anyhow::Result<()> {
+        Ok(self.strike(peer, violation)?)
+    }
+
+    /// Like [`Violations::record`], but with the typed error.
+    pub fn strike(
+        &mut self,
+        peer: impl std::fmt::Display,
+        violation: impl std::fmt::Display,
+    ) -> Result<(), TooManyViolations> {
+        self.count += 1;
+        eprintln!(
+            "protocol violation by {peer} ({}/{}): {violation}",
+            self.count,
+            Self::MAX
+        );
+        if self.count >= Self::MAX {
+            return Err(TooManyViolations {
+                peer: peer.to_string(),
+                count: self.count,
+                last: violation.to_string(),
+            });
+        }
         Ok(())
     }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+/// The error a peer's connection ends with once [`Violations`] has run out.
+#[derive(Debug, thiserror::Error)]
+#[error("disconnecting from {peer} after {count} protocol violations, the last being: {last}")]
+pub struct TooManyViolations {
+    pub peer: String,
+    pub count: u32,
+    pub last: String,
 }
 
 pub struct Bitfield {
@@ -208,7 +997,7 @@ impl Bitfield {
         byte & 1u8.rotate_right(bit_i + 1) != 0
     }
 
-    pub(crate) fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
         self.payload.iter().enumerate().flat_map(|(byte_i, byte)| {
             (0..u8::BITS).filter_map(move |bit_i| {
                 let piece_i = byte_i * (u8::BITS as usize) + (bit_i as usize);
@@ -218,9 +1007,71 @@ impl Bitfield {
         })
     }
 
-    fn from_payload(payload: Vec<u8>) -> Bitfield {
+    /// How many pieces the peer has.
+    pub fn count_ones(&self) -> usize {
+        self.payload
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// A bitfield for `npieces` pieces, with all of them or none of them set.
+    fn uniform(npieces: usize, all: bool) -> Bitfield {
+        let mut payload = vec![if all { 0xff } else { 0 }; npieces.div_ceil(8)];
+        if let Some(last) = payload.last_mut() {
+            *last &= spare_mask(npieces);
+        }
         Self { payload }
     }
+
+    /// Take note of a Have message, ignoring one for a piece that can't exist.
+    fn saw_have(&mut self, index: u32) {
+        let index = index as usize;
+        if let Some(byte) = self.payload.get_mut(index / 8) {
+            *byte |= 1u8.rotate_right(index as u32 % 8 + 1);
+        }
+    }
+}
+
+/// The bits of a bitfield's last byte that stand for actual pieces among `npieces`.
+fn spare_mask(npieces: usize) -> u8 {
+    match npieces % 8 {
+        0 => 0xff,
+        used => !(0xff >> used),
+    }
+}
+
+/// Make sense of the first message a peer sends after the handshake.
+///
+/// That should be a bitfield of `npieces` bits, but a peer with no pieces may leave it out,
+/// fast-extension peers (BEP 6) send HaveAll or HaveNone instead, and sloppy ones set spare bits
+/// at the end. Only a bitfield of the wrong length is an error; anything other than a bitfield
+/// means the peer has nothing yet, and comes back to be handled like any other message.
+fn opening(
+    peer: SocketAddr,
+    first: Message,
+    npieces: usize,
+) -> anyhow::Result<(Bitfield, Option<Message>)> {
+    match first {
+        Message::Bitfield(payload) => {
+            let mut payload = payload.to_vec();
+            anyhow::ensure!(
+                payload.len() == npieces.div_ceil(8),
+                "{peer} sent a bitfield of {} bytes for a torrent of {npieces} pieces",
+                payload.len()
+            );
+            if let Some(last) = payload.last_mut() {
+                if *last & !spare_mask(npieces) != 0 {
+                    eprintln!("{peer} set spare bits at the end of its bitfield; ignoring them");
+                    *last &= spare_mask(npieces);
+                }
+            }
+            Ok((Bitfield { payload }, None))
+        }
+        Message::HaveAll => Ok((Bitfield::uniform(npieces, true), None)),
+        Message::HaveNone => Ok((Bitfield::uniform(npieces, false), None)),
+        first => Ok((Bitfield::uniform(npieces, false), Some(first))),
+    }
 }
 
 #[test]
@@ -235,6 +1086,15 @@ fn bitfield_has() {
     assert!(bf.has_piece(15));
 }
 
+#[test]
+fn bitfield_counts() {
+    let bf = Bitfield {
+        payload: vec![0b10101010, 0b01010101],
+    };
+    assert_eq!(bf.count_ones(), 8);
+    assert!(!bf.has_piece(16));
+}
+
 #[test]
 fn bitfield_iter() {
     let bf = Bitfield {
@@ -252,114 +1112,887 @@ fn bitfield_iter() {
     assert_eq!(pieces.next(), None);
 }
 
-#[repr(C)]
-#[repr(packed)]
+#[test]
+fn piece_mismatches() {
+    let block = [7; 10];
+    assert!(check_answer((3, 16384, 10), 3, 16384, &block).is_ok());
+    assert_eq!(
+        check_answer((4, 16384, 10), 3, 16384, &block).err(),
+        Some(PieceMismatch::Index {
+            expected: 4,
+            received: 3
+        })
+    );
+    assert_eq!(
+        check_answer((3, 0, 10), 3, 16384, &block).err(),
+        Some(PieceMismatch::Begin {
+            expected: 0,
+            received: 16384
+        })
+    );
+    assert_eq!(
+        check_answer((3, 16384, 16384), 3, 16384, &block).err(),
+        Some(PieceMismatch::Length {
+            expected: 16384,
+            received: 10
+        })
+    );
+}
+
+#[test]
+fn violations_run_out() {
+    let mut violations = Violations::default();
+    assert!(violations.record("peer", "oops").is_ok());
+    assert!(violations.record("peer", "oops").is_ok());
+    assert!(violations.record("peer", "oops").is_err());
+    assert_eq!(violations.count(), Violations::MAX);
+}
+
+#[cfg(test)]
+async fn download_from_mock(
+    behaviour: crate::mock::Behaviour,
+) -> (Result<Vec<u8>, PieceError>, Vec<u8>, crate::mock::MockPeer) {
+    use crate::mock;
+
+    // eight blocks to the first piece, so there's always more to ask for than fits the pipeline
+    let plength = 8 * BLOCK_MAX;
+    let data = mock::data(plength + 1000);
+    let (_, mut seeds, t) = mock::swarm(&data, plength, vec![behaviour]).await;
+    let seed = seeds.remove(0);
+    let mut peer = Peer::new(seed.addr().into(), t.info_hash().unwrap(), t.num_pieces())
+        .await
+        .unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), peer.download_piece(&t, 0))
+        .await
+        .expect("the mock always answers eventually");
+    (result, data[..plength].to_vec(), seed)
+}
+
+#[tokio::test]
+async fn download_piece_rerequests_after_a_choke() {
+    let (result, expected, seed) = download_from_mock(crate::mock::Behaviour {
+        choke_after: Some(2),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(result.unwrap(), expected);
+    // everything in the pipeline at the time of the choke had to be asked for again
+    assert!(seed.requests() > 8, "{} requests", seed.requests());
+}
+
+#[tokio::test]
+async fn abandoned_requests_are_cancelled() {
+    use crate::mock;
+
+    let data = mock::data(2 * BLOCK_MAX);
+    let stalled = mock::Behaviour {
+        stall: true,
+        ..Default::default()
+    };
+    let (_, seeds, t) = mock::swarm(&data, 2 * BLOCK_MAX, vec![stalled]).await;
+    let seed = &seeds[0];
+    let mut peer = Peer::new(seed.addr().into(), t.info_hash().unwrap(), t.num_pieces())
+        .await
+        .unwrap();
+    let piece = crate::piece::Piece::new(0, &t, std::slice::from_ref(&peer));
+    let fetch = peer.fetch_piece(&piece, |_| {});
+    assert!(tokio::time::timeout(Duration::from_millis(200), fetch)
+        .await
+        .is_err());
+
+    let cancels = |begin| Message::Cancel {
+        index: 0,
+        begin,
+        length: BLOCK_MAX as u32,
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while seed.cancels().len() < 2 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(seed.cancels(), [cancels(0), cancels(BLOCK_MAX as u32)]);
+}
+
+#[tokio::test]
+async fn haves_while_choked_still_count() {
+    use crate::mock;
+
+    let data = mock::data(3 * BLOCK_MAX);
+    let behaviour = mock::Behaviour {
+        choke_after: Some(1),
+        lacks: Some(1),
+        ..Default::default()
+    };
+    let (_, seeds, t) = mock::swarm(&data, 2 * BLOCK_MAX, vec![behaviour]).await;
+    let seed = &seeds[0];
+    let mut peer = Peer::new(seed.addr().into(), t.info_hash().unwrap(), t.num_pieces())
+        .await
+        .unwrap();
+    assert!(!peer.has_piece(1));
+    let got = tokio::time::timeout(Duration::from_secs(5), peer.download_piece(&t, 0))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(got, data[..2 * BLOCK_MAX]);
+    assert!(peer.has_piece(1));
+}
+
+#[tokio::test]
+async fn download_piece_tolerates_a_lie() {
+    let (result, expected, _) = download_from_mock(crate::mock::Behaviour {
+        lie: Some(crate::mock::Lie::Begin),
+        lies: 1,
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(result.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn download_piece_errors_are_typed() {
+    let (result, _, _) = download_from_mock(crate::mock::Behaviour {
+        corrupt: true,
+        ..Default::default()
+    })
+    .await;
+    assert!(
+        matches!(result, Err(PieceError::HashMismatch { index: 0, .. })),
+        "{result:?}"
+    );
+
+    let (result, _, _) = download_from_mock(crate::mock::Behaviour {
+        lie: Some(crate::mock::Lie::Length),
+        lies: usize::MAX,
+        ..Default::default()
+    })
+    .await;
+    assert!(
+        matches!(result, Err(PieceError::Violations(_))),
+        "{result:?}"
+    );
+}
+
+#[tokio::test]
+async fn download_piece_checks_the_index() {
+    use crate::mock;
+
+    let data = mock::data(BLOCK_MAX);
+    let (_, seeds, t) = mock::swarm(&data, BLOCK_MAX, vec![Default::default()]).await;
+    let seed = &seeds[0];
+    let mut peer = Peer::new(seed.addr().into(), t.info_hash().unwrap(), t.num_pieces())
+        .await
+        .unwrap();
+    let e = peer.download_piece(&t, 1).await.unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "the torrent only has 1 pieces, so there is no piece 1"
+    );
+}
+
+#[tokio::test]
+async fn our_pieces_are_announced() {
+    use crate::mock;
+    use crate::progress::PieceState::{Done, Pending};
+
+    let data = mock::data(10 * BLOCK_MAX);
+    let (_, seeds, t) = mock::swarm(&data, BLOCK_MAX, vec![Default::default()]).await;
+    let seed = &seeds[0];
+    let info_hash = t.info_hash().unwrap();
+
+    // having nothing, there's nothing to say
+    let mut peer = Peer::new(seed.addr().into(), info_hash, 10).await.unwrap();
+    peer.send_bitfield(&PieceMap(vec![Pending; 10]))
+        .await
+        .unwrap();
+
+    // half of it, resumed
+    let mut peer = Peer::new(seed.addr().into(), info_hash, 10).await.unwrap();
+    let half = [Done, Pending].repeat(5);
+    peer.send_bitfield(&PieceMap(half)).await.unwrap();
+    peer.send_have(3).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        seed.announcements(),
+        vec![
+            (
+                1,
+                Message::Bitfield(Bytes::from_static(&[0b1010_1010, 0b1000_0000]))
+            ),
+            (1, Message::Have(3)),
+        ]
+    );
+}
+
+/// Connect to a peer that answers the handshake and then follows `script`, as a peer of a torrent
+/// with `npieces` pieces.
+#[cfg(test)]
+async fn open_scripted(npieces: usize, script: Vec<Message>) -> anyhow::Result<Peer> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        // same info hash, and nobody checks the peer id
+        stream.write_all(&handshake).await.unwrap();
+        let mut stream = tokio_util::codec::Framed::new(stream, MessageFramer::default());
+        for msg in script {
+            stream.send(msg).await.unwrap();
+        }
+        // hold the connection open until the other end is done with it
+        let _ = stream.next().await;
+    });
+    Peer::new(addr, InfoHash([7; 20]), npieces).await
+}
+
+#[cfg(test)]
+fn bitfield(payload: &[u8]) -> Message {
+    Message::Bitfield(Bytes::copy_from_slice(payload))
+}
+
+#[tokio::test]
+async fn openings_without_a_bitfield() {
+    let peer = open_scripted(10, vec![Message::KeepAlive, Message::Unchoke])
+        .await
+        .unwrap();
+    assert!(!peer.choked);
+    assert!((0..10).all(|i| !peer.has_piece(i)));
+
+    let peer = open_scripted(10, vec![Message::Have(3)]).await.unwrap();
+    assert!(peer.choked);
+    assert_eq!(peer.bitfield.pieces().collect::<Vec<_>>(), vec![3]);
+
+    let extended = Message::Extended {
+        id: 0,
+        payload: Bytes::from_static(b"de"),
+    };
+    let peer = open_scripted(10, vec![extended, Message::Unchoke])
+        .await
+        .unwrap();
+    assert!(!peer.choked);
+    assert_eq!(peer.bitfield.pieces().count(), 0);
+}
+
+#[tokio::test]
+async fn openings_with_have_all_or_none() {
+    let peer = open_scripted(10, vec![Message::HaveAll]).await.unwrap();
+    assert_eq!(
+        peer.bitfield.pieces().collect::<Vec<_>>(),
+        (0..10).collect::<Vec<_>>()
+    );
+
+    let peer = open_scripted(10, vec![Message::HaveNone]).await.unwrap();
+    assert_eq!(peer.bitfield.pieces().count(), 0);
+}
+
+#[tokio::test]
+async fn openings_with_bad_bitfields() {
+    // spare bits set: only the ones that stand for pieces count
+    let peer = open_scripted(10, vec![bitfield(&[0x80, 0xff])])
+        .await
+        .unwrap();
+    assert_eq!(peer.bitfield.pieces().collect::<Vec<_>>(), vec![0, 8, 9]);
+
+    for payload in [&[0xff][..], &[0xff, 0xff, 0x00]] {
+        let e = open_scripted(10, vec![bitfield(payload)])
+            .await
+            .err()
+            .expect("a bitfield of the wrong length");
+        assert!(
+            e.to_string().ends_with(&format!(
+                "sent a bitfield of {} bytes for a torrent of 10 pieces",
+                payload.len()
+            )),
+            "{e}"
+        );
+    }
+}
+
+/// The 20 bytes this client goes by, in tracker announces and handshakes alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 20]);
+
+static OURS: OnceLock<PeerId> = OnceLock::new();
+
+impl PeerId {
+    /// The Azureus-style start of every id we generate: client `RS`, version 0001.
+    pub const PREFIX: &'static [u8; 8] = b"-RS0001-";
+
+    /// [`PeerId::PREFIX`] followed by 12 random bytes.
+    pub fn generate() -> Self {
+        let mut id = [0; 20];
+        id[..8].copy_from_slice(Self::PREFIX);
+        for byte in &mut id[8..] {
+            *byte = fastrand::u8(..);
+        }
+        PeerId(id)
+    }
+
+    /// The id of this process, generated the first time it's asked for unless [`PeerId::set`]
+    /// picked one before that.
+    pub fn ours() -> Self {
+        *OURS.get_or_init(Self::generate)
+    }
+
+    /// Go by `id` for the rest of the process; this fails once [`PeerId::ours`] has been handed
+    /// out, since trackers and peers may already know us by that.
+    pub fn set(id: PeerId) -> anyhow::Result<()> {
+        OURS.set(id)
+            .map_err(|_| anyhow::anyhow!("the peer id is already in use"))
+    }
+}
+
+impl std::str::FromStr for PeerId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.as_bytes()
+            .try_into()
+            .map(PeerId)
+            .map_err(|_| format!("a peer id is 20 bytes, not {}", s.len()))
+    }
+}
+
+/// How many bytes a handshake takes on the wire.
+pub const HANDSHAKE_LEN: usize = 68;
+
+/// What every handshake starts with: the length of the protocol name, then the name.
+const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// The first thing either side of a connection sends: the protocol, the reserved bytes whose bits
+/// announce extensions, and which torrent and peer the connection is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Handshake {
-    pub length: u8,
-    pub bittorrent: [u8; 19],
-    pub reserved: [u8; 8],
-    pub info_hash: [u8; 20],
+    pub reserved: Reserved,
+    pub info_hash: InfoHash,
     pub peer_id: [u8; 20],
 }
 
+/// The 8 reserved bytes of a handshake, whose bits say which extensions a peer supports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Reserved(pub [u8; 8]);
+
+impl Reserved {
+    /// The byte and bit for the extension protocol (BEP 10).
+    pub const LTEP: (usize, u8) = (5, 0x10);
+    /// The byte and bit for the DHT (BEP 5).
+    pub const DHT: (usize, u8) = (7, 0x01);
+    /// The byte and bit for the Fast Extension (BEP 6).
+    pub const FAST: (usize, u8) = (7, 0x04);
+
+    pub fn with_ltep(self) -> Self {
+        self.with(Self::LTEP)
+    }
+
+    pub fn with_dht(self) -> Self {
+        self.with(Self::DHT)
+    }
+
+    pub fn with_fast(self) -> Self {
+        self.with(Self::FAST)
+    }
+
+    pub fn ltep(&self) -> bool {
+        self.has(Self::LTEP)
+    }
+
+    pub fn dht(&self) -> bool {
+        self.has(Self::DHT)
+    }
+
+    pub fn fast(&self) -> bool {
+        self.has(Self::FAST)
+    }
+
+    /// The names of the extensions these bits announce.
+    pub fn extensions(&self) -> Vec<&'static str> {
+        [
+            ("LTEP", self.ltep()),
+            ("DHT", self.dht()),
+            ("Fast", self.fast()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect()
+    }
+
+    fn with(mut self, (byte, bit): (usize, u8)) -> Self {
+        self.0[byte] |= bit;
+        self
+    }
+
+    fn has(&self, (byte, bit): (usize, u8)) -> bool {
+        self.0[byte] & bit != 0
+    }
+}
+
+impl fmt::Display for Reserved {
+    /// The extensions as a list like `LTEP, DHT`, or `none`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.extensions().join(", ") {
+            none if none.is_empty() => f.write_str("none"),
+            extensions => f.write_str(&extensions),
+        }
+    }
+}
+
+#[test]
+fn reserved_bits() {
+    let none = Reserved::default();
+    assert!(!none.ltep() && !none.dht() && !none.fast());
+    assert_eq!(none.to_string(), "none");
+
+    assert_eq!(
+        Reserved::default().with_ltep().0,
+        [0, 0, 0, 0, 0, 0x10, 0, 0]
+    );
+    assert_eq!(
+        Reserved::default().with_dht().0,
+        [0, 0, 0, 0, 0, 0, 0, 0x01]
+    );
+    assert_eq!(
+        Reserved::default().with_fast().0,
+        [0, 0, 0, 0, 0, 0, 0, 0x04]
+    );
+
+    // each is read from its own bit and no other
+    let ltep = Reserved([0, 0, 0, 0, 0, 0x10, 0, 0]);
+    assert!(ltep.ltep() && !ltep.dht() && !ltep.fast());
+    let dht = Reserved([0, 0, 0, 0, 0, 0, 0, 0x01]);
+    assert!(!dht.ltep() && dht.dht() && !dht.fast());
+    let fast = Reserved([0, 0, 0, 0, 0, 0, 0, 0x04]);
+    assert!(!fast.ltep() && !fast.dht() && fast.fast());
+    let neighbours = Reserved([0xff, 0xff, 0xff, 0xff, 0xff, !0x10, 0xff, !0x05]);
+    assert!(neighbours.extensions().is_empty());
+
+    let all = Reserved::default().with_dht().with_ltep().with_fast();
+    assert_eq!(all.0, [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+    assert_eq!(all.to_string(), "LTEP, DHT, Fast");
+    assert_eq!(dht.with_ltep().to_string(), "LTEP, DHT");
+}
+
+/// Why the bytes a peer opened with aren't a handshake we can go on with.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("not a BitTorrent handshake: it starts with \"{0}\"")]
+    Protocol(String),
+}
+
+/// A peer whose handshake shows it isn't the one we meant to talk to.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PeerError {
+    #[error("{addr} answered the handshake for a different torrent ({received})")]
+    InfoHashMismatch {
+        addr: SocketAddr,
+        received: InfoHash,
+    },
+    #[error(
+        "{addr} answered the handshake as peer \"{}\", but the tracker said it was \"{}\"",
+        .received.escape_ascii(),
+        .expected.escape_ascii()
+    )]
+    PeerIdMismatch {
+        addr: SocketAddr,
+        expected: [u8; 20],
+        received: [u8; 20],
+    },
+}
+
+/// What to do about a peer whose handshake carries a different peer id than the tracker gave for
+/// it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PeerIdCheck {
+    /// Drop the connection with a [`PeerError::PeerIdMismatch`].
+    #[default]
+    Refuse,
+    /// Say so and carry on, for clients that don't give trackers and peers the same id.
+    Warn,
+}
+
 impl Handshake {
-    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+    pub fn new(info_hash: InfoHash, peer_id: [u8; 20]) -> Self {
         Self {
-            length: 19,
-            bittorrent: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved: Reserved::default(),
             info_hash,
             peer_id,
         }
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        // Safety: Self is a POD with repr(c) and repr(packed)
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0; HANDSHAKE_LEN];
+        bytes[..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved.0);
+        bytes[28..48].copy_from_slice(&self.info_hash.0);
+        bytes[48..].copy_from_slice(&self.peer_id);
         bytes
     }
-}
-
-#[repr(C)]
-#[repr(packed)]
-pub struct Request {
-    index: [u8; 4],
-    begin: [u8; 4],
-    length: [u8; 4],
-}
 
-impl Request {
-    pub fn new(index: u32, begin: u32, length: u32) -> Self {
-        Self {
-            index: index.to_be_bytes(),
-            begin: begin.to_be_bytes(),
-            length: length.to_be_bytes(),
+    pub fn from_bytes(bytes: &[u8; HANDSHAKE_LEN]) -> Result<Self, HandshakeError> {
+        let (protocol, rest) = bytes.split_at(PROTOCOL.len());
+        if protocol != PROTOCOL {
+            return Err(HandshakeError::Protocol(
+                protocol.escape_ascii().to_string(),
+            ));
         }
+        let field = |range: std::ops::Range<usize>| &rest[range];
+        Ok(Self {
+            reserved: Reserved(field(0..8).try_into().expect("8 bytes")),
+            info_hash: InfoHash(field(8..28).try_into().expect("20 bytes")),
+            peer_id: field(28..48).try_into().expect("20 bytes"),
+        })
     }
 
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
+    /// Check the handshake `addr` answered ours with: it has to be about `info_hash`, and to
+    /// carry `peer_id` if we know which id the peer goes by.
+    pub fn check(
+        &self,
+        addr: SocketAddr,
+        info_hash: InfoHash,
+        peer_id: Option<[u8; 20]>,
+    ) -> Result<(), PeerError> {
+        if self.info_hash != info_hash {
+            return Err(PeerError::InfoHashMismatch {
+                addr,
+                received: self.info_hash,
+            });
+        }
+        match peer_id {
+            Some(expected) if expected != self.peer_id => Err(PeerError::PeerIdMismatch {
+                addr,
+                expected,
+                received: self.peer_id,
+            }),
+            _ => Ok(()),
+        }
     }
 
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
+    /// Read a handshake off `stream`.
+    pub async fn read(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Self> {
+        let mut bytes = [0; HANDSHAKE_LEN];
+        stream
+            .read_exact(&mut bytes)
+            .await
+            .context("read handshake")?;
+        Ok(Self::from_bytes(&bytes)?)
     }
 
-    pub fn length(&self) -> u32 {
-        u32::from_be_bytes(self.length)
+    /// Send this handshake over `stream`.
+    pub async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<()> {
+        stream
+            .write_all(&self.to_bytes())
+            .await
+            .context("write handshake")
     }
+}
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        // Safety: Self is a POD with repr(c) and repr(packed)
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
-        bytes
+#[tokio::test]
+async fn connections_give_up_on_silent_peers() {
+    // accepts connections (into its backlog), but never answers the handshake
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = silent.local_addr().unwrap();
+    let started = Instant::now();
+    let timeout = Duration::from_millis(200);
+    let e = PeerConnection::connect(addr, InfoHash([7; 20]), PeerId::ours(), timeout)
+        .await
+        .err()
+        .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(e.to_string(), format!("{addr} didn't answer within 200ms"));
+
+    // and on peers that answer the handshake, then never say what they have
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let theirs = Handshake::read(&mut stream).await.unwrap();
+        let mut reply = Handshake::new(theirs.info_hash, *b"-SCRIPT-000000000000");
+        reply.reserved = reply.reserved.with_dht();
+        reply.write(&mut stream).await.unwrap();
+        let mut stream = tokio_util::codec::Framed::new(stream, MessageFramer::default());
+        stream.send(Message::KeepAlive).await.unwrap();
+        let _ = stream.next().await;
+    });
+    let mut conn = PeerConnection::connect(addr, InfoHash([7; 20]), PeerId::ours(), timeout)
+        .await
+        .unwrap();
+    assert_eq!(conn.peer_id(), *b"-SCRIPT-000000000000");
+    assert!(conn.reserved().dht());
+    let e = conn.wait_bitfield(10, timeout).await.err().unwrap();
+    assert_eq!(e.to_string(), format!("{addr} sent nothing within 200ms"));
+}
+
+#[tokio::test]
+async fn idle_connections_keep_alive_until_the_peer_goes_quiet() {
+    // a peer that answers the handshake, then only listens and counts keep-alives
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (heard, mut keep_alives) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let theirs = Handshake::read(&mut stream).await.unwrap();
+        Handshake::new(theirs.info_hash, *b"-SCRIPT-000000000000")
+            .write(&mut stream)
+            .await
+            .unwrap();
+        let mut stream = tokio_util::codec::Framed::new(stream, MessageFramer::default());
+        while let Some(Ok(msg)) = stream.next().await {
+            heard.send((Instant::now(), msg)).unwrap();
+        }
+    });
+    let timeout = Duration::from_secs(5);
+    let mut conn = PeerConnection::connect(addr, InfoHash([7; 20]), PeerId::ours(), timeout)
+        .await
+        .unwrap();
+    let started = Instant::now();
+    conn.idle_times(Duration::from_millis(50), Duration::from_millis(300));
+    conn.interested().await.unwrap();
+
+    let e = conn.recv().await.err().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert_eq!(
+        e.root_cause().to_string(),
+        format!("{addr} sent nothing for 300ms")
+    );
+    drop(conn);
+    let mut heard = Vec::new();
+    while let Some(msg) = keep_alives.recv().await {
+        heard.push(msg);
     }
+    assert_eq!(heard[0].1, Message::Interested);
+    assert!(heard[1..].iter().all(|(_, msg)| *msg == Message::KeepAlive));
+    assert!((2..=6).contains(&(heard.len() - 1)), "{heard:?}");
+    // the first only once the Interested was 50ms old
+    assert!(heard[1].0 - heard[0].0 >= Duration::from_millis(50));
 }
 
-#[repr(C)]
-// NOTE: needs to be (and is)
-// #[repr(packed)]
-// but can't be marked as such because of the T: ?Sized part
-pub struct Piece<T: ?Sized = [u8]> {
-    index: [u8; 4],
-    begin: [u8; 4],
-    block: T,
+#[tokio::test]
+async fn downloads_ride_over_keep_alives() {
+    let (result, expected, _) = download_from_mock(crate::mock::Behaviour {
+        keep_alives: true,
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(result.unwrap(), expected);
 }
 
-impl Piece {
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
-    }
+#[tokio::test]
+async fn download_piece_gives_up_on_peers_that_never_unchoke() {
+    use crate::mock;
 
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
-    }
+    let data = mock::data(1000);
+    let never_unchoke = mock::Behaviour {
+        never_unchoke: true,
+        ..Default::default()
+    };
+    let (_, seeds, t) = mock::swarm(&data, 512, vec![never_unchoke]).await;
+    let seed = &seeds[0];
+    let mut peer = Peer::new(seed.addr().into(), t.info_hash().unwrap(), t.num_pieces())
+        .await
+        .unwrap();
+    peer.set_unchoke_timeout(Duration::from_millis(100));
+    let e = tokio::time::timeout(Duration::from_secs(5), peer.download_piece(&t, 0))
+        .await
+        .expect("the unchoke timeout is much shorter")
+        .unwrap_err();
+    assert!(
+        matches!(e, PieceError::ChokedTimeout { waited, .. } if waited == Duration::from_millis(100)),
+        "{e}"
+    );
+}
+
+#[tokio::test]
+async fn download_piece_takes_blocks_in_any_order() {
+    let (result, expected, _) = download_from_mock(crate::mock::Behaviour {
+        reorder: true,
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(result.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn downloads_pass_over_messages_we_dont_know() {
+    let (result, expected, _) = download_from_mock(crate::mock::Behaviour {
+        chatter: true,
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(result.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn connections_carry_messages() {
+    use crate::mock;
+
+    let data = mock::data(1000);
+    let (_, seeds, t) = mock::swarm(&data, 512, vec![Default::default()]).await;
+    let seed = &seeds[0];
+    let addr = seed.addr().into();
+    let timeout = Duration::from_secs(5);
+    let mut conn = PeerConnection::connect(addr, t.info_hash().unwrap(), PeerId::ours(), timeout)
+        .await
+        .unwrap();
+    let (mut bitfield, first) = conn.wait_bitfield(2, timeout).await.unwrap();
+    assert_eq!(bitfield.pieces().collect::<Vec<_>>(), [0, 1]);
+    assert_eq!(first, None);
+    conn.interested().await.unwrap();
+    conn.wait_unchoke(&mut bitfield).await.unwrap();
+    conn.send(Message::Request {
+        index: 1,
+        begin: 0,
+        length: 100,
+    })
+    .await
+    .unwrap();
+    let Some(Message::Piece {
+        index: 1,
+        begin: 0,
+        block,
+    }) = conn.recv().await.unwrap()
+    else {
+        panic!("the mock answers requests with pieces");
+    };
+    assert_eq!(block[..], data[512..612]);
+}
+
+#[tokio::test]
+async fn handshakes_must_be_for_our_torrent_and_peer() {
+    use crate::mock;
+
+    // a peer of another torrent, which answers with that torrent's info hash
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        Handshake::read(&mut stream).await.unwrap();
+        let theirs = Handshake::new(InfoHash([8; 20]), *b"-SCRIPT-000000000000");
+        theirs.write(&mut stream).await.unwrap();
+        let _ = stream.read_u8().await;
+    });
+    let e = Peer::new(addr, InfoHash([7; 20]), 10).await.err().unwrap();
+    assert_eq!(
+        e.downcast_ref::<PeerError>(),
+        Some(&PeerError::InfoHashMismatch {
+            addr,
+            received: InfoHash([8; 20])
+        })
+    );
+
+    // the mock goes by -MOCK00-000000000000
+    let data = mock::data(1000);
+    let (_, seeds, t) = mock::swarm(&data, 512, vec![Default::default()]).await;
+    let seed = &seeds[0];
+    let (addr, info_hash) = (seed.addr().into(), t.info_hash().unwrap());
+    let connect =
+        |peer_id: &[u8; 20], check| Peer::expecting(addr, info_hash, 2, Some(*peer_id), check);
+    connect(b"-MOCK00-000000000000", PeerIdCheck::Refuse)
+        .await
+        .unwrap();
+    let e = connect(b"-OTHER0-000000000000", PeerIdCheck::Refuse)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        format!(
+            "{addr} answered the handshake as peer \"-MOCK00-000000000000\", but the tracker said \
+             it was \"-OTHER0-000000000000\""
+        )
+    );
+    assert!(matches!(
+        e.downcast_ref(),
+        Some(PeerError::PeerIdMismatch { .. })
+    ));
+    // unless we'd rather just hear about it
+    connect(b"-OTHER0-000000000000", PeerIdCheck::Warn)
+        .await
+        .unwrap();
+    assert_eq!(seed.connections(), 3);
+}
 
-    pub fn block(&self) -> &[u8] {
-        &self.block
+#[test]
+fn handshakes_round_trip() {
+    let mut handshake = Handshake::new(InfoHash([0xab; 20]), *b"-RS0001-0123456789ab");
+    handshake.reserved = handshake.reserved.with_ltep();
+    let bytes = handshake.to_bytes();
+    assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+    assert_eq!(bytes[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0]);
+    assert_eq!(bytes[28..48], [0xab; 20]);
+    assert_eq!(&bytes[48..], b"-RS0001-0123456789ab");
+    assert_eq!(Handshake::from_bytes(&bytes), Ok(handshake));
+}
+
+#[test]
+fn malformed_handshakes() {
+    let good = Handshake::new(InfoHash([1; 20]), [2; 20]).to_bytes();
+    let mut wrong_length = good;
+    wrong_length[0] = 18;
+    let mut wrong_name = good;
+    wrong_name[1..20].copy_from_slice(b"BitTorrent Protocol");
+    let mut http = [0; HANDSHAKE_LEN];
+    http[..20].copy_from_slice(b"GET / HTTP/1.1\r\nHost");
+    for (bytes, start) in [
+        (wrong_length, "\\x12BitTorrent protocol"),
+        (wrong_name, "\\x13BitTorrent Protocol"),
+        (http, "GET / HTTP/1.1\\r\\nHost"),
+    ] {
+        assert_eq!(
+            Handshake::from_bytes(&bytes).unwrap_err().to_string(),
+            format!("not a BitTorrent handshake: it starts with \"{start}\"")
+        );
     }
+}
 
-    const PIECE_LEAD: usize = std::mem::size_of::<Piece<()>>();
-    pub fn ref_from_bytes(data: &[u8]) -> Option<&Self> {
-        if data.len() < Self::PIECE_LEAD {
-            return None;
-        }
-        let n = data.len();
-        // NOTE: The slicing here looks really weird. The reason we do it is because we need the
-        // length part of the fat pointer to Piece to hold the length of _just_ the `block` field.
-        // And the only way we can change the length of the fat pointer to Piece is by changing the
-        // length of the fat pointer to the slice, which we do by slicing it. We can't slice it at
-        // the front (as it would invalidate the ptr part of the fat pointer), so we slice it at
-        // the back!
-        let piece = &data[..n - Self::PIECE_LEAD] as *const [u8] as *const Piece;
-        // Safety: Piece is a POD with repr(c) and repr(packed), _and_ the fat pointer data length
-        // is the length of the trailing DST field (thanks to the PIECE_LEAD offset).
-        Some(unsafe { &*piece })
+/// Why a Piece message doesn't answer the request we sent.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PieceMismatch {
+    #[error("expected piece index {expected}, received {received}")]
+    Index { expected: u32, received: u32 },
+    #[error("expected block offset {expected}, received {received}")]
+    Begin { expected: u32, received: u32 },
+    #[error("expected a block of {expected} bytes, received {received}")]
+    Length { expected: usize, received: usize },
+}
+
+/// Check that a Piece message holding `block` at `begin` in piece `index` answers our request for
+/// `length` bytes at `requested_begin` in piece `requested`, trusting none of what the peer says.
+pub fn check_answer(
+    (requested, requested_begin, length): (u32, u32, usize),
+    index: u32,
+    begin: u32,
+    block: &[u8],
+) -> Result<(), PieceMismatch> {
+    if index != requested {
+        return Err(PieceMismatch::Index {
+            expected: requested,
+            received: index,
+        });
     }
+    if begin != requested_begin {
+        return Err(PieceMismatch::Begin {
+            expected: requested_begin,
+            received: begin,
+        });
+    }
+    if block.len() != length {
+        return Err(PieceMismatch::Length {
+            expected: length,
+            received: block.len(),
+        });
+    }
+    Ok(())
+}
+
+/// A block that arrived for the piece [`Peer::participate`] is working on.
+#[derive(Debug)]
+pub(crate) struct Block {
+    /// The peer that sent it.
+    pub(crate) from: SocketAddr,
+    pub(crate) begin: usize,
+    pub(crate) data: Bytes,
 }
 
+/// The type byte of each message on the wire.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum MessageTag {
@@ -372,17 +2005,184 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    /// The peer's DHT port (BEP 5).
+    Port = 9,
+    /// The peer has every piece (BEP 6), in place of a bitfield.
+    HaveAll = 14,
+    /// The peer has no pieces (BEP 6), in place of a bitfield.
+    HaveNone = 15,
+    /// A BEP 10 extension message; the first payload byte says which extension.
+    Extended = 20,
+}
+
+impl MessageTag {
+    fn from_byte(tag: u8) -> Option<Self> {
+        Some(match tag {
+            0 => MessageTag::Choke,
+            1 => MessageTag::Unchoke,
+            2 => MessageTag::Interested,
+            3 => MessageTag::NotInterested,
+            4 => MessageTag::Have,
+            5 => MessageTag::Bitfield,
+            6 => MessageTag::Request,
+            7 => MessageTag::Piece,
+            8 => MessageTag::Cancel,
+            9 => MessageTag::Port,
+            14 => MessageTag::HaveAll,
+            15 => MessageTag::HaveNone,
+            20 => MessageTag::Extended,
+            _ => return None,
+        })
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Message {
-    pub tag: MessageTag,
-    pub payload: Vec<u8>,
+/// A peer wire message, with its payload already taken apart.
+///
+/// [`MessageFramer`] is the only thing that deals in the bytes, and it won't hand out a message
+/// whose payload is the wrong length for its type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    /// The raw bitfield; [`Peer`] checks it against the torrent it's for.
+    Bitfield(Bytes),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Bytes,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    HaveAll,
+    HaveNone,
+    /// A BEP 10 message for the extension numbered `id`, or the extended handshake for 0.
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+    /// A message of a type we don't implement, such as BEP 6's Suggest Piece, passed along
+    /// untouched so that it can be ignored rather than end the connection.
+    Unknown {
+        tag: u8,
+        payload: Bytes,
+    },
 }
 
-pub struct MessageFramer;
+impl Message {
+    /// The message's type, or `None` for a keep-alive, which has none, and for a message of a
+    /// type we don't know.
+    pub fn tag(&self) -> Option<MessageTag> {
+        Some(match self {
+            Message::KeepAlive | Message::Unknown { .. } => return None,
+            Message::Choke => MessageTag::Choke,
+            Message::Unchoke => MessageTag::Unchoke,
+            Message::Interested => MessageTag::Interested,
+            Message::NotInterested => MessageTag::NotInterested,
+            Message::Have(_) => MessageTag::Have,
+            Message::Bitfield(_) => MessageTag::Bitfield,
+            Message::Request { .. } => MessageTag::Request,
+            Message::Piece { .. } => MessageTag::Piece,
+            Message::Cancel { .. } => MessageTag::Cancel,
+            Message::Port(_) => MessageTag::Port,
+            Message::HaveAll => MessageTag::HaveAll,
+            Message::HaveNone => MessageTag::HaveNone,
+            Message::Extended { .. } => MessageTag::Extended,
+        })
+    }
 
-const MAX: usize = 1 << 16;
+    /// Take apart the payload of a message of type `tag`.
+    fn parse(tag: MessageTag, mut payload: Bytes) -> Option<Self> {
+        let fixed = |len: usize| payload.len() == len;
+        Some(match tag {
+            MessageTag::Choke if fixed(0) => Message::Choke,
+            MessageTag::Unchoke if fixed(0) => Message::Unchoke,
+            MessageTag::Interested if fixed(0) => Message::Interested,
+            MessageTag::NotInterested if fixed(0) => Message::NotInterested,
+            MessageTag::HaveAll if fixed(0) => Message::HaveAll,
+            MessageTag::HaveNone if fixed(0) => Message::HaveNone,
+            MessageTag::Have if fixed(4) => Message::Have(payload.get_u32()),
+            MessageTag::Bitfield => Message::Bitfield(payload),
+            MessageTag::Request if fixed(12) => Message::Request {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            },
+            MessageTag::Cancel if fixed(12) => Message::Cancel {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            },
+            MessageTag::Piece if payload.len() >= 8 => Message::Piece {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                block: payload,
+            },
+            MessageTag::Port if fixed(2) => Message::Port(payload.get_u16()),
+            MessageTag::Extended if !payload.is_empty() => Message::Extended {
+                id: payload.get_u8(),
+                payload,
+            },
+            _ => return None,
+        })
+    }
+
+    /// How many bytes the payload takes up on the wire.
+    fn payload_len(&self) -> usize {
+        match self {
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => 0,
+            Message::Have(_) => 4,
+            Message::Bitfield(bitfield) => bitfield.len(),
+            Message::Request { .. } | Message::Cancel { .. } => 12,
+            Message::Piece { block, .. } => 8 + block.len(),
+            Message::Port(_) => 2,
+            Message::Extended { payload, .. } => 1 + payload.len(),
+            Message::Unknown { payload, .. } => payload.len(),
+        }
+    }
+}
+
+/// The most bytes a frame may take by default: room for a block, or for the bitfield of a torrent
+/// with millions of pieces, with plenty to spare.
+pub const MAX_FRAME: usize = 2 << 20;
+
+/// Turns the bytes of a connection into [`Message`]s and back, refusing frames longer than its
+/// limit before buffering any of them.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageFramer {
+    max_frame: usize,
+}
+
+impl MessageFramer {
+    /// A framer that takes frames of up to `max_frame` bytes, instead of [`MAX_FRAME`].
+    pub fn with_max_frame(max_frame: usize) -> Self {
+        Self { max_frame }
+    }
+}
+
+impl Default for MessageFramer {
+    fn default() -> Self {
+        Self::with_max_frame(MAX_FRAME)
+    }
+}
 
 impl Decoder for MessageFramer {
     type Item = Message;
@@ -400,21 +2200,13 @@ impl Decoder for MessageFramer {
         let length = u32::from_be_bytes(length_bytes) as usize;
 
         if length == 0 {
-            // this is a heartbeat message.
-            // discard it.
             src.advance(4);
-            // and then try again in case the buffer has more messages
-            return self.decode(src);
-        }
-
-        if src.len() < 5 {
-            // Not enough data to read tag marker.
-            return Ok(None);
+            return Ok(Some(Message::KeepAlive));
         }
 
         // Check that the length is not too large to avoid a denial of
         // service attack where the server runs out of memory.
-        if length > MAX {
+        if length > self.max_frame {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Frame of length {} is too large.", length),
@@ -435,31 +2227,23 @@ impl Decoder for MessageFramer {
 
         // Use advance to modify src such that it no longer contains
         // this frame.
-        let tag = match src[4] {
-            0 => MessageTag::Choke,
-            1 => MessageTag::Unchoke,
-            2 => MessageTag::Interested,
-            3 => MessageTag::NotInterested,
-            4 => MessageTag::Have,
-            5 => MessageTag::Bitfield,
-            6 => MessageTag::Request,
-            7 => MessageTag::Piece,
-            8 => MessageTag::Cancel,
-            tag => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Unknown message type {}.", tag),
-                ))
-            }
-        };
-        let data = if src.len() > 5 {
-            src[5..4 + length].to_vec()
-        } else {
-            Vec::new()
+        src.advance(4);
+        let mut frame = src.split_to(length).freeze();
+        let tag = frame.get_u8();
+        let Some(tag) = MessageTag::from_byte(tag) else {
+            return Ok(Some(Message::Unknown {
+                tag,
+                payload: frame,
+            }));
         };
-        src.advance(4 + length);
-
-        Ok(Some(Message { tag, payload: data }))
+        let payload_len = frame.len();
+        match Message::parse(tag, frame) {
+            Some(message) => Ok(Some(message)),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{tag:?} message with a payload of {payload_len} bytes."),
+            )),
+        }
     }
 }
 
@@ -467,25 +2251,244 @@ impl Encoder<Message> for MessageFramer {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let tag = match (&item, item.tag()) {
+            (Message::Unknown { tag, .. }, _) => *tag,
+            (_, Some(tag)) => tag as u8,
+            (_, None) => {
+                dst.put_u32(0);
+                return Ok(());
+            }
+        };
+        let payload_len = item.payload_len();
         // Don't send a message if it is longer than the other end will
         // accept.
-        if item.payload.len() + 1 > MAX {
+        if payload_len + 1 > self.max_frame {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", item.payload.len()),
+                format!("Frame of length {} is too large.", payload_len),
             ));
         }
 
-        // Convert the length into a byte array.
-        let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
-
         // Reserve space in the buffer.
-        dst.reserve(4 /* length */ + 1 /* tag */ + item.payload.len());
+        dst.reserve(4 /* length */ + 1 /* tag */ + payload_len);
 
-        // Write the length and string to the buffer.
-        dst.extend_from_slice(&len_slice);
-        dst.put_u8(item.tag as u8);
-        dst.extend_from_slice(&item.payload);
+        // Write the length, tag and payload to the buffer.
+        dst.put_u32(payload_len as u32 + 1);
+        dst.put_u8(tag);
+        match item {
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => {}
+            Message::Have(index) => dst.put_u32(index),
+            Message::Bitfield(bitfield) => dst.extend_from_slice(&bitfield),
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_u32(length);
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.extend_from_slice(&block);
+            }
+            Message::Port(port) => dst.put_u16(port),
+            Message::Extended { id, payload } => {
+                dst.put_u8(id);
+                dst.extend_from_slice(&payload);
+            }
+            Message::Unknown { payload, .. } => dst.extend_from_slice(&payload),
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+fn wire(msg: Message) -> Vec<u8> {
+    let mut bytes = BytesMut::new();
+    MessageFramer::default().encode(msg, &mut bytes).unwrap();
+    bytes.to_vec()
+}
+
+#[test]
+fn messages_on_the_wire() {
+    let golden: Vec<(Message, &[u8])> = vec![
+        (Message::KeepAlive, &[0, 0, 0, 0]),
+        (Message::Choke, &[0, 0, 0, 1, 0]),
+        (Message::Unchoke, &[0, 0, 0, 1, 1]),
+        (Message::Interested, &[0, 0, 0, 1, 2]),
+        (Message::NotInterested, &[0, 0, 0, 1, 3]),
+        (Message::Have(0x0102), &[0, 0, 0, 5, 4, 0, 0, 1, 2]),
+        (
+            Message::Bitfield(Bytes::from_static(&[0xa0, 0x40])),
+            &[0, 0, 0, 3, 5, 0xa0, 0x40],
+        ),
+        (
+            Message::Request {
+                index: 1,
+                begin: 0x4000,
+                length: 0x4000,
+            },
+            &[0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+        ),
+        (
+            Message::Piece {
+                index: 1,
+                begin: 0x4000,
+                block: Bytes::from_static(b"abc"),
+            },
+            &[0, 0, 0, 12, 7, 0, 0, 0, 1, 0, 0, 0x40, 0, b'a', b'b', b'c'],
+        ),
+        (
+            Message::Cancel {
+                index: 2,
+                begin: 0,
+                length: 5,
+            },
+            &[0, 0, 0, 13, 8, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 5],
+        ),
+        (Message::Port(6881), &[0, 0, 0, 3, 9, 0x1a, 0xe1]),
+        (Message::HaveAll, &[0, 0, 0, 1, 14]),
+        (Message::HaveNone, &[0, 0, 0, 1, 15]),
+        (
+            Message::Extended {
+                id: 3,
+                payload: Bytes::from_static(b"de"),
+            },
+            &[0, 0, 0, 4, 20, 3, b'd', b'e'],
+        ),
+        // a torrent with no pieces has a bitfield of no bytes, which is still a bitfield
+        (Message::Bitfield(Bytes::new()), &[0, 0, 0, 1, 5]),
+        (
+            Message::Unknown {
+                tag: 17,
+                payload: Bytes::from_static(&[0, 0, 0, 4]),
+            },
+            &[0, 0, 0, 5, 17, 0, 0, 0, 4],
+        ),
+    ];
+    for (msg, bytes) in golden {
+        assert_eq!(wire(msg.clone()), bytes, "{msg:?}");
+        let mut src = BytesMut::from(bytes);
+        assert_eq!(
+            MessageFramer::default().decode(&mut src).unwrap(),
+            Some(msg)
+        );
+        assert!(src.is_empty());
+    }
+
+    // a frame that hasn't all arrived yet stays put
+    let mut src = BytesMut::from(&[0, 0, 0, 5, 4, 0, 0][..]);
+    assert_eq!(MessageFramer::default().decode(&mut src).unwrap(), None);
+    assert_eq!(src.len(), 7);
+}
+
+#[test]
+fn malformed_messages_are_rejected() {
+    let decode = |bytes: &[u8]| {
+        MessageFramer::default()
+            .decode(&mut BytesMut::from(bytes))
+            .map_err(|e| e.to_string())
+    };
+    assert_eq!(
+        decode(&[0, 0, 0, 4, 4, 0, 0, 1]),
+        Err("Have message with a payload of 3 bytes.".into())
+    );
+    assert_eq!(
+        decode(&[0, 0, 0, 2, 1, 0]),
+        Err("Unchoke message with a payload of 1 bytes.".into())
+    );
+    assert_eq!(
+        decode(&[0, 0, 0, 8, 7, 0, 0, 0, 1, 0, 0, 0]),
+        Err("Piece message with a payload of 7 bytes.".into())
+    );
+    assert_eq!(
+        decode(&[0, 0, 0, 12, 6, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1]),
+        Err("Request message with a payload of 11 bytes.".into())
+    );
+    assert_eq!(
+        decode(&[0, 0, 0, 1, 20]),
+        Err("Extended message with a payload of 0 bytes.".into())
+    );
+    // a type we don't know isn't malformed, just passed along for whoever reads it to ignore
+    assert_eq!(
+        decode(&[0, 0, 0, 3, 42, 1, 2]),
+        Ok(Some(Message::Unknown {
+            tag: 42,
+            payload: Bytes::from_static(&[1, 2]),
+        }))
+    );
+    assert_eq!(
+        decode(&[0, 0x20, 0, 1, 7]),
+        Err("Frame of length 2097153 is too large.".into())
+    );
+}
+
+#[test]
+fn oversized_frames_are_refused_before_buffering() {
+    // the length alone is enough to turn a frame down, however little of it has arrived
+    let mut src = BytesMut::from(&[0xff, 0xff, 0xff, 0xff, 7][..]);
+    let e = MessageFramer::default().decode(&mut src).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(e.to_string(), "Frame of length 4294967295 is too large.");
+    assert!(src.capacity() < 1 << 10);
+
+    // and the limit is the framer's to set
+    let mut framer = MessageFramer::with_max_frame(1 + 8 + 16);
+    let piece = |len: usize| Message::Piece {
+        index: 0,
+        begin: 0,
+        block: vec![0; len].into(),
+    };
+    let mut bytes = BytesMut::new();
+    framer.encode(piece(16), &mut bytes).unwrap();
+    assert_eq!(framer.decode(&mut bytes).unwrap(), Some(piece(16)));
+    assert!(framer.encode(piece(17), &mut BytesMut::new()).is_err());
+    let mut bytes = BytesMut::new();
+    MessageFramer::default()
+        .encode(piece(17), &mut bytes)
+        .unwrap();
+    assert_eq!(
+        framer.decode(&mut bytes).unwrap_err().to_string(),
+        "Frame of length 26 is too large."
+    );
+
+    // while a frame that fits waits for the rest of itself, however it's cut
+    let whole = wire(piece(16));
+    for cut in 0..whole.len() {
+        let mut src = BytesMut::from(&whole[..cut]);
+        assert_eq!(framer.decode(&mut src).unwrap(), None, "cut at {cut}");
+        src.extend_from_slice(&whole[cut..]);
+        assert_eq!(framer.decode(&mut src).unwrap(), Some(piece(16)));
+    }
+}
+
+#[test]
+fn generated_peer_ids_are_prefixed_and_differ() {
+    let (a, b) = (PeerId::generate(), PeerId::generate());
+    assert_ne!(a, b);
+    assert!(a.0.starts_with(PeerId::PREFIX) && b.0.starts_with(PeerId::PREFIX));
+    assert_eq!(PeerId::ours(), PeerId::ours());
+    assert!(PeerId::set(PeerId::generate()).is_err());
+    assert_eq!(
+        "00112233445566778899".parse(),
+        Ok(PeerId(*b"00112233445566778899"))
+    );
+    assert!("-RS0001-".parse::<PeerId>().is_err());
+}
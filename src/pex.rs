@@ -0,0 +1,137 @@
+//! Peer exchange (BEP 11): peers telling each other about other peers in the swarm.
+
+use crate::bencode::{self, Value};
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+/// The payload of a `ut_pex` extension message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    /// Peers that joined the sender's view of the swarm since its last message.
+    pub added: Vec<SocketAddr>,
+    /// Peers the sender has seen leave since its last message.
+    pub dropped: Vec<SocketAddr>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PexError {
+    #[error(transparent)]
+    Bencode(#[from] bencode::Error),
+    #[error("expected a dictionary, found {0}")]
+    NotADict(&'static str),
+    #[error("`{key}` should be a byte string, found {found}")]
+    NotBytes { key: String, found: &'static str },
+    #[error("`{key}` is {len} bytes long, which isn't a whole number of {entry}-byte addresses")]
+    Length {
+        key: String,
+        len: usize,
+        entry: usize,
+    },
+}
+
+impl PexMessage {
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, PexError> {
+        let (value, _) = bencode::decode(payload)?;
+        let Value::Dict(dict) = value else {
+            return Err(PexError::NotADict(value.kind()));
+        };
+        let mut added = compact(&dict, "added", 6)?;
+        added.extend(compact(&dict, "added6", 18)?);
+        let mut dropped = compact(&dict, "dropped", 6)?;
+        dropped.extend(compact(&dict, "dropped6", 18)?);
+        Ok(Self { added, dropped })
+    }
+}
+
+/// The compact addresses under `key`, if it is present.
+fn compact(
+    dict: &BTreeMap<Vec<u8>, Value>,
+    key: &str,
+    entry: usize,
+) -> Result<Vec<SocketAddr>, PexError> {
+    let bytes = match dict.get(key.as_bytes()) {
+        None => return Ok(Vec::new()),
+        Some(Value::Bytes(bytes)) => bytes,
+        Some(other) => {
+            return Err(PexError::NotBytes {
+                key: key.to_string(),
+                found: other.kind(),
+            })
+        }
+    };
+    if !bytes.len().is_multiple_of(entry) {
+        return Err(PexError::Length {
+            key: key.to_string(),
+            len: bytes.len(),
+            entry,
+        });
+    }
+    Ok(bytes
+        .chunks_exact(entry)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(entry - 2);
+            let port = u16::from_be_bytes([port[0], port[1]]);
+            if let Ok(ip) = <[u8; 4]>::try_from(ip) {
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port))
+            } else {
+                let ip = <[u8; 16]>::try_from(ip).expect("entries are 6 or 18 bytes");
+                SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0))
+            }
+        })
+        .collect())
+}
+
+#[test]
+fn parse_added_and_dropped() {
+    let mut payload = b"d5:added12:".to_vec();
+    payload.extend([10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+    payload.extend(b"7:added.f2:");
+    payload.extend([0x10, 0x00]);
+    payload.extend(b"7:dropped6:");
+    payload.extend([192, 0, 2, 7, 0, 80]);
+    payload.extend(b"8:dropped618:");
+    payload.extend(Ipv6Addr::LOCALHOST.octets());
+    payload.extend([0x1a, 0xe1]);
+    payload.extend(b"e");
+
+    let msg = PexMessage::from_bytes(&payload).unwrap();
+    assert_eq!(
+        msg.added,
+        vec![
+            "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "10.0.0.2:6882".parse().unwrap()
+        ]
+    );
+    assert_eq!(
+        msg.dropped,
+        vec![
+            "192.0.2.7:80".parse::<SocketAddr>().unwrap(),
+            "[::1]:6881".parse().unwrap()
+        ]
+    );
+}
+
+#[test]
+fn parse_rejects_malformed_payloads() {
+    assert_eq!(
+        PexMessage::from_bytes(b"de").unwrap(),
+        PexMessage::default()
+    );
+    assert!(matches!(
+        PexMessage::from_bytes(b"le"),
+        Err(PexError::NotADict("a list"))
+    ));
+    assert!(matches!(
+        PexMessage::from_bytes(b"d7:droppedi3ee"),
+        Err(PexError::NotBytes { .. })
+    ));
+    let e = PexMessage::from_bytes(b"d7:dropped5:abcdee").unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "`dropped` is 5 bytes long, which isn't a whole number of 6-byte addresses"
+    );
+    assert!(matches!(
+        PexMessage::from_bytes(b"d7:dropped"),
+        Err(PexError::Bencode(_))
+    ));
+}
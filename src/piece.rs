@@ -1,5 +1,9 @@
-use crate::{peer::Peer, torrent::Torrent};
-use std::collections::HashSet;
+use crate::peer::Peer;
+use crate::torrent::{Keys, Md5Sum, Torrent};
+use md5::{Digest, Md5};
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Piece {
@@ -30,17 +34,8 @@ impl PartialOrd for Piece {
 
 impl Piece {
     pub(crate) fn new(piece_i: usize, t: &Torrent, peers: &[Peer]) -> Self {
-        let piece_hash = t.info.pieces.0[piece_i];
-        let piece_size = if piece_i == t.info.pieces.0.len() - 1 {
-            let md = t.length() % t.info.plength;
-            if md == 0 {
-                t.info.plength
-            } else {
-                md
-            }
-        } else {
-            t.info.plength
-        };
+        let piece_hash = t.info.pieces[piece_i];
+        let piece_size = t.piece_length_for(piece_i);
 
         let peers = peers
             .iter()
@@ -64,11 +59,546 @@ impl Piece {
         self.piece_i
     }
 
-    pub(crate) fn hash(&self) -> [u8; 20] {
-        self.hash
-    }
-
     pub(crate) fn length(&self) -> usize {
         self.length
     }
 }
+
+/// A run of bytes that lies within a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSpan {
+    pub file_index: usize,
+    /// Where the run starts within the file.
+    pub file_offset: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Placed {
+    /// Where the file starts in the torrent's concatenated data.
+    start: usize,
+    length: usize,
+    padding: bool,
+}
+
+/// Which files each piece's bytes belong to, and which pieces each file's bytes are in.
+///
+/// A single-file torrent is the trivial case of one file covering everything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMap {
+    plength: usize,
+    total: usize,
+    npieces: usize,
+    files: Vec<Placed>,
+}
+
+impl FileMap {
+    pub fn new(t: &Torrent) -> Self {
+        let lengths: Vec<(usize, bool)> = match &t.info.keys {
+            Keys::SingleFile { length } => vec![(*length, false)],
+            Keys::MultiFile { files } => files
+                .iter()
+                .map(|file| (file.length, file.is_padding()))
+                .collect(),
+        };
+        Self::from_lengths(t.info.plength, t.num_pieces(), &lengths)
+    }
+
+    /// Lay out files of the given lengths (and whether each is padding) back to back.
+    fn from_lengths(plength: usize, npieces: usize, lengths: &[(usize, bool)]) -> Self {
+        let mut start = 0;
+        let files = lengths
+            .iter()
+            .map(|&(length, padding)| {
+                let placed = Placed {
+                    start,
+                    length,
+                    padding,
+                };
+                start += length;
+                placed
+            })
+            .collect();
+        Self {
+            plength,
+            total: start,
+            npieces,
+            files,
+        }
+    }
+
+    pub fn files(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Whether file `file_index` is a padding file, whose bytes are all zeros and which never
+    /// goes to disk.
+    pub fn is_padding(&self, file_index: usize) -> bool {
+        self.files[file_index].padding
+    }
+
+    /// The files piece `index` covers, in order; the spans add up to the length of the piece.
+    ///
+    /// Zero-length files never show up, since they hold none of the piece's bytes.
+    pub fn spans_for_piece(&self, index: usize) -> Vec<FileSpan> {
+        assert!(index < self.npieces, "piece {index} out of range");
+        let start = index * self.plength;
+        let end = (start + self.plength).min(self.total);
+        // the first file that ends after the piece starts
+        let first = self
+            .files
+            .partition_point(|file| file.start + file.length <= start);
+        self.files[first..]
+            .iter()
+            .enumerate()
+            .take_while(|(_, file)| file.start < end)
+            .filter(|(_, file)| file.length > 0)
+            .map(|(i, file)| {
+                let from = start.max(file.start);
+                let to = end.min(file.start + file.length);
+                FileSpan {
+                    file_index: first + i,
+                    file_offset: from - file.start,
+                    len: to - from,
+                }
+            })
+            .collect()
+    }
+
+    /// The first and last piece (inclusive) holding any of file `file_index`'s bytes, or `None`
+    /// for an empty file, which needs no pieces at all.
+    pub fn piece_range_for_file(&self, file_index: usize) -> Option<(usize, usize)> {
+        let file = &self.files[file_index];
+        if file.length == 0 {
+            return None;
+        }
+        let last_byte = file.start + file.length - 1;
+        Some((file.start / self.plength, last_byte / self.plength))
+    }
+}
+
+/// Checks a torrent's pieces against data on disk, yielding whether each one is intact, in order.
+///
+/// At most one piece per hashing thread is in memory at a time, however big the torrent. A piece
+/// that runs into a missing or truncated file just fails; only other I/O errors are errors.
+pub struct FileVerifier<'t> {
+    t: &'t Torrent,
+    map: FileMap,
+    files: Vec<Option<std::fs::File>>,
+    next: usize,
+    threads: usize,
+    ready: VecDeque<io::Result<(usize, bool)>>,
+}
+
+impl<'t> FileVerifier<'t> {
+    /// Verify `t` against `files`, which hold a handle for each of its files in order, or `None`
+    /// for files that aren't there. Padding files never need one.
+    pub fn new(t: &'t Torrent, files: Vec<Option<std::fs::File>>) -> Self {
+        Self {
+            map: FileMap::new(t),
+            t,
+            files,
+            next: 0,
+            threads: 1,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Verify `t` against the files under `root`: the file at `root` itself for a single-file
+    /// torrent, or each file at its path below `root` for a multi-file one.
+    pub fn open(t: &'t Torrent, root: &Path) -> Self {
+        let files = match &t.info.keys {
+            Keys::SingleFile { .. } => vec![std::fs::File::open(root).ok()],
+            Keys::MultiFile { files } => files
+                .iter()
+                .map(|file| {
+                    // a file whose path would take us out of `root` counts as missing
+                    let path = file.safe_path(root).ok()?;
+                    (!file.is_padding())
+                        .then(|| std::fs::File::open(path).ok())
+                        .flatten()
+                })
+                .collect(),
+        };
+        Self::new(t, files)
+    }
+
+    /// Hash up to `threads` pieces at once.
+    pub fn parallel(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// The bytes of piece `index` as they are on disk, or `None` if some of them aren't.
+    fn read_piece(&mut self, index: usize) -> io::Result<Option<Vec<u8>>> {
+        let spans = self.map.spans_for_piece(index);
+        let mut data = vec![0u8; spans.iter().map(|span| span.len).sum()];
+        let mut at = 0;
+        for span in spans {
+            let buf = &mut data[at..][..span.len];
+            at += span.len;
+            if self.map.is_padding(span.file_index) {
+                // already zeros
+                continue;
+            }
+            let Some(file) = &mut self.files[span.file_index] else {
+                return Ok(None);
+            };
+            file.seek(SeekFrom::Start(span.file_offset as u64))?;
+            match file.read_exact(buf) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Some(data))
+    }
+}
+
+impl Iterator for FileVerifier<'_> {
+    type Item = io::Result<(usize, bool)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(result) = self.ready.pop_front() {
+            return Some(result);
+        }
+        let npieces = self.t.num_pieces();
+        if self.next >= npieces {
+            return None;
+        }
+        let batch: Vec<_> = (self.next..npieces.min(self.next + self.threads))
+            .map(|index| (index, self.read_piece(index)))
+            .collect();
+        self.next += batch.len();
+
+        let t = self.t;
+        let verify = |(index, data): (usize, io::Result<Option<Vec<u8>>>)| {
+            data.map(|data| (index, data.is_some_and(|data| t.verify_piece(index, &data))))
+        };
+        if batch.len() == 1 {
+            self.ready.extend(batch.into_iter().map(verify));
+        } else {
+            std::thread::scope(|scope| {
+                let hashing: Vec<_> = batch
+                    .into_iter()
+                    .map(|piece| scope.spawn(move || verify(piece)))
+                    .collect();
+                for hashing in hashing {
+                    self.ready
+                        .push_back(hashing.join().expect("hashing doesn't panic"));
+                }
+            });
+        }
+        self.ready.pop_front()
+    }
+}
+
+/// The files under `root` (laid out as for [`FileVerifier::open`]) that don't match the
+/// `md5sum` the torrent gives for them.
+///
+/// Files without a sum, and files that aren't there, are passed over: the pieces in a missing
+/// file fail anyway.
+pub fn md5_mismatches(t: &Torrent, root: &Path) -> io::Result<Vec<PathBuf>> {
+    let sums: Vec<(PathBuf, &Md5Sum)> = match &t.info.keys {
+        Keys::SingleFile { .. } => t
+            .info
+            .md5sum
+            .iter()
+            .map(|sum| (root.to_path_buf(), sum))
+            .collect(),
+        Keys::MultiFile { files } => files
+            .iter()
+            .filter(|file| !file.is_padding())
+            .filter_map(|file| Some((file.safe_path(root).ok()?, file.md5sum.as_ref()?)))
+            .collect(),
+    };
+    let mut mismatches = Vec::new();
+    for (path, sum) in sums {
+        let mut file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let mut md5 = Md5::new();
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            match file.read(&mut buf)? {
+                0 => break,
+                n => md5.update(&buf[..n]),
+            }
+        }
+        if sum.digest() != Some(md5.finalize().into()) {
+            mismatches.push(path);
+        }
+    }
+    Ok(mismatches)
+}
+
+/// The answers [`FileMap`] should give, worked out the slow way: byte by byte.
+#[cfg(test)]
+fn check_layout(plength: usize, lengths: &[usize]) {
+    let total: usize = lengths.iter().sum();
+    let npieces = total.div_ceil(plength);
+    let map = FileMap::from_lengths(
+        plength,
+        npieces,
+        &lengths.iter().map(|&l| (l, false)).collect::<Vec<_>>(),
+    );
+    let owner: Vec<(usize, usize)> = lengths
+        .iter()
+        .enumerate()
+        .flat_map(|(file_i, &length)| (0..length).map(move |offset| (file_i, offset)))
+        .collect();
+
+    for piece_i in 0..npieces {
+        let expected = owner[piece_i * plength..total.min((piece_i + 1) * plength)]
+            .chunk_by(|a, b| a.0 == b.0)
+            .map(|run| FileSpan {
+                file_index: run[0].0,
+                file_offset: run[0].1,
+                len: run.len(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            map.spans_for_piece(piece_i),
+            expected,
+            "piece {piece_i} of {lengths:?} in pieces of {plength}"
+        );
+    }
+    for (file_i, &length) in lengths.iter().enumerate() {
+        let pieces: Vec<usize> = (0..total)
+            .filter(|&byte| owner[byte].0 == file_i)
+            .map(|byte| byte / plength)
+            .collect();
+        let expected = (length > 0).then(|| (pieces[0], *pieces.last().unwrap()));
+        assert_eq!(
+            map.piece_range_for_file(file_i),
+            expected,
+            "file {file_i} of {lengths:?} in pieces of {plength}"
+        );
+    }
+}
+
+#[test]
+fn file_spans_at_boundaries() {
+    // one file, with and without a short last piece
+    check_layout(4, &[8]);
+    check_layout(4, &[9]);
+    // files ending exactly on, just before, and just after piece boundaries
+    check_layout(4, &[4, 4]);
+    check_layout(4, &[3, 5]);
+    check_layout(4, &[5, 3]);
+    // a file entirely inside one piece, and one spanning several
+    check_layout(8, &[2, 1, 30, 3]);
+    // empty files at the start, in the middle, on a boundary and at the end
+    check_layout(4, &[0, 6, 0, 2, 0, 0, 5, 0]);
+    // one byte per piece
+    check_layout(1, &[1, 0, 2, 3]);
+}
+
+#[test]
+fn file_spans_for_random_layouts() {
+    let mut rng = fastrand::Rng::with_seed(226);
+    for _ in 0..500 {
+        let plength = rng.usize(1..=16);
+        let nfiles = rng.usize(1..=8);
+        let lengths: Vec<usize> = (0..nfiles)
+            .map(|_| {
+                if rng.u8(..4) == 0 {
+                    0
+                } else {
+                    rng.usize(1..40)
+                }
+            })
+            .collect();
+        if lengths.iter().sum::<usize>() == 0 {
+            continue;
+        }
+        check_layout(plength, &lengths);
+    }
+}
+
+#[test]
+fn file_map_of_a_torrent_with_padding() {
+    let t = Torrent::from_bytes(include_bytes!(
+        "../tests/fixtures/torrents/multi-file-attrs.torrent"
+    ))
+    .unwrap();
+    // docs/readme.txt (1000), a 15384-byte pad, data/blob.bin (40000), café.txt (7)
+    let map = FileMap::new(&t);
+    assert_eq!(map.files(), 4);
+    assert!(map.is_padding(1));
+    assert!(!map.is_padding(2));
+    assert_eq!(
+        map.spans_for_piece(0),
+        vec![
+            FileSpan {
+                file_index: 0,
+                file_offset: 0,
+                len: 1000
+            },
+            FileSpan {
+                file_index: 1,
+                file_offset: 0,
+                len: 15384
+            },
+        ]
+    );
+    // the padding lines blob.bin up with the start of piece 1
+    assert_eq!(map.piece_range_for_file(2), Some((1, 3)));
+    assert_eq!(map.piece_range_for_file(3), Some((3, 3)));
+    assert_eq!(
+        map.spans_for_piece(3),
+        vec![
+            FileSpan {
+                file_index: 2,
+                file_offset: 2 * 16384,
+                len: 40000 - 2 * 16384
+            },
+            FileSpan {
+                file_index: 3,
+                file_offset: 0,
+                len: 7
+            },
+        ]
+    );
+}
+
+#[cfg(test)]
+pub(crate) fn multi_file_torrent(
+    data: &[u8],
+    plength: usize,
+    files: &[(&str, usize, bool)],
+) -> Torrent {
+    use crate::torrent::{File, Info};
+    use sha1::{Digest, Sha1};
+
+    let info = Info {
+        name: String::from("multi"),
+        plength,
+        pieces: data
+            .chunks(plength)
+            .map(|p| Sha1::digest(p).into())
+            .collect(),
+        keys: Keys::MultiFile {
+            files: files
+                .iter()
+                .map(|&(path, length, padding)| File {
+                    length,
+                    path: vec![path.to_string()],
+                    attr: padding.then(|| String::from("p")),
+                    md5sum: None,
+                    extra: Default::default(),
+                })
+                .collect(),
+        },
+        private: None,
+        md5sum: None,
+        extra: Default::default(),
+    };
+    Torrent::new(String::from("http://unused/announce"), info)
+}
+
+#[test]
+fn verifying_a_single_file() {
+    let data = crate::mock::data(5 * 1000 + 10);
+    let t = crate::mock::torrent_for("http://unused/announce", &data, 1000);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mock.bin");
+
+    let results = |t| {
+        FileVerifier::open(t, &path)
+            .map(|r| r.unwrap().1)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(results(&t), vec![false; 6], "there's no file yet");
+
+    let mut corrupted = data.clone();
+    corrupted[2500] ^= 1;
+    // and it stops short in the middle of the last piece
+    std::fs::write(&path, &corrupted[..corrupted.len() - 5]).unwrap();
+    assert_eq!(results(&t), vec![true, true, false, true, true, false]);
+
+    std::fs::write(&path, &data).unwrap();
+    assert_eq!(results(&t), vec![true; 6]);
+}
+
+#[test]
+fn verifying_multiple_files_in_parallel() {
+    // a piece spanning three files, a padding file, and a file that isn't there
+    let lengths = [
+        ("a", 250, false),
+        ("b", 100, false),
+        ("pad", 50, true),
+        ("c", 700, false),
+    ];
+    let mut data = crate::mock::data(1100);
+    data[350..400].fill(0);
+    let t = multi_file_torrent(&data, 300, &lengths);
+    assert_eq!(t.num_pieces(), 4);
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a"), &data[..250]).unwrap();
+    std::fs::write(dir.path().join("b"), &data[250..350]).unwrap();
+    let results = |threads| {
+        FileVerifier::open(&t, dir.path())
+            .parallel(threads)
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>()
+    };
+    // only the first piece lies wholly in files that exist
+    assert_eq!(
+        results(1),
+        vec![(0, true), (1, false), (2, false), (3, false)]
+    );
+
+    let mut c = data[400..].to_vec();
+    c[650] ^= 1;
+    std::fs::write(dir.path().join("c"), &c).unwrap();
+    for threads in [1, 3, 8] {
+        assert_eq!(
+            results(threads),
+            vec![(0, true), (1, true), (2, true), (3, false)]
+        );
+    }
+}
+
+#[test]
+fn md5sums_are_checked_where_given() {
+    use crate::torrent::Md5Sum;
+
+    let data = crate::mock::data(1000);
+    let mut t = multi_file_torrent(
+        &data,
+        256,
+        &[
+            ("a", 300, false),
+            ("b", 300, false),
+            ("c", 300, false),
+            ("d", 100, false),
+        ],
+    );
+    let md5 = |bytes: &[u8]| {
+        let mut md5 = Md5::new();
+        md5.update(bytes);
+        md5.finalize()
+    };
+    let Keys::MultiFile { files } = &mut t.info.keys else {
+        unreachable!()
+    };
+    // in hex, as raw bytes, wrong, and for a file that isn't there; d has none
+    files[0].md5sum = Some(Md5Sum(hex::encode(md5(&data[..300])).into_bytes()));
+    files[1].md5sum = Some(Md5Sum(md5(&data[300..600]).to_vec()));
+    files[2].md5sum = Some(Md5Sum(hex::encode(md5(b"something else")).into_bytes()));
+    files[3].md5sum = None;
+
+    let dir = tempfile::tempdir().unwrap();
+    for (name, range) in [("a", 0..300), ("b", 300..600), ("c", 600..900)] {
+        std::fs::write(dir.path().join(name), &data[range]).unwrap();
+    }
+    let mismatches = md5_mismatches(&t, dir.path()).unwrap();
+    assert_eq!(mismatches, [dir.path().join("c")]);
+
+    std::fs::remove_file(dir.path().join("c")).unwrap();
+    assert!(md5_mismatches(&t, dir.path()).unwrap().is_empty());
+}
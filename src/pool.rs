@@ -0,0 +1,409 @@
+//! The set of peer addresses we know about, and when each may next be dialed.
+
+use crate::pex::PexMessage;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// How long to wait before redialing a peer after its first disconnect.
+pub const BACKOFF_BASE: Duration = Duration::from_secs(30);
+
+/// The longest we'll ever wait before redialing a peer.
+pub const BACKOFF_MAX: Duration = Duration::from_secs(15 * 60);
+
+/// The most addresses any one peer's PEX messages may remove from the pool per [`PEX_WINDOW`].
+///
+/// This limits how much damage a peer feeding us bogus `dropped` lists can do.
+pub const PEX_DROP_CAP: usize = 25;
+
+pub const PEX_WINDOW: Duration = Duration::from_secs(60);
+
+/// How many different peers have to agree on our external address before we believe them.
+pub const YOURIP_QUORUM: usize = 2;
+
+#[derive(Debug, Default)]
+struct Entry {
+    /// Disconnects and failed dials since we last exchanged data with this peer.
+    failures: u32,
+    /// Not dialable again before this time.
+    retry_at: Option<Instant>,
+    /// Currently being dialed or connected.
+    in_use: bool,
+    /// The peer id the tracker gave for this address, which its handshake should carry.
+    peer_id: Option<[u8; 20]>,
+}
+
+/// Known peer addresses, with per-address cooldowns after disconnects.
+///
+/// All methods take the current time as an argument rather than reading the clock, which keeps
+/// the backoff logic deterministic.
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    entries: HashMap<SocketAddr, Entry>,
+    /// Addresses in the order we learned about them, which is also the order we dial them in.
+    order: Vec<SocketAddr>,
+    /// Per PEX source: when its current window started, and how many addresses it removed since.
+    pex_drops: HashMap<SocketAddr, (Instant, usize)>,
+    /// Per reporting peer IP: the address it last said we have.
+    yourip: HashMap<IpAddr, IpAddr>,
+    /// Whether the torrent is private, so that peers may not tell us about other peers.
+    private: bool,
+}
+
+impl PeerPool {
+    /// A pool for a private torrent (BEP 27), which ignores whatever peers tell us about the
+    /// swarm: its peers only come from the tracker, or from whoever we were told to dial.
+    pub fn private() -> Self {
+        Self {
+            private: true,
+            ..Self::default()
+        }
+    }
+
+    /// Learn about `addr`. Returns `false` if we already knew it, in which case any cooldown it is
+    /// serving stays in place.
+    pub fn add(&mut self, addr: SocketAddr) -> bool {
+        if self.entries.contains_key(&addr) {
+            return false;
+        }
+        self.entries.insert(addr, Entry::default());
+        self.order.push(addr);
+        true
+    }
+
+    /// Remember that the tracker says `addr` goes by `peer_id`, if we know `addr`.
+    pub fn expect_id(&mut self, addr: SocketAddr, peer_id: [u8; 20]) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.peer_id = Some(peer_id);
+        }
+    }
+
+    /// The peer id the tracker gave for `addr`, if it gave one.
+    pub fn peer_id(&self, addr: SocketAddr) -> Option<[u8; 20]> {
+        self.entries.get(&addr).and_then(|entry| entry.peer_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Pick the next address that isn't in use or cooling down, and mark it as in use.
+    pub fn next_dialable(&mut self, now: Instant) -> Option<SocketAddr> {
+        let entries = &mut self.entries;
+        let addr = *self.order.iter().find(|addr| {
+            let entry = &entries[addr];
+            !entry.in_use && entry.retry_at.is_none_or(|at| at <= now)
+        })?;
+        entries.get_mut(&addr).expect("in order").in_use = true;
+        Some(addr)
+    }
+
+    /// Hand back an address from [`PeerPool::next_dialable`] that we ended up not dialing.
+    pub fn release(&mut self, addr: SocketAddr) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.in_use = false;
+        }
+    }
+
+    /// A peer sent us useful data, so it is healthy again.
+    pub fn exchanged_data(&mut self, addr: SocketAddr) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.failures = 0;
+            entry.retry_at = None;
+        }
+    }
+
+    /// A dial to `addr` failed, or an established connection to it went away.
+    pub fn disconnected(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.in_use = false;
+            entry.failures += 1;
+            entry.retry_at = Some(now + backoff(entry.failures));
+        }
+    }
+
+    /// Forget `addr` entirely, cooldown and all. Returns `false` if we didn't know it.
+    pub fn remove(&mut self, addr: SocketAddr) -> bool {
+        if self.entries.remove(&addr).is_none() {
+            return false;
+        }
+        self.order.retain(|&a| a != addr);
+        true
+    }
+
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        self.entries.contains_key(&addr)
+    }
+
+    /// A peer we know as `seen` (usually the source address of its connection) says it listens
+    /// on `listen`, so from now on that is the address we keep, dial, and hand out.
+    ///
+    /// Whatever state `seen` had moves over, unless we already knew `listen`, in which case that
+    /// entry wins and `seen` is just forgotten.
+    pub fn canonicalize(&mut self, seen: SocketAddr, listen: SocketAddr) {
+        if seen == listen {
+            self.add(listen);
+            return;
+        }
+        let Some(entry) = self.entries.remove(&seen) else {
+            self.add(listen);
+            return;
+        };
+        if let Some(known) = self.entries.get_mut(&listen) {
+            known.in_use |= entry.in_use;
+            self.order.retain(|&a| a != seen);
+        } else {
+            self.entries.insert(listen, entry);
+            for addr in &mut self.order {
+                if *addr == seen {
+                    *addr = listen;
+                }
+            }
+        }
+    }
+
+    /// The peer at `reporter` told us (its `yourip`) that our address is `ours`.
+    pub fn observe_yourip(&mut self, reporter: IpAddr, ours: IpAddr) {
+        self.yourip.insert(reporter, ours);
+    }
+
+    /// Our external address, once at least [`YOURIP_QUORUM`] peers agree on it and more of them
+    /// say so than say anything else.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        let mut votes = HashMap::<IpAddr, usize>::new();
+        for &ip in self.yourip.values() {
+            *votes.entry(ip).or_default() += 1;
+        }
+        let mut ranked: Vec<(IpAddr, usize)> = votes.into_iter().collect();
+        ranked.sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+        match ranked[..] {
+            [(ip, n), ..]
+                if n >= YOURIP_QUORUM && ranked.get(1).is_none_or(|other| other.1 < n) =>
+            {
+                Some(ip)
+            }
+            _ => None,
+        }
+    }
+
+    /// Apply a PEX message from `source`: learn the added peers and forget the dropped ones.
+    ///
+    /// Peers we are dialing or connected to are kept no matter what `source` says, and `source`
+    /// can remove at most [`PEX_DROP_CAP`] addresses per [`PEX_WINDOW`]. Returns how many addresses
+    /// were removed. A [`PeerPool::private`] one ignores PEX altogether.
+    pub fn apply_pex(&mut self, source: SocketAddr, msg: &PexMessage, now: Instant) -> usize {
+        if self.private {
+            return 0;
+        }
+        for &addr in &msg.added {
+            self.add(addr);
+        }
+
+        self.pex_drops
+            .retain(|_, (since, _)| now.duration_since(*since) < PEX_WINDOW);
+        let (_, removed) = self.pex_drops.entry(source).or_insert((now, 0));
+        let before = *removed;
+        for &addr in &msg.dropped {
+            if *removed >= PEX_DROP_CAP {
+                break;
+            }
+            let Some(entry) = self.entries.get(&addr) else {
+                continue;
+            };
+            if entry.in_use || addr == source {
+                continue;
+            }
+            self.entries.remove(&addr);
+            self.order.retain(|&a| a != addr);
+            *removed += 1;
+        }
+        *removed - before
+    }
+
+    /// When `addr` may next be dialed, if it is cooling down.
+    pub fn retry_at(&self, addr: SocketAddr) -> Option<Instant> {
+        self.entries.get(&addr)?.retry_at
+    }
+}
+
+/// The cooldown after the `failures`th consecutive failure.
+fn backoff(failures: u32) -> Duration {
+    BACKOFF_BASE
+        .checked_mul(1 << failures.saturating_sub(1).min(16))
+        .map_or(BACKOFF_MAX, |d| d.min(BACKOFF_MAX))
+}
+
+#[test]
+fn backoff_schedule() {
+    let schedule: Vec<_> = (1..=7).map(|n| backoff(n).as_secs()).collect();
+    assert_eq!(schedule, vec![30, 60, 120, 240, 480, 900, 900]);
+    assert_eq!(backoff(u32::MAX), BACKOFF_MAX);
+}
+
+#[test]
+fn cooldowns_gate_dialing() {
+    let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+    let t0 = Instant::now();
+    let mut pool = PeerPool::default();
+    assert!(pool.add(a));
+    assert!(pool.add(b));
+
+    assert_eq!(pool.next_dialable(t0), Some(a));
+    assert_eq!(pool.next_dialable(t0), Some(b));
+    assert_eq!(pool.next_dialable(t0), None);
+
+    pool.disconnected(a, t0);
+    pool.release(b);
+    assert_eq!(pool.retry_at(a), Some(t0 + Duration::from_secs(30)));
+    assert_eq!(pool.next_dialable(t0), Some(b));
+    assert_eq!(pool.next_dialable(t0 + Duration::from_secs(29)), None);
+    assert_eq!(pool.next_dialable(t0 + Duration::from_secs(30)), Some(a));
+
+    // a second failure in a row doubles the wait
+    let t1 = t0 + Duration::from_secs(30);
+    pool.disconnected(a, t1);
+    assert_eq!(pool.retry_at(a), Some(t1 + Duration::from_secs(60)));
+
+    // the tracker handing it out again doesn't skip the cooldown
+    assert!(!pool.add(a));
+    assert_eq!(pool.len(), 2);
+    assert_eq!(pool.next_dialable(t1), None);
+
+    // but trading data with it does reset the schedule
+    let t2 = t1 + Duration::from_secs(60);
+    assert_eq!(pool.next_dialable(t2), Some(a));
+    pool.exchanged_data(a);
+    pool.disconnected(a, t2);
+    assert_eq!(pool.retry_at(a), Some(t2 + Duration::from_secs(30)));
+}
+
+#[test]
+fn pex_drops_idle_peers_only() {
+    let source: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let connected: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+    let idle: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+    let t0 = Instant::now();
+    let mut pool = PeerPool::default();
+    for addr in [source, connected, idle] {
+        pool.add(addr);
+    }
+    assert_eq!(pool.next_dialable(t0), Some(source));
+    assert_eq!(pool.next_dialable(t0), Some(connected));
+    pool.disconnected(idle, t0);
+
+    let msg = PexMessage {
+        added: vec!["10.0.0.4:6881".parse().unwrap()],
+        dropped: vec![source, connected, idle, "10.0.0.9:6881".parse().unwrap()],
+    };
+    assert_eq!(pool.apply_pex(source, &msg, t0), 1);
+    assert_eq!(pool.len(), 3);
+    // its cooldown went with it, so if it turns up again it is dialable straight away
+    assert_eq!(pool.retry_at(idle), None);
+    assert!(pool.add(idle));
+    assert_eq!(
+        pool.next_dialable(t0),
+        Some("10.0.0.4:6881".parse().unwrap())
+    );
+    assert_eq!(pool.next_dialable(t0), Some(idle));
+}
+
+#[test]
+fn private_pools_ignore_pex() {
+    let t0 = Instant::now();
+    let source: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let known: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+    let mut pool = PeerPool::private();
+    pool.add(known);
+    let msg = PexMessage {
+        added: vec!["10.0.0.4:6881".parse().unwrap()],
+        dropped: vec![known],
+    };
+    assert_eq!(pool.apply_pex(source, &msg, t0), 0);
+    assert_eq!(pool.len(), 1);
+    assert_eq!(pool.next_dialable(t0), Some(known));
+}
+
+#[test]
+fn pex_drops_are_capped_per_source() {
+    let t0 = Instant::now();
+    let mut pool = PeerPool::default();
+    let addrs: Vec<SocketAddr> = (0..100)
+        .map(|i| SocketAddr::from(([10, 1, 0, i as u8], 6881)))
+        .collect();
+    for &addr in &addrs {
+        pool.add(addr);
+    }
+    let a: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+    let b: SocketAddr = "192.0.2.2:6881".parse().unwrap();
+    let msg = PexMessage {
+        added: Vec::new(),
+        dropped: addrs.clone(),
+    };
+
+    assert_eq!(pool.apply_pex(a, &msg, t0), PEX_DROP_CAP);
+    assert_eq!(pool.apply_pex(a, &msg, t0 + Duration::from_secs(1)), 0);
+    // another peer has its own budget
+    assert_eq!(pool.apply_pex(b, &msg, t0), PEX_DROP_CAP);
+    // and the budget refills once the window is over
+    assert_eq!(pool.apply_pex(a, &msg, t0 + PEX_WINDOW), PEX_DROP_CAP);
+    assert_eq!(pool.len(), 100 - 3 * PEX_DROP_CAP);
+}
+
+#[test]
+fn canonical_addresses_replace_source_addresses() {
+    let t0 = Instant::now();
+    let seen: SocketAddr = "10.0.0.1:51234".parse().unwrap();
+    let listen: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let other: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+    let mut pool = PeerPool::default();
+    pool.add(seen);
+    pool.add(other);
+    pool.disconnected(seen, t0);
+
+    // the entry keeps its place in the dial order and its cooldown, under the new address
+    pool.canonicalize(seen, listen);
+    assert!(!pool.contains(seen));
+    assert_eq!(pool.retry_at(listen), Some(t0 + BACKOFF_BASE));
+    assert_eq!(pool.len(), 2);
+    let later = t0 + BACKOFF_BASE;
+    assert_eq!(pool.next_dialable(later), Some(listen));
+
+    // if we already knew the listen address, the two merge
+    let seen2: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+    pool.add(seen2);
+    pool.canonicalize(seen2, other);
+    assert_eq!(pool.len(), 2);
+    assert!(!pool.contains(seen2));
+
+    // and one we never heard of is simply learned
+    pool.canonicalize(
+        "10.0.0.3:40000".parse().unwrap(),
+        "10.0.0.3:7000".parse().unwrap(),
+    );
+    assert!(pool.contains("10.0.0.3:7000".parse().unwrap()));
+}
+
+#[test]
+fn external_ip_needs_a_quorum() {
+    let ours: IpAddr = "203.0.113.5".parse().unwrap();
+    let liar: IpAddr = "198.51.100.1".parse().unwrap();
+    let mut pool = PeerPool::default();
+    pool.observe_yourip("10.0.0.1".parse().unwrap(), ours);
+    assert_eq!(pool.external_ip(), None);
+    // one peer repeating itself doesn't count twice
+    pool.observe_yourip("10.0.0.1".parse().unwrap(), ours);
+    assert_eq!(pool.external_ip(), None);
+    pool.observe_yourip("10.0.0.2".parse().unwrap(), ours);
+    assert_eq!(pool.external_ip(), Some(ours));
+
+    pool.observe_yourip("10.0.0.3".parse().unwrap(), liar);
+    pool.observe_yourip("10.0.0.4".parse().unwrap(), liar);
+    assert_eq!(pool.external_ip(), None, "a tie settles nothing");
+    pool.observe_yourip("10.0.0.5".parse().unwrap(), ours);
+    assert_eq!(pool.external_ip(), Some(ours));
+}
@@ -0,0 +1,212 @@
+//! Rendering where a download is at.
+//!
+//! Everything here works on a [`PieceMap`] snapshot, so it is pure and doesn't care whether the
+//! download is still running.
+
+use crate::tracker::{Disconnect, TransferStats};
+use serde::Serialize;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PieceState {
+    #[default]
+    Pending,
+    InFlight,
+    Done,
+    /// The last attempt at this piece failed its hash check.
+    Failed,
+}
+
+/// The state of every piece of a torrent at one point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceMap(pub Vec<PieceState>);
+
+impl PieceMap {
+    pub fn done(&self) -> usize {
+        self.0.iter().filter(|&&s| s == PieceState::Done).count()
+    }
+
+    /// Draw the map as rows of at most `width` characters, one per piece or, when there are more
+    /// pieces than fit in `rows` rows, one per bucket of adjacent pieces.
+    ///
+    /// `#` is done, `.` pending, `>` in flight and `x` failed. A bucket shows its most interesting
+    /// state: a failure anywhere in it, then anything in flight, then `+` if it's partly done.
+    pub fn grid(&self, width: usize, rows: usize) -> String {
+        let width = width.max(1);
+        let cells = width * rows.max(1);
+        let per_cell = self.0.len().div_ceil(cells).max(1);
+        let chars: Vec<char> = self.0.chunks(per_cell).map(cell).collect();
+        chars
+            .chunks(width)
+            .map(|row| row.iter().collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The done pieces as a BEP 3 bitfield: piece 0 is the high bit of the first byte.
+    pub fn bitfield(&self) -> Vec<u8> {
+        let mut bitfield = vec![0u8; self.0.len().div_ceil(8)];
+        for (piece_i, &state) in self.0.iter().enumerate() {
+            if state == PieceState::Done {
+                bitfield[piece_i / 8] |= 0x80 >> (piece_i % 8);
+            }
+        }
+        bitfield
+    }
+}
+
+fn cell(pieces: &[PieceState]) -> char {
+    let any = |state| pieces.contains(&state);
+    if any(PieceState::Failed) {
+        'x'
+    } else if any(PieceState::InFlight) {
+        '>'
+    } else if pieces.iter().all(|&s| s == PieceState::Done) {
+        '#'
+    } else if any(PieceState::Done) {
+        '+'
+    } else {
+        '.'
+    }
+}
+
+/// Columns in the progress map.
+pub const MAP_WIDTH: usize = 64;
+
+/// Rows in the progress map; bigger torrents get bucketed to fit.
+pub const MAP_ROWS: usize = 8;
+
+/// The status block we redraw on every progress tick: a summary line, optionally followed by the
+/// piece map.
+pub fn status(map: &PieceMap, downloaded: usize, total: usize, with_map: bool) -> String {
+    let percent = if total == 0 {
+        100.0
+    } else {
+        downloaded as f64 * 100.0 / total as f64
+    };
+    let mut status = format!(
+        "{percent:5.1}% ({downloaded}/{total} bytes, {}/{} pieces)",
+        map.done(),
+        map.0.len()
+    );
+    if with_map {
+        status.push('\n');
+        status.push_str(&map.grid(MAP_WIDTH, MAP_ROWS));
+    }
+    status
+}
+
+/// The line an interactive download shows under the status when asked for connection stats.
+pub fn connection_stats(stats: &TransferStats) -> String {
+    let disconnects: Vec<String> = Disconnect::ALL
+        .iter()
+        .map(|&reason| format!("{} {}", reason.as_str(), stats.disconnects(reason)))
+        .collect();
+    let mut line = format!(
+        "{} peers connected, {} hash failures, disconnects: {}",
+        stats.connected_peers(),
+        stats.hash_failures(),
+        disconnects.join(", ")
+    );
+    if let Some(at) = stats.next_announce() {
+        let secs = at.saturating_duration_since(Instant::now()).as_secs();
+        line.push_str(&format!(", next announce in {secs}s"));
+    }
+    line
+}
+
+/// One line of `--json-progress` output.
+#[derive(Debug, Serialize)]
+pub struct ProgressEvent {
+    pub downloaded: usize,
+    pub total: usize,
+    pub pieces_done: usize,
+    pub pieces_total: usize,
+    /// [`PieceMap::bitfield`], hex-encoded.
+    pub bitfield: String,
+}
+
+impl ProgressEvent {
+    pub fn new(map: &PieceMap, downloaded: usize, total: usize) -> Self {
+        Self {
+            downloaded,
+            total,
+            pieces_done: map.done(),
+            pieces_total: map.0.len(),
+            bitfield: hex::encode(map.bitfield()),
+        }
+    }
+}
+
+#[cfg(test)]
+fn map(states: &str) -> PieceMap {
+    PieceMap(
+        states
+            .chars()
+            .map(|c| match c {
+                '#' => PieceState::Done,
+                '>' => PieceState::InFlight,
+                'x' => PieceState::Failed,
+                _ => PieceState::Pending,
+            })
+            .collect(),
+    )
+}
+
+#[test]
+fn grid_one_char_per_piece() {
+    let m = map("##>..x#.#");
+    assert_eq!(m.grid(4, 10), "##>.\n.x#.\n#");
+    assert_eq!(m.done(), 4);
+}
+
+#[test]
+fn grid_buckets_large_torrents() {
+    // 4000 pieces into a 10x2 grid: 200 pieces per cell
+    let mut states = vec![PieceState::Done; 4000];
+    states[1000] = PieceState::Pending; // one hole in cell 5
+    states[2500] = PieceState::InFlight; // cell 12
+    states[3999] = PieceState::Failed; // the last cell
+    for state in &mut states[1400..1800] {
+        *state = PieceState::Pending; // cells 7 and 8
+    }
+    let grid = PieceMap(states).grid(10, 2);
+    assert_eq!(grid, "#####+#..#\n##>######x");
+}
+
+#[test]
+fn bitfield_of_done_pieces() {
+    let m = map("#.#.....##");
+    assert_eq!(m.bitfield(), vec![0b1010_0000, 0b1100_0000]);
+    let event = ProgressEvent::new(&m, 40, 100);
+    assert_eq!(event.bitfield, "a0c0");
+    assert_eq!(event.pieces_done, 4);
+    assert_eq!(event.pieces_total, 10);
+}
+
+#[test]
+fn status_block() {
+    let m = map("##..");
+    assert_eq!(
+        status(&m, 50, 100, false),
+        " 50.0% (50/100 bytes, 2/4 pieces)"
+    );
+    assert_eq!(
+        status(&m, 50, 100, true),
+        " 50.0% (50/100 bytes, 2/4 pieces)\n##.."
+    );
+}
+
+#[test]
+fn connection_stats_line() {
+    let stats = TransferStats::default();
+    let _peer = stats.connected();
+    stats.record_hash_failure();
+    stats.record_disconnect(Disconnect::Dial);
+    assert_eq!(
+        connection_stats(&stats),
+        "1 peers connected, 1 hash failures, disconnects: dial 1, protocol_violation 0, error 0"
+    );
+    stats.set_next_announce(Instant::now() + std::time::Duration::from_millis(90_500));
+    assert!(connection_stats(&stats).ends_with("error 0, next announce in 90s"));
+}
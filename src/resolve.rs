@@ -0,0 +1,208 @@
+//! Turning `host:port` strings into connections.
+//!
+//! Peers can be named by hostname as well as by address literal, wherever they come from (the
+//! command line, or a tracker's dictionary-model peer list), and all of those go through here.
+
+use anyhow::Context;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tokio::net::{TcpSocket, TcpStream};
+
+static BIND: OnceLock<IpAddr> = OnceLock::new();
+
+/// Make every connection we start, to peers and trackers alike, come from `ip`, for hosts with
+/// more than one address to pick from.
+///
+/// This fails if `ip` isn't one of ours, or if there's already an address to bind to.
+pub fn bind_to(ip: IpAddr) -> anyhow::Result<()> {
+    std::net::TcpListener::bind((ip, 0)).with_context(|| format!("can't bind to {ip}"))?;
+    BIND.set(ip)
+        .map_err(|_| anyhow::anyhow!("already binding to {}", BIND.get().expect("just set")))
+}
+
+/// The address [`bind_to`] said to connect from, if any.
+pub fn bound() -> Option<IpAddr> {
+    BIND.get().copied()
+}
+
+/// Connect to `addr`, from the address given to [`bind_to`] if there is one.
+pub async fn tcp_connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    connect_from(addr, bound()).await
+}
+
+async fn connect_from(addr: SocketAddr, from: Option<IpAddr>) -> io::Result<TcpStream> {
+    let Some(ip) = from else {
+        return TcpStream::connect(addr).await;
+    };
+    if ip.is_ipv4() != addr.is_ipv4() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("can't reach {addr} from {ip}, which is the other address family"),
+        ));
+    }
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket
+        .bind(SocketAddr::new(ip, 0))
+        .map_err(|e| io::Error::new(e.kind(), format!("can't bind to {ip}: {e}")))?;
+    socket.connect(addr).await
+}
+
+/// Which address family to try first when a name resolves to both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Prefer {
+    /// Keep whatever order the resolver gave us.
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    #[error("could not resolve `{host}`")]
+    Resolve {
+        host: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("`{host}` resolved to no addresses")]
+    NoAddresses { host: String },
+    #[error("could not connect to `{host}` (tried {})", list(.tried))]
+    Connect {
+        host: String,
+        tried: Vec<SocketAddr>,
+        /// Why the last address failed.
+        #[source]
+        source: io::Error,
+    },
+}
+
+fn list(addrs: &[SocketAddr]) -> String {
+    addrs
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Resolve `host` (a `name:port` or an address literal) into the addresses to try, in order.
+pub async fn resolve(host: &str, prefer: Prefer) -> Result<Vec<SocketAddr>, ConnectError> {
+    // skip the resolver for literals, so they work even where DNS doesn't
+    let mut addrs: Vec<SocketAddr> = match SocketAddr::from_str(host) {
+        Ok(addr) => vec![addr],
+        Err(_) => tokio::net::lookup_host(host)
+            .await
+            .map_err(|source| ConnectError::Resolve {
+                host: host.to_string(),
+                source,
+            })?
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err(ConnectError::NoAddresses {
+            host: host.to_string(),
+        });
+    }
+    // a stable sort keeps the resolver's order within each family
+    match prefer {
+        Prefer::Any => {}
+        Prefer::V4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+        Prefer::V6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+    }
+    Ok(addrs)
+}
+
+/// Connect to the first address `host` resolves to that accepts, returning which one that was.
+pub async fn connect(host: &str, prefer: Prefer) -> Result<(TcpStream, SocketAddr), ConnectError> {
+    let addrs = resolve(host, prefer).await?;
+    let mut last_error = None;
+    for &addr in &addrs {
+        match tcp_connect(addr).await {
+            Ok(stream) => return Ok((stream, addr)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(ConnectError::Connect {
+        host: host.to_string(),
+        tried: addrs,
+        source: last_error.expect("resolve never returns an empty list"),
+    })
+}
+
+#[tokio::test]
+async fn literals_and_preferences() {
+    assert_eq!(
+        resolve("10.0.0.1:6881", Prefer::V6).await.unwrap(),
+        vec!["10.0.0.1:6881".parse().unwrap()]
+    );
+    let v4 = resolve("localhost:6881", Prefer::V4).await.unwrap();
+    assert!(v4[0].is_ipv4(), "{v4:?}");
+    assert!(v4.iter().all(|addr| addr.port() == 6881));
+    let v6 = resolve("localhost:6881", Prefer::V6).await.unwrap();
+    assert_eq!(v6.len(), v4.len());
+    if v6.iter().any(|addr| addr.is_ipv6()) {
+        assert!(v6[0].is_ipv6(), "{v6:?}");
+    }
+}
+
+#[tokio::test]
+async fn connect_by_name() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // whatever order localhost resolves in, we end up at the one address that listens
+    let (_, addr) = connect(&format!("localhost:{port}"), Prefer::V6)
+        .await
+        .unwrap();
+    assert_eq!(addr, SocketAddr::from(([127, 0, 0, 1], port)));
+}
+
+#[tokio::test]
+async fn resolution_and_connection_failures_differ() {
+    let e = connect("no-such-host.invalid:6881", Prefer::Any)
+        .await
+        .unwrap_err();
+    assert!(matches!(e, ConnectError::Resolve { .. }), "{e:?}");
+    let e = connect("localhost", Prefer::Any).await.unwrap_err();
+    assert!(matches!(e, ConnectError::Resolve { .. }), "{e:?}");
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    let e = connect(&addr.to_string(), Prefer::Any).await.unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        format!("could not connect to `{addr}` (tried {addr})")
+    );
+}
+
+#[tokio::test]
+async fn connections_come_from_the_bound_address() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let from: IpAddr = "127.0.0.2".parse().unwrap();
+    let (stream, accepted) = tokio::join!(connect_from(addr, Some(from)), listener.accept());
+    assert_eq!(stream.unwrap().local_addr().unwrap().ip(), from);
+    assert_eq!(accepted.unwrap().1.ip(), from);
+
+    let e = connect_from(addr, Some("192.0.2.1".parse().unwrap()))
+        .await
+        .unwrap_err();
+    assert!(
+        e.to_string().starts_with("can't bind to 192.0.2.1: "),
+        "{e}"
+    );
+    let e = connect_from(addr, Some("::1".parse().unwrap()))
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    assert!(bind_to("192.0.2.1".parse().unwrap())
+        .unwrap_err()
+        .to_string()
+        .contains("192.0.2.1"));
+}
@@ -0,0 +1,570 @@
+//! Uploading a torrent we already have all of.
+//!
+//! [`Seed`] is as small as a seed gets while still interoperating with our downloads: it has
+//! every piece, hands its upload slots to interested peers as a [`Choker`] sees fit, and answers
+//! their requests out of a [`Storage`]. It talks to no tracker.
+
+use crate::choke::{Choker, Mode, Observation};
+use crate::download::{self, FileStorage, Storage};
+use crate::lock::SessionLock;
+use crate::peer::{Handshake, Message, MessageFramer};
+use crate::piece::FileVerifier;
+use crate::progress::{PieceMap, PieceState};
+use crate::torrent::{InfoHash, Keys, Torrent};
+use crate::BLOCK_MAX;
+use anyhow::Context;
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Mutex};
+use tokio_util::codec::Framed;
+
+/// The most requests a peer may have waiting on us; one that sends more is dropped, since no
+/// well-behaved peer pipelines anywhere near this deep.
+pub const MAX_QUEUED: usize = 250;
+
+/// How often a seed reconsiders who gets its upload slots.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A single torrent's worth of data, and everything needed to hand it out.
+pub struct Seed<S> {
+    info_hash: InfoHash,
+    npieces: usize,
+    plength: usize,
+    length: usize,
+    storage: Mutex<S>,
+    choking: std::sync::Mutex<Choking>,
+    /// Keeps downloads off the file while we serve it, if it is one.
+    _lock: Option<SessionLock>,
+}
+
+/// Who has an upload slot, and what the choker goes on to decide that.
+struct Choking {
+    choker: Choker,
+    connections: HashMap<SocketAddr, Connection>,
+    /// When the last rechoke round ran, which is what upload rates are measured from.
+    last_round: Instant,
+}
+
+/// What the choker knows about one connection, and how it tells the connection what it decided.
+struct Connection {
+    interested: bool,
+    last_request: Option<Instant>,
+    /// Bytes sent since the last rechoke round.
+    uploaded: usize,
+    unchoked: watch::Sender<bool>,
+}
+
+impl<S: Storage> Seed<S> {
+    /// Seed `t` out of `storage`, which must already hold all of it.
+    pub fn new(t: &Torrent, storage: S) -> anyhow::Result<Self> {
+        Ok(Self {
+            info_hash: t.info_hash()?,
+            npieces: t.num_pieces(),
+            plength: t.info.plength,
+            length: t.length(),
+            storage: Mutex::new(storage),
+            choking: std::sync::Mutex::new(Choking {
+                choker: Choker::new(Mode::Seed),
+                connections: HashMap::new(),
+                last_round: Instant::now(),
+            }),
+            _lock: None,
+        })
+    }
+
+    /// Accept peers on `listener` until it fails, uploading to each of them until they leave, and
+    /// rechoking every [`RECHOKE_INTERVAL`].
+    pub async fn serve(self, listener: TcpListener) -> anyhow::Result<()> {
+        let seed = Arc::new(self);
+        let mut rechoke = tokio::time::interval(RECHOKE_INTERVAL);
+        // the first tick is due straight away, when there's nobody to rechoke yet
+        rechoke.tick().await;
+        loop {
+            let (stream, peer_addr) = tokio::select! {
+                accepted = listener.accept() => accepted.context("accept a peer")?,
+                _ = rechoke.tick() => {
+                    seed.rechoke(Instant::now());
+                    continue;
+                }
+            };
+            let seed = Arc::clone(&seed);
+            tokio::spawn(async move {
+                let unchoked = seed.connected(peer_addr);
+                if let Err(e) = seed.upload(stream, peer_addr, unchoked).await {
+                    eprintln!("{peer_addr}: {e:#}");
+                }
+                seed.disconnected(peer_addr);
+            });
+        }
+    }
+
+    /// Start keeping track of a connection for the choker, which keeps the returned receiver up
+    /// to date with whether the connection should be unchoked.
+    fn connected(&self, peer_addr: SocketAddr) -> watch::Receiver<bool> {
+        let (unchoked, decision) = watch::channel(false);
+        let mut choking = self
+            .choking
+            .lock()
+            .expect("nobody panics holding the choker");
+        choking.connections.insert(
+            peer_addr,
+            Connection {
+                interested: false,
+                last_request: None,
+                uploaded: 0,
+                unchoked,
+            },
+        );
+        decision
+    }
+
+    fn disconnected(&self, peer_addr: SocketAddr) {
+        let mut choking = self
+            .choking
+            .lock()
+            .expect("nobody panics holding the choker");
+        choking.connections.remove(&peer_addr);
+        choking.choker.disconnected(peer_addr);
+    }
+
+    /// The peer at `peer_addr` says whether it is interested; one that is gets a free slot
+    /// straight away, if there is one.
+    fn interested(&self, peer_addr: SocketAddr, interested: bool) {
+        let mut choking = self
+            .choking
+            .lock()
+            .expect("nobody panics holding the choker");
+        let Choking {
+            choker,
+            connections,
+            ..
+        } = &mut *choking;
+        let Some(connection) = connections.get_mut(&peer_addr) else {
+            return;
+        };
+        connection.interested = interested;
+        if interested && choker.unchoke_if_free(peer_addr, Instant::now()) {
+            connection.unchoked.send_replace(true);
+        }
+    }
+
+    fn observe(&self, peer_addr: SocketAddr, update: impl FnOnce(&mut Connection)) {
+        let mut choking = self
+            .choking
+            .lock()
+            .expect("nobody panics holding the choker");
+        if let Some(connection) = choking.connections.get_mut(&peer_addr) {
+            update(connection);
+        }
+    }
+
+    /// Run a rechoke round over the connections we have, and let each know how it came out.
+    fn rechoke(&self, now: Instant) {
+        let mut choking = self
+            .choking
+            .lock()
+            .expect("nobody panics holding the choker");
+        let Choking {
+            choker,
+            connections,
+            last_round,
+        } = &mut *choking;
+        let elapsed = now
+            .duration_since(*last_round)
+            .max(Duration::from_millis(1))
+            .as_secs_f64();
+        *last_round = now;
+        let observations: Vec<_> = connections
+            .iter_mut()
+            .map(|(&addr, connection)| Observation {
+                addr,
+                interested: connection.interested,
+                last_request: connection.last_request,
+                // peers don't send a seed anything to reciprocate
+                download_rate: 0.0,
+                upload_rate: std::mem::take(&mut connection.uploaded) as f64 / elapsed,
+            })
+            .collect();
+        let unchoked = choker.rechoke(&observations, now);
+        for (addr, connection) in connections.iter() {
+            connection.unchoked.send_replace(unchoked.contains(addr));
+        }
+    }
+
+    async fn upload(
+        &self,
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+        mut unchoked: watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
+        let handshake = Handshake::read(&mut stream).await?;
+        anyhow::ensure!(
+            handshake.info_hash == self.info_hash,
+            "{peer_addr} asked for a different torrent"
+        );
+        Handshake::new(self.info_hash, crate::peer::PeerId::ours().0)
+            .write(&mut stream)
+            .await?;
+
+        let mut stream = Framed::new(stream, MessageFramer::default());
+        let have = PieceMap(vec![PieceState::Done; self.npieces]);
+        stream
+            .send(Message::Bitfield(have.bitfield().into()))
+            .await
+            .context("send bitfield")?;
+
+        let mut choking = true;
+        // requests taken in but not answered yet: one is only answered when there's nothing more
+        // to read, so that a Cancel already on its way can still take it back
+        let mut queue = VecDeque::new();
+        loop {
+            // pass on whatever the choker last decided, unless the peer already knows
+            if *unchoked.borrow_and_update() == choking {
+                choking = !choking;
+                let msg = if choking {
+                    // the requests of a peer we choke are dropped; it asks again once unchoked
+                    queue.clear();
+                    Message::Choke
+                } else {
+                    Message::Unchoke
+                };
+                stream.send(msg).await.context("send (un)choke")?;
+                continue;
+            }
+            let msg = if queue.is_empty() {
+                tokio::select! {
+                    msg = stream.next() => msg,
+                    Ok(()) = unchoked.changed() => continue,
+                }
+            } else if let Some(msg) = stream.next().now_or_never() {
+                msg
+            } else {
+                let (index, begin, length) = queue.pop_front().expect("the queue isn't empty");
+                let block = self
+                    .storage
+                    .lock()
+                    .await
+                    .read_block(index as usize, begin as usize, length)
+                    .await
+                    .with_context(|| format!("read block {begin} of piece {index}"))?;
+                stream
+                    .send(Message::Piece {
+                        index,
+                        begin,
+                        block: block.into(),
+                    })
+                    .await
+                    .context("send block")?;
+                self.observe(peer_addr, |connection| connection.uploaded += length);
+                continue;
+            };
+            let Some(msg) = msg else {
+                break;
+            };
+            match msg.context("peer message was invalid")? {
+                Message::Interested => self.interested(peer_addr, true),
+                Message::NotInterested => self.interested(peer_addr, false),
+                Message::Request {
+                    index,
+                    begin,
+                    length,
+                } if !choking => {
+                    let length = self.check_request(index, begin, length)?;
+                    anyhow::ensure!(
+                        queue.len() < MAX_QUEUED,
+                        "{peer_addr} has more than {MAX_QUEUED} requests waiting on us"
+                    );
+                    self.observe(peer_addr, |connection| {
+                        connection.last_request = Some(Instant::now());
+                    });
+                    queue.push_back((index, begin, length));
+                }
+                Message::Cancel {
+                    index,
+                    begin,
+                    length,
+                } => queue.retain(|&request| request != (index, begin, length as usize)),
+                // requests from a peer we still choke are dropped, as BEP 3 allows
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The length of a requested block, if it's one we have.
+    fn check_request(&self, index: u32, begin: u32, length: u32) -> anyhow::Result<usize> {
+        let length = length as usize;
+        let piece_length = self
+            .length
+            .saturating_sub(index as usize * self.plength)
+            .min(self.plength);
+        anyhow::ensure!(
+            (index as usize) < self.npieces
+                && (1..=BLOCK_MAX).contains(&length)
+                && begin as usize + length <= piece_length,
+            "requested {length} bytes at {begin} of piece {index}, which we don't have"
+        );
+        Ok(length)
+    }
+}
+
+impl Seed<FileStorage> {
+    /// Seed `t` out of the file at `path`, once every piece in it checks out.
+    ///
+    /// The file and its `.stats` stay locked for as long as the seed lives, the same as for a
+    /// download into them, so neither can start on the other's files.
+    pub async fn from_file(t: &Torrent, path: &Path) -> anyhow::Result<Self> {
+        // a multi-file torrent is downloaded into one file all the same, but FileVerifier would
+        // look for its files under a directory
+        anyhow::ensure!(
+            matches!(t.info.keys, Keys::SingleFile { .. }),
+            "only single-file torrents can be served"
+        );
+        let file = std::fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+        let lock = SessionLock::acquire(&[path, &download::stats_path(path)])?;
+        let mut bad = Vec::new();
+        for result in FileVerifier::new(t, vec![Some(file)]) {
+            let (piece_i, ok) = result.with_context(|| format!("read {}", path.display()))?;
+            if !ok {
+                bad.push(piece_i);
+            }
+        }
+        anyhow::ensure!(
+            bad.is_empty(),
+            "{} of {} pieces in {} don't match the torrent, starting with piece {}",
+            bad.len(),
+            t.num_pieces(),
+            path.display(),
+            bad[0]
+        );
+        let seed = Self::new(t, FileStorage::open(path, t).await?)?;
+        Ok(Self {
+            _lock: Some(lock),
+            ..seed
+        })
+    }
+}
+
+#[tokio::test]
+async fn downloads_from_our_own_seed() {
+    use crate::download::{DownloadHandle, Downloaded, Outcome};
+
+    let data = crate::mock::data(3 * 32768 + 1000);
+    let t = crate::mock::torrent_for("http://unused/announce", &data, 32768);
+    let mut storage = Downloaded::new(&t);
+    for (piece_i, piece) in data.chunks(32768).enumerate() {
+        storage.write_block(piece_i, 0, piece).await.unwrap();
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Seed::new(&t, storage).unwrap().serve(listener));
+
+    let mut download = DownloadHandle::builder(t, Arc::default())
+        .peers(vec![addr])
+        .spawn();
+    let Outcome::Complete(downloaded) = download.wait().await.unwrap() else {
+        panic!("download was cancelled");
+    };
+    assert_eq!((&downloaded).into_iter().next().unwrap().bytes(), data);
+}
+
+#[tokio::test]
+async fn cancelled_requests_go_unanswered() {
+    use crate::download::Downloaded;
+    use crate::peer::PeerId;
+
+    let data = crate::mock::data(3 * BLOCK_MAX);
+    let t = crate::mock::torrent_for("http://unused/announce", &data, 3 * BLOCK_MAX);
+    let mut storage = Downloaded::new(&t);
+    storage.write_block(0, 0, &data).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Seed::new(&t, storage).unwrap().serve(listener));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let info_hash = t.info_hash().unwrap();
+    Handshake::new(info_hash, PeerId::ours().0)
+        .write(&mut stream)
+        .await
+        .unwrap();
+    Handshake::read(&mut stream).await.unwrap();
+    let mut stream = Framed::new(stream, MessageFramer::default());
+    assert!(matches!(
+        stream.next().await.unwrap().unwrap(),
+        Message::Bitfield(_)
+    ));
+    stream.send(Message::Interested).await.unwrap();
+    assert_eq!(stream.next().await.unwrap().unwrap(), Message::Unchoke);
+
+    let request = |block: usize| (0, (block * BLOCK_MAX) as u32, BLOCK_MAX as u32);
+    let asking = |(index, begin, length)| Message::Request {
+        index,
+        begin,
+        length,
+    };
+    let cancelling = |(index, begin, length)| Message::Cancel {
+        index,
+        begin,
+        length,
+    };
+    // all in one go, so the cancel is there to be read before the second block goes out
+    stream.feed(asking(request(0))).await.unwrap();
+    stream.feed(asking(request(1))).await.unwrap();
+    stream.feed(cancelling(request(1))).await.unwrap();
+    stream.flush().await.unwrap();
+    stream.send(asking(request(2))).await.unwrap();
+
+    let mut answered = Vec::new();
+    for _ in 0..2 {
+        let Message::Piece { begin, block, .. } = stream.next().await.unwrap().unwrap() else {
+            panic!("only blocks come back");
+        };
+        assert_eq!(block.len(), BLOCK_MAX);
+        answered.push(begin as usize / BLOCK_MAX);
+    }
+    assert_eq!(answered, [0, 2]);
+}
+
+#[tokio::test]
+async fn peers_flooding_us_with_requests_are_dropped() {
+    use crate::download::Downloaded;
+    use crate::peer::PeerId;
+
+    let data = crate::mock::data(BLOCK_MAX);
+    let t = crate::mock::torrent_for("http://unused/announce", &data, BLOCK_MAX);
+    let mut storage = Downloaded::new(&t);
+    storage.write_block(0, 0, &data).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Seed::new(&t, storage).unwrap().serve(listener));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    Handshake::new(t.info_hash().unwrap(), PeerId::ours().0)
+        .write(&mut stream)
+        .await
+        .unwrap();
+    Handshake::read(&mut stream).await.unwrap();
+    let mut stream = Framed::new(stream, MessageFramer::default());
+    stream.next().await.unwrap().unwrap();
+    stream.send(Message::Interested).await.unwrap();
+    assert_eq!(stream.next().await.unwrap().unwrap(), Message::Unchoke);
+
+    let flood = 4 * MAX_QUEUED;
+    for _ in 0..flood {
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: BLOCK_MAX as u32,
+        };
+        stream.feed(request).await.unwrap();
+    }
+    stream.flush().await.unwrap();
+    let mut answered = 0;
+    while let Some(Ok(Message::Piece { .. })) = stream.next().await {
+        answered += 1;
+    }
+    assert!(answered < flood, "all {answered} requests were answered");
+}
+
+#[tokio::test]
+async fn upload_slots_are_limited() {
+    use crate::choke::UNCHOKE_SLOTS;
+    use crate::download::Downloaded;
+    use crate::peer::PeerId;
+    use std::time::Duration;
+
+    let data = crate::mock::data(BLOCK_MAX);
+    let t = crate::mock::torrent_for("http://unused/announce", &data, BLOCK_MAX);
+    let mut storage = Downloaded::new(&t);
+    storage.write_block(0, 0, &data).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Seed::new(&t, storage).unwrap().serve(listener));
+
+    let mut peers = Vec::new();
+    for _ in 0..=UNCHOKE_SLOTS {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        Handshake::new(t.info_hash().unwrap(), PeerId::ours().0)
+            .write(&mut stream)
+            .await
+            .unwrap();
+        Handshake::read(&mut stream).await.unwrap();
+        let mut stream = Framed::new(stream, MessageFramer::default());
+        stream.next().await.unwrap().unwrap();
+        stream.send(Message::Interested).await.unwrap();
+        peers.push(stream);
+    }
+    // the first ones in take every slot, and the last one waits for a rechoke round
+    for stream in &mut peers[..UNCHOKE_SLOTS] {
+        assert_eq!(stream.next().await.unwrap().unwrap(), Message::Unchoke);
+    }
+    let wait = tokio::time::timeout(Duration::from_millis(200), peers[UNCHOKE_SLOTS].next());
+    assert!(
+        wait.await.is_err(),
+        "more peers were unchoked than there are slots"
+    );
+}
+
+#[tokio::test]
+async fn bad_requests_end_the_connection() {
+    use crate::download::Downloaded;
+
+    let data = crate::mock::data(1000);
+    let t = crate::mock::torrent_for("http://unused/announce", &data, 512);
+    let seed = Seed::new(&t, Downloaded::new(&t)).unwrap();
+    let request = |index, begin, length| seed.check_request(index, begin, length);
+    assert_eq!(request(1, 0, 488).unwrap(), 488);
+    assert!(request(1, 0, 489).is_err(), "the last piece is short");
+    assert!(request(2, 0, 1).is_err());
+    assert!(request(0, 500, 13).is_err());
+    assert!(request(0, 0, 0).is_err());
+}
+
+#[tokio::test]
+async fn only_complete_files_are_served() {
+    let data = crate::mock::data(3 * 32768 + 1000);
+    let t = crate::mock::torrent_for("http://unused/announce", &data, 32768);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("file");
+    std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+    let Err(e) = Seed::from_file(&t, &path).await else {
+        panic!("a short file was served");
+    };
+    assert!(e.to_string().starts_with("1 of 4 pieces in"), "{e:#}");
+    std::fs::write(&path, &data).unwrap();
+    Seed::from_file(&t, &path).await.unwrap();
+}
+
+#[tokio::test]
+async fn seeds_and_downloads_keep_off_each_others_files() {
+    use crate::lock::LockError;
+
+    let data = crate::mock::data(32768);
+    let t = crate::mock::torrent_for("http://unused/announce", &data, 32768);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("file");
+    std::fs::write(&path, &data).unwrap();
+    let download_lock = |path: &Path| SessionLock::acquire(&[path, &download::stats_path(path)]);
+
+    // a download still going on into the file can't be seeded from
+    let lock = download_lock(&path).unwrap();
+    let Err(e) = Seed::from_file(&t, &path).await else {
+        panic!("a file being downloaded into was served");
+    };
+    assert!(
+        matches!(e.downcast_ref(), Some(LockError::Held { .. })),
+        "{e:#}"
+    );
+    drop(lock);
+
+    // nor can a download start on a file we're seeding
+    let seed = Seed::from_file(&t, &path).await.unwrap();
+    assert!(matches!(download_lock(&path), Err(LockError::Held { .. })));
+    drop(seed);
+    download_lock(&path).unwrap();
+}
@@ -0,0 +1,78 @@
+//! Capping how fast a download goes.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A limit on bytes per second, shared by every connection of a download and adjustable while
+/// they use it.
+///
+/// Rather than a bucket of tokens this keeps the time from which the limit lets the next bytes
+/// through; every request books its bytes onto the end of that schedule and waits its turn.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    /// Bytes per second, with 0 meaning no limit.
+    limit: AtomicU64,
+    next: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    /// Change the limit; `None` lifts it. Whoever is already waiting keeps their old slot.
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+        // the old schedule was computed at the old rate, and would hold a raised limit back
+        *self
+            .next
+            .lock()
+            .expect("nobody panics holding the schedule") = None;
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|&limit| limit != 0)
+    }
+
+    /// Book `bytes` onto the schedule, returning when they may go.
+    pub fn reserve(&self, bytes: usize, now: Instant) -> Instant {
+        let Some(limit) = self.limit() else {
+            return now;
+        };
+        let mut next = self
+            .next
+            .lock()
+            .expect("nobody panics holding the schedule");
+        let at = next.map_or(now, |next| next.max(now));
+        *next = Some(at + Duration::from_secs_f64(bytes as f64 / limit as f64));
+        at
+    }
+
+    /// Wait until `bytes` more may go.
+    pub async fn take(&self, bytes: usize) {
+        let at = self.reserve(bytes, Instant::now());
+        tokio::time::sleep_until(at.into()).await;
+    }
+}
+
+#[test]
+fn reservations_queue_up_at_the_limit() {
+    let throttle = Throttle::default();
+    let t0 = Instant::now();
+    assert_eq!(throttle.reserve(1 << 20, t0), t0);
+    assert_eq!(throttle.reserve(1 << 20, t0), t0, "no limit, no waiting");
+
+    throttle.set_limit(Some(1000));
+    assert_eq!(throttle.limit(), Some(1000));
+    assert_eq!(throttle.reserve(500, t0), t0);
+    assert_eq!(throttle.reserve(500, t0), t0 + Duration::from_millis(500));
+    assert_eq!(throttle.reserve(2000, t0), t0 + Duration::from_secs(1));
+    assert_eq!(throttle.reserve(1, t0), t0 + Duration::from_secs(3));
+
+    // idle time isn't saved up for a burst later
+    let later = t0 + Duration::from_secs(10);
+    assert_eq!(throttle.reserve(1000, later), later);
+    assert_eq!(throttle.reserve(1, later), later + Duration::from_secs(1));
+
+    // and changing the limit starts the schedule over
+    throttle.set_limit(None);
+    assert_eq!(throttle.limit(), None);
+    assert_eq!(throttle.reserve(1 << 20, later), later);
+}
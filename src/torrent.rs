@@ -1,37 +1,273 @@
 use super::download;
-use crate::download::Downloaded;
-use anyhow::Context;
+use crate::bencode::{self, Value};
+use crate::download::{DownloadHandle, Downloaded, Outcome};
+use crate::tracker::{lenient, Listeners, Tiers, TransferStats};
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
-use std::convert::TryInto;
-use std::path::Path;
+use sha2::Sha256;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
 
 pub use hashes::Hashes;
 
 /// A Metainfo file (also known as .torrent files).
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Torrent {
-    /// The URL of the tracker.
+    /// The URL of the tracker, or empty for a trackerless torrent.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
     pub info: Info,
+
+    /// DHT nodes to bootstrap from (BEP 5), as `[host, port]` pairs; entries of any other shape
+    /// are skipped.
+    #[serde(
+        default,
+        deserialize_with = "lenient::nodes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub nodes: Option<Vec<(String, u16)>>,
+
+    /// Web seeds (BEP 19): HTTP mirrors of the content, written as a single URL or a list of them.
+    #[serde(
+        default,
+        rename = "url-list",
+        deserialize_with = "lenient::urls",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub url_list: Option<Vec<String>>,
+
+    /// When the torrent was made, in seconds since the Unix epoch.
+    #[serde(
+        default,
+        rename = "creation date",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+
+    #[serde(
+        default,
+        deserialize_with = "lenient::text",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub comment: Option<String>,
+
+    /// The program that made the torrent.
+    #[serde(
+        default,
+        rename = "created by",
+        deserialize_with = "lenient::text",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+
+    /// The character set of the torrent's strings, if it says.
+    #[serde(
+        default,
+        deserialize_with = "lenient::text",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub encoding: Option<String>,
+
+    /// Top-level keys we don't model, like `announce-list`.
+    #[serde(flatten, with = "extra")]
+    pub extra: BTreeMap<Vec<u8>, Value>,
+
+    /// The exact bytes of the `info` dictionary as they appeared in the file, if we parsed one.
+    ///
+    /// The info hash is defined over these bytes, which re-serializing `info` may not reproduce.
+    #[serde(skip)]
+    info_bytes: Option<Vec<u8>>,
+
+    /// The order we try the trackers in, which changes as they answer or don't.
+    #[serde(skip)]
+    pub(crate) tiers: Tiers,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TorrentError {
+    #[error("read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("{}: {source}", path.display())]
+    InFile {
+        path: PathBuf,
+        source: Box<TorrentError>,
+    },
+
+    #[error("malformed bencode: {0}")]
+    Bencode(#[from] bencode::Error),
+
+    #[error("missing key `{key}` in dictionary at byte {offset}")]
+    MissingKey { key: String, offset: usize },
+
+    #[error("invalid value for `{key}` at byte {offset}: expected {expected}, found {found}")]
+    InvalidValue {
+        key: String,
+        offset: usize,
+        expected: &'static str,
+        found: String,
+    },
+
+    #[error("parse torrent: {0}")]
+    Parse(#[from] serde_bencode::Error),
+
+    #[error("invalid torrent: {0}")]
+    Invalid(String),
+
+    #[error("unsafe file path {path}: {reason}")]
+    UnsafePath { path: String, reason: &'static str },
+
+    #[error("this torrent is v2-only (BEP 52), and v2-only torrents are not yet downloadable")]
+    V2Only,
 }
 
 impl Torrent {
-    pub fn info_hash(&self) -> Result<[u8; 20], serde_bencode::Error> {
-        let info_encoded = serde_bencode::to_bytes(&self.info)?;
-        let mut hasher = Sha1::new();
-        hasher.update(&info_encoded);
-        Ok(hasher.finalize().try_into().map_err(|e| {
-            serde::de::Error::custom(format!("Error converting to [u8; 20]: {}", e))
-        })?)
+    pub fn new(announce: String, info: Info) -> Self {
+        Self {
+            announce,
+            info,
+            nodes: None,
+            url_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+            extra: BTreeMap::new(),
+            info_bytes: None,
+            tiers: Tiers::default(),
+        }
     }
 
-    pub async fn read(file: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let dot_torrent = tokio::fs::read(file).await.context("read torrent file")?;
-        let t: Torrent = serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+    /// Parse and validate a metainfo file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TorrentError> {
+        let mut t: Torrent = match serde_bencode::from_bytes(bytes) {
+            Ok(t) => t,
+            Err(e) => {
+                if is_v2_only(bytes) {
+                    return Err(TorrentError::V2Only);
+                }
+                // serde only tells us what it expected, not where, so look for the culprit
+                // ourselves and only fall back to its message if we can't find one.
+                probe(bytes)?;
+                return Err(TorrentError::Parse(e));
+            }
+        };
+        let info = bencode::dict_spans(bytes)?
+            .into_iter()
+            .find_map(|(key, span)| (key == b"info").then_some(span))
+            .expect("typed parsing found an info dictionary");
+        t.info_bytes = Some(bytes[info].to_vec());
+        t.validate()?;
         Ok(t)
     }
 
+    /// Read, parse, and validate the metainfo file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, TorrentError> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).map_err(|source| TorrentError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_bytes(&bytes).map_err(|e| TorrentError::InFile {
+            path: path.to_path_buf(),
+            source: Box::new(e),
+        })
+    }
+
+    #[deprecated(note = "use `Torrent::from_file`")]
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, TorrentError> {
+        Self::from_file(path)
+    }
+
+    /// Check that the metainfo is internally consistent.
+    pub fn validate(&self) -> Result<(), TorrentError> {
+        if self.info.name.is_empty() {
+            return Err(TorrentError::Invalid(String::from("info.name is empty")));
+        }
+        if self.info.plength == 0 {
+            return Err(TorrentError::Invalid(String::from(
+                "info.piece length is zero",
+            )));
+        }
+        if let Keys::MultiFile { files } = &self.info.keys {
+            if let Some(i) = files.iter().position(|file| file.path.is_empty()) {
+                return Err(TorrentError::Invalid(format!(
+                    "info.files[{i}].path is empty"
+                )));
+            }
+            // which on a 32-bit target doesn't take very big files
+            let total = files
+                .iter()
+                .try_fold(0usize, |total, file| total.checked_add(file.length));
+            if total.is_none() {
+                return Err(TorrentError::Invalid(format!(
+                    "info.files add up to more than the {} bytes we can address",
+                    usize::MAX
+                )));
+            }
+        }
+        let expected = self.length().div_ceil(self.info.plength);
+        if self.info.pieces.len() != expected {
+            return Err(TorrentError::Invalid(format!(
+                "info.pieces holds {} hashes, but {} bytes in pieces of {} need {expected}",
+                self.info.pieces.len(),
+                self.length(),
+                self.info.plength
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether `data` is piece `index` of this torrent, going by its hash.
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        self.info
+            .pieces
+            .get(index)
+            .is_some_and(|hash| <[u8; 20]>::from(Sha1::digest(data)) == *hash)
+    }
+
+    pub fn info_hash(&self) -> Result<InfoHash, serde_bencode::Error> {
+        let mut hasher = Sha1::new();
+        hasher.update(self.info_bytes()?);
+        Ok(InfoHash(hasher.finalize().into()))
+    }
+
+    /// The bencoded info dictionary: exactly as it appeared in the metainfo we were parsed from,
+    /// if we were, so that it matches the info hash.
+    pub fn info_bytes(&self) -> Result<Cow<'_, [u8]>, serde_bencode::Error> {
+        match &self.info_bytes {
+            Some(info_bytes) => Ok(Cow::Borrowed(info_bytes)),
+            None => bencode::to_value(&self.info).map(|info| Cow::Owned(bencode::encode(&info))),
+        }
+    }
+
+    /// The metainfo file for this torrent, as canonical bencode.
+    ///
+    /// If we were parsed from a file, the info dictionary is written out exactly as it appeared
+    /// there, so that no edit to the rest of the torrent can change its info hash.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_bencode::Error> {
+        let Value::Dict(dict) = bencode::to_value(self)? else {
+            unreachable!("a struct always serializes to a dictionary");
+        };
+        let mut out = vec![b'd'];
+        for (key, value) in &dict {
+            bencode::encode_bytes(key, &mut out);
+            match &self.info_bytes {
+                Some(raw) if key == b"info" => out.extend_from_slice(raw),
+                _ => bencode::encode_into(value, &mut out),
+            }
+        }
+        out.push(b'e');
+        Ok(out)
+    }
+
     pub fn print_tree(&self) {
         match &self.info.keys {
             Keys::SingleFile { .. } => {
@@ -45,6 +281,193 @@ impl Torrent {
         }
     }
 
+    /// The tiers of trackers in the torrent's `announce-list` (BEP 12), if it has any.
+    ///
+    /// The key stays among the [`Torrent::extra`] ones, so whatever in it isn't a list of URLs
+    /// is skipped here rather than failing the whole torrent.
+    pub fn announce_list(&self) -> Option<Vec<Vec<String>>> {
+        let Some(Value::List(tiers)) = self.extra.get(&b"announce-list"[..]) else {
+            return None;
+        };
+        let tiers: Vec<Vec<String>> = tiers
+            .iter()
+            .filter_map(|tier| match tier {
+                Value::List(urls) => Some(
+                    urls.iter()
+                        .filter_map(|url| match url {
+                            Value::Bytes(url) => Some(String::from_utf8_lossy(url).into_owned()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        (!tiers.is_empty()).then_some(tiers)
+    }
+
+    /// The tiers we announce to: those of the `announce-list`, or just `announce` if there is
+    /// none, since BEP 12 has the list take its place.
+    ///
+    /// A trackerless torrent has no tiers at all.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        self.announce_list().unwrap_or_else(|| {
+            if self.announce.is_empty() {
+                Vec::new()
+            } else {
+                vec![vec![self.announce.clone()]]
+            }
+        })
+    }
+
+    /// Every tracker the torrent names, each once: those of its `announce-list` tiers (BEP 12) in
+    /// order, or just `announce` if it doesn't have one.
+    pub fn trackers(&self) -> Vec<String> {
+        let mut trackers: Vec<String> = Vec::new();
+        for url in self.tracker_tiers().into_iter().flatten() {
+            if !trackers.contains(&url) {
+                trackers.push(url);
+            }
+        }
+        trackers
+    }
+
+    /// The URLs to fetch the content from over HTTP, one per web seed.
+    ///
+    /// Per BEP 19, a URL ending in `/` names a directory the content is in, so the torrent's name
+    /// goes on the end of it; any other URL is the content itself.
+    pub fn web_seeds(&self) -> Vec<String> {
+        self.url_list
+            .iter()
+            .flatten()
+            .map(|url| {
+                if url.ends_with('/') {
+                    url.clone() + &crate::tracker::percent_encode(self.info.name.as_bytes())
+                } else {
+                    url.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// Whether the torrent is private, so that we may only get peers for it from its trackers.
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
+    /// The `meta version` of the info dictionary: 2 for BEP 52 torrents, 1 for the rest.
+    pub fn meta_version(&self) -> i64 {
+        match self.info.extra.get(&b"meta version"[..]) {
+            Some(Value::Int(version)) => *version,
+            _ => 1,
+        }
+    }
+
+    /// Whether this is a hybrid torrent, with a v2 side besides the v1 one we download with.
+    ///
+    /// There's no v2-only kind: [`Torrent::from_bytes`] turns those away.
+    pub fn is_hybrid(&self) -> bool {
+        self.meta_version() == 2
+    }
+
+    /// The v2 file tree of a hybrid torrent.
+    pub fn file_tree(&self) -> Result<Option<FileTree>, TorrentError> {
+        if !self.is_hybrid() {
+            return Ok(None);
+        }
+        let tree = self
+            .info
+            .extra
+            .get(&b"file tree"[..])
+            .ok_or_else(|| TorrentError::Invalid(String::from("info.file tree is missing")))?;
+        FileTree::parse(tree, "info.file tree").map(Some)
+    }
+
+    /// The SHA-256 v2 info hash of a hybrid torrent, which trackers and peers know it by
+    /// truncated to its first 20 bytes.
+    pub fn info_hash_v2(&self) -> Result<Option<[u8; 32]>, serde_bencode::Error> {
+        if !self.is_hybrid() {
+            return Ok(None);
+        }
+        Ok(Some(Sha256::digest(self.info_bytes()?).into()))
+    }
+
+    /// What the `info` command prints: the tracker, the length (and for a multi-file torrent,
+    /// every file), whichever of the optional fields are there, the info hashes and the piece
+    /// hashes.
+    pub fn summary(&self) -> Result<String, serde_bencode::Error> {
+        let mut summary = String::new();
+        if !self.announce.is_empty() {
+            summary.push_str(&format!(
+                "Tracker URL: {}\n",
+                crate::tracker::redacted(&self.announce)
+            ));
+        }
+        summary.push_str(&format!("Length: {}\n", self.length()));
+        if let Keys::MultiFile { files } = &self.info.keys {
+            summary.push_str("Files:\n");
+            for file in files.iter().filter(|file| !file.is_padding()) {
+                summary.push_str(&format!("{} {}", file.path.join("/"), file.length));
+                if let Err(e) = file.safe_path(Path::new("")) {
+                    summary.push_str(&format!(" (suspicious: {e})"));
+                }
+                summary.push('\n');
+            }
+        }
+        if let Some(nodes) = self.nodes.as_ref().filter(|nodes| !nodes.is_empty()) {
+            summary.push_str("DHT Nodes:\n");
+            for (host, port) in nodes {
+                if host.contains(':') {
+                    summary.push_str(&format!("[{host}]:{port}\n"));
+                } else {
+                    summary.push_str(&format!("{host}:{port}\n"));
+                }
+            }
+        }
+        if let Some(urls) = self.url_list.as_ref().filter(|urls| !urls.is_empty()) {
+            summary.push_str("Web Seeds:\n");
+            for url in urls {
+                summary.push_str(url);
+                summary.push('\n');
+            }
+        }
+        if let Some(date) = self.creation_date {
+            summary.push_str(&format!("Creation Date: {}\n", rfc3339(date)));
+        }
+        let optional = [
+            ("Created By", &self.created_by),
+            ("Comment", &self.comment),
+            ("Encoding", &self.encoding),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                summary.push_str(&format!("{name}: {value}\n"));
+            }
+        }
+        summary.push_str(&format!(
+            "Private: {}\n",
+            if self.is_private() { "yes" } else { "no" }
+        ));
+        summary.push_str(&format!("Info Hash: {}\n", self.info_hash()?));
+        if let Some(v2) = self.info_hash_v2()? {
+            summary.push_str(&format!(
+                "Versions: v1, v2\nInfo Hash v2 (truncated): {}\n",
+                hex::encode(&v2[..20])
+            ));
+        }
+        summary.push_str(&format!(
+            "Piece Length: {}\nPiece Hashes:\n",
+            self.info.plength
+        ));
+        for i in 0..self.info.pieces.len() {
+            summary.push_str(&self.info.pieces.hex(i));
+            summary.push('\n');
+        }
+        Ok(summary)
+    }
+
+    /// The total length of the torrent, over all of its files.
     pub fn length(&self) -> usize {
         match &self.info.keys {
             Keys::SingleFile { length } => *length,
@@ -52,11 +475,147 @@ impl Torrent {
         }
     }
 
-    pub async fn download_all(&self) -> anyhow::Result<Downloaded> {
-        download::all(self).await
+    pub fn num_pieces(&self) -> usize {
+        self.info.pieces.len()
+    }
+
+    /// The length of piece `index`: `plength`, except for the last piece, which only has what's
+    /// left of the torrent's total length. Indices past the end have length 0.
+    pub fn piece_length_for(&self, index: usize) -> usize {
+        self.length()
+            .saturating_sub(index.saturating_mul(self.info.plength))
+            .min(self.info.plength)
+    }
+
+    /// Every piece in order, with where it starts in the concatenated files.
+    pub fn pieces(&self) -> impl Iterator<Item = PieceInfo> + '_ {
+        let length = self.length();
+        let plength = self.info.plength;
+        self.info
+            .pieces
+            .iter()
+            .enumerate()
+            .map(move |(index, &hash)| PieceInfo {
+                index,
+                offset: index * plength,
+                length: length.saturating_sub(index * plength).min(plength),
+                hash,
+            })
+    }
+
+    pub async fn download_all(&self, stats: &TransferStats) -> anyhow::Result<Downloaded> {
+        let none = download::Controls::none();
+        let source = download::Source::Tracker(Listeners::default());
+        let cancel = CancellationToken::new();
+        match download::all(self, stats, &source, &cancel, none, Downloaded::new(self)).await? {
+            Outcome::Complete(downloaded) => Ok(downloaded),
+            Outcome::Cancelled => unreachable!("nobody else holds the token"),
+        }
+    }
+
+    /// Start downloading in the background, returning a handle that can cancel the download.
+    pub fn download(&self, stats: Arc<TransferStats>) -> DownloadHandle {
+        DownloadHandle::spawn(self.clone(), stats)
+    }
+}
+
+/// The SHA-1 of a torrent's info dictionary, which is what peers, trackers and magnet links know
+/// the torrent by.
+///
+/// It displays as 40 lowercase hex digits, and parses from those in either case or from the 32
+/// base32 characters older magnet links use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+#[repr(transparent)]
+pub struct InfoHash(pub [u8; 20]);
+
+#[derive(Debug, thiserror::Error)]
+pub enum InfoHashError {
+    #[error("info hash `{0}` is neither 40 hex digits nor 32 base32 characters")]
+    Length(String),
+
+    #[error("info hash `{hash}` is not valid hex: {source}")]
+    Hex {
+        hash: String,
+        source: hex::FromHexError,
+    },
+
+    #[error("info hash `{hash}` is not valid base32: {found:?} is not in A-Z or 2-7")]
+    Base32 { hash: String, found: char },
+}
+
+impl InfoHash {
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(bytes: [u8; 20]) -> Self {
+        InfoHash(bytes)
     }
 }
 
+impl std::fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl std::str::FromStr for InfoHash {
+    type Err = InfoHashError;
+
+    fn from_str(hash: &str) -> Result<Self, InfoHashError> {
+        match hash.len() {
+            40 => {
+                let mut info_hash = [0; 20];
+                hex::decode_to_slice(hash, &mut info_hash).map_err(|source| {
+                    InfoHashError::Hex {
+                        hash: hash.to_string(),
+                        source,
+                    }
+                })?;
+                Ok(InfoHash(info_hash))
+            }
+            32 => {
+                // every 8 characters of 5 bits each make 5 bytes
+                let mut bits: u64 = 0;
+                let mut info_hash = Vec::with_capacity(20);
+                for (i, c) in hash.chars().enumerate() {
+                    let value = match c.to_ascii_uppercase() {
+                        c @ 'A'..='Z' => c as u64 - 'A' as u64,
+                        c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+                        found => {
+                            return Err(InfoHashError::Base32 {
+                                hash: hash.to_string(),
+                                found,
+                            })
+                        }
+                    };
+                    bits = bits << 5 | value;
+                    if i % 8 == 7 {
+                        info_hash.extend(&bits.to_be_bytes()[3..]);
+                        bits = 0;
+                    }
+                }
+                Ok(InfoHash(
+                    info_hash.try_into().expect("32 characters are 20 bytes"),
+                ))
+            }
+            _ => Err(InfoHashError::Length(hash.to_string())),
+        }
+    }
+}
+
+/// One piece of a torrent, as [`Torrent::pieces`] yields them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceInfo {
+    pub index: usize,
+    /// Where the piece starts, counting over all of the torrent's files.
+    pub offset: usize,
+    pub length: usize,
+    pub hash: [u8; 20],
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Info {
     /// The suggested name to save the file (or directory) as. It is purely advisory.
@@ -80,6 +639,20 @@ pub struct Info {
     /// Either single file length or multiple files.
     #[serde(flatten)]
     pub keys: Keys,
+
+    /// 1 if the torrent is private (BEP 27): its peers are only to come from its trackers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+
+    /// The MD5 of a single-file torrent's file; each of a multi-file torrent's is in its
+    /// [`File`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<Md5Sum>,
+
+    /// Keys we don't model (like `source`), kept so that re-serializing the info
+    /// dictionary reproduces it, and with it the info hash.
+    #[serde(flatten, with = "extra")]
+    pub extra: BTreeMap<Vec<u8>, Value>,
 }
 
 /// There is a key `length` or a key `files`, but not both or neither.
@@ -106,6 +679,429 @@ pub struct File {
     /// Subdirectory names for this file, the last of which is the actual file name
     /// (a zero-length list is an error case).
     pub path: Vec<String>,
+
+    /// File attributes (BEP 47), one letter each: `p` for padding, `x` for executable, `h` for
+    /// hidden and `l` for a symlink.
+    #[serde(
+        default,
+        deserialize_with = "lenient::text",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub attr: Option<String>,
+
+    /// The MD5 of the file, which older creators put in and nothing depends on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<Md5Sum>,
+
+    /// Keys we don't model, like `sha1`.
+    #[serde(flatten, with = "extra")]
+    pub extra: BTreeMap<Vec<u8>, Value>,
+}
+
+/// An `md5sum`, kept as the bytes it was written as so that re-serializing doesn't change the
+/// info hash.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Md5Sum(#[serde(with = "serde_bytes")] pub Vec<u8>);
+
+impl Md5Sum {
+    /// The digest this names: 32 hex digits by the spec, though some tools wrote the 16 bytes.
+    pub fn digest(&self) -> Option<[u8; 16]> {
+        if let Ok(raw) = <[u8; 16]>::try_from(&self.0[..]) {
+            return Some(raw);
+        }
+        let mut digest = [0; 16];
+        hex::decode_to_slice(&self.0, &mut digest).ok()?;
+        Some(digest)
+    }
+}
+
+impl File {
+    /// Whether this is a padding file (BEP 47), which only exists to align the next file to a
+    /// piece boundary and is never written to disk.
+    ///
+    /// Those are marked with a `p` attribute, but some older creators only put them under `.pad/`.
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+            || (self.path.len() > 1 && self.path[0] == ".pad")
+    }
+
+    /// Where under `base` this file goes, unless its path (which comes straight from the
+    /// metainfo) would put it anywhere else.
+    ///
+    /// Each component has to be a plain name on this platform: not empty, `.` or `..`, not
+    /// absolute, and without separators or NULs.
+    pub fn safe_path(&self, base: &Path) -> Result<PathBuf, TorrentError> {
+        if self.path.is_empty() {
+            return Err(TorrentError::UnsafePath {
+                path: String::new(),
+                reason: "it is empty",
+            });
+        }
+        let mut path = base.to_path_buf();
+        for component in &self.path {
+            if let Some(reason) = unsafe_component(component) {
+                return Err(TorrentError::UnsafePath {
+                    path: format!("{:?}", self.path),
+                    reason,
+                });
+            }
+            path.push(component);
+        }
+        Ok(path)
+    }
+}
+
+/// The v2 layout of a hybrid torrent's files (BEP 52): directories by name, down to files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileTree {
+    File {
+        length: usize,
+        /// The root of the merkle tree over the file's 16 KiB blocks; empty files have none.
+        pieces_root: Option<[u8; 32]>,
+    },
+    Dir(BTreeMap<String, FileTree>),
+}
+
+impl FileTree {
+    /// Read the tree out of its bencode, `at` being where in the torrent it is for errors.
+    fn parse(value: &Value, at: &str) -> Result<Self, TorrentError> {
+        let invalid = |what: &str| TorrentError::Invalid(format!("{at} {what}"));
+        let Value::Dict(entries) = value else {
+            return Err(invalid("is not a dictionary"));
+        };
+        // a file is a dictionary with just an empty key, mapping to its attributes
+        if let Some(file) = entries.get(&b""[..]) {
+            let Value::Dict(file) = file else {
+                return Err(invalid("has a file that is not a dictionary"));
+            };
+            let length = match file.get(&b"length"[..]) {
+                Some(&Value::Int(length)) => {
+                    usize::try_from(length).map_err(|_| invalid("has a negative length"))?
+                }
+                _ => return Err(invalid("has a file without a length")),
+            };
+            let pieces_root = match file.get(&b"pieces root"[..]) {
+                Some(Value::Bytes(root)) => Some(
+                    <[u8; 32]>::try_from(&root[..])
+                        .map_err(|_| invalid("has a pieces root that isn't 32 bytes"))?,
+                ),
+                None => None,
+                Some(_) => return Err(invalid("has a pieces root that isn't a string")),
+            };
+            return Ok(FileTree::File {
+                length,
+                pieces_root,
+            });
+        }
+        entries
+            .iter()
+            .map(|(name, subtree)| {
+                let name = String::from_utf8_lossy(name).into_owned();
+                let subtree = FileTree::parse(subtree, &format!("{at}.{name}"))?;
+                Ok((name, subtree))
+            })
+            .collect::<Result<_, _>>()
+            .map(FileTree::Dir)
+    }
+
+    /// Every file in the tree, in the tree's order.
+    pub fn files(&self) -> Vec<TreeFile> {
+        let mut files = Vec::new();
+        self.collect(&mut Vec::new(), &mut files);
+        files
+    }
+
+    fn collect(&self, path: &mut Vec<String>, files: &mut Vec<TreeFile>) {
+        match self {
+            FileTree::File {
+                length,
+                pieces_root,
+            } => files.push(TreeFile {
+                path: path.clone(),
+                length: *length,
+                pieces_root: *pieces_root,
+            }),
+            FileTree::Dir(entries) => {
+                for (name, subtree) in entries {
+                    path.push(name.clone());
+                    subtree.collect(path, files);
+                    path.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Why `component` of a file's path can't be used as a file or directory name, if it can't.
+fn unsafe_component(component: &str) -> Option<&'static str> {
+    use std::path::Component;
+
+    if component.contains('\0') {
+        return Some("it contains a NUL byte");
+    }
+    // a separator inside a component would sneak in components of its own, on some platform
+    if component.contains(['/', '\\']) {
+        return Some("a component contains a path separator");
+    }
+    let mut components = Path::new(component).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => None,
+        (None, _) => Some("a component is empty"),
+        (Some(Component::CurDir | Component::ParentDir), _) => Some("it has `.` or `..` in it"),
+        _ => Some("a component is absolute"),
+    }
+}
+
+/// A file of a [`FileTree`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeFile {
+    /// Where the file is below the root of the tree.
+    pub path: Vec<String>,
+    pub length: usize,
+    pub pieces_root: Option<[u8; 32]>,
+}
+
+/// `secs` since the Unix epoch as an RFC 3339 timestamp in UTC.
+fn rfc3339(secs: i64) -> String {
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // the proleptic Gregorian calendar in eras of 400 years, starting from March so that leap
+    // days come last (see Howard Hinnant's `civil_from_days`)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// (De)serializing the unmodeled keys of a dictionary.
+mod extra {
+    use crate::bencode::Value;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+    use std::collections::BTreeMap;
+
+    /// [`super::Keys`] is untagged, so serde can't tell its keys are spoken for and hands them to
+    /// us as well.
+    const KEYS: [&[u8]; 2] = [b"length", b"files"];
+
+    pub(super) fn serialize<S>(
+        extra: &BTreeMap<Vec<u8>, Value>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(extra.iter().map(|(key, value)| (Bytes::new(key), value)))
+    }
+
+    pub(super) fn deserialize<'de, D>(deserializer: D) -> Result<BTreeMap<Vec<u8>, Value>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let extra = BTreeMap::<ByteBuf, Value>::deserialize(deserializer)?;
+        Ok(extra
+            .into_iter()
+            .map(|(key, value)| (key.into_vec(), value))
+            .filter(|(key, _)| !KEYS.contains(&&key[..]))
+            .collect())
+    }
+}
+
+/// Whether `bytes` look like a v2-only torrent: one with a meta version of 2 and no v1 pieces.
+fn is_v2_only(bytes: &[u8]) -> bool {
+    let Ok((bencode::ValueRef::Dict(torrent), _)) = bencode::decode_ref(bytes) else {
+        return false;
+    };
+    let Some(bencode::ValueRef::Dict(info)) = torrent.get(b"info") else {
+        return false;
+    };
+    info.get(b"meta version") == Some(bencode::ValueRef::Int(2)) && info.get(b"pieces").is_none()
+}
+
+/// Walk the raw bencode of a metainfo file looking for the first structural problem.
+fn probe(bytes: &[u8]) -> Result<(), TorrentError> {
+    let root = Dict::at(bytes, 0..bytes.len(), "")?;
+    if root.get("announce").is_some() {
+        root.string("announce")?;
+    }
+    let info = root.dict("info")?;
+    info.string("name")?;
+    info.int("piece length")?;
+    let (pieces, at) = info.bytes("pieces")?;
+    if !pieces.len().is_multiple_of(20) {
+        return Err(info.invalid(
+            "pieces",
+            at,
+            "a multiple of 20 bytes",
+            format!("{} bytes", pieces.len()),
+        ));
+    }
+    match (info.get("length"), info.get("files")) {
+        (Some(_), None) => {
+            info.int("length")?;
+        }
+        (None, Some(_)) => {
+            let (files, _) = info.list("files")?;
+            for (i, span) in files.into_iter().enumerate() {
+                let file = Dict::at(bytes, span, &format!("info.files[{i}]."))?;
+                file.int("length")?;
+                let (path, _) = file.list("path")?;
+                for (j, span) in path.into_iter().enumerate() {
+                    let key = format!("info.files[{i}].path[{j}]");
+                    match bencode::decode(&bytes[span.clone()])?.0 {
+                        Value::Bytes(b) if std::str::from_utf8(&b).is_ok() => {}
+                        v => {
+                            return Err(TorrentError::InvalidValue {
+                                key,
+                                offset: span.start,
+                                expected: "a UTF-8 string",
+                                found: describe(&v),
+                            })
+                        }
+                    }
+                }
+            }
+        }
+        (Some(_), Some(_)) => {
+            return Err(TorrentError::Invalid(String::from(
+                "info has both `length` and `files`",
+            )))
+        }
+        (None, None) => {
+            return Err(TorrentError::MissingKey {
+                key: String::from("info.length` or `info.files"),
+                offset: info.offset,
+            })
+        }
+    }
+    Ok(())
+}
+
+/// A dictionary found while probing, along with where it sits in the file.
+struct Dict<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    prefix: String,
+    entries: bencode::DictSpans,
+}
+
+impl<'a> Dict<'a> {
+    fn at(bytes: &'a [u8], span: Range<usize>, prefix: &str) -> Result<Self, TorrentError> {
+        let offset = span.start;
+        let entries = bencode::dict_spans(&bytes[span]).map_err(|mut e| {
+            e.offset += offset;
+            e
+        })?;
+        Ok(Self {
+            bytes,
+            offset,
+            prefix: prefix.to_string(),
+            entries: entries
+                .into_iter()
+                .map(|(key, span)| (key, span.start + offset..span.end + offset))
+                .collect(),
+        })
+    }
+
+    fn get(&self, key: &str) -> Option<Range<usize>> {
+        self.entries
+            .iter()
+            .find_map(|(k, span)| (k == key.as_bytes()).then(|| span.clone()))
+    }
+
+    fn value(&self, key: &str) -> Result<(Value, usize), TorrentError> {
+        let span = self.get(key).ok_or_else(|| TorrentError::MissingKey {
+            key: format!("{}{key}", self.prefix),
+            offset: self.offset,
+        })?;
+        let (value, _) = bencode::decode(&self.bytes[span.clone()])?;
+        Ok((value, span.start))
+    }
+
+    fn invalid(
+        &self,
+        key: &str,
+        offset: usize,
+        expected: &'static str,
+        found: String,
+    ) -> TorrentError {
+        TorrentError::InvalidValue {
+            key: format!("{}{key}", self.prefix),
+            offset,
+            expected,
+            found,
+        }
+    }
+
+    fn string(&self, key: &str) -> Result<(), TorrentError> {
+        match self.value(key)? {
+            (Value::Bytes(b), _) if std::str::from_utf8(&b).is_ok() => Ok(()),
+            (v, at) => Err(self.invalid(key, at, "a UTF-8 string", describe(&v))),
+        }
+    }
+
+    fn int(&self, key: &str) -> Result<(), TorrentError> {
+        match self.value(key)? {
+            (Value::Int(n), _) if n >= 0 => Ok(()),
+            (v, at) => Err(self.invalid(key, at, "a non-negative integer", describe(&v))),
+        }
+    }
+
+    fn bytes(&self, key: &str) -> Result<(Vec<u8>, usize), TorrentError> {
+        match self.value(key)? {
+            (Value::Bytes(b), at) => Ok((b, at)),
+            (v, at) => Err(self.invalid(key, at, "a byte string", describe(&v))),
+        }
+    }
+
+    fn list(&self, key: &str) -> Result<(Vec<Range<usize>>, usize), TorrentError> {
+        match self.value(key)? {
+            (Value::List(_), at) => {
+                let span = self.get(key).expect("just found it");
+                let items = bencode::list_spans(&self.bytes[span])?;
+                Ok((
+                    items
+                        .into_iter()
+                        .map(|item| item.start + at..item.end + at)
+                        .collect(),
+                    at,
+                ))
+            }
+            (v, at) => Err(self.invalid(key, at, "a list", describe(&v))),
+        }
+    }
+
+    fn dict(&self, key: &str) -> Result<Dict<'a>, TorrentError> {
+        match self.value(key)? {
+            (Value::Dict(_), _) => Dict::at(
+                self.bytes,
+                self.get(key).expect("just found it"),
+                &format!("{}{key}.", self.prefix),
+            ),
+            (v, at) => Err(self.invalid(key, at, "a dictionary", describe(&v))),
+        }
+    }
+}
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Int(n) => format!("the integer {n}"),
+        Value::Bytes(b) if std::str::from_utf8(b).is_err() => {
+            format!("{} bytes of non-UTF-8 data", b.len())
+        }
+        v => v.kind().to_string(),
+    }
 }
 
 mod hashes {
@@ -113,11 +1109,60 @@ mod hashes {
     use serde::ser::{Serialize, Serializer};
     use std::convert::TryInto;
     use std::fmt;
+    use std::ops::Index;
 
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
     pub struct Hashes(pub Vec<[u8; 20]>);
     struct HashesVisitor;
 
+    impl Hashes {
+        pub fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        pub fn get(&self, index: usize) -> Option<&[u8; 20]> {
+            self.0.get(index)
+        }
+
+        pub fn iter(&self) -> std::slice::Iter<'_, [u8; 20]> {
+            self.0.iter()
+        }
+
+        /// The hash of piece `index` in hex, as `info` prints them.
+        ///
+        /// Panics if there is no such piece, like indexing does.
+        pub fn hex(&self, index: usize) -> String {
+            hex::encode(self[index])
+        }
+    }
+
+    impl Index<usize> for Hashes {
+        type Output = [u8; 20];
+
+        fn index(&self, index: usize) -> &[u8; 20] {
+            &self.0[index]
+        }
+    }
+
+    impl<'a> IntoIterator for &'a Hashes {
+        type Item = &'a [u8; 20];
+        type IntoIter = std::slice::Iter<'a, [u8; 20]>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.0.iter()
+        }
+    }
+
+    impl FromIterator<[u8; 20]> for Hashes {
+        fn from_iter<I: IntoIterator<Item = [u8; 20]>>(iter: I) -> Self {
+            Hashes(iter.into_iter().collect())
+        }
+    }
+
     impl<'de> Visitor<'de> for HashesVisitor {
         type Value = Hashes;
 
@@ -129,7 +1174,7 @@ mod hashes {
         where
             E: de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom(format!("length is {}", v.len())));
             }
             // TODO: use array_chunks when stable
@@ -159,3 +1204,557 @@ mod hashes {
         }
     }
 }
+
+#[cfg(test)]
+const GOOD: &[u8] =
+    b"d8:announce9:localhost4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+
+#[test]
+fn from_bytes_keeps_raw_info() {
+    let t = Torrent::from_bytes(GOOD).unwrap();
+    let info = &GOOD[28..GOOD.len() - 1];
+    assert_eq!(t.info_bytes.as_deref(), Some(info));
+    let expected: [u8; 20] = Sha1::digest(info).into();
+    assert_eq!(t.info_hash().unwrap(), InfoHash(expected));
+}
+
+#[test]
+fn broken_torrents_name_the_key() {
+    let cases: &[(&[u8], &str)] = &[
+        (
+            include_bytes!("../tests/fixtures/torrents/broken/announce-integer.torrent"),
+            "invalid value for `announce` at byte 11: expected a UTF-8 string, found the integer 1",
+        ),
+        (
+            include_bytes!("../tests/fixtures/torrents/broken/piece-length-string.torrent"),
+            "invalid value for `info.piece length` at byte 64: expected a non-negative integer, found a byte string",
+        ),
+        (
+            include_bytes!("../tests/fixtures/torrents/broken/pieces-not-hash-multiple.torrent"),
+            "invalid value for `info.pieces` at byte 75: expected a multiple of 20 bytes, found 3 bytes",
+        ),
+        (
+            include_bytes!("../tests/fixtures/torrents/broken/no-length-or-files.torrent"),
+            "missing key `info.length` or `info.files` in dictionary at byte 28",
+        ),
+        (
+            include_bytes!("../tests/fixtures/torrents/broken/path-integer.torrent"),
+            "invalid value for `info.files[0].path[0]` at byte 56: expected a UTF-8 string, found the integer 7",
+        ),
+        (
+            include_bytes!("../tests/fixtures/torrents/broken/truncated.torrent"),
+            "malformed bencode: unexpected end of input at byte 98",
+        ),
+        (
+            include_bytes!("../tests/fixtures/torrents/broken/too-few-hashes.torrent"),
+            "invalid torrent: info.pieces holds 1 hashes, but 9 bytes in pieces of 4 need 3",
+        ),
+        (
+            include_bytes!("../tests/fixtures/torrents/broken/empty-name.torrent"),
+            "invalid torrent: info.name is empty",
+        ),
+        (
+            include_bytes!("../tests/fixtures/torrents/broken/zero-piece-length.torrent"),
+            "invalid torrent: info.piece length is zero",
+        ),
+        (
+            include_bytes!("../tests/fixtures/torrents/broken/empty-path.torrent"),
+            "invalid torrent: info.files[0].path is empty",
+        ),
+    ];
+    for (bytes, expected) in cases {
+        let e = Torrent::from_bytes(bytes).unwrap_err();
+        assert_eq!(
+            &e.to_string(),
+            expected,
+            "{}",
+            String::from_utf8_lossy(bytes)
+        );
+    }
+}
+
+#[test]
+fn from_file_names_the_file_and_the_field() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("broken.torrent");
+    std::fs::write(&path, b"d8:announcei1ee").unwrap();
+    let e = Torrent::from_file(&path).unwrap_err();
+    assert!(e.to_string().starts_with(&format!("{}: ", path.display())));
+
+    std::fs::write(
+        &path,
+        b"d8:announce9:localhost4:infod6:lengthi3e4:name1:a12:piece length1:46:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+    )
+    .unwrap();
+    assert_eq!(
+        Torrent::from_file(&path).unwrap_err().to_string(),
+        format!(
+            "{}: invalid value for `info.piece length` at byte 64: expected a non-negative integer, found a byte string",
+            path.display()
+        )
+    );
+
+    let missing = dir.path().join("missing.torrent");
+    let e = Torrent::from_file(&missing).unwrap_err();
+    assert!(e
+        .to_string()
+        .starts_with(&format!("read {}: ", missing.display())));
+}
+
+#[test]
+fn summary_of_a_multi_file_torrent() {
+    let t = Torrent::from_bytes(include_bytes!(
+        "../tests/fixtures/torrents/multi-file.torrent"
+    ))
+    .unwrap();
+    assert_eq!(t.length(), 20500);
+    assert_eq!(
+        t.summary().unwrap(),
+        "Tracker URL: http://tracker.example.com/announce
+Length: 20500
+Files:
+disc1/track01.flac 20000
+cover.jpg 500
+Private: no
+Info Hash: 9499d58a59aeed2a8f1698f75822f07b5d053f7c
+Piece Length: 16384
+Piece Hashes:
+68f3b81a11de1e1629e81555b4e70aed955d1140
+97545971d29bd74e7d3da6e91ee6222edf24c9dd
+"
+    );
+}
+
+#[test]
+fn optional_fields_are_shown_when_present() {
+    let t = Torrent::from_bytes(include_bytes!(
+        "../tests/fixtures/torrents/private-source.torrent"
+    ))
+    .unwrap();
+    assert_eq!(t.comment.as_deref(), Some("private upload"));
+    assert_eq!(t.created_by.as_deref(), Some("mktorrent 1.1"));
+    assert_eq!(t.encoding, None);
+    let summary = t.summary().unwrap();
+    let date = format!("Creation Date: {}\n", rfc3339(t.creation_date.unwrap()));
+    assert!(summary.contains(&date), "{summary}");
+    assert!(summary.contains("Created By: mktorrent 1.1\nComment: private upload\n"));
+    assert!(summary.contains("Private: yes\n"));
+    assert!(!summary.contains("Encoding"));
+    assert!(!t.extra.contains_key(&b"comment"[..]));
+
+    assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+    assert_eq!(rfc3339(951_825_600), "2000-02-29T12:00:00Z");
+    assert_eq!(rfc3339(1_700_000_000), "2023-11-14T22:13:20Z");
+    assert_eq!(rfc3339(-1), "1969-12-31T23:59:59Z");
+}
+
+#[test]
+fn hybrid_torrents_have_both_hashes() {
+    let bytes = include_bytes!("../tests/fixtures/torrents/hybrid.torrent");
+    let t = Torrent::from_bytes(bytes).unwrap();
+    assert!(t.is_hybrid());
+    assert_eq!(
+        t.info_hash().unwrap().to_string(),
+        "0f67585f3e48f5b3c1b618ed8698ef8992d69be1"
+    );
+    assert_eq!(
+        hex::encode(t.info_hash_v2().unwrap().unwrap()),
+        "945398a21559bd0d2307e535a52b634c25fff3fec409e296d045e34adb97af14"
+    );
+    let root: [u8; 32] = Sha256::digest(b"hello").into();
+    assert_eq!(
+        t.file_tree().unwrap().unwrap().files(),
+        [TreeFile {
+            path: vec![String::from("a.txt")],
+            length: 5,
+            pieces_root: Some(root)
+        }]
+    );
+    assert!(t.summary().unwrap().contains(
+        "Versions: v1, v2\nInfo Hash v2 (truncated): 945398a21559bd0d2307e535a52b634c25fff3fe\n"
+    ));
+
+    let v1 = Torrent::from_bytes(GOOD).unwrap();
+    assert!(!v1.is_hybrid());
+    assert_eq!(v1.info_hash_v2().unwrap(), None);
+    assert_eq!(v1.file_tree().unwrap(), None);
+
+    // without the v1 keys, there's nothing we can download
+    let v2_only = b"d8:announce3:url4:infod9:file treed1:xd0:d6:lengthi0eeee12:meta versioni2e4:name1:x12:piece lengthi16384eee";
+    let e = Torrent::from_bytes(v2_only).unwrap_err();
+    assert!(matches!(e, TorrentError::V2Only), "{e}");
+    assert!(e.to_string().contains("not yet downloadable"));
+
+    let tree = |tree: &[u8]| FileTree::parse(&bencode::decode(tree).unwrap().0, "tree");
+    let nested = tree(b"d1:bd1:cd0:d6:lengthi3eeee1:ad0:d6:lengthi0eeee").unwrap();
+    let paths: Vec<_> = nested
+        .files()
+        .iter()
+        .map(|file| file.path.join("/"))
+        .collect();
+    assert_eq!(paths, ["a", "b/c"]);
+    assert!(tree(b"d1:ad0:d11:pieces root3:abceee").is_err());
+    assert!(tree(b"d1:ai3ee").is_err());
+}
+
+#[test]
+fn info_round_trips_byte_for_byte() {
+    let corpus: &[(&str, &[u8])] = &[
+        ("sample", include_bytes!("../sample.torrent")),
+        (
+            "private-source",
+            include_bytes!("../tests/fixtures/torrents/private-source.torrent"),
+        ),
+        (
+            "multi-file-attrs",
+            include_bytes!("../tests/fixtures/torrents/multi-file-attrs.torrent"),
+        ),
+        (
+            "nested-extras",
+            include_bytes!("../tests/fixtures/torrents/nested-extras.torrent"),
+        ),
+        (
+            "hybrid",
+            include_bytes!("../tests/fixtures/torrents/hybrid.torrent"),
+        ),
+    ];
+    for (name, bytes) in corpus {
+        let t = Torrent::from_bytes(bytes).unwrap_or_else(|e| panic!("{name}: {e}"));
+        let raw = t.info_bytes.as_deref().unwrap();
+        let reencoded = serde_bencode::to_bytes(&t.info).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&reencoded),
+            String::from_utf8_lossy(raw),
+            "{name}"
+        );
+        assert_eq!(reencoded, raw, "{name}");
+
+        // and so a torrent nobody parsed the raw bytes for still hashes the same
+        let rebuilt = Torrent {
+            info_bytes: None,
+            ..t.clone()
+        };
+        assert_eq!(
+            rebuilt.info_hash().unwrap(),
+            t.info_hash().unwrap(),
+            "{name}"
+        );
+    }
+
+    let t = Torrent::from_bytes(corpus[1].1).unwrap();
+    assert!(t.is_private());
+    assert!(!t.info.extra.contains_key(&b"private"[..]));
+    assert_eq!(
+        t.info.extra[&b"source"[..]],
+        Value::Bytes(b"EXAMPLE".to_vec())
+    );
+    assert!(!t.info.extra.contains_key(&b"length"[..]));
+    // what `sha1sum` says of the info dict cut out of the file by hand
+    assert_eq!(
+        t.info_hash().unwrap().to_string(),
+        "92cdb1007fba7c27a64f9103bf48f601188afd49"
+    );
+}
+
+#[test]
+fn created_torrents_rewrite_identically() {
+    let info = Info {
+        name: String::from("bundle"),
+        plength: 16384,
+        pieces: Hashes(vec![[7; 20]; 2]),
+        keys: Keys::MultiFile {
+            files: vec![
+                File {
+                    length: 20000,
+                    path: vec![String::from("b.bin")],
+                    attr: Some(String::from("x")),
+                    md5sum: None,
+                    extra: BTreeMap::new(),
+                },
+                File {
+                    length: 100,
+                    path: vec![String::from("a"), String::from("c.txt")],
+                    attr: None,
+                    md5sum: None,
+                    extra: BTreeMap::from([(b"md5sum".to_vec(), Value::Bytes(vec![b'0'; 32]))]),
+                },
+            ],
+        },
+        private: Some(1),
+        md5sum: None,
+        extra: BTreeMap::new(),
+    };
+    let mut t = Torrent::new(String::from("http://tracker.example.com/announce"), info);
+    t.creation_date = Some(1_700_000_000);
+    t.comment = Some(String::from("made by hand"));
+
+    let written = t.to_bytes().unwrap();
+    assert_eq!(
+        bencode::encode(&bencode::decode(&written).unwrap().0),
+        written
+    );
+    let parsed = Torrent::from_bytes(&written).unwrap();
+    assert_eq!(parsed.to_bytes().unwrap(), written);
+    assert_eq!(parsed.info_hash().unwrap(), t.info_hash().unwrap());
+}
+
+#[test]
+fn padding_files_by_attribute_or_directory() {
+    let t = Torrent::from_bytes(include_bytes!(
+        "../tests/fixtures/torrents/multi-file-attrs.torrent"
+    ))
+    .unwrap();
+    let Keys::MultiFile { files } = &t.info.keys else {
+        panic!("multi-file fixture");
+    };
+    let attrs: Vec<_> = files.iter().map(|file| file.attr.as_deref()).collect();
+    assert_eq!(attrs, [None, Some("p"), Some("x"), None]);
+    let padding: Vec<_> = files.iter().map(File::is_padding).collect();
+    assert_eq!(padding, [false, true, false, false]);
+    assert!(!t.summary().unwrap().contains(".pad"));
+
+    let file = |path: &[&str]| File {
+        length: 1,
+        path: path.iter().map(|c| c.to_string()).collect(),
+        attr: None,
+        md5sum: None,
+        extra: BTreeMap::new(),
+    };
+    assert!(file(&[".pad", "1"]).is_padding());
+    assert!(
+        !file(&[".pad"]).is_padding(),
+        "a file called .pad is just a file"
+    );
+    assert!(!file(&["docs", ".pad", "1"]).is_padding());
+}
+
+#[test]
+fn paths_that_would_escape_are_refused() {
+    let file = |path: &[&str]| File {
+        length: 1,
+        path: path.iter().map(|c| c.to_string()).collect(),
+        attr: None,
+        md5sum: None,
+        extra: BTreeMap::new(),
+    };
+    let base = Path::new("out");
+    assert_eq!(
+        file(&["docs", "a.txt"]).safe_path(base).unwrap(),
+        base.join("docs").join("a.txt")
+    );
+    let refused = |path: &[&str]| file(path).safe_path(base).unwrap_err().to_string();
+    assert_eq!(
+        refused(&["..", "etc", "passwd"]),
+        r#"unsafe file path ["..", "etc", "passwd"]: it has `.` or `..` in it"#
+    );
+    assert!(refused(&["a\0b"]).ends_with("it contains a NUL byte"));
+    assert!(refused(&["/etc", "passwd"]).ends_with("a component contains a path separator"));
+    assert!(refused(&["a/../../b"]).ends_with("a component contains a path separator"));
+    assert!(refused(&["a\\b"]).ends_with("a component contains a path separator"));
+    assert!(refused(&["a", ""]).ends_with("a component is empty"));
+    assert!(refused(&["."]).ends_with("it has `.` or `..` in it"));
+    assert!(refused(&[]).ends_with("it is empty"));
+
+    // info shows such a torrent, but says what's wrong with it
+    let mut t = Torrent::from_bytes(include_bytes!(
+        "../tests/fixtures/torrents/multi-file.torrent"
+    ))
+    .unwrap();
+    let Keys::MultiFile { files } = &mut t.info.keys else {
+        panic!("multi-file fixture");
+    };
+    files[1].path = vec![String::from(".."), String::from("cover.jpg")];
+    assert!(t.summary().unwrap().contains(
+        "../cover.jpg 500 (suspicious: unsafe file path [\"..\", \"cover.jpg\"]: it has `.` or `..` in it)\n"
+    ));
+}
+
+#[test]
+fn edits_leave_the_info_dictionary_alone() {
+    let original = include_bytes!("../tests/fixtures/torrents/multi-file-attrs.torrent");
+    let mut t = Torrent::from_bytes(original).unwrap();
+    let info_hash = t.info_hash().unwrap();
+    t.announce = String::from("http://new.example.com/announce");
+    t.extra.remove(&b"announce-list"[..]);
+    let edited = Torrent::from_bytes(&t.to_bytes().unwrap()).unwrap();
+    assert_eq!(edited.announce, "http://new.example.com/announce");
+    assert_eq!(edited.info_bytes, t.info_bytes);
+    assert_eq!(edited.info_hash().unwrap(), info_hash);
+
+    // even an info dictionary that isn't canonical itself is kept as is
+    let unsorted = b"d8:announce3:url4:infod4:name1:x6:lengthi3e12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+    let t = Torrent::from_bytes(unsorted).unwrap();
+    assert_eq!(t.to_bytes().unwrap(), unsorted);
+}
+
+#[test]
+fn trackers_from_the_announce_list() {
+    let mut t = Torrent::from_bytes(GOOD).unwrap();
+    assert_eq!(t.trackers(), vec!["localhost"]);
+
+    let url = |url: &str| Value::Bytes(url.as_bytes().to_vec());
+    t.extra.insert(
+        b"announce-list".to_vec(),
+        Value::List(vec![
+            Value::List(vec![url("http://a/announce"), url("http://b/announce")]),
+            Value::List(vec![url("http://a/announce"), Value::Int(1)]),
+            Value::Int(2),
+            Value::List(vec![url("udp://c:80")]),
+        ]),
+    );
+    assert_eq!(
+        t.trackers(),
+        vec!["http://a/announce", "http://b/announce", "udp://c:80"]
+    );
+    assert_eq!(
+        t.announce_list().unwrap(),
+        [
+            vec!["http://a/announce", "http://b/announce"],
+            vec!["http://a/announce"],
+            vec!["udp://c:80"]
+        ]
+    );
+}
+
+#[test]
+fn piece_lengths_when_the_length_is_a_multiple() {
+    let data = crate::mock::data(3 * 512);
+    let t = crate::mock::torrent_for("http://unused/announce", &data, 512);
+    assert_eq!(t.num_pieces(), 3);
+    assert_eq!(t.piece_length_for(2), 512);
+    assert_eq!(t.piece_length_for(3), 0);
+    let offsets: Vec<_> = t
+        .pieces()
+        .map(|piece| (piece.offset, piece.length))
+        .collect();
+    assert_eq!(offsets, [(0, 512), (512, 512), (1024, 512)]);
+}
+
+#[test]
+fn piece_lengths_when_the_last_piece_is_short() {
+    // over several files, which only the total length matters for
+    let data = crate::mock::data(1000);
+    let t = crate::piece::multi_file_torrent(&data, 512, &[("a", 700, false), ("b", 300, false)]);
+    assert_eq!(t.num_pieces(), 2);
+    assert_eq!(t.piece_length_for(0), 512);
+    assert_eq!(t.piece_length_for(1), 488);
+    let pieces: Vec<_> = t.pieces().collect();
+    assert_eq!(pieces.len(), 2);
+    assert_eq!(
+        pieces[1],
+        PieceInfo {
+            index: 1,
+            offset: 512,
+            length: 488,
+            hash: t.info.pieces[1],
+        }
+    );
+    assert_eq!(
+        pieces.iter().map(|piece| piece.length).sum::<usize>(),
+        t.length()
+    );
+}
+
+#[test]
+fn hashes_round_trip_and_index() {
+    let hashes: Hashes = (0..3u8).map(|i| [i; 20]).collect();
+    assert_eq!(hashes.len(), 3);
+    assert!(!hashes.is_empty() && Hashes(Vec::new()).is_empty());
+    assert_eq!(hashes[2], [2; 20]);
+    assert_eq!(hashes.get(3), None);
+    assert_eq!(hashes.hex(1), "01".repeat(20));
+    assert_eq!((&hashes).into_iter().count(), hashes.iter().count());
+
+    let bytes = serde_bencode::to_bytes(&hashes).unwrap();
+    assert_eq!(
+        bytes,
+        [b"60:".as_slice(), &[0; 20], &[1; 20], &[2; 20]].concat()
+    );
+    assert_eq!(serde_bencode::from_bytes::<Hashes>(&bytes).unwrap(), hashes);
+}
+
+#[test]
+fn trackerless_torrents_list_dht_nodes() {
+    let trackerless = b"d4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll9:127.0.0.1i6881eel6:dht.exi-1eei3el3:::1i6882eeee";
+    let t = Torrent::from_bytes(trackerless).unwrap();
+    assert_eq!(
+        t.nodes.as_deref(),
+        Some(&[("127.0.0.1".to_string(), 6881), ("::1".to_string(), 6882)][..])
+    );
+    assert!(t.tracker_tiers().is_empty() && t.trackers().is_empty());
+    let summary = t.summary().unwrap();
+    assert!(
+        summary.starts_with("Length: 3\nDHT Nodes:\n127.0.0.1:6881\n[::1]:6882\n"),
+        "{summary}"
+    );
+    // and neither an empty announce nor the nodes we skipped come back
+    let again = Torrent::from_bytes(&t.to_bytes().unwrap()).unwrap();
+    assert!(again.announce.is_empty());
+    assert_eq!(again.nodes, t.nodes);
+
+    assert_eq!(Torrent::from_bytes(GOOD).unwrap().nodes, None);
+}
+
+#[test]
+fn web_seeds_as_one_url_or_a_list() {
+    let with_urls = |urls: &str| {
+        let bytes = format!(
+            "d8:announce9:localhost4:infod6:lengthi3e4:name5:a b.c12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list{urls}e"
+        );
+        Torrent::from_bytes(bytes.as_bytes()).unwrap()
+    };
+    let one = with_urls("23:http://mirror.example/a");
+    assert_eq!(
+        one.url_list.as_deref(),
+        Some(&["http://mirror.example/a".to_string()][..])
+    );
+    assert_eq!(one.web_seeds(), ["http://mirror.example/a"]);
+
+    let list = with_urls("l22:http://mirror.example/i1e19:https://other.org/xe");
+    assert_eq!(
+        list.web_seeds(),
+        ["http://mirror.example/a%20b.c", "https://other.org/x"]
+    );
+    assert!(list
+        .summary()
+        .unwrap()
+        .contains("Web Seeds:\nhttp://mirror.example/\nhttps://other.org/x\n"));
+
+    assert!(Torrent::from_bytes(GOOD).unwrap().web_seeds().is_empty());
+}
+
+#[test]
+fn file_lengths_that_overflow_are_refused() {
+    let file = format!("d6:lengthi{}e4:pathl1:aee", usize::MAX / 2);
+    let bytes = format!(
+        "d8:announce9:localhost4:infod5:filesl{}e4:name1:a12:piece lengthi4e6:pieces0:ee",
+        file.repeat(3)
+    );
+    let e = Torrent::from_bytes(bytes.as_bytes()).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        format!(
+            "invalid torrent: info.files add up to more than the {} bytes we can address",
+            usize::MAX
+        )
+    );
+}
+
+#[test]
+fn info_hashes_parse_and_print() {
+    let hex = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+    let info_hash: InfoHash = hex.parse().unwrap();
+    assert_eq!(info_hash.to_string(), hex);
+    assert_eq!(hex.to_uppercase().parse::<InfoHash>().unwrap(), info_hash);
+    assert_eq!(
+        "22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7"
+            .parse::<InfoHash>()
+            .unwrap(),
+        info_hash
+    );
+    assert_eq!(info_hash.as_bytes()[..2], [0xd6, 0x9f]);
+
+    for bad in ["d69f", &"g".repeat(40), "22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT1"] {
+        assert!(bad.parse::<InfoHash>().is_err(), "{bad}");
+    }
+}
@@ -0,0 +1,377 @@
+//! The UDP tracker protocol (BEP 15): a connect exchange for a connection ID, and then announces
+//! that present it, each request a single datagram retransmitted until the tracker answers.
+
+use super::{Event, Family, Peers, TrackerError, TrackerRequest, TrackerResponse};
+use crate::torrent::InfoHash;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// The magic number a connect request starts with.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const CONNECT: u32 = 0;
+const ANNOUNCE: u32 = 1;
+const ERROR: u32 = 3;
+
+/// How long to wait for the first answer to a request; every retransmission waits twice as long
+/// as the one before it.
+pub const RETRY_BASE: Duration = Duration::from_secs(15);
+
+/// How many times a request is retransmitted before we give up on the tracker.
+pub const MAX_RETRIES: u32 = 8;
+
+/// How long we keep using a connection ID; trackers accept them for two minutes, but clients are
+/// only meant to rely on one.
+pub const CONNECTION_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The connection ID each tracker address last gave us, and when.
+static CONNECTIONS: LazyLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> =
+    LazyLock::new(Mutex::default);
+
+/// Announce `request` for `info_hash` to the `udp://` tracker at `url`, optionally over one
+/// address family only.
+pub(super) async fn announce(
+    url: &reqwest::Url,
+    info_hash: InfoHash,
+    request: &TrackerRequest,
+    family: Option<Family>,
+) -> anyhow::Result<TrackerResponse> {
+    announce_with(url, info_hash, request, family, RETRY_BASE).await
+}
+
+async fn announce_with(
+    url: &reqwest::Url,
+    info_hash: InfoHash,
+    request: &TrackerRequest,
+    family: Option<Family>,
+    retry_base: Duration,
+) -> anyhow::Result<TrackerResponse> {
+    let addr = tracker_addr(url, family).await?;
+    let local: SocketAddr = match crate::resolve::bound() {
+        Some(ip) => (ip, 0).into(),
+        None if addr.is_ipv4() => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        None => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)
+        .await
+        .with_context(|| format!("bind UDP socket to {local}"))?;
+    socket
+        .connect(addr)
+        .await
+        .with_context(|| format!("connect UDP socket to {addr}"))?;
+
+    let connection_id = connection_id(&socket, addr, retry_base).await?;
+    let transaction_id = fastrand::u32(..);
+    let packet = announce_packet(connection_id, transaction_id, info_hash, request)?;
+    let reply = match exchange(&socket, &packet, ANNOUNCE, transaction_id, retry_base).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            // the tracker may well have forgotten the connection ID; don't offer it again
+            CONNECTIONS
+                .lock()
+                .expect("nobody panics holding the connections")
+                .remove(&addr);
+            return Err(e);
+        }
+    };
+    parse_announce(&reply, addr.is_ipv6())
+}
+
+/// The address of the tracker at `url`, in `family` if there is one.
+pub(super) async fn tracker_addr(
+    url: &reqwest::Url,
+    family: Option<Family>,
+) -> anyhow::Result<SocketAddr> {
+    let host = url.host_str().context("tracker URL has no host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url
+        .port_or_known_default()
+        .with_context(|| format!("{} tracker URL has no port", url.scheme().to_uppercase()))?;
+    let addr = tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("resolve tracker host {host}"))?
+        .find(|addr| family.is_none_or(|family| family.matches(addr)));
+    match (addr, family) {
+        (Some(addr), _) => Ok(addr),
+        (None, Some(family)) => anyhow::bail!("tracker host {host} has no {family} addresses"),
+        (None, None) => anyhow::bail!("tracker host {host} resolved to no addresses"),
+    }
+}
+
+/// A connection ID for the tracker at `addr`: the last one it gave us if that's still fresh, or
+/// else a new one.
+async fn connection_id(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    retry_base: Duration,
+) -> anyhow::Result<u64> {
+    let cached = CONNECTIONS
+        .lock()
+        .expect("nobody panics holding the connections")
+        .get(&addr)
+        .filter(|(_, since)| since.elapsed() < CONNECTION_LIFETIME)
+        .map(|&(id, _)| id);
+    if let Some(id) = cached {
+        return Ok(id);
+    }
+
+    let transaction_id = fastrand::u32(..);
+    let mut packet = Vec::with_capacity(16);
+    packet.extend(PROTOCOL_ID.to_be_bytes());
+    packet.extend(CONNECT.to_be_bytes());
+    packet.extend(transaction_id.to_be_bytes());
+    let reply = exchange(socket, &packet, CONNECT, transaction_id, retry_base).await?;
+    let id = reply
+        .get(8..16)
+        .context("UDP tracker's connect response is too short")?;
+    let id = u64::from_be_bytes(id.try_into().expect("8 bytes"));
+    CONNECTIONS
+        .lock()
+        .expect("nobody panics holding the connections")
+        .insert(addr, (id, Instant::now()));
+    Ok(id)
+}
+
+fn announce_packet(
+    connection_id: u64,
+    transaction_id: u32,
+    info_hash: InfoHash,
+    request: &TrackerRequest,
+) -> anyhow::Result<Vec<u8>> {
+    let event: u32 = match request.event {
+        None => 0,
+        Some(Event::Completed) => 1,
+        Some(Event::Started) => 2,
+        Some(Event::Stopped) => 3,
+    };
+    // only an IPv4 address fits; anything else leaves it to the tracker, as 0 does
+    let ip = request
+        .ip
+        .as_deref()
+        .and_then(|ip| ip.parse::<Ipv4Addr>().ok())
+        .map_or(0, u32::from);
+    let numwant = request
+        .numwant
+        .map_or(-1, |numwant| i32::try_from(numwant).unwrap_or(i32::MAX));
+
+    let mut packet = Vec::with_capacity(98);
+    packet.extend(connection_id.to_be_bytes());
+    packet.extend(ANNOUNCE.to_be_bytes());
+    packet.extend(transaction_id.to_be_bytes());
+    packet.extend(info_hash.as_bytes());
+    packet.extend(request.peer_id.0);
+    packet.extend((request.downloaded as u64).to_be_bytes());
+    packet.extend((request.left as u64).to_be_bytes());
+    packet.extend((request.uploaded as u64).to_be_bytes());
+    packet.extend(event.to_be_bytes());
+    packet.extend(ip.to_be_bytes());
+    packet.extend(
+        request
+            .key
+            .unwrap_or_else(|| fastrand::u32(..))
+            .to_be_bytes(),
+    );
+    packet.extend(numwant.to_be_bytes());
+    packet.extend(request.port.to_be_bytes());
+    Ok(packet)
+}
+
+/// Send `packet` until the answer to `transaction_id` arrives, waiting `retry_base`·2ⁿ for it
+/// after the nth retransmission.
+///
+/// Datagrams for other transactions (late answers to an earlier request, say) are passed over.
+async fn exchange(
+    socket: &UdpSocket,
+    packet: &[u8],
+    action: u32,
+    transaction_id: u32,
+    retry_base: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0; 64 * 1024];
+    for n in 0..=MAX_RETRIES {
+        socket.send(packet).await.context("send to UDP tracker")?;
+        let deadline = tokio::time::Instant::now() + retry_base * 2u32.pow(n);
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let len = received.context("receive from UDP tracker")?;
+            let reply = &buf[..len];
+            if reply.get(4..8) != Some(&transaction_id.to_be_bytes()[..]) {
+                continue;
+            }
+            let got = u32::from_be_bytes(reply[..4].try_into().expect("4 bytes"));
+            if got == ERROR {
+                let reason = String::from_utf8_lossy(&reply[8..]);
+                return Err(TrackerError::Failure(reason.into_owned()).into());
+            }
+            anyhow::ensure!(
+                got == action,
+                "UDP tracker answered action {action} with action {got}"
+            );
+            return Ok(reply.to_vec());
+        }
+    }
+    anyhow::bail!("UDP tracker didn't answer after {} tries", MAX_RETRIES + 1)
+}
+
+/// An announce response: the interval, the leecher and seeder counts, and then the peers, which
+/// are IPv6 addresses if we asked over IPv6.
+fn parse_announce(reply: &[u8], ipv6: bool) -> anyhow::Result<TrackerResponse> {
+    let word = |i: usize| {
+        reply
+            .get(i..i + 4)
+            .map(|word| u32::from_be_bytes(word.try_into().expect("4 bytes")) as usize)
+            .context("UDP tracker's announce response is too short")
+    };
+    let (interval, leechers, seeders) = (word(8)?, word(12)?, word(16)?);
+    let entry = if ipv6 { 18 } else { 6 };
+    let peers = &reply[20..];
+    anyhow::ensure!(
+        peers.len().is_multiple_of(entry),
+        "UDP tracker's peer list is {} bytes, which isn't a whole number of peers",
+        peers.len()
+    );
+    let addrs = peers
+        .chunks_exact(entry)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(entry - 2);
+            let ip = match <[u8; 4]>::try_from(ip) {
+                Ok(ip) => IpAddr::from(ip),
+                Err(_) => {
+                    IpAddr::from(<[u8; 16]>::try_from(ip).expect("entries are 6 or 18 bytes"))
+                }
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+        })
+        .collect();
+    Ok(TrackerResponse {
+        interval,
+        min_interval: None,
+        complete: Some(seeders),
+        incomplete: Some(leechers),
+        downloaded: None,
+        external_ip: None,
+        failure_reason: None,
+        warning_message: None,
+        tracker_id: None,
+        tracker: None,
+        peers: Peers {
+            addrs,
+            ..Peers::default()
+        },
+    })
+}
+
+#[cfg(test)]
+fn request() -> TrackerRequest {
+    let mut request =
+        TrackerRequest::new(crate::peer::PeerId(*b"00112233445566778899"), 6881, 1000);
+    request.uploaded = 300;
+    request.downloaded = 200;
+    request.event = Some(Event::Started);
+    request.numwant = Some(30);
+    request
+}
+
+#[tokio::test]
+async fn announces_reuse_their_connection() {
+    use crate::mock::{MockUdpTracker, UdpBehaviour};
+
+    let peers: Vec<std::net::SocketAddrV4> = vec![
+        "10.0.0.1:6881".parse().unwrap(),
+        "10.0.0.2:51413".parse().unwrap(),
+    ];
+    let tracker = MockUdpTracker::serve(&peers, UdpBehaviour::default()).await;
+    let info_hash = InfoHash([7; 20]);
+    for _ in 0..2 {
+        let response = super::TrackerClient::shared()
+            .announce(&tracker.announce_url(), info_hash, &request(), None)
+            .await
+            .unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!((response.complete, response.incomplete), (Some(2), Some(0)));
+        let addrs: Vec<SocketAddr> = peers.iter().map(|&peer| peer.into()).collect();
+        assert_eq!(response.peers.addrs, addrs);
+    }
+    // one connect is good for both announces
+    assert_eq!(tracker.packets().len(), 3);
+
+    let announce = &tracker.announces()[0];
+    assert_eq!(announce.len(), 98);
+    let u64_at = |i: usize| u64::from_be_bytes(announce[i..i + 8].try_into().unwrap());
+    let u32_at = |i: usize| u32::from_be_bytes(announce[i..i + 4].try_into().unwrap());
+    assert_eq!(u64_at(0), crate::mock::UDP_CONNECTION_ID);
+    assert_eq!(&announce[16..36], info_hash.as_bytes());
+    assert_eq!(&announce[36..56], b"00112233445566778899");
+    assert_eq!((u64_at(56), u64_at(64), u64_at(72)), (200, 1000, 300));
+    assert_eq!(u32_at(80), 2, "started");
+    assert_eq!(u32_at(84), 0, "no IP override");
+    assert_eq!(u32_at(92), 30);
+    assert_eq!(&announce[96..], 6881u16.to_be_bytes());
+}
+
+#[tokio::test]
+async fn lost_datagrams_are_retransmitted() {
+    use crate::mock::{MockUdpTracker, UdpBehaviour};
+
+    let behaviour = UdpBehaviour {
+        drop_first: 1,
+        ..UdpBehaviour::default()
+    };
+    let tracker = MockUdpTracker::serve(&["10.0.0.1:6881".parse().unwrap()], behaviour).await;
+    let url = reqwest::Url::parse(&tracker.announce_url()).unwrap();
+    let response = announce_with(
+        &url,
+        InfoHash([1; 20]),
+        &request(),
+        None,
+        Duration::from_millis(50),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.peers.addrs.len(), 1);
+    // the connect request went out twice
+    let packets = tracker.packets();
+    assert_eq!(packets.len(), 3);
+    assert_eq!(packets[0], packets[1]);
+
+    // a tracker that never answers is given up on eventually
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url =
+        reqwest::Url::parse(&format!("udp://{}/announce", silent.local_addr().unwrap())).unwrap();
+    let e = announce_with(
+        &url,
+        InfoHash([1; 20]),
+        &request(),
+        None,
+        Duration::from_millis(1),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(e.to_string(), "UDP tracker didn't answer after 9 tries");
+}
+
+#[tokio::test]
+async fn refusals_are_errors_and_forget_the_connection() {
+    use crate::mock::{MockUdpTracker, UdpBehaviour};
+
+    let behaviour = UdpBehaviour {
+        refuse: Some("torrent not registered"),
+        ..UdpBehaviour::default()
+    };
+    let tracker = MockUdpTracker::serve(&[], behaviour).await;
+    for _ in 0..2 {
+        let e = super::TrackerClient::shared()
+            .announce(&tracker.announce_url(), InfoHash([1; 20]), &request(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "tracker refused announce: torrent not registered"
+        );
+    }
+    // so each announce connected afresh
+    assert_eq!(tracker.packets().len(), 4);
+}
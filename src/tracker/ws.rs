@@ -0,0 +1,264 @@
+//! WebSocket trackers, the kind WebTorrent swarms list: each announce is a JSON message over a
+//! WebSocket (RFC 6455), plain (`ws://`) or over TLS (`wss://`), and the answer another.
+//!
+//! Those trackers mostly broker WebRTC offers between browsers, which we can't take up; what we
+//! can use are the peers some also hand out with a plain TCP address.
+
+use super::{Event, Family, Peers, TrackerError, TrackerRequest, TrackerResponse};
+use super::{DEFAULT_INTERVAL, MAX_RESPONSE, USER_AGENT};
+use crate::torrent::InfoHash;
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::Connector;
+
+/// Announce `request` for `info_hash` to the WebSocket tracker at `url`, optionally over one
+/// address family only, speaking TLS through `tls` to a `wss://` one.
+pub(super) async fn announce(
+    url: &reqwest::Url,
+    info_hash: InfoHash,
+    request: &TrackerRequest,
+    family: Option<Family>,
+    tls: &native_tls::TlsConnector,
+) -> anyhow::Result<TrackerResponse> {
+    let addr = super::udp::tracker_addr(url, family).await?;
+    let stream = crate::resolve::tcp_connect(addr)
+        .await
+        .with_context(|| format!("connect to WebSocket tracker at {addr}"))?;
+    let mut handshake = url
+        .as_str()
+        .into_client_request()
+        .context("make WebSocket handshake")?;
+    handshake
+        .headers_mut()
+        .insert("User-Agent", HeaderValue::from_static(USER_AGENT));
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_RESPONSE),
+        max_frame_size: Some(MAX_RESPONSE),
+        ..WebSocketConfig::default()
+    };
+    let connector = Connector::NativeTls(tls.clone());
+    let (mut socket, _) = tokio_tungstenite::client_async_tls_with_config(
+        handshake,
+        stream,
+        Some(config),
+        Some(connector),
+    )
+    .await
+    .context("WebSocket handshake with tracker")?;
+
+    let message = announce_message(info_hash, request);
+    socket
+        .send(Message::text(message.to_string()))
+        .await
+        .context("send WebSocket announce")?;
+    // browsers' offers come to us one message each, so they're only worth a word at the end
+    let mut offers = 0;
+    let response = loop {
+        let message = socket
+            .next()
+            .await
+            .context("tracker closed the WebSocket before answering")?
+            .context("read WebSocket tracker message")?;
+        let message = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => anyhow::bail!("tracker closed the WebSocket before answering"),
+            // pings get their pongs as we read; trackers have no reason to send binary messages
+            _ => continue,
+        };
+        let answer: Answer =
+            serde_json::from_str(&message).context("parse WebSocket tracker message")?;
+        if let Some(reason) = answer.failure_reason {
+            return Err(TrackerError::Failure(reason).into());
+        }
+        if answer.offer.is_some() {
+            offers += 1;
+            continue;
+        }
+        // an announce's own answer is the one that says when to come back
+        if answer.action.as_deref() != Some("announce") || answer.interval.is_none() {
+            continue;
+        }
+        break answer.into_response();
+    };
+    if offers > 0 {
+        eprintln!("skipped {offers} WebRTC offers from browser peers, which we can't take up");
+    }
+    let _ = socket.close(None).await;
+    Ok(response)
+}
+
+/// The announce message for `request`, with the info hash and peer id as the "binary strings"
+/// WebTorrent uses: one character per byte.
+fn announce_message(info_hash: InfoHash, request: &TrackerRequest) -> serde_json::Value {
+    let binary = |bytes: &[u8]| bytes.iter().map(|&b| char::from(b)).collect::<String>();
+    let mut message = serde_json::json!({
+        "action": "announce",
+        "info_hash": binary(info_hash.as_bytes()),
+        "peer_id": binary(&request.peer_id.0),
+        "uploaded": request.uploaded,
+        "downloaded": request.downloaded,
+        "left": request.left,
+        "numwant": request.numwant.unwrap_or(super::DEFAULT_NUMWANT),
+        // we can't answer WebRTC offers, so we make none either
+        "offers": [],
+    });
+    if let Some(event) = request.event {
+        let event = match event {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        };
+        message["event"] = event.into();
+    }
+    message
+}
+
+/// Any message a WebSocket tracker sends us.
+#[derive(Deserialize)]
+struct Answer {
+    action: Option<String>,
+    interval: Option<usize>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<usize>,
+    complete: Option<usize>,
+    incomplete: Option<usize>,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    /// A WebRTC offer some browser peer relayed through the tracker.
+    offer: Option<serde_json::Value>,
+    /// Peers with a plain TCP address, which only some trackers hand out.
+    #[serde(default)]
+    peers: Vec<TcpPeer>,
+}
+
+#[derive(Deserialize)]
+struct TcpPeer {
+    ip: Option<String>,
+    port: Option<u16>,
+}
+
+impl Answer {
+    fn into_response(self) -> TrackerResponse {
+        let mut addrs = Vec::new();
+        for peer in self.peers {
+            let addr = match (peer.ip, peer.port) {
+                (Some(ip), Some(port)) if port != 0 => {
+                    ip.parse::<IpAddr>().ok().map(|ip| (ip, port))
+                }
+                _ => None,
+            };
+            match addr {
+                Some(addr) => addrs.push(SocketAddr::from(addr)),
+                None => eprintln!("skipping a WebSocket tracker peer without a TCP address"),
+            }
+        }
+        TrackerResponse {
+            interval: self.interval.unwrap_or(DEFAULT_INTERVAL),
+            min_interval: self.min_interval,
+            complete: self.complete,
+            incomplete: self.incomplete,
+            downloaded: None,
+            external_ip: None,
+            failure_reason: None,
+            warning_message: self.warning_message,
+            tracker_id: None,
+            tracker: None,
+            peers: Peers {
+                addrs,
+                ..Peers::default()
+            },
+        }
+    }
+}
+
+#[tokio::test]
+async fn websocket_announces() {
+    use crate::mock::MockWsTracker;
+    use crate::peer::PeerId;
+    use crate::tracker::TrackerClient;
+    use serde_json::json;
+
+    let tracker = MockWsTracker::serve(vec![
+        json!({"action": "announce", "offer": {"type": "offer", "sdp": "v=0"}, "offer_id": "1"}),
+        json!({
+            "action": "announce",
+            "interval": 120,
+            "complete": 3,
+            "incomplete": 1,
+            "peers": [{"ip": "10.0.0.1", "port": 6881}, {"ip": "10.0.0.2"}, {"ip": "rtc", "port": 1}],
+        }),
+    ])
+    .await;
+    let mut request = TrackerRequest::new(PeerId(*b"00112233445566778899"), 6881, 100);
+    request.event = Some(Event::Started);
+    let response = TrackerClient::shared()
+        .announce(
+            &tracker.announce_url(),
+            InfoHash([0xff; 20]),
+            &request,
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.interval, 120);
+    assert_eq!((response.complete, response.incomplete), (Some(3), Some(1)));
+    assert_eq!(response.peers.addrs, ["10.0.0.1:6881".parse().unwrap()]);
+
+    let sent = &tracker.announces()[0];
+    assert_eq!(sent["action"], "announce");
+    assert_eq!(sent["info_hash"], "\u{ff}".repeat(20));
+    assert_eq!(sent["peer_id"], "00112233445566778899");
+    assert_eq!(sent["event"], "started");
+    assert_eq!(sent["left"], 100);
+    assert_eq!(sent["offers"], json!([]));
+
+    let refusing = MockWsTracker::serve(vec![json!({"failure reason": "unknown torrent"})]).await;
+    let e = TrackerClient::shared()
+        .announce(&refusing.announce_url(), InfoHash([1; 20]), &request, None)
+        .await
+        .unwrap_err();
+    assert_eq!(e.to_string(), "tracker refused announce: unknown torrent");
+}
+
+#[tokio::test]
+async fn secure_websocket_announces() {
+    use crate::mock::MockWsTracker;
+    use crate::peer::PeerId;
+    use crate::tracker::TrackerClient;
+    use serde_json::json;
+
+    let tracker = MockWsTracker::serve_tls(vec![json!({
+        "action": "announce",
+        "interval": 120,
+        "peers": [{"ip": "10.0.0.1", "port": 6881}],
+    })])
+    .await;
+    let request = TrackerRequest::new(PeerId(*b"00112233445566778899"), 6881, 100);
+    let url = tracker.announce_url();
+    assert!(url.starts_with("wss://"));
+
+    // the mock's certificate comes from a CA of our own, which only clients told to trust it do
+    let e = TrackerClient::shared()
+        .announce(&url, InfoHash([1; 20]), &request, None)
+        .await
+        .unwrap_err();
+    assert!(format!("{e:#}").contains("WebSocket handshake"), "{e:#}");
+    let client = TrackerClient::builder()
+        .root_certificate(include_bytes!("../../tests/fixtures/tls/ca.pem"))
+        .build()
+        .unwrap();
+    let response = client
+        .announce(&url, InfoHash([1; 20]), &request, None)
+        .await
+        .unwrap();
+    assert_eq!(response.peers.addrs, ["10.0.0.1:6881".parse().unwrap()]);
+    assert_eq!(tracker.announces().len(), 1);
+}